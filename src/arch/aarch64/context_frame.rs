@@ -5,7 +5,7 @@ use core::fmt::Formatter;
 use cortex_a::registers::*;
 
 use crate::{msr, mrs};
-use crate::arch::gic::GicState;
+use crate::arch::gic::{GicVersion, VgicCpuState};
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug)]
@@ -16,6 +16,8 @@ pub struct Aarch64ContextFrame {
     pub spsr: u64,
 }
 
+impl_snapshot_fields!(Aarch64ContextFrame { gpr, sp, elr, spsr });
+
 impl core::fmt::Display for Aarch64ContextFrame {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), core::fmt::Error> {
         for i in 0..31 {
@@ -100,11 +102,11 @@ pub struct VmContext {
     // generic timer
     pub cntvoff_el2: u64,
     cntp_cval_el0: u64,
-    cntv_cval_el0: u64,
+    pub cntv_cval_el0: u64,
     pub cntkctl_el1: u32,
     pub cntvct_el0: u64,
     cntp_ctl_el0: u32,
-    cntv_ctl_el0: u32,
+    pub cntv_ctl_el0: u32,
     cntp_tval_el0: u32,
     cntv_tval_el0: u32,
 
@@ -136,6 +138,7 @@ pub struct VmContext {
 
     // hypervisor context
     pub hcr_el2: u64,
+    pub mdcr_el2: u64,
     cptr_el2: u64,
     hstr_el2: u64,
     pub pmcr_el0: u64,
@@ -144,9 +147,17 @@ pub struct VmContext {
     // exception
     far_el2: u64,
     hpfar_el2: u64,
-    pub gic_state: GicState,
+    pub gic_state: VgicCpuState,
 }
 
+impl_snapshot_fields!(VmContext {
+    cntvoff_el2, cntp_cval_el0, cntv_cval_el0, cntkctl_el1, cntvct_el0, cntp_ctl_el0,
+    cntv_ctl_el0, cntp_tval_el0, cntv_tval_el0, vpidr_el2, vmpidr_el2, sp_el0, sp_el1, elr_el1,
+    spsr_el1, sctlr_el1, actlr_el1, cpacr_el1, ttbr0_el1, ttbr1_el1, tcr_el1, esr_el1, far_el1,
+    par_el1, mair_el1, amair_el1, vbar_el1, contextidr_el1, tpidr_el0, tpidr_el1, tpidrro_el0,
+    hcr_el2, mdcr_el2, cptr_el2, hstr_el2, pmcr_el0, vtcr_el2, far_el2, hpfar_el2, gic_state,
+});
+
 impl VmContext {
     pub fn default() -> VmContext {
         VmContext {
@@ -191,13 +202,14 @@ impl VmContext {
             hcr_el2: 0,
             cptr_el2: 0,
             hstr_el2: 0,
+            mdcr_el2: 0,
 
             // exception
             pmcr_el0: 0,
             vtcr_el2: 0,
             far_el2: 0,
             hpfar_el2: 0,
-            gic_state: GicState::default(),
+            gic_state: VgicCpuState::new(GicVersion::V2),
         }
     }
 
@@ -210,6 +222,7 @@ impl VmContext {
         self.cntkctl_el1 = 0;
         self.cntvct_el0 = 0;
         self.cntp_ctl_el0 = 0;
+        self.cntv_ctl_el0 = 0;
         self.vpidr_el2 = 0;
         self.vmpidr_el2 = 0;
         self.sp_el0 = 0;
@@ -235,6 +248,7 @@ impl VmContext {
         self.hcr_el2 = 0;
         self.cptr_el2 = 0;
         self.hstr_el2 = 0;
+        self.mdcr_el2 = 0;
         self.far_el2 = 0;
         self.hpfar_el2 = 0;
     }
@@ -275,6 +289,7 @@ impl VmContext {
         mrs!(self.pmcr_el0, PMCR_EL0);
         mrs!(self.vtcr_el2, VTCR_EL2);
         mrs!(self.hcr_el2, HCR_EL2);
+        mrs!(self.mdcr_el2, MDCR_EL2);
         // MRS!(self.cptr_el2, CPTR_EL2);
         // MRS!(self.hstr_el2, HSTR_EL2);
         // MRS!(self.far_el2, FAR_EL2);
@@ -323,6 +338,7 @@ impl VmContext {
 
         msr!(VTCR_EL2, self.vtcr_el2);
         msr!(HCR_EL2, self.hcr_el2);
+        msr!(MDCR_EL2, self.mdcr_el2);
         // MSR!(VPIDR_EL2, self.vpidr_el2, "x");
         msr!(VMPIDR_EL2, self.vmpidr_el2);
         msr!(CNTVOFF_EL2, self.cntvoff_el2);
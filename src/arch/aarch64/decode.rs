@@ -0,0 +1,89 @@
+use crate::arch::emu::EmuContext;
+use crate::{GuestPhysAddr, HyperError, HyperResult};
+
+/// Base register update of a pre- or post-indexed load/store.
+#[derive(Debug, Clone, Copy)]
+pub struct Writeback {
+    /// The base register.
+    pub reg: usize,
+    /// Signed offset added to the base register.
+    pub offset: i64,
+}
+
+fn sign_extend(value: u32, bits: u32) -> i64 {
+    let shift = 64 - bits;
+    ((value as i64) << shift) >> shift
+}
+
+/// Decodes a single-register load or store instruction which caused a data
+/// abort with `ESR_EL2.ISS.ISV == 0`, i.e. without a valid syndrome.
+/// (ARM DDI 0487, C4.1.66 "Loads and Stores")
+///
+/// Pair, exclusive and SIMD&FP accesses cannot be emulated and are rejected.
+pub fn decode_data_abort_instruction(
+    inst: u32,
+    address: GuestPhysAddr,
+) -> HyperResult<(EmuContext, Option<Writeback>)> {
+    let size = (inst >> 30) & 0b11;
+    let opc = (inst >> 22) & 0b11;
+    let rt = (inst & 0x1f) as usize;
+    let rn = ((inst >> 5) & 0x1f) as usize;
+
+    // Load/store register, with bits [29:27] == 0b111 and [25:24] being
+    // 0b00 (unscaled, indexed, unprivileged, register offset) or 0b01
+    // (unsigned immediate).
+    let is_unsigned_imm = inst & 0x3b00_0000 == 0x3900_0000;
+    let is_other_form = inst & 0x3b00_0000 == 0x3800_0000;
+    if !is_unsigned_imm && !is_other_form {
+        return Err(HyperError::InvalidInstruction);
+    }
+    if inst & (1 << 26) != 0 {
+        // SIMD&FP registers
+        return Err(HyperError::NotSupported);
+    }
+
+    let mut writeback = None;
+    if is_other_form {
+        // 0b00: unscaled immediate, 0b01: post-index, 0b10: register offset
+        // (bit 21 set) or unprivileged, 0b11: pre-index
+        let kind = (inst >> 10) & 0b11;
+        if inst & (1 << 21) != 0 && kind != 0b10 {
+            // Atomic memory operations and pointer authentication loads
+            return Err(HyperError::NotSupported);
+        }
+        if kind == 0b01 || kind == 0b11 {
+            if rn == 31 {
+                // Writeback to SP is not supported.
+                return Err(HyperError::NotSupported);
+            }
+            writeback = Some(Writeback {
+                reg: rn,
+                offset: sign_extend((inst >> 12) & 0x1ff, 9),
+            });
+        }
+    }
+
+    let width = 1 << size;
+    let (write, sign_ext, reg_width) = match (size, opc) {
+        (_, 0b00) => (true, false, if size == 3 { 8 } else { 4 }),
+        (_, 0b01) => (false, false, if size == 3 { 8 } else { 4 }),
+        // LDRSB, LDRSH and LDRSW to an X register
+        (0 | 1 | 2, 0b10) => (false, true, 8),
+        // LDRSB and LDRSH to a W register
+        (0 | 1, 0b11) => (false, true, 4),
+        // PRFM and unallocated encodings
+        _ => return Err(HyperError::InvalidInstruction),
+    };
+
+    Ok((
+        EmuContext {
+            address,
+            width,
+            write,
+            sign_ext,
+            reg: rt,
+            reg_width,
+        },
+        writeback,
+    ))
+}
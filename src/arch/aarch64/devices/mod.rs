@@ -0,0 +1,3 @@
+mod pl011;
+
+pub use pl011::{Pl011, PL011_SIZE};
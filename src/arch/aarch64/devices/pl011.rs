@@ -0,0 +1,291 @@
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use core::ops::Range;
+
+use crate::devices::{CharBackend, MmioDevice};
+use crate::{GuestPhysAddr, HyperError, HyperResult};
+
+/// Size of the PL011 register window.
+pub const PL011_SIZE: usize = 0x1000;
+
+const PL011_FIFO_SIZE: usize = 16;
+
+const UARTDR: usize = 0x000;
+const UARTRSR: usize = 0x004;
+const UARTFR: usize = 0x018;
+const UARTILPR: usize = 0x020;
+const UARTIBRD: usize = 0x024;
+const UARTFBRD: usize = 0x028;
+const UARTLCR_H: usize = 0x02c;
+const UARTCR: usize = 0x030;
+const UARTIFLS: usize = 0x034;
+const UARTIMSC: usize = 0x038;
+const UARTRIS: usize = 0x03c;
+const UARTMIS: usize = 0x040;
+const UARTICR: usize = 0x044;
+const UARTDMACR: usize = 0x048;
+const UARTPERIPHID0: usize = 0xfe0;
+
+/// Peripheral and PrimeCell identification registers, 0xFE0 - 0xFFC.
+const PL011_ID: [u8; 8] = [0x11, 0x10, 0x14, 0x00, 0x0d, 0xf0, 0x05, 0xb1];
+
+const FR_RXFE: u32 = 1 << 4;
+const FR_RXFF: u32 = 1 << 6;
+const FR_TXFE: u32 = 1 << 7;
+
+const LCR_H_FEN: u32 = 1 << 4;
+
+const INT_RX: u32 = 1 << 4;
+const INT_TX: u32 = 1 << 5;
+const INT_RT: u32 = 1 << 6;
+const INT_OE: u32 = 1 << 10;
+const INT_ALL: u32 = 0x7ff;
+
+const DR_OE: u32 = 1 << 11;
+const RSR_OE: u32 = 1 << 3;
+
+/// A virtual ARM PrimeCell PL011 UART. (ARM DDI 0183G)
+///
+/// Transmitted bytes are written to a [`CharBackend`] immediately. Received
+/// bytes are fetched from the backend whenever the guest looks at the
+/// receiver, and by [`Pl011::poll`]. The combined interrupt output is
+/// reported by [`Pl011::irq_level`]. [`VM::attach_pl011`](crate::VM::attach_pl011)
+/// does both and drives an SPI of the virtual GIC with it.
+pub struct Pl011 {
+    base: GuestPhysAddr,
+    backend: Box<dyn CharBackend>,
+    rx_fifo: VecDeque<u8>,
+    rsr: u32,
+    ilpr: u32,
+    ibrd: u32,
+    fbrd: u32,
+    lcr_h: u32,
+    cr: u32,
+    ifls: u32,
+    imsc: u32,
+    ris: u32,
+    dmacr: u32,
+}
+
+// The base and the backend are set up by the host.
+impl_snapshot_fields!(Pl011 {
+    rx_fifo,
+    rsr,
+    ilpr,
+    ibrd,
+    fbrd,
+    lcr_h,
+    cr,
+    ifls,
+    imsc,
+    ris,
+    dmacr,
+});
+
+impl Pl011 {
+    /// Create a PL011 at guest physical address `base`, connected to `backend`.
+    pub fn new(base: GuestPhysAddr, backend: Box<dyn CharBackend>) -> Self {
+        Self {
+            base,
+            backend,
+            rx_fifo: VecDeque::with_capacity(PL011_FIFO_SIZE),
+            rsr: 0,
+            ilpr: 0,
+            ibrd: 0,
+            fbrd: 0,
+            lcr_h: 0,
+            // UARTEN is clear, TXE and RXE are set at reset.
+            cr: 0x300,
+            // Both FIFO levels at 1/2.
+            ifls: 0x12,
+            imsc: 0,
+            ris: 0,
+            dmacr: 0,
+        }
+    }
+
+    /// The byte stream the UART sends the output of the guest to.
+    pub fn backend_mut(&mut self) -> &mut dyn CharBackend {
+        self.backend.as_mut()
+    }
+
+    fn fifo_enabled(&self) -> bool {
+        self.lcr_h & LCR_H_FEN != 0
+    }
+
+    fn rx_capacity(&self) -> usize {
+        if self.fifo_enabled() {
+            PL011_FIFO_SIZE
+        } else {
+            1
+        }
+    }
+
+    fn rx_trigger_level(&self) -> usize {
+        if !self.fifo_enabled() {
+            return 1;
+        }
+        match (self.ifls >> 3) & 0x7 {
+            0 => 2,
+            1 => 4,
+            2 => 8,
+            3 => 12,
+            _ => 14,
+        }
+    }
+
+    fn update_rx_interrupt(&mut self) {
+        if self.rx_fifo.len() >= self.rx_trigger_level() {
+            self.ris |= INT_RX;
+        } else {
+            self.ris &= !INT_RX;
+        }
+    }
+
+    /// Fetch received bytes from the backend. Sets the receive timeout
+    /// interrupt if data below the trigger level is left unread since the
+    /// last poll.
+    pub fn poll(&mut self) {
+        let count = self.fill_rx_fifo();
+        if count == 0 && !self.rx_fifo.is_empty() && self.ris & INT_RX == 0 {
+            self.ris |= INT_RT;
+        }
+    }
+
+    fn fill_rx_fifo(&mut self) -> usize {
+        let room = self.rx_capacity().saturating_sub(self.rx_fifo.len());
+        if room == 0 {
+            return 0;
+        }
+        let mut buf = [0u8; PL011_FIFO_SIZE];
+        let count = self.backend.read(&mut buf[..room]);
+        self.rx_fifo.extend(&buf[..count]);
+        self.update_rx_interrupt();
+        count
+    }
+
+    /// The level of the combined interrupt output (UARTINTR).
+    pub fn irq_level(&self) -> bool {
+        self.ris & self.imsc != 0
+    }
+
+    fn read_data(&mut self) -> u32 {
+        if self.rx_fifo.is_empty() {
+            self.fill_rx_fifo();
+        }
+        let mut value = self.rx_fifo.pop_front().unwrap_or(0) as u32;
+        if self.rsr & RSR_OE != 0 {
+            value |= DR_OE;
+        }
+        self.ris &= !INT_RT;
+        self.update_rx_interrupt();
+        value
+    }
+
+    fn write_data(&mut self, value: u32) {
+        let byte = value as u8;
+        // Loopback enable
+        if self.cr & (1 << 7) != 0 {
+            if self.rx_fifo.len() >= self.rx_capacity() {
+                self.rsr |= RSR_OE;
+                self.ris |= INT_OE;
+            } else {
+                self.rx_fifo.push_back(byte);
+            }
+            self.update_rx_interrupt();
+        } else if self.backend.write(&[byte]) == 0 {
+            warn!("PL011: backend is full, dropping byte {:#x}", byte);
+        }
+        // The transmit FIFO drains immediately.
+        self.ris |= INT_TX;
+    }
+
+    fn flags(&mut self) -> u32 {
+        if self.rx_fifo.is_empty() {
+            self.fill_rx_fifo();
+        }
+        let mut flags = FR_TXFE;
+        if self.rx_fifo.is_empty() {
+            flags |= FR_RXFE;
+        }
+        if self.rx_fifo.len() >= self.rx_capacity() {
+            flags |= FR_RXFF;
+        }
+        flags
+    }
+
+    fn read_register(&mut self, offset: usize) -> u32 {
+        match offset {
+            UARTDR => self.read_data(),
+            UARTRSR => self.rsr,
+            UARTFR => self.flags(),
+            UARTILPR => self.ilpr,
+            UARTIBRD => self.ibrd,
+            UARTFBRD => self.fbrd,
+            UARTLCR_H => self.lcr_h,
+            UARTCR => self.cr,
+            UARTIFLS => self.ifls,
+            UARTIMSC => self.imsc,
+            UARTRIS => self.ris,
+            UARTMIS => self.ris & self.imsc,
+            UARTDMACR => self.dmacr,
+            UARTPERIPHID0..=0xffc => PL011_ID[(offset - UARTPERIPHID0) >> 2] as u32,
+            _ => {
+                warn!("PL011: read of unknown register {:#x}", offset);
+                0
+            }
+        }
+    }
+
+    fn write_register(&mut self, offset: usize, value: u32) {
+        match offset {
+            UARTDR => self.write_data(value),
+            // Writing UARTECR clears the error flags.
+            UARTRSR => self.rsr = 0,
+            UARTILPR => self.ilpr = value & 0xff,
+            UARTIBRD => self.ibrd = value & 0xffff,
+            UARTFBRD => self.fbrd = value & 0x3f,
+            UARTLCR_H => {
+                if (self.lcr_h ^ value) & LCR_H_FEN != 0 {
+                    // Changing the FIFO mode flushes the receive FIFO.
+                    self.rx_fifo.clear();
+                }
+                self.lcr_h = value & 0xff;
+                self.update_rx_interrupt();
+            }
+            UARTCR => self.cr = value & 0xff87,
+            UARTIFLS => {
+                self.ifls = value & 0x3f;
+                self.update_rx_interrupt();
+            }
+            UARTIMSC => self.imsc = value & INT_ALL,
+            UARTICR => self.ris &= !value,
+            UARTDMACR => self.dmacr = value & 0x7,
+            // Flag, status and identification registers are read-only.
+            _ => warn!("PL011: write to read-only or unknown register {:#x}", offset),
+        }
+    }
+}
+
+impl MmioDevice for Pl011 {
+    fn mmio_range(&self) -> Range<GuestPhysAddr> {
+        self.base..self.base + PL011_SIZE
+    }
+
+    fn read(&mut self, addr: GuestPhysAddr, width: usize) -> HyperResult<u64> {
+        let offset = addr - self.base;
+        if width > 4 || offset & 0x3 != 0 {
+            return Err(HyperError::InvalidParam);
+        }
+        Ok(self.read_register(offset) as u64)
+    }
+
+    fn write(&mut self, addr: GuestPhysAddr, width: usize, value: u64) -> HyperResult {
+        let offset = addr - self.base;
+        if width > 4 || offset & 0x3 != 0 {
+            return Err(HyperError::InvalidParam);
+        }
+        self.write_register(offset, value as u32);
+        Ok(())
+    }
+}
@@ -0,0 +1,197 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::Range;
+use spin::{Mutex, Once};
+
+use cortex_a::registers::VTTBR_EL2;
+use tock_registers::interfaces::Readable;
+
+use crate::arch::ContextFrame;
+use crate::mrs;
+use crate::devices::MmioDevice;
+use crate::traits::ContextFrameTrait;
+use crate::{GuestPhysAddr, HostPhysAddr, HostVirtAddr, HyperCraftHal, HyperError, HyperResult};
+
+/// A shared emulated device.
+pub type EmuDevice = Arc<Mutex<dyn MmioDevice + Send>>;
+
+/// Description of a trapped guest memory access.
+#[derive(Debug, Clone, Copy)]
+pub struct EmuContext {
+    /// Faulting intermediate physical address.
+    pub address: GuestPhysAddr,
+    /// Access width in bytes.
+    pub width: usize,
+    /// Whether the access is a store.
+    pub write: bool,
+    /// Whether a load is sign-extended into the register.
+    pub sign_ext: bool,
+    /// The transfer register, 31 stands for XZR.
+    pub reg: usize,
+    /// Width of the transfer register in bytes (4 or 8).
+    pub reg_width: usize,
+}
+
+struct EmuDevEntry {
+    vm_id: usize,
+    ipa: GuestPhysAddr,
+    size: usize,
+    dev: EmuDevice,
+}
+
+impl EmuDevEntry {
+    fn overlaps(&self, vm_id: usize, range: &Range<GuestPhysAddr>) -> bool {
+        self.vm_id == vm_id && range.start < self.ipa + self.size && self.ipa < range.end
+    }
+}
+
+static EMU_DEVS_LIST: Mutex<Vec<EmuDevEntry>> = Mutex::new(Vec::new());
+
+/// The ranges of guest RAM backed on demand, by VM id.
+static LAZY_RAM_LIST: Mutex<Vec<(usize, Range<GuestPhysAddr>)>> = Mutex::new(Vec::new());
+
+/// `HyperCraftHal::phys_to_virt` of the host, for reading guest instructions
+/// from the (non-generic) exception handlers.
+static HOST_PHYS_TO_VIRT: Once<fn(HostPhysAddr) -> HostVirtAddr> = Once::new();
+
+/// Record the host address translation used by the emulation path.
+pub(crate) fn emu_init<H: HyperCraftHal>() {
+    HOST_PHYS_TO_VIRT.call_once(|| H::phys_to_virt);
+}
+
+/// Read the 32-bit guest instruction at host physical address `pa`.
+pub(crate) fn emu_read_guest_instruction(pa: HostPhysAddr) -> Option<u32> {
+    let phys_to_virt = HOST_PHYS_TO_VIRT.get()?;
+    // Instructions are always 4-byte aligned in AArch64 state.
+    if pa & 0x3 != 0 {
+        return None;
+    }
+    Some(unsafe { core::ptr::read_volatile(phys_to_virt(pa) as *const u32) })
+}
+
+/// The VMID of the running guest, as programmed into `VTTBR_EL2` by `VM::run`.
+pub(crate) fn active_vm_id() -> usize {
+    (VTTBR_EL2.get() >> 48) as usize
+}
+
+/// The id of the running vCPU, from the affinity level 0 of `VMPIDR_EL2`.
+pub(crate) fn current_vcpu_id() -> usize {
+    let vmpidr: u64;
+    mrs!(vmpidr, VMPIDR_EL2);
+    (vmpidr & 0xff) as usize
+}
+
+/// Whether `range` overlaps a device registered for VM `vm_id`.
+pub(crate) fn emu_overlaps(vm_id: usize, range: &Range<GuestPhysAddr>) -> bool {
+    EMU_DEVS_LIST.lock().iter().any(|entry| entry.overlaps(vm_id, range))
+}
+
+/// Register `dev` to handle stage-2 data aborts of VM `vm_id` in its MMIO range.
+pub fn emu_register_dev(vm_id: usize, dev: EmuDevice) -> HyperResult {
+    let range = dev.lock().mmio_range();
+    let mut list = EMU_DEVS_LIST.lock();
+    if list.iter().any(|entry| entry.overlaps(vm_id, &range)) {
+        warn!(
+            "emu_register_dev: range {:#x?} of VM {} already registered",
+            range, vm_id
+        );
+        return Err(HyperError::InvalidParam);
+    }
+    list.push(EmuDevEntry {
+        vm_id,
+        ipa: range.start,
+        size: range.end - range.start,
+        dev,
+    });
+    Ok(())
+}
+
+/// Remove the device of VM `vm_id` registered at `ipa`.
+pub fn emu_remove_dev(vm_id: usize, ipa: GuestPhysAddr) -> HyperResult {
+    let mut list = EMU_DEVS_LIST.lock();
+    let index = list
+        .iter()
+        .position(|entry| entry.vm_id == vm_id && entry.ipa == ipa)
+        .ok_or(HyperError::NotFound)?;
+    list.remove(index);
+    Ok(())
+}
+
+/// Remove all devices and lazy RAM ranges of VM `vm_id`.
+pub fn emu_remove_vm_devs(vm_id: usize) {
+    EMU_DEVS_LIST.lock().retain(|entry| entry.vm_id != vm_id);
+    LAZY_RAM_LIST.lock().retain(|(id, _)| *id != vm_id);
+}
+
+/// Make the stage-2 translation faults of VM `vm_id` in `range` exit as
+/// [`VmExitInfo::LazyRamFault`](crate::VmExitInfo::LazyRamFault) rather
+/// than MMIO accesses.
+pub(crate) fn emu_register_lazy_ram(vm_id: usize, range: Range<GuestPhysAddr>) {
+    LAZY_RAM_LIST.lock().push((vm_id, range));
+}
+
+/// Whether `address` of VM `vm_id` is RAM backed on demand.
+pub(crate) fn emu_is_lazy_ram(vm_id: usize, address: GuestPhysAddr) -> bool {
+    LAZY_RAM_LIST
+        .lock()
+        .iter()
+        .any(|(id, range)| *id == vm_id && range.contains(&address))
+}
+
+fn emu_find_dev(vm_id: usize, address: GuestPhysAddr) -> Option<EmuDevice> {
+    EMU_DEVS_LIST
+        .lock()
+        .iter()
+        .find(|entry| {
+            entry.vm_id == vm_id && address >= entry.ipa && address < entry.ipa + entry.size
+        })
+        .map(|entry| entry.dev.clone())
+}
+
+fn width_mask(bytes: usize) -> u64 {
+    if bytes >= 8 {
+        u64::MAX
+    } else {
+        (1 << (bytes * 8)) - 1
+    }
+}
+
+/// The value a store described by `emu_ctx` writes, from the guest
+/// registers in `ctx`.
+pub(crate) fn emu_store_value(emu_ctx: &EmuContext, ctx: &ContextFrame) -> u64 {
+    if emu_ctx.reg == 31 {
+        return 0;
+    }
+    ctx.gpr(emu_ctx.reg) as u64 & width_mask(emu_ctx.width)
+}
+
+/// Complete the load described by `emu_ctx` with `value`, sign- or
+/// zero-extended to the register width. Writes to a W register clear the
+/// upper 32 bits of the X register.
+pub(crate) fn emu_complete_load(emu_ctx: &EmuContext, ctx: &mut ContextFrame, value: u64) {
+    let mut value = value & width_mask(emu_ctx.width);
+    if emu_ctx.sign_ext && emu_ctx.width < 8 {
+        let shift = 64 - emu_ctx.width * 8;
+        value = (((value << shift) as i64) >> shift) as u64;
+    }
+    value &= width_mask(emu_ctx.reg_width);
+    if emu_ctx.reg != 31 {
+        ctx.set_gpr(emu_ctx.reg, value as usize);
+    }
+}
+
+/// Forward the access described by `emu_ctx` to the registered device, and
+/// transfer the value from or to the guest registers in `ctx`. Returns
+/// `NotFound` if no device claims the address, so that the host can
+/// emulate it.
+pub fn emu_handler(emu_ctx: &EmuContext, ctx: &mut ContextFrame) -> HyperResult {
+    let dev = emu_find_dev(active_vm_id(), emu_ctx.address).ok_or(HyperError::NotFound)?;
+    let mut dev = dev.lock();
+    if emu_ctx.write {
+        dev.write(emu_ctx.address, emu_ctx.width, emu_store_value(emu_ctx, ctx))
+    } else {
+        let value = dev.read(emu_ctx.address, emu_ctx.width)?;
+        emu_complete_load(emu_ctx, ctx, value);
+        Ok(())
+    }
+}
@@ -14,7 +14,13 @@ use tock_registers::interfaces::*;
 
 use crate::mrs;
 use crate::arch::ContextFrame;
-use crate::arch::sync::{data_abort_handler, hvc_handler};
+use crate::arch::hvc::{current_vcpu_regs, exit_to_host};
+use crate::arch::sync::{
+    data_abort_handler, fp_trap_handler, hvc_handler, instruction_abort_handler, smc_handler,
+    sysreg_handler, wfx_handler,
+};
+use crate::arch::vmexit::VmExitInfo;
+use crate::arch::vgic::vgic_flush_current;
 use crate::traits::ContextFrameTrait;
 
 //global_asm!(include_str!("exception.S"));
@@ -85,6 +91,23 @@ fn translate_far_to_hpfar(far: usize) -> Result<usize, ()> {
     }
 }
 
+/// Translate the guest virtual address `va` through both stages of the
+/// current guest translation regime, for reads at EL1.
+pub fn translate_guest_va_to_pa(va: usize) -> Option<usize> {
+    use cortex_a::registers::PAR_EL1;
+
+    let par = PAR_EL1.get();
+    arm_at!("s12e1r", va);
+    let tmp = PAR_EL1.get();
+    PAR_EL1.set(par);
+    if (tmp & PAR_EL1::F::TranslationAborted.value) != 0 {
+        None
+    } else {
+        let mask = ((1 << (52 - 12)) - 1) << 12;
+        Some((tmp & mask) as usize | (va & 0xfff))
+    }
+}
+
 // addr be ipa
 #[inline(always)]
 pub fn exception_fault_addr() -> usize {
@@ -121,6 +144,12 @@ pub fn exception_data_abort_handleable() -> bool {
     (!(exception_iss() & (1 << 10)) | (exception_iss() & (1 << 24))) != 0
 }
 
+/// Whether the ISS holds a valid instruction syndrome (ISV).
+#[inline(always)]
+pub fn exception_data_abort_syndrome_valid() -> bool {
+    (exception_iss() & (1 << 24)) != 0
+}
+
 #[inline(always)]
 pub fn exception_data_abort_is_translate_fault() -> bool {
     (exception_iss() & 0b111111 & (0xf << 2)) == 4
@@ -167,15 +196,30 @@ pub extern "C" fn lower_aarch64_synchronous(ctx: &mut ContextFrame) {
     debug!("lower_aarch64_synchronous exception class:0x{:X}", exception_class());
     // current_cpu().set_context_addr(ctx);
 
-    match exception_class() {
+    let exit = match exception_class() {
+        0x01 => {
+            wfx_handler(ctx)
+        }
+        0x07 | 0x19 => {
+            fp_trap_handler()
+        }
+        0x20 => {
+            instruction_abort_handler(ctx)
+        }
         0x24 => {
             // info!("Core[{}] data_abort_handler", cpu_id());
-            data_abort_handler(ctx);
+            data_abort_handler(ctx)
         }
         0x16 => {
-            hvc_handler(ctx);
+            hvc_handler(ctx)
+        }
+        0x17 => {
+            smc_handler(ctx)
+        }
+        0x18 => {
+            sysreg_handler(ctx);
+            None
         }
-        // 0x18 todo？
         _ => {   
             panic!(
                 "handler not presents for EC_{} @ipa 0x{:x}, @pc 0x{:x}, @esr 0x{:x}, @sctlr_el1 0x{:x}, @vttbr_el2 0x{:x}, ",
@@ -187,5 +231,21 @@ pub extern "C" fn lower_aarch64_synchronous(ctx: &mut ContextFrame) {
                 cortex_a::registers::VTTBR_EL2.get() as usize,
             );
         },
+    };
+    match exit {
+        Some(info) => exit_to_host(ctx, info),
+        // Deliver interrupts made pending while handling the trap.
+        None => vgic_flush_current(),
+    }
+}
+
+/// deal with lower aarch64 irq exception
+///
+/// Physical interrupts are routed to EL2 while a guest with a virtual GIC
+/// runs. The vCPU returns to the host, which takes the interrupt at EL1.
+#[no_mangle]
+pub extern "C" fn lower_aarch64_irq(ctx: &mut ContextFrame) {
+    if current_vcpu_regs().is_some() {
+        exit_to_host(ctx, VmExitInfo::Irq);
     }
 }
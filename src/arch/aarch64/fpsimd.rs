@@ -0,0 +1,338 @@
+use alloc::vec::Vec;
+
+use crate::snapshot::{Snapshot, SnapshotReader, SnapshotWriter};
+use crate::{mrs, msr, HyperError, HyperResult};
+
+/// CPTR_EL2.TFP: trap FP/SIMD accesses, at EL2 too.
+const CPTR_EL2_TFP: u64 = 1 << 10;
+/// CPTR_EL2.TZ: trap SVE accesses, at EL2 too.
+const CPTR_EL2_TZ: u64 = 1 << 8;
+/// The RES1 bits of CPTR_EL2 when HCR_EL2.E2H is 0.
+const CPTR_EL2_RES1: u64 = 0x32ff;
+
+/// The largest SVE vector length in bytes.
+const SVE_VL_MAX: usize = 256;
+/// The size of the SVE registers at the largest vector length: 32 Z
+/// registers, and 16 P registers plus FFR of `SVE_VL_MAX / 8` bytes.
+const SVE_AREA_MAX: usize = SVE_VL_MAX * 32 + SVE_VL_MAX / 8 * 17;
+
+/// The FP/SIMD registers of a vCPU or of the host. When the CPU implements
+/// SVE the Z, P and FFR registers are kept instead of V0-V31, whose contents
+/// they include.
+///
+/// The registers are switched lazily: the guest runs with `CPTR_EL2.TFP`
+/// set, and its state is only loaded on the first FP/SIMD or SVE access.
+/// This relies on the hypervisor itself not using FP/SIMD registers, as
+/// with the `aarch64-unknown-none-softfloat` target.
+#[repr(C, align(16))]
+#[derive(Clone, Debug)]
+pub struct FpContext {
+    v: [u128; 32],
+    fpcr: u64,
+    fpsr: u64,
+    zcr_el1: u64,
+    /// Z0-Z31, P0-P15 and FFR, for the vector length set in `ZCR_EL2`.
+    /// Allocated for the largest vector length when the context is created,
+    /// as the registers are switched at EL2, which cannot allocate.
+    sve: Vec<u8>,
+}
+
+impl Default for FpContext {
+    fn default() -> Self {
+        Self {
+            v: [0; 32],
+            fpcr: 0,
+            fpsr: 0,
+            zcr_el1: 0,
+            sve: if cpu_has_sve() {
+                vec![0; SVE_AREA_MAX]
+            } else {
+                Vec::new()
+            },
+        }
+    }
+}
+
+impl Snapshot for FpContext {
+    fn save(&self, w: &mut SnapshotWriter) {
+        self.v.save(w);
+        self.fpcr.save(w);
+        self.fpsr.save(w);
+        self.zcr_el1.save(w);
+        self.sve.save(w);
+    }
+
+    /// The SVE registers can only be restored on a CPU which implements SVE
+    /// too, and the other way round.
+    fn restore(&mut self, r: &mut SnapshotReader<'_>) -> HyperResult {
+        self.v.restore(r)?;
+        self.fpcr.restore(r)?;
+        self.fpsr.restore(r)?;
+        self.zcr_el1.restore(r)?;
+        let mut sve = Vec::new();
+        sve.restore(r)?;
+        if sve.len() != self.sve.len() {
+            return Err(HyperError::NotSupported);
+        }
+        self.sve = sve;
+        Ok(())
+    }
+}
+
+/// Whether the CPU implements SVE, from `ID_AA64PFR0_EL1.SVE`.
+fn cpu_has_sve() -> bool {
+    let pfr0: u64;
+    mrs!(pfr0, ID_AA64PFR0_EL1);
+    (pfr0 >> 32) & 0xf != 0
+}
+
+/// The maximum SVE vector length of EL2 and below in bytes, from
+/// `ZCR_EL2.LEN`.
+fn sve_vector_bytes() -> usize {
+    let zcr: u64;
+    mrs!(zcr, S3_4_C1_C2_0);
+    ((zcr & 0xf) as usize + 1) * 16
+}
+
+impl FpContext {
+    /// Split the SVE area for vector length `vl` in bytes: 32 Z registers,
+    /// and 16 P registers plus FFR of `vl / 8` bytes. It is sized for the
+    /// largest vector length.
+    fn sve_regions(&mut self, vl: usize) -> (*mut u8, *mut u8, *mut u8) {
+        let (z_size, p_size) = (vl * 32, vl / 8 * 16);
+        assert!(z_size + p_size + vl / 8 <= self.sve.len());
+        let base = self.sve.as_mut_ptr();
+        unsafe { (base, base.add(z_size), base.add(z_size + p_size)) }
+    }
+
+    /// Save the FP/SIMD registers of the CPU. FP/SIMD accesses must not
+    /// be trapped.
+    pub fn save(&mut self) {
+        let (fpcr, fpsr): (u64, u64);
+        unsafe {
+            core::arch::asm!(
+                ".arch_extension fp",
+                "mrs {fpcr}, fpcr",
+                "mrs {fpsr}, fpsr",
+                fpcr = out(reg) fpcr,
+                fpsr = out(reg) fpsr,
+                options(nomem, nostack)
+            );
+        }
+        self.fpcr = fpcr;
+        self.fpsr = fpsr;
+        if cpu_has_sve() {
+            mrs!(self.zcr_el1, S3_0_C1_C2_0);
+            let (z, p, ffr) = self.sve_regions(sve_vector_bytes());
+            unsafe { sve_save(z, p, ffr) };
+        } else {
+            unsafe { fpsimd_save(self.v.as_mut_ptr()) };
+        }
+    }
+
+    /// Load this state into the FP/SIMD registers of the CPU. FP/SIMD
+    /// accesses must not be trapped.
+    pub fn restore(&mut self) {
+        unsafe {
+            core::arch::asm!(
+                ".arch_extension fp",
+                "msr fpcr, {fpcr}",
+                "msr fpsr, {fpsr}",
+                fpcr = in(reg) self.fpcr,
+                fpsr = in(reg) self.fpsr,
+                options(nomem, nostack)
+            );
+        }
+        if cpu_has_sve() {
+            msr!(S3_0_C1_C2_0, self.zcr_el1);
+            let (z, p, ffr) = self.sve_regions(sve_vector_bytes());
+            unsafe { sve_restore(z, p, ffr) };
+        } else {
+            unsafe { fpsimd_restore(self.v.as_ptr()) };
+        }
+    }
+}
+
+/// Trap the guest's FP/SIMD and SVE accesses, so that its state is loaded
+/// on first use.
+pub(crate) fn fp_trap_guest() {
+    msr!(CPTR_EL2, CPTR_EL2_RES1 | CPTR_EL2_TFP | CPTR_EL2_TZ);
+}
+
+/// Stop trapping FP/SIMD and SVE accesses.
+pub(crate) fn fp_untrap() {
+    msr!(CPTR_EL2, CPTR_EL2_RES1);
+    unsafe { core::arch::asm!("isb") };
+}
+
+unsafe fn fpsimd_save(v: *mut u128) {
+    core::arch::asm!(
+        ".arch_extension fp",
+        ".arch_extension simd",
+        "stp q0, q1, [{v}, #0x0]",
+        "stp q2, q3, [{v}, #0x20]",
+        "stp q4, q5, [{v}, #0x40]",
+        "stp q6, q7, [{v}, #0x60]",
+        "stp q8, q9, [{v}, #0x80]",
+        "stp q10, q11, [{v}, #0xa0]",
+        "stp q12, q13, [{v}, #0xc0]",
+        "stp q14, q15, [{v}, #0xe0]",
+        "stp q16, q17, [{v}, #0x100]",
+        "stp q18, q19, [{v}, #0x120]",
+        "stp q20, q21, [{v}, #0x140]",
+        "stp q22, q23, [{v}, #0x160]",
+        "stp q24, q25, [{v}, #0x180]",
+        "stp q26, q27, [{v}, #0x1a0]",
+        "stp q28, q29, [{v}, #0x1c0]",
+        "stp q30, q31, [{v}, #0x1e0]",
+        v = in(reg) v,
+        options(nostack)
+    );
+}
+
+unsafe fn fpsimd_restore(v: *const u128) {
+    core::arch::asm!(
+        ".arch_extension fp",
+        ".arch_extension simd",
+        "ldp q0, q1, [{v}, #0x0]",
+        "ldp q2, q3, [{v}, #0x20]",
+        "ldp q4, q5, [{v}, #0x40]",
+        "ldp q6, q7, [{v}, #0x60]",
+        "ldp q8, q9, [{v}, #0x80]",
+        "ldp q10, q11, [{v}, #0xa0]",
+        "ldp q12, q13, [{v}, #0xc0]",
+        "ldp q14, q15, [{v}, #0xe0]",
+        "ldp q16, q17, [{v}, #0x100]",
+        "ldp q18, q19, [{v}, #0x120]",
+        "ldp q20, q21, [{v}, #0x140]",
+        "ldp q22, q23, [{v}, #0x160]",
+        "ldp q24, q25, [{v}, #0x180]",
+        "ldp q26, q27, [{v}, #0x1a0]",
+        "ldp q28, q29, [{v}, #0x1c0]",
+        "ldp q30, q31, [{v}, #0x1e0]",
+        v = in(reg) v,
+        options(nostack)
+    );
+}
+
+unsafe fn sve_save(z: *mut u8, p: *mut u8, ffr: *mut u8) {
+    core::arch::asm!(
+        ".arch_extension sve",
+        "str z0, [{z}, #0, MUL VL]",
+        "str z1, [{z}, #1, MUL VL]",
+        "str z2, [{z}, #2, MUL VL]",
+        "str z3, [{z}, #3, MUL VL]",
+        "str z4, [{z}, #4, MUL VL]",
+        "str z5, [{z}, #5, MUL VL]",
+        "str z6, [{z}, #6, MUL VL]",
+        "str z7, [{z}, #7, MUL VL]",
+        "str z8, [{z}, #8, MUL VL]",
+        "str z9, [{z}, #9, MUL VL]",
+        "str z10, [{z}, #10, MUL VL]",
+        "str z11, [{z}, #11, MUL VL]",
+        "str z12, [{z}, #12, MUL VL]",
+        "str z13, [{z}, #13, MUL VL]",
+        "str z14, [{z}, #14, MUL VL]",
+        "str z15, [{z}, #15, MUL VL]",
+        "str z16, [{z}, #16, MUL VL]",
+        "str z17, [{z}, #17, MUL VL]",
+        "str z18, [{z}, #18, MUL VL]",
+        "str z19, [{z}, #19, MUL VL]",
+        "str z20, [{z}, #20, MUL VL]",
+        "str z21, [{z}, #21, MUL VL]",
+        "str z22, [{z}, #22, MUL VL]",
+        "str z23, [{z}, #23, MUL VL]",
+        "str z24, [{z}, #24, MUL VL]",
+        "str z25, [{z}, #25, MUL VL]",
+        "str z26, [{z}, #26, MUL VL]",
+        "str z27, [{z}, #27, MUL VL]",
+        "str z28, [{z}, #28, MUL VL]",
+        "str z29, [{z}, #29, MUL VL]",
+        "str z30, [{z}, #30, MUL VL]",
+        "str z31, [{z}, #31, MUL VL]",
+        "str p0, [{p}, #0, MUL VL]",
+        "str p1, [{p}, #1, MUL VL]",
+        "str p2, [{p}, #2, MUL VL]",
+        "str p3, [{p}, #3, MUL VL]",
+        "str p4, [{p}, #4, MUL VL]",
+        "str p5, [{p}, #5, MUL VL]",
+        "str p6, [{p}, #6, MUL VL]",
+        "str p7, [{p}, #7, MUL VL]",
+        "str p8, [{p}, #8, MUL VL]",
+        "str p9, [{p}, #9, MUL VL]",
+        "str p10, [{p}, #10, MUL VL]",
+        "str p11, [{p}, #11, MUL VL]",
+        "str p12, [{p}, #12, MUL VL]",
+        "str p13, [{p}, #13, MUL VL]",
+        "str p14, [{p}, #14, MUL VL]",
+        "str p15, [{p}, #15, MUL VL]",
+        // FFR is only accessible through a P register.
+        "rdffr p0.b",
+        "str p0, [{ffr}]",
+        "ldr p0, [{p}]",
+        z = in(reg) z,
+        p = in(reg) p,
+        ffr = in(reg) ffr,
+        options(nostack)
+    );
+}
+
+unsafe fn sve_restore(z: *const u8, p: *const u8, ffr: *const u8) {
+    core::arch::asm!(
+        ".arch_extension sve",
+        "ldr p0, [{ffr}]",
+        "wrffr p0.b",
+        "ldr p0, [{p}, #0, MUL VL]",
+        "ldr p1, [{p}, #1, MUL VL]",
+        "ldr p2, [{p}, #2, MUL VL]",
+        "ldr p3, [{p}, #3, MUL VL]",
+        "ldr p4, [{p}, #4, MUL VL]",
+        "ldr p5, [{p}, #5, MUL VL]",
+        "ldr p6, [{p}, #6, MUL VL]",
+        "ldr p7, [{p}, #7, MUL VL]",
+        "ldr p8, [{p}, #8, MUL VL]",
+        "ldr p9, [{p}, #9, MUL VL]",
+        "ldr p10, [{p}, #10, MUL VL]",
+        "ldr p11, [{p}, #11, MUL VL]",
+        "ldr p12, [{p}, #12, MUL VL]",
+        "ldr p13, [{p}, #13, MUL VL]",
+        "ldr p14, [{p}, #14, MUL VL]",
+        "ldr p15, [{p}, #15, MUL VL]",
+        "ldr z0, [{z}, #0, MUL VL]",
+        "ldr z1, [{z}, #1, MUL VL]",
+        "ldr z2, [{z}, #2, MUL VL]",
+        "ldr z3, [{z}, #3, MUL VL]",
+        "ldr z4, [{z}, #4, MUL VL]",
+        "ldr z5, [{z}, #5, MUL VL]",
+        "ldr z6, [{z}, #6, MUL VL]",
+        "ldr z7, [{z}, #7, MUL VL]",
+        "ldr z8, [{z}, #8, MUL VL]",
+        "ldr z9, [{z}, #9, MUL VL]",
+        "ldr z10, [{z}, #10, MUL VL]",
+        "ldr z11, [{z}, #11, MUL VL]",
+        "ldr z12, [{z}, #12, MUL VL]",
+        "ldr z13, [{z}, #13, MUL VL]",
+        "ldr z14, [{z}, #14, MUL VL]",
+        "ldr z15, [{z}, #15, MUL VL]",
+        "ldr z16, [{z}, #16, MUL VL]",
+        "ldr z17, [{z}, #17, MUL VL]",
+        "ldr z18, [{z}, #18, MUL VL]",
+        "ldr z19, [{z}, #19, MUL VL]",
+        "ldr z20, [{z}, #20, MUL VL]",
+        "ldr z21, [{z}, #21, MUL VL]",
+        "ldr z22, [{z}, #22, MUL VL]",
+        "ldr z23, [{z}, #23, MUL VL]",
+        "ldr z24, [{z}, #24, MUL VL]",
+        "ldr z25, [{z}, #25, MUL VL]",
+        "ldr z26, [{z}, #26, MUL VL]",
+        "ldr z27, [{z}, #27, MUL VL]",
+        "ldr z28, [{z}, #28, MUL VL]",
+        "ldr z29, [{z}, #29, MUL VL]",
+        "ldr z30, [{z}, #30, MUL VL]",
+        "ldr z31, [{z}, #31, MUL VL]",
+        z = in(reg) z,
+        p = in(reg) p,
+        ffr = in(reg) ffr,
+        options(nostack)
+    );
+}
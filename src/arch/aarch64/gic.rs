@@ -1,14 +1,17 @@
-use spin::Mutex;
+use spin::{Mutex, Once};
 use spinlock::SpinNoIrq;
 
 use arm_gic::gic_v2::{GicDistributor, GicHypervisorInterface, GicCpuInterface};
 use arm_gic::GIC_LIST_REGS_NUM;
 
-use crate::arch::utils::bit_extract;
+use crate::arch::gicv3::GicV3State;
+use crate::snapshot::{Snapshot, SnapshotReader, SnapshotWriter};
+use crate::utils::bit_extract;
+use crate::{HyperError, HyperResult};
 
-pub static GICD: Option<&SpinNoIrq<GicDistributor>> = None;
-pub static GICC: Option<&GicCpuInterface> = None;
-pub static GICH: Option<&GicHypervisorInterface> = None;
+pub static GICD: Once<&SpinNoIrq<GicDistributor>> = Once::new();
+pub static GICC: Once<&GicCpuInterface> = Once::new();
+pub static GICH: Once<&GicHypervisorInterface> = Once::new();
 
 pub const GICD_BASE: usize = 0x08000000;
 pub const GICC_BASE: usize = 0x08010000;
@@ -22,6 +25,132 @@ pub const GICC_CTLR_EOIMODENS_BIT: usize = 1 << 9;
 
 pub static GIC_LRS_NUM: Mutex<usize> = Mutex::new(0);
 
+/// Hand the host's GIC interfaces to the hypervisor. Must be called before
+/// any vCPU runs, as the GIC state of vCPUs and the virtual GIC are switched
+/// through them.
+pub fn gic_hw_init(
+    gicd: &'static SpinNoIrq<GicDistributor>,
+    gicc: &'static GicCpuInterface,
+    gich: &'static GicHypervisorInterface,
+) {
+    GICD.call_once(|| gicd);
+    GICC.call_once(|| gicc);
+    GICH.call_once(|| gich);
+    *GIC_LRS_NUM.lock() = gich.get_lrs_num();
+}
+
+
+/// The GIC architecture version presented to a guest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GicVersion {
+    /// GICv2, with memory-mapped GICC and GICH interfaces.
+    V2,
+    /// GICv3, with the system-register CPU interface and redistributors.
+    V3,
+}
+
+// List register states, the same in GICv2 and GICv3.
+pub const LR_STATE_PENDING: u8 = 0b01;
+pub const LR_STATE_ACTIVE: u8 = 0b10;
+
+// GICH_HCR and ICH_HCR_EL2 bits
+pub const GICH_HCR_EN: u32 = 1 << 0;
+pub const GICH_HCR_UIE: u32 = 1 << 1;
+
+/// A virtual interrupt to load into a list register.
+#[derive(Debug, Clone, Copy)]
+pub struct ListRegister {
+    /// Virtual interrupt ID
+    pub vintid: u32,
+    /// The physical interrupt deactivated together with the virtual one.
+    pub pintid: Option<u32>,
+    /// The requesting vCPU of an SGI, used by GICv2 only.
+    pub source: u32,
+    pub priority: u8,
+    /// `LR_STATE_PENDING` and/or `LR_STATE_ACTIVE`
+    pub state: u8,
+    pub group1: bool,
+}
+
+/// The virtual CPU interface of a GIC version. Instances hold the saved
+/// state of a vCPU, the associated functions access the hypervisor
+/// interface of the current physical CPU.
+pub trait VgicCpuInterface {
+    /// Save the virtual CPU interface state of the running vCPU.
+    fn save_state(&mut self);
+    /// Load the saved state into the hardware.
+    fn restore_state(&self);
+    /// The number of implemented list registers.
+    fn lrs_num() -> usize;
+    /// The `LR_STATE_*` bits of list register `idx`.
+    fn lr_state(idx: usize) -> u8;
+    fn write_lr(idx: usize, lr: &ListRegister);
+    fn hcr() -> u32;
+    fn set_hcr(hcr: u32);
+}
+
+/// The saved virtual CPU interface state of a vCPU.
+#[derive(Debug, Clone)]
+pub enum VgicCpuState {
+    V2(GicState),
+    V3(GicV3State),
+}
+
+impl VgicCpuState {
+    pub fn new(version: GicVersion) -> Self {
+        match version {
+            GicVersion::V2 => VgicCpuState::V2(GicState::default()),
+            GicVersion::V3 => VgicCpuState::V3(GicV3State::default()),
+        }
+    }
+
+    pub fn save_state(&mut self) {
+        match self {
+            VgicCpuState::V2(state) => state.save_state(),
+            VgicCpuState::V3(state) => state.save_state(),
+        }
+    }
+
+    pub fn restore_state(&self) {
+        match self {
+            VgicCpuState::V2(state) => state.restore_state(),
+            VgicCpuState::V3(state) => state.restore_state(),
+        }
+    }
+
+    /// Turn the virtual CPU interface off, after saving its state.
+    pub fn disable(&self) {
+        match self {
+            VgicCpuState::V2(_) => GicState::set_hcr(0),
+            VgicCpuState::V3(_) => GicV3State::set_hcr(0),
+        }
+    }
+}
+
+impl Snapshot for VgicCpuState {
+    fn save(&self, w: &mut SnapshotWriter) {
+        match self {
+            VgicCpuState::V2(state) => {
+                2u8.save(w);
+                state.save(w);
+            }
+            VgicCpuState::V3(state) => {
+                3u8.save(w);
+                state.save(w);
+            }
+        }
+    }
+
+    /// The state of a vCPU can only be restored into one of the same GIC
+    /// version.
+    fn restore(&mut self, r: &mut SnapshotReader<'_>) -> HyperResult {
+        match (self, r.read::<u8>()?) {
+            (VgicCpuState::V2(state), 2) => state.restore(r),
+            (VgicCpuState::V3(state), 3) => state.restore(r),
+            _ => Err(HyperError::InvalidParam),
+        }
+    }
+}
 
 #[repr(C)]
 #[derive(Debug, Clone)]
@@ -33,6 +162,16 @@ pub struct GicState {
     pub saved_lr: [u32; GIC_LIST_REGS_NUM],
     pub saved_ctlr: u32,
 }
+
+impl_snapshot_fields!(GicState {
+    saved_hcr,
+    saved_eisr,
+    saved_elrsr,
+    saved_apr,
+    saved_lr,
+    saved_ctlr,
+});
+
 impl GicState {
     pub fn default() -> GicState {
         GicState {
@@ -44,9 +183,11 @@ impl GicState {
             saved_ctlr: 0,
         }
     }
+}
 
-    pub fn save_state(&mut self) { 
-        if let Some(gich) = GICH {
+impl VgicCpuInterface for GicState {
+    fn save_state(&mut self) {
+        if let Some(gich) = GICH.get() {
             self.saved_hcr = gich.get_hcr();
             self.saved_apr = gich.get_apr();
             for i in 0..(GIC_LIST_REGS_NUM / 32) {
@@ -63,15 +204,15 @@ impl GicState {
         } else {
             warn!("No available gich in save_state!")
         }
-        if let Some(gicc) = GICC {
+        if let Some(gicc) = GICC.get() {
             self.saved_ctlr = gicc.get_ctlr();
         }else {
             warn!("No available gicc in save_state!")
         }
     }
 
-    pub fn restore_state(&self) {
-        if let Some(gich) = GICH {
+    fn restore_state(&self) {
+        if let Some(gich) = GICH.get() {
             gich.set_hcr(self.saved_hcr);
             gich.set_apr(self.saved_apr);
             for i in 0..gich.get_lrs_num() {
@@ -80,13 +221,48 @@ impl GicState {
         } else {
             warn!("No available gich in restore_state!")
         }
-        if let Some(gicc) = GICC {
+        if let Some(gicc) = GICC.get() {
             gicc.set_ctlr(self.saved_ctlr);
         }else {
             warn!("No available gicc in restore_state!")
         }
     }
 
+    fn lrs_num() -> usize {
+        GICH.get().map_or(0, |gich| gich.get_lrs_num().min(GIC_LIST_REGS_NUM))
+    }
+
+    fn lr_state(idx: usize) -> u8 {
+        GICH.get().map_or(0, |gich| ((gich.get_lr_by_idx(idx) >> 28) & 0b11) as u8)
+    }
+
+    /// GICv2 list register layout: vID [9:0], pID or the SGI source [19:10],
+    /// priority [27:23], state [29:28], Grp1 [30], HW [31].
+    fn write_lr(idx: usize, lr: &ListRegister) {
+        let mut value = (lr.vintid & 0x3ff)
+            | ((lr.priority as u32 >> 3) << 23)
+            | ((lr.state as u32) << 28);
+        if lr.group1 {
+            value |= 1 << 30;
+        }
+        match lr.pintid {
+            Some(pintid) => value |= (1 << 31) | ((pintid & 0x3ff) << 10),
+            None => value |= (lr.source & 0x7) << 10,
+        }
+        if let Some(gich) = GICH.get() {
+            gich.set_lr_by_idx(idx, value);
+        }
+    }
+
+    fn hcr() -> u32 {
+        GICH.get().map_or(0, |gich| gich.get_hcr())
+    }
+
+    fn set_hcr(hcr: u32) {
+        if let Some(gich) = GICH.get() {
+            gich.set_hcr(hcr);
+        }
+    }
 }
 
 /* 
@@ -0,0 +1,168 @@
+use crate::arch::gic::{ListRegister, VgicCpuInterface};
+use crate::{mrs, msr};
+
+/// Maximum number of list registers of the GICv3 system register interface.
+pub const GICV3_LIST_REGS_NUM: usize = 16;
+
+/// Read or write `ICH_LR<idx>_EL2`, whose number must be encoded in the instruction.
+macro_rules! ich_lr {
+    ($idx: expr) => {{
+        let value: u64;
+        match $idx {
+            0 => mrs!(value, ICH_LR0_EL2),
+            1 => mrs!(value, ICH_LR1_EL2),
+            2 => mrs!(value, ICH_LR2_EL2),
+            3 => mrs!(value, ICH_LR3_EL2),
+            4 => mrs!(value, ICH_LR4_EL2),
+            5 => mrs!(value, ICH_LR5_EL2),
+            6 => mrs!(value, ICH_LR6_EL2),
+            7 => mrs!(value, ICH_LR7_EL2),
+            8 => mrs!(value, ICH_LR8_EL2),
+            9 => mrs!(value, ICH_LR9_EL2),
+            10 => mrs!(value, ICH_LR10_EL2),
+            11 => mrs!(value, ICH_LR11_EL2),
+            12 => mrs!(value, ICH_LR12_EL2),
+            13 => mrs!(value, ICH_LR13_EL2),
+            14 => mrs!(value, ICH_LR14_EL2),
+            _ => mrs!(value, ICH_LR15_EL2),
+        }
+        value
+    }};
+    ($idx: expr, $value: expr) => {{
+        let value: u64 = $value;
+        match $idx {
+            0 => msr!(ICH_LR0_EL2, value),
+            1 => msr!(ICH_LR1_EL2, value),
+            2 => msr!(ICH_LR2_EL2, value),
+            3 => msr!(ICH_LR3_EL2, value),
+            4 => msr!(ICH_LR4_EL2, value),
+            5 => msr!(ICH_LR5_EL2, value),
+            6 => msr!(ICH_LR6_EL2, value),
+            7 => msr!(ICH_LR7_EL2, value),
+            8 => msr!(ICH_LR8_EL2, value),
+            9 => msr!(ICH_LR9_EL2, value),
+            10 => msr!(ICH_LR10_EL2, value),
+            11 => msr!(ICH_LR11_EL2, value),
+            12 => msr!(ICH_LR12_EL2, value),
+            13 => msr!(ICH_LR13_EL2, value),
+            14 => msr!(ICH_LR14_EL2, value),
+            _ => msr!(ICH_LR15_EL2, value),
+        }
+    }};
+}
+
+fn ich_vtr() -> u64 {
+    let vtr: u64;
+    mrs!(vtr, ICH_VTR_EL2);
+    vtr
+}
+
+/// The number of implemented active priority registers of each group,
+/// from `ICH_VTR_EL2.PREbits`.
+fn ich_aprs_num() -> usize {
+    let pre_bits = ((ich_vtr() >> 26) & 0x7) + 1;
+    1 << (pre_bits - 5)
+}
+
+/// The saved GICv3 virtual CPU interface (`ICH_*_EL2`) state of a vCPU.
+#[derive(Debug, Clone, Default)]
+pub struct GicV3State {
+    pub saved_hcr: u32,
+    pub saved_vmcr: u32,
+    saved_ap0r: [u32; 4],
+    saved_ap1r: [u32; 4],
+    pub saved_lr: [u64; GICV3_LIST_REGS_NUM],
+}
+
+impl_snapshot_fields!(GicV3State {
+    saved_hcr,
+    saved_vmcr,
+    saved_ap0r,
+    saved_ap1r,
+    saved_lr,
+});
+
+impl VgicCpuInterface for GicV3State {
+    fn save_state(&mut self) {
+        let (hcr, vmcr): (u64, u64);
+        mrs!(hcr, ICH_HCR_EL2);
+        mrs!(vmcr, ICH_VMCR_EL2);
+        self.saved_hcr = hcr as u32;
+        self.saved_vmcr = vmcr as u32;
+        let aprs_num = ich_aprs_num();
+        let (mut ap0r, mut ap1r) = ([0u64; 4], [0u64; 4]);
+        mrs!(ap0r[0], ICH_AP0R0_EL2);
+        mrs!(ap1r[0], ICH_AP1R0_EL2);
+        if aprs_num > 1 {
+            mrs!(ap0r[1], ICH_AP0R1_EL2);
+            mrs!(ap1r[1], ICH_AP1R1_EL2);
+        }
+        if aprs_num > 2 {
+            mrs!(ap0r[2], ICH_AP0R2_EL2);
+            mrs!(ap0r[3], ICH_AP0R3_EL2);
+            mrs!(ap1r[2], ICH_AP1R2_EL2);
+            mrs!(ap1r[3], ICH_AP1R3_EL2);
+        }
+        for i in 0..4 {
+            self.saved_ap0r[i] = ap0r[i] as u32;
+            self.saved_ap1r[i] = ap1r[i] as u32;
+        }
+        let elrsr: u64;
+        mrs!(elrsr, ICH_ELRSR_EL2);
+        for i in 0..Self::lrs_num() {
+            self.saved_lr[i] = if elrsr & (1 << i) == 0 { ich_lr!(i) } else { 0 };
+        }
+    }
+
+    fn restore_state(&self) {
+        msr!(ICH_HCR_EL2, self.saved_hcr as u64);
+        msr!(ICH_VMCR_EL2, self.saved_vmcr as u64);
+        let aprs_num = ich_aprs_num();
+        msr!(ICH_AP0R0_EL2, self.saved_ap0r[0] as u64);
+        msr!(ICH_AP1R0_EL2, self.saved_ap1r[0] as u64);
+        if aprs_num > 1 {
+            msr!(ICH_AP0R1_EL2, self.saved_ap0r[1] as u64);
+            msr!(ICH_AP1R1_EL2, self.saved_ap1r[1] as u64);
+        }
+        if aprs_num > 2 {
+            msr!(ICH_AP0R2_EL2, self.saved_ap0r[2] as u64);
+            msr!(ICH_AP0R3_EL2, self.saved_ap0r[3] as u64);
+            msr!(ICH_AP1R2_EL2, self.saved_ap1r[2] as u64);
+            msr!(ICH_AP1R3_EL2, self.saved_ap1r[3] as u64);
+        }
+        for i in 0..Self::lrs_num() {
+            ich_lr!(i, self.saved_lr[i]);
+        }
+    }
+
+    fn lrs_num() -> usize {
+        ((ich_vtr() & 0x1f) as usize + 1).min(GICV3_LIST_REGS_NUM)
+    }
+
+    fn lr_state(idx: usize) -> u8 {
+        (ich_lr!(idx) >> 62) as u8
+    }
+
+    /// GICv3 list register layout: vINTID [31:0], pINTID [44:32],
+    /// priority [55:48], Group [60], HW [61], state [63:62].
+    fn write_lr(idx: usize, lr: &ListRegister) {
+        let mut value = lr.vintid as u64 | ((lr.priority as u64) << 48) | ((lr.state as u64) << 62);
+        if lr.group1 {
+            value |= 1 << 60;
+        }
+        if let Some(pintid) = lr.pintid {
+            value |= (1 << 61) | ((pintid as u64 & 0x1fff) << 32);
+        }
+        ich_lr!(idx, value);
+    }
+
+    fn hcr() -> u32 {
+        let hcr: u64;
+        mrs!(hcr, ICH_HCR_EL2);
+        hcr as u32
+    }
+
+    fn set_hcr(hcr: u32) {
+        msr!(ICH_HCR_EL2, hcr as u64);
+    }
+}
@@ -1,15 +1,37 @@
 
 use aarch64_cpu::{asm, asm::barrier, registers::*};
+use core::sync::atomic::{AtomicUsize, Ordering};
 use tock_registers::interfaces::{ReadWriteable, Readable, Writeable};
 
+use crate::arch::fpsimd::fp_trap_guest;
+use crate::arch::sysreg::MDCR_EL2_HPMN;
 use crate::arch::vcpu::VmCpuRegisters;
-use crate::msr;
+use crate::arch::vgic::vgic_flush_current;
+use crate::arch::vmexit::VmExitInfo;
+use crate::arch::ContextFrame;
+use crate::traits::ContextFrameTrait;
+use crate::vcpus::MAX_CPUS;
+use crate::{mrs, msr};
 
 pub const HVC_SYS: usize = 0;
 
+/// The HVC immediate of the host's calls, which tells them apart from the
+/// SMCCC calls of guests using `HVC #0`.
+pub const HVC_SYS_IMM: usize = 0x5359;
+
 /// HVC SYS event
 pub const HVC_SYS_BOOT: usize = 0;
 
+/// HVC SYS event: invalidate the stage-2 TLB entries of a VM.
+pub const HVC_SYS_TLB_FLUSH: usize = 1;
+
+/// HCR_EL2.IMO, set for the vCPUs of VMs with a virtual GIC.
+const HCR_EL2_IMO: u64 = 1 << 4;
+
+/// The address of the registers of the vCPU running on each physical CPU,
+/// by [`this_cpu_id`], or 0 while the host runs.
+static RUNNING_VCPUS: [AtomicUsize; MAX_CPUS] = [const { AtomicUsize::new(0) }; MAX_CPUS];
+
 #[repr(C)]
 pub struct HvcDefaultMsg {
     pub fid: usize,
@@ -37,11 +59,88 @@ pub fn hvc_guest_handler(
     }
 }
 
+/// Run the vCPU whose registers are at `regs_addr` until it exits. The
+/// exit reason is left in its `VmCpuRegisters::exit_info`.
 pub fn run_guest_by_trap2el2(token: usize, regs_addr: usize) -> usize {
     // mode is in x7. hvc_type: HVC_SYS; event: HVC_SYS_BOOT
     hvc_call(token, regs_addr, 0, 0, 0, 0, 0, 0)
 }
 
+/// Invalidate the stage-2 TLB entries of the VM whose `VTTBR_EL2` value is
+/// `token`, on all CPUs, after changing its page table.
+pub fn flush_guest_tlb_by_trap2el2(token: usize) -> usize {
+    hvc_call(token, 0, 0, 0, 0, 0, 0, (HVC_SYS << 8) | HVC_SYS_TLB_FLUSH)
+}
+
+/// The index of this physical CPU, from the affinity level 0 of `MPIDR_EL1`.
+fn this_cpu_id() -> usize {
+    let mpidr: u64;
+    mrs!(mpidr, MPIDR_EL1);
+    let cpu_id = (mpidr & 0xff) as usize;
+    assert!(cpu_id < MAX_CPUS, "CPU {} beyond MAX_CPUS", cpu_id);
+    cpu_id
+}
+
+fn set_current_vcpu_regs(regs: Option<&mut VmCpuRegisters>) {
+    let regs = regs.map_or(0, |regs| regs as *mut VmCpuRegisters as usize);
+    RUNNING_VCPUS[this_cpu_id()].store(regs, Ordering::Relaxed);
+}
+
+/// The registers of the vCPU running on this CPU, or `None` while the host
+/// runs.
+pub(crate) fn current_vcpu_regs() -> Option<&'static mut VmCpuRegisters> {
+    let regs = RUNNING_VCPUS[this_cpu_id()].load(Ordering::Relaxed);
+    unsafe { (regs as *mut VmCpuRegisters).as_mut() }
+}
+
+/// Switch from the host, trapped in `ctx`, to the vCPU `regs`. The host's
+/// EL1 state is saved, and restored by [`exit_to_host`].
+pub(crate) fn enter_guest(ctx: &mut ContextFrame, regs: &mut VmCpuRegisters) {
+    regs.host_system_regs.ext_regs_store();
+    // The guest gets the PMU counters the host has.
+    let vm_regs = &mut regs.vm_system_regs;
+    vm_regs.mdcr_el2 = (vm_regs.mdcr_el2 & !MDCR_EL2_HPMN)
+        | (regs.host_system_regs.mdcr_el2 & MDCR_EL2_HPMN);
+    regs.save_for_os_context_regs = *ctx;
+    *ctx = regs.guest_trap_context_regs;
+
+    let vm_regs = &regs.vm_system_regs;
+    vm_regs.ext_regs_restore();
+    if vm_regs.hcr_el2 & HCR_EL2_IMO != 0 {
+        vm_regs.gic_restore_state();
+        // Load the interrupts the host made pending while the vCPU was out,
+        // and refill the list registers freed since the last exit.
+        vgic_flush_current();
+    }
+    // The guest's fp registers are loaded on first use.
+    fp_trap_guest();
+    set_current_vcpu_regs(Some(regs));
+}
+
+/// Leave the running vCPU, trapped in `ctx`, and return to the host from
+/// its `HVC_SYS_BOOT` call with `info` as the exit reason.
+pub(crate) fn exit_to_host(ctx: &mut ContextFrame, info: VmExitInfo) {
+    let regs = match current_vcpu_regs() {
+        Some(regs) => regs,
+        None => panic!("exit_to_host: no running vCPU, exit {:?}", info),
+    };
+    regs.guest_trap_context_regs = *ctx;
+    let vm_regs = &mut regs.vm_system_regs;
+    vm_regs.ext_regs_store();
+    if vm_regs.hcr_el2 & HCR_EL2_IMO != 0 {
+        vm_regs.gic_save_state();
+        // No maintenance interrupts while the host runs.
+        vm_regs.gic_state.disable();
+    }
+
+    regs.put_guest_fp();
+    regs.host_system_regs.ext_regs_restore();
+    *ctx = regs.save_for_os_context_regs;
+    ctx.set_gpr(0, 0);
+    regs.exit_info = Some(info);
+    set_current_vcpu_regs(None);
+}
+
 #[inline(never)]
 fn hvc_sys_handler(event: usize, root_paddr: usize, vm_ctx_addr: usize) -> Result<usize, ()> {
     match event {
@@ -49,6 +148,10 @@ fn hvc_sys_handler(event: usize, root_paddr: usize, vm_ctx_addr: usize) -> Resul
             init_hv(root_paddr, vm_ctx_addr);
             Ok(0)
         }
+        HVC_SYS_TLB_FLUSH => {
+            flush_stage2_tlb(root_paddr);
+            Ok(0)
+        }
 
         _ => Err(()),
     }
@@ -57,7 +160,7 @@ fn hvc_sys_handler(event: usize, root_paddr: usize, vm_ctx_addr: usize) -> Resul
 #[inline(never)]
 /// hvc handler for initial hv
 /// x0: root_paddr, x1: vm regs context addr
-fn init_hv(root_paddr: usize, vm_ctx_addr: usize) {
+fn init_hv(root_paddr: usize, _vm_ctx_addr: usize) {
     // cptr_el2: Condtrols trapping to EL2 for accesses to the CPACR, Trace functionality 
     //           an registers associated with floating-point and Advanced SIMD execution.
 
@@ -78,10 +181,27 @@ fn init_hv(root_paddr: usize, vm_ctx_addr: usize) {
             isb"
         );
     }
-    
-    let regs: &VmCpuRegisters = unsafe{core::mem::transmute(vm_ctx_addr)};
-    // set vm system related register
-    regs.vm_system_regs.ext_regs_restore();
+    // The vm system related registers are set by `enter_guest`, after
+    // saving the host's.
+}
+
+/// Invalidate the stage-1 and stage-2 TLB entries of VMID of `vttbr`, in
+/// the inner shareable domain.
+fn flush_stage2_tlb(vttbr: usize) {
+    let old_vttbr: u64;
+    mrs!(old_vttbr, VTTBR_EL2);
+    msr!(VTTBR_EL2, vttbr);
+    unsafe {
+        core::arch::asm!("
+            isb
+            dsb ishst
+            tlbi vmalls12e1is
+            dsb ish
+            isb"
+        );
+    }
+    msr!(VTTBR_EL2, old_vttbr);
+    barrier::isb(barrier::SY);
 }
 
 fn init_sysregs() {
@@ -163,7 +283,8 @@ fn hvc_call(
     #[cfg(target_arch = "aarch64")]
     unsafe {
         core::arch::asm!(
-            "hvc #0",
+            "hvc #{imm}",
+            imm = const HVC_SYS_IMM,
             inout("x0") x0 => r0,
             inout("x1") x1 => _,
             inout("x2") x2 => _,
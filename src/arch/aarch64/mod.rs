@@ -1,12 +1,21 @@
 mod context_frame;
 mod cpu;
+mod decode;
+mod devices;
+mod emu;
 mod exception;
+mod fpsimd;
 mod hvc;
+mod psci;
 mod sync;
-mod utils;
+mod sysreg;
 mod vcpu;
+mod vgic;
 mod vm;
+mod vmexit;
+mod vtimer;
 mod gic;
+mod gicv3;
 mod ept;
 
 // pub use gic::{GICC, GICD, GICH, GICD_BASE};
@@ -14,11 +23,20 @@ pub use ept::NestedPageTable;
 pub use vcpu::VCpu;
 pub use vm::VM;
 pub use cpu::PerCpu;
+pub use devices::{Pl011, PL011_SIZE};
+pub use emu::{emu_register_dev, emu_remove_dev, EmuContext, EmuDevice};
+pub use gic::{gic_hw_init, GicVersion};
+pub use psci::VmLifecycleEvent;
+pub use vmexit::VmExitInfo;
+pub use vtimer::VTIMER_IRQ;
+pub use vgic::{
+    Vgic, VgicRedistributors, GICD_SIZE, GICR_SIZE_PER_VCPU, GICV3_GICD_SIZE, GIC_MAINTENANCE_IRQ,
+};
 
 // pub use config::*;
 
 pub use page_table::PageSize;
-pub use exception::lower_aarch64_synchronous;
+pub use exception::{lower_aarch64_irq, lower_aarch64_synchronous};
 
 type ContextFrame = crate::arch::context_frame::Aarch64ContextFrame;
 
@@ -0,0 +1,281 @@
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::arch::emu::{active_vm_id, current_vcpu_id};
+use crate::arch::ContextFrame;
+use crate::snapshot::{Snapshot, SnapshotReader, SnapshotWriter};
+use crate::traits::ContextFrameTrait;
+use crate::{GuestPhysAddr, HyperError, HyperResult};
+
+// PSCI function IDs (ARM DEN 0022D), SMC32 and SMC64 variants.
+pub const PSCI_VERSION: usize = 0x8400_0000;
+pub const PSCI_CPU_SUSPEND_32: usize = 0x8400_0001;
+pub const PSCI_CPU_SUSPEND_64: usize = 0xc400_0001;
+pub const PSCI_CPU_OFF: usize = 0x8400_0002;
+pub const PSCI_CPU_ON_32: usize = 0x8400_0003;
+pub const PSCI_CPU_ON_64: usize = 0xc400_0003;
+pub const PSCI_AFFINITY_INFO_32: usize = 0x8400_0004;
+pub const PSCI_AFFINITY_INFO_64: usize = 0xc400_0004;
+pub const PSCI_MIGRATE_INFO_TYPE: usize = 0x8400_0006;
+pub const PSCI_SYSTEM_OFF: usize = 0x8400_0008;
+pub const PSCI_SYSTEM_RESET: usize = 0x8400_0009;
+pub const PSCI_FEATURES: usize = 0x8400_000a;
+
+/// PSCI 1.1
+const PSCI_VERSION_1_1: usize = 0x0001_0001;
+
+// PSCI return codes
+const PSCI_SUCCESS: isize = 0;
+const PSCI_NOT_SUPPORTED: isize = -1;
+const PSCI_INVALID_PARAMETERS: isize = -2;
+const PSCI_ALREADY_ON: isize = -4;
+const PSCI_ON_PENDING: isize = -5;
+
+// AFFINITY_INFO results
+const PSCI_AFFINITY_ON: usize = 0;
+const PSCI_AFFINITY_OFF: usize = 1;
+const PSCI_AFFINITY_ON_PENDING: usize = 2;
+
+/// MIGRATE_INFO_TYPE: no Trusted OS, or one which does not require migration.
+const PSCI_TOS_NOT_PRESENT: usize = 2;
+
+/// A guest request which the host has to act on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmLifecycleEvent {
+    /// `CPU_ON`: start `vcpu_id` at `entry` with `context_id` in x0, e.g.
+    /// with `VM::init_vm_vcpu(vcpu_id, entry, context_id)`.
+    CpuOn {
+        vcpu_id: usize,
+        entry: GuestPhysAddr,
+        context_id: usize,
+    },
+    /// `CPU_OFF`: `vcpu_id` stopped itself.
+    CpuOff { vcpu_id: usize },
+    /// `SYSTEM_OFF`: the guest powered the VM off.
+    SystemOff,
+    /// `SYSTEM_RESET`: the guest requested a reset of the VM.
+    SystemReset,
+}
+
+/// What the trapping vCPU does after a PSCI call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PsciAction {
+    /// Resume the guest with the result in x0.
+    Resume,
+    /// The vCPU must not run again until the host restarts it.
+    Stop,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PowerState {
+    On,
+    Off,
+    OnPending,
+}
+
+struct PsciVm {
+    vm_id: usize,
+    power: Vec<PowerState>,
+    events: VecDeque<VmLifecycleEvent>,
+}
+
+/// The PSCI state of the VMs, by VM id.
+static PSCI_VMS: Mutex<Vec<PsciVm>> = Mutex::new(Vec::new());
+
+/// Track the power state of the `nr_vcpus` vCPUs of VM `vm_id`. vCPU 0 is
+/// the boot CPU and is on, the others start off.
+pub(crate) fn psci_register_vm(vm_id: usize, nr_vcpus: usize) {
+    let mut power = alloc::vec![PowerState::Off; nr_vcpus];
+    if let Some(boot) = power.first_mut() {
+        *boot = PowerState::On;
+    }
+    let mut vms = PSCI_VMS.lock();
+    vms.retain(|vm| vm.vm_id != vm_id);
+    vms.push(PsciVm {
+        vm_id,
+        power,
+        events: VecDeque::new(),
+    });
+}
+
+pub(crate) fn psci_remove_vm(vm_id: usize) {
+    PSCI_VMS.lock().retain(|vm| vm.vm_id != vm_id);
+}
+
+/// Take the oldest lifecycle event of VM `vm_id`.
+pub(crate) fn psci_pop_event(vm_id: usize) -> Option<VmLifecycleEvent> {
+    let mut vms = PSCI_VMS.lock();
+    let vm = vms.iter_mut().find(|vm| vm.vm_id == vm_id)?;
+    vm.events.pop_front()
+}
+
+/// Mark `vcpu_id` of VM `vm_id` as running, after the host started it.
+pub(crate) fn psci_set_vcpu_on(vm_id: usize, vcpu_id: usize) {
+    let mut vms = PSCI_VMS.lock();
+    if let Some(state) = vms
+        .iter_mut()
+        .find(|vm| vm.vm_id == vm_id)
+        .and_then(|vm| vm.power.get_mut(vcpu_id))
+    {
+        *state = PowerState::On;
+    }
+}
+
+/// Save the power states of the vCPUs of VM `vm_id`. Its lifecycle events
+/// are left to the host, which takes them before the snapshot.
+pub(crate) fn psci_save_vm(vm_id: usize, w: &mut SnapshotWriter) {
+    let vms = PSCI_VMS.lock();
+    let power = vms
+        .iter()
+        .find(|vm| vm.vm_id == vm_id)
+        .map_or(&[][..], |vm| &vm.power);
+    power.len().save(w);
+    for state in power {
+        (*state as u8).save(w);
+    }
+}
+
+/// Restore the power states of the vCPUs of VM `vm_id`, which must have
+/// as many vCPUs as the saved VM.
+pub(crate) fn psci_restore_vm(vm_id: usize, r: &mut SnapshotReader<'_>) -> HyperResult {
+    let mut vms = PSCI_VMS.lock();
+    let vm = vms
+        .iter_mut()
+        .find(|vm| vm.vm_id == vm_id)
+        .ok_or(HyperError::NotFound)?;
+    if r.read::<usize>()? != vm.power.len() {
+        return Err(HyperError::InvalidParam);
+    }
+    for state in vm.power.iter_mut() {
+        *state = match r.read::<u8>()? {
+            0 => PowerState::On,
+            1 => PowerState::Off,
+            2 => PowerState::OnPending,
+            _ => return Err(HyperError::DecodeError),
+        };
+    }
+    Ok(())
+}
+
+/// Whether `fid` is a PSCI function ID (SMCCC standard secure service
+/// calls 0x00-0x1f).
+pub fn is_psci_function(fid: usize) -> bool {
+    let fid = fid & !0x4000_0000;
+    (PSCI_VERSION..=PSCI_VERSION + 0x1f).contains(&fid)
+}
+
+/// The target vCPU of an MPIDR argument. vCPUs are addressed by affinity
+/// level 0 only, as set in their `VMPIDR_EL2`.
+fn mpidr_to_vcpu(mpidr: usize) -> Option<usize> {
+    if mpidr & 0xff_00ff_ff00 != 0 {
+        return None;
+    }
+    Some(mpidr & 0xff)
+}
+
+fn cpu_on(vm: &mut PsciVm, target: usize, entry: usize, context_id: usize) -> isize {
+    let vcpu_id = match mpidr_to_vcpu(target) {
+        Some(vcpu_id) if vcpu_id < vm.power.len() => vcpu_id,
+        _ => return PSCI_INVALID_PARAMETERS,
+    };
+    match vm.power[vcpu_id] {
+        PowerState::On => PSCI_ALREADY_ON,
+        PowerState::OnPending => PSCI_ON_PENDING,
+        PowerState::Off => {
+            vm.power[vcpu_id] = PowerState::OnPending;
+            vm.events.push_back(VmLifecycleEvent::CpuOn {
+                vcpu_id,
+                entry,
+                context_id,
+            });
+            PSCI_SUCCESS
+        }
+    }
+}
+
+fn affinity_info(vm: &PsciVm, target: usize, lowest_level: usize) -> isize {
+    if lowest_level != 0 {
+        return PSCI_INVALID_PARAMETERS;
+    }
+    match mpidr_to_vcpu(target).and_then(|vcpu_id| vm.power.get(vcpu_id)) {
+        Some(PowerState::On) => PSCI_AFFINITY_ON as isize,
+        Some(PowerState::Off) => PSCI_AFFINITY_OFF as isize,
+        Some(PowerState::OnPending) => PSCI_AFFINITY_ON_PENDING as isize,
+        None => PSCI_INVALID_PARAMETERS,
+    }
+}
+
+fn psci_features(fid: usize) -> isize {
+    match fid {
+        PSCI_VERSION
+        | PSCI_CPU_SUSPEND_32
+        | PSCI_CPU_SUSPEND_64
+        | PSCI_CPU_OFF
+        | PSCI_CPU_ON_32
+        | PSCI_CPU_ON_64
+        | PSCI_AFFINITY_INFO_32
+        | PSCI_AFFINITY_INFO_64
+        | PSCI_MIGRATE_INFO_TYPE
+        | PSCI_SYSTEM_OFF
+        | PSCI_SYSTEM_RESET
+        | PSCI_FEATURES => PSCI_SUCCESS,
+        _ => PSCI_NOT_SUPPORTED,
+    }
+}
+
+/// Handle a PSCI call of the running vCPU, made through HVC or SMC with the
+/// function ID in x0 and the arguments in x1-x3. The result is written to x0.
+pub fn psci_guest_handler(ctx: &mut ContextFrame) -> PsciAction {
+    let fid = ctx.gpr(0);
+    let (x1, x2, x3) = (ctx.gpr(1), ctx.gpr(2), ctx.gpr(3));
+    // SMC32 calls only use the lower 32 bits of the arguments.
+    let (x1, x2, x3) = if fid & 0x4000_0000 == 0 {
+        (x1 as u32 as usize, x2 as u32 as usize, x3 as u32 as usize)
+    } else {
+        (x1, x2, x3)
+    };
+    let vm_id = active_vm_id();
+    let vcpu_id = current_vcpu_id();
+
+    let mut vms = PSCI_VMS.lock();
+    let vm = match vms.iter_mut().find(|vm| vm.vm_id == vm_id) {
+        Some(vm) => vm,
+        None => {
+            warn!("PSCI call {:#x} from unknown VM {}", fid, vm_id);
+            ctx.set_gpr(0, PSCI_NOT_SUPPORTED as usize);
+            return PsciAction::Resume;
+        }
+    };
+    debug!("VM {} vCPU {} PSCI call {:#x}", vm_id, vcpu_id, fid);
+
+    let (ret, action) = match fid {
+        PSCI_VERSION => (PSCI_VERSION_1_1 as isize, PsciAction::Resume),
+        // Only standby states, which return like WFI.
+        PSCI_CPU_SUSPEND_32 | PSCI_CPU_SUSPEND_64 => (PSCI_SUCCESS, PsciAction::Resume),
+        PSCI_CPU_OFF => {
+            if let Some(state) = vm.power.get_mut(vcpu_id) {
+                *state = PowerState::Off;
+            }
+            vm.events.push_back(VmLifecycleEvent::CpuOff { vcpu_id });
+            (PSCI_SUCCESS, PsciAction::Stop)
+        }
+        PSCI_CPU_ON_32 | PSCI_CPU_ON_64 => (cpu_on(vm, x1, x2, x3), PsciAction::Resume),
+        PSCI_AFFINITY_INFO_32 | PSCI_AFFINITY_INFO_64 => {
+            (affinity_info(vm, x1, x2), PsciAction::Resume)
+        }
+        PSCI_MIGRATE_INFO_TYPE => (PSCI_TOS_NOT_PRESENT as isize, PsciAction::Resume),
+        PSCI_SYSTEM_OFF => {
+            vm.events.push_back(VmLifecycleEvent::SystemOff);
+            (PSCI_SUCCESS, PsciAction::Stop)
+        }
+        PSCI_SYSTEM_RESET => {
+            vm.events.push_back(VmLifecycleEvent::SystemReset);
+            (PSCI_SUCCESS, PsciAction::Stop)
+        }
+        PSCI_FEATURES => (psci_features(x1), PsciAction::Resume),
+        _ => (PSCI_NOT_SUPPORTED, PsciAction::Resume),
+    };
+    ctx.set_gpr(0, ret as usize);
+    action
+}
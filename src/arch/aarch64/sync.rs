@@ -8,71 +8,233 @@
 // MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+use crate::arch::decode::{decode_data_abort_instruction, Writeback};
+use crate::arch::emu::{
+    active_vm_id, emu_handler, emu_is_lazy_ram, emu_read_guest_instruction, emu_store_value,
+    EmuContext,
+};
 use crate::arch::exception::*;
-use crate::arch::hvc::hvc_guest_handler;
+use crate::arch::hvc::{current_vcpu_regs, enter_guest, hvc_guest_handler};
+use crate::arch::sysreg::{sysreg_read, sysreg_write};
 use crate::arch::ContextFrame;
 use crate::traits::ContextFrameTrait;
 use crate::arch::vcpu::VmCpuRegisters;
-use crate::arch::hvc::{HVC_SYS, HVC_SYS_BOOT};
+use crate::arch::hvc::{HVC_SYS, HVC_SYS_BOOT, HVC_SYS_IMM};
+use crate::arch::psci::{is_psci_function, psci_guest_handler, PsciAction};
+use crate::arch::vmexit::VmExitInfo;
+use crate::HyperError;
 
 pub const HVC_RETURN_REG: usize = 0;
 
-pub fn data_abort_handler(ctx: &mut ContextFrame) {
-    /* 
-    let emu_ctx = EmuContext {
-        address: exception_fault_addr(),
-        width: exception_data_abort_access_width(),
-        write: exception_data_abort_access_is_write(),
-        sign_ext: exception_data_abort_access_is_sign_ext(),
-        reg: exception_data_abort_access_reg(),
-        reg_width: exception_data_abort_access_reg_width(),
-    };
-    */
+/// Build the access descriptor of a data abort from the ISS, or by decoding
+/// the faulting instruction if the syndrome is not valid. Also returns the
+/// base register update of a decoded pre- or post-indexed access.
+fn data_abort_emu_context(ctx: &ContextFrame) -> Option<(EmuContext, Option<Writeback>)> {
+    let address = exception_fault_addr();
+    if exception_data_abort_syndrome_valid() {
+        let emu_ctx = EmuContext {
+            address,
+            width: exception_data_abort_access_width(),
+            write: exception_data_abort_access_is_write(),
+            sign_ext: exception_data_abort_access_is_sign_ext(),
+            reg: exception_data_abort_access_reg(),
+            reg_width: exception_data_abort_access_reg_width(),
+        };
+        return Some((emu_ctx, None));
+    }
+    let inst = translate_guest_va_to_pa(ctx.exception_pc())
+        .and_then(emu_read_guest_instruction)?;
+    match decode_data_abort_instruction(inst, address) {
+        Ok(decoded) => Some(decoded),
+        Err(err) => {
+            warn!("Failed to decode instruction {:#010x}: {:?}", inst, err);
+            None
+        }
+    }
+}
+
+/// The exit of a data abort which cannot be emulated.
+fn data_abort_exit(ctx: &ContextFrame) -> Option<VmExitInfo> {
+    Some(VmExitInfo::DataAbort {
+        ipa: exception_fault_addr(),
+        pc: ctx.exception_pc(),
+        esr: exception_esr(),
+    })
+}
+
+/// Handle a stage-2 data abort. Accesses which no registered device claims
+/// exit to the host, and those which cannot be emulated exit with
+/// [`VmExitInfo::DataAbort`].
+pub fn data_abort_handler(ctx: &mut ContextFrame) -> Option<VmExitInfo> {
     debug!("data fault addr 0x{:x}, esr: 0x{:x}",
         exception_fault_addr(), exception_esr());
     let elr = ctx.exception_pc();
 
     if !exception_data_abort_handleable() {
-        panic!(
+        warn!(
             "Data abort not handleable 0x{:x}, esr 0x{:x}",
             exception_fault_addr(),
             exception_esr()
         );
+        return data_abort_exit(ctx);
+    }
+
+    // Writes to read-only RAM, e.g. pages write-protected for dirty logging.
+    // Stage-1 table walks writing access flags count as writes.
+    let is_write = exception_data_abort_access_is_write()
+        || exception_data_abort_access_in_stage2();
+    if exception_data_abort_is_permission_fault() && is_write {
+        return Some(VmExitInfo::WriteProtectFault {
+            ipa: exception_fault_addr(),
+        });
     }
 
     if !exception_data_abort_is_translate_fault() {
-        // No migrate need
-        panic!(
+        warn!(
             "Data abort is not translate fault 0x{:x}\n ctx: {}",
             exception_fault_addr(), ctx
-        );           
-    }
-    /* 
-    if !emu_handler(&emu_ctx) {
-        active_vm().unwrap().show_pagetable(emu_ctx.address);
-        info!(
-            "write {}, width {}, reg width {}, addr {:x}, iss {:x}, reg idx {}, reg val 0x{:x}, esr 0x{:x}",
-            exception_data_abort_access_is_write(),
-            emu_ctx.width,
-            emu_ctx.reg_width,
-            emu_ctx.address,
-            exception_iss(),
-            emu_ctx.reg,
-            ctx.get_gpr(emu_ctx.reg),
-            exception_esr()
-        );
-        panic!(
-            "data_abort_handler: Failed to handler emul device request, ipa 0x{:x} elr 0x{:x}",
-            emu_ctx.address, elr
         );
+        return data_abort_exit(ctx);
+    }
+
+    let ipa = exception_fault_addr();
+    if emu_is_lazy_ram(active_vm_id(), ipa) {
+        return Some(VmExitInfo::LazyRamFault { ipa });
+    }
+
+    let (emu_ctx, writeback) = match data_abort_emu_context(ctx) {
+        Some(decoded) => decoded,
+        None => {
+            warn!(
+                "data_abort_handler: Failed to decode access to ipa 0x{:x} elr 0x{:x} esr 0x{:x}",
+                exception_fault_addr(), elr, exception_esr()
+            );
+            return data_abort_exit(ctx);
+        }
+    };
+    let mut exit = None;
+    match emu_handler(&emu_ctx, ctx) {
+        Ok(()) => {}
+        Err(HyperError::NotFound) if emu_ctx.write => {
+            let value = emu_store_value(&emu_ctx, ctx);
+            exit = Some(VmExitInfo::MmioWrite { access: emu_ctx, value });
+        }
+        Err(HyperError::NotFound) => exit = Some(VmExitInfo::MmioRead(emu_ctx)),
+        Err(err) => {
+            info!(
+                "write {}, width {}, reg width {}, addr {:x}, iss {:x}, reg idx {}, esr 0x{:x}",
+                exception_data_abort_access_is_write(),
+                emu_ctx.width,
+                emu_ctx.reg_width,
+                emu_ctx.address,
+                exception_iss(),
+                emu_ctx.reg,
+                exception_esr()
+            );
+            warn!(
+                "data_abort_handler: Failed to handler emul device request, ipa 0x{:x} elr 0x{:x}: {:?}",
+                emu_ctx.address, elr, err
+            );
+            return data_abort_exit(ctx);
+        }
+    }
+    if let Some(wb) = writeback {
+        let base = ctx.gpr(wb.reg) as i64;
+        ctx.set_gpr(wb.reg, base.wrapping_add(wb.offset) as usize);
     }
-    */
     let val = elr + exception_next_instruction_step();
     ctx.set_exception_pc(val);
+    exit
+}
+
+/// Handle a trapped MSR or MRS (EC 0x18) with the system register
+/// emulation table.
+pub fn sysreg_handler(ctx: &mut ContextFrame) {
+    let iss = exception_iss();
+    let rt = (iss >> 5) & 0x1f;
+    let is_read = iss & 1 != 0;
+    if is_read {
+        let value = sysreg_read(iss);
+        if rt != 31 {
+            ctx.set_gpr(rt, value as usize);
+        }
+    } else {
+        let value = if rt == 31 { 0 } else { ctx.gpr(rt) };
+        sysreg_write(iss, value as u64);
+    }
+    let val = ctx.exception_pc() + exception_next_instruction_step();
+    ctx.set_exception_pc(val);
+}
+
+/// Handle a trapped FP/SIMD (EC 0x07) or SVE (EC 0x19) access: load the
+/// guest's registers and execute the instruction again.
+pub fn fp_trap_handler() -> Option<VmExitInfo> {
+    if let Some(regs) = current_vcpu_regs() {
+        regs.load_guest_fp();
+    }
+    None
+}
+
+/// Handle a trapped WFI or WFE (EC 0x01). WFI yields the physical CPU to the
+/// host until the vCPU has an interrupt to take. WFE is only a hint and
+/// returns to the guest.
+pub fn wfx_handler(ctx: &mut ContextFrame) -> Option<VmExitInfo> {
+    let is_wfe = exception_iss() & 1 != 0;
+    let val = ctx.exception_pc() + exception_next_instruction_step();
+    ctx.set_exception_pc(val);
+    if is_wfe {
+        None
+    } else {
+        Some(VmExitInfo::WaitForInterrupt)
+    }
+}
+
+/// Handle a stage-2 instruction abort (EC 0x20): the guest executes from
+/// an address with no memory behind it. The host decides whether to map it
+/// and resume, or stop the VM.
+pub fn instruction_abort_handler(ctx: &mut ContextFrame) -> Option<VmExitInfo> {
+    debug!("instruction fault addr 0x{:x}, esr: 0x{:x}",
+        exception_fault_addr(), exception_esr());
+    Some(VmExitInfo::InstructionAbort {
+        ipa: exception_fault_addr(),
+        pc: ctx.exception_pc(),
+    })
+}
+
+/// SMCCC return value of unknown function identifiers.
+const SMCCC_NOT_SUPPORTED: usize = usize::MAX;
+
+/// Handle an SMC Calling Convention call of a guest, through HVC or SMC.
+/// The vCPU exits to the host after `CPU_OFF`, `SYSTEM_OFF` or
+/// `SYSTEM_RESET`, which learns about it from the VM's lifecycle events.
+fn smccc_guest_handler(ctx: &mut ContextFrame) -> Option<VmExitInfo> {
+    let fid = ctx.gpr(0);
+    if !is_psci_function(fid) {
+        debug!("Unsupported SMCCC function 0x{:x}", fid);
+        ctx.set_gpr(0, SMCCC_NOT_SUPPORTED);
+        return None;
+    }
+    match psci_guest_handler(ctx) {
+        PsciAction::Resume => None,
+        PsciAction::Stop => Some(VmExitInfo::Lifecycle),
+    }
+}
+
+/// Handle a trapped SMC (EC 0x17). `ELR_EL2` holds the address of the SMC
+/// instruction itself.
+pub fn smc_handler(ctx: &mut ContextFrame) -> Option<VmExitInfo> {
+    let exit = smccc_guest_handler(ctx);
+    let val = ctx.exception_pc() + exception_next_instruction_step();
+    ctx.set_exception_pc(val);
+    exit
 }
 
 #[inline(never)]
-pub fn hvc_handler(ctx: &mut ContextFrame) {
+pub fn hvc_handler(ctx: &mut ContextFrame) -> Option<VmExitInfo> {
+    // Only the host uses the HVC_SYS immediate, guests make SMCCC calls.
+    if current_vcpu_regs().is_some() || exception_iss() & 0xffff != HVC_SYS_IMM {
+        return smccc_guest_handler(ctx);
+    }
     let x0 = ctx.gpr(0);
     let x1 = ctx.gpr(1);
     let x2 = ctx.gpr(2);
@@ -95,18 +257,8 @@ pub fn hvc_handler(ctx: &mut ContextFrame) {
         }
     }
     if hvc_type==HVC_SYS && event== HVC_SYS_BOOT {
-        unsafe {
-            let regs: &mut VmCpuRegisters = core::mem::transmute(x1);   // x1 is the vm regs context
-            // save arceos context
-            regs.save_for_os_context_regs.gpr = ctx.gpr;
-            regs.save_for_os_context_regs.sp = ctx.sp;
-            regs.save_for_os_context_regs.elr = ctx.elr;
-            regs.save_for_os_context_regs.spsr = ctx.spsr;
-
-            ctx.gpr = regs.guest_trap_context_regs.gpr;
-            ctx.sp = regs.guest_trap_context_regs.sp;
-            ctx.elr = regs.guest_trap_context_regs.elr;
-            ctx.spsr = regs.guest_trap_context_regs.spsr;
-        }
+        let regs: &mut VmCpuRegisters = unsafe { core::mem::transmute(x1) };   // x1 is the vm regs context
+        enter_guest(ctx, regs);
     }
+    None
 }
@@ -0,0 +1,144 @@
+use crate::arch::vgic::vgic_send_sgi_current;
+use crate::mrs;
+
+/// The ISS encoding (Op0, Op2, Op1, CRn, CRm) of a system register in an
+/// MSR/MRS trap (EC 0x18).
+pub const fn sysreg_iss(op0: usize, op1: usize, crn: usize, crm: usize, op2: usize) -> usize {
+    (op0 << 20) | (op2 << 17) | (op1 << 14) | (crn << 10) | (crm << 1)
+}
+
+/// The register encoding bits of the ISS, without Rt and the direction.
+pub const ISS_SYSREG_MASK: usize = 0x3ffc1e;
+
+/// HCR_EL2.TID3: trap the ID group 3 registers.
+pub const HCR_EL2_TID3: u64 = 1 << 18;
+
+/// MDCR_EL2.TDA and MDCR_EL2.TDOSA: trap the debug and OS lock registers.
+pub const MDCR_EL2_TDA_TDOSA: u64 = (1 << 9) | (1 << 10);
+
+/// MDCR_EL2.HPMN, the number of PMU event counters accessible from EL1.
+pub const MDCR_EL2_HPMN: u64 = 0x1f;
+
+/// `OSLSR_EL1` with OSLM = 0b10: the OS lock is implemented and unlocked.
+const OSLSR_EL1_OSLM_IMPLEMENTED: u64 = 1 << 3;
+
+/// How the accesses to a trapped register are emulated.
+#[derive(Clone, Copy)]
+enum SysRegAccess {
+    /// Reads return the value of the function, writes are ignored.
+    Read(fn() -> u64),
+    /// Writes are passed to the function, reads return zero.
+    Write(fn(u64)),
+    /// Reads return the value, writes are ignored.
+    Const(u64),
+}
+
+struct SysRegEmu {
+    name: &'static str,
+    iss: usize,
+    access: SysRegAccess,
+}
+
+/// An ID register read from the host, `mrs` taking the generic
+/// `S3_0_C0_C<crm>_<op2>` name.
+macro_rules! host_id_reg {
+    ($name: literal, $reg: ident, $crm: literal, $op2: literal) => {
+        SysRegEmu {
+            name: $name,
+            iss: sysreg_iss(3, 0, 0, $crm, $op2),
+            access: SysRegAccess::Read(|| {
+                let value: u64;
+                mrs!(value, $reg);
+                value
+            }),
+        }
+    };
+}
+
+/// The emulated system registers. Other registers of the ID and debug
+/// spaces read as zero and ignore writes.
+static SYSREG_EMU_TABLE: &[SysRegEmu] = &[
+    // GICv3 SGI generation, with the system register interface of the vGIC.
+    SysRegEmu {
+        name: "ICC_SGI1R_EL1",
+        iss: sysreg_iss(3, 0, 12, 11, 5),
+        access: SysRegAccess::Write(vgic_send_sgi_current),
+    },
+    // ID group 3, trapped by HCR_EL2.TID3.
+    host_id_reg!("ID_AA64PFR0_EL1", S3_0_C0_C4_0, 4, 0),
+    host_id_reg!("ID_AA64PFR1_EL1", S3_0_C0_C4_1, 4, 1),
+    host_id_reg!("ID_AA64ZFR0_EL1", S3_0_C0_C4_4, 4, 4),
+    host_id_reg!("ID_AA64DFR0_EL1", S3_0_C0_C5_0, 5, 0),
+    host_id_reg!("ID_AA64DFR1_EL1", S3_0_C0_C5_1, 5, 1),
+    host_id_reg!("ID_AA64ISAR0_EL1", S3_0_C0_C6_0, 6, 0),
+    host_id_reg!("ID_AA64ISAR1_EL1", S3_0_C0_C6_1, 6, 1),
+    host_id_reg!("ID_AA64ISAR2_EL1", S3_0_C0_C6_2, 6, 2),
+    host_id_reg!("ID_AA64MMFR0_EL1", S3_0_C0_C7_0, 7, 0),
+    host_id_reg!("ID_AA64MMFR1_EL1", S3_0_C0_C7_1, 7, 1),
+    host_id_reg!("ID_AA64MMFR2_EL1", S3_0_C0_C7_2, 7, 2),
+    // OS lock, trapped by MDCR_EL2.TDOSA.
+    SysRegEmu {
+        name: "OSLSR_EL1",
+        iss: sysreg_iss(2, 0, 1, 1, 4),
+        access: SysRegAccess::Const(OSLSR_EL1_OSLM_IMPLEMENTED),
+    },
+];
+
+/// Whether `iss` encodes a register of the ID group 3 (Op0 3, Op1 0, CRn 0,
+/// CRm 1-7), the AArch32 ones among them.
+fn is_id_reg(iss: usize) -> bool {
+    let crm = (iss >> 1) & 0xf;
+    iss & sysreg_iss(3, 7, 15, 0, 0) == sysreg_iss(3, 0, 0, 0, 0) && (1..=7).contains(&crm)
+}
+
+/// Whether `iss` encodes a debug register (Op0 2).
+fn is_debug_reg(iss: usize) -> bool {
+    iss & sysreg_iss(3, 0, 0, 0, 0) == sysreg_iss(2, 0, 0, 0, 0)
+}
+
+fn find_sysreg(iss: usize) -> Option<&'static SysRegEmu> {
+    let iss = iss & ISS_SYSREG_MASK;
+    SYSREG_EMU_TABLE.iter().find(|reg| reg.iss == iss)
+}
+
+fn warn_unhandled(iss: usize, is_read: bool) {
+    let iss = iss & ISS_SYSREG_MASK;
+    if !is_id_reg(iss) && !is_debug_reg(iss) {
+        warn!(
+            "Unhandled system register {}, iss 0x{:x}",
+            if is_read { "read" } else { "write" },
+            iss
+        );
+    }
+}
+
+/// Emulate the trapped read of the register encoded in `iss`.
+pub fn sysreg_read(iss: usize) -> u64 {
+    match find_sysreg(iss) {
+        Some(reg) => match reg.access {
+            SysRegAccess::Read(read) => read(),
+            SysRegAccess::Const(value) => value,
+            SysRegAccess::Write(_) => {
+                debug!("Read of write-only {}", reg.name);
+                0
+            }
+        },
+        None => {
+            warn_unhandled(iss, true);
+            0
+        }
+    }
+}
+
+/// Emulate the trapped write of `value` to the register encoded in `iss`.
+pub fn sysreg_write(iss: usize, value: u64) {
+    match find_sysreg(iss) {
+        Some(reg) => match reg.access {
+            SysRegAccess::Write(write) => write(value),
+            SysRegAccess::Read(_) | SysRegAccess::Const(_) => {
+                debug!("Write of read-only {}", reg.name);
+            }
+        },
+        None => warn_unhandled(iss, false),
+    }
+}
@@ -24,6 +24,10 @@ use crate::arch::context_frame::VmContext;
 use crate::traits::ContextFrameTrait;
 use crate::HyperCraftHal;
 use crate::arch::hvc::run_guest_by_trap2el2;
+use crate::arch::emu::{emu_complete_load, EmuContext};
+use crate::arch::vmexit::VmExitInfo;
+use crate::arch::fpsimd::{fp_untrap, FpContext};
+use crate::arch::sysreg::{HCR_EL2_TID3, MDCR_EL2_TDA_TDOSA};
 
 /// (v)CPU register state that must be saved or restored when entering/exiting a VM or switching
 /// between VMs.
@@ -36,6 +40,16 @@ pub struct VmCpuRegisters {
     pub save_for_os_context_regs: ContextFrame,
     /// virtual machine system regs setting
     pub vm_system_regs: VmContext,
+    /// arceos system regs, saved while the guest runs
+    pub host_system_regs: VmContext,
+    /// why the vcpu last returned to arceos
+    pub exit_info: Option<VmExitInfo>,
+    /// guest fp/simd and sve registers
+    pub guest_fp: FpContext,
+    /// arceos fp/simd and sve registers, saved while the guest's are loaded
+    host_fp: FpContext,
+    /// whether the guest's fp registers are loaded in the cpu
+    fp_loaded: bool,
 }
 
 impl VmCpuRegisters {
@@ -45,10 +59,44 @@ impl VmCpuRegisters {
             guest_trap_context_regs: ContextFrame::default(),
             save_for_os_context_regs: ContextFrame::default(),
             vm_system_regs: VmContext::default(),
+            host_system_regs: VmContext::default(),
+            exit_info: None,
+            guest_fp: FpContext::default(),
+            host_fp: FpContext::default(),
+            fp_loaded: false,
         }
     }
+
+    /// Load the guest's fp registers on its first fp/simd or sve access,
+    /// after saving the host's.
+    pub(crate) fn load_guest_fp(&mut self) {
+        fp_untrap();
+        if !self.fp_loaded {
+            self.host_fp.save();
+            self.guest_fp.restore();
+            self.fp_loaded = true;
+        }
+    }
+
+    /// Give the fp registers back to the host when the vcpu exits.
+    pub(crate) fn put_guest_fp(&mut self) {
+        if self.fp_loaded {
+            self.guest_fp.save();
+            self.host_fp.restore();
+            self.fp_loaded = false;
+        }
+        fp_untrap();
+    }
 }
 
+// Only the guest's registers are saved, the host's are saved again on each
+// entry.
+impl_snapshot_fields!(VmCpuRegisters {
+    guest_trap_context_regs,
+    vm_system_regs,
+    guest_fp,
+});
+
 /// A virtual CPU within a guest
 #[derive(Clone)]
 pub struct VCpu<H:HyperCraftHal> {
@@ -88,11 +136,20 @@ impl <H:HyperCraftHal> VCpu<H> {
         self.vcpu_id
     }
 
-    /// Run this vcpu
-    pub fn run(&self, vttbr_token: usize) {
-        // loop {  // because of elr_el2, it will not return to this?
-            _ = run_guest_by_trap2el2(vttbr_token, self.vcpu_ctx_addr());
-        // }
+    /// Run this vcpu until it traps to the host, and return why.
+    pub fn run(&mut self, vttbr_token: usize) -> VmExitInfo {
+        self.regs.exit_info = None;
+        _ = run_guest_by_trap2el2(vttbr_token, self.vcpu_ctx_addr());
+        self.regs
+            .exit_info
+            .take()
+            .expect("vcpu returned to the host without an exit reason")
+    }
+
+    /// Complete the load of a [`VmExitInfo::MmioRead`] exit with `value`
+    /// read from the device emulated by the host.
+    pub fn complete_mmio_read(&mut self, access: &EmuContext, value: u64) {
+        emu_complete_load(access, &mut self.regs.guest_trap_context_regs, value);
     }
     
     /// Get vcpu whole context address
@@ -121,7 +178,10 @@ impl <H:HyperCraftHal> VCpu<H> {
 
     /// Init guest context. Also set some el2 register value.
     fn init_vm_context(&mut self) {
+        // The VM sets its virtual counter offset after init.
         self.regs.vm_system_regs.cntvoff_el2 = 0;
+        self.regs.vm_system_regs.cntv_ctl_el0 = 0;
+        self.regs.vm_system_regs.cntv_cval_el0 = 0;
         self.regs.vm_system_regs.sctlr_el1 = 0x30C50830;
         self.regs.vm_system_regs.cntkctl_el1 = 0;
         self.regs.vm_system_regs.pmcr_el0 = 0;
@@ -134,8 +194,13 @@ impl <H:HyperCraftHal> VCpu<H> {
                                           + VTCR_EL2::SL0.val(0b01)
                                           + VTCR_EL2::T0SZ.val(64 - 40)).into();
         //self.regs.vm_system_regs.hcr_el2 = 0x80000001;  // Maybe we do not need smc setting? passthrough gic.
-        self.regs.vm_system_regs.hcr_el2 = (HCR_EL2::VM::Enable
+        let hcr_el2: u64 = (HCR_EL2::VM::Enable
                                          + HCR_EL2::RW::EL1IsAarch64).into();
+        // Trap SMC (HCR_EL2.TSC), so that PSCI calls over the SMC conduit reach the hypervisor,
+        // WFI (HCR_EL2.TWI) to yield to the host, and the ID registers.
+        self.regs.vm_system_regs.hcr_el2 = hcr_el2 | (1 << 19) | (1 << 13) | HCR_EL2_TID3;
+        // The debug registers are not switched, so trap them too.
+        self.regs.vm_system_regs.mdcr_el2 = MDCR_EL2_TDA_TDOSA;
         let mut vmpidr = 0;
         vmpidr |= 1 << 31;
         vmpidr |= self.vcpu_id;
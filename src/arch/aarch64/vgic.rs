@@ -0,0 +1,1049 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::Range;
+use spin::Mutex;
+
+use arm_gic::GIC_LIST_REGS_NUM;
+
+use crate::arch::emu::{active_vm_id, current_vcpu_id};
+use crate::arch::gic::{
+    GicState, GicVersion, ListRegister, VgicCpuInterface, GICH_HCR_EN, GICH_HCR_UIE,
+    LR_STATE_ACTIVE, LR_STATE_PENDING,
+};
+use crate::arch::gicv3::GicV3State;
+use crate::devices::MmioDevice;
+use crate::snapshot::{Snapshot, SnapshotReader, SnapshotWriter};
+use crate::{GuestPhysAddr, HyperError, HyperResult};
+
+/// Size of the GICv2 distributor register window.
+pub const GICD_SIZE: usize = 0x1000;
+/// Size of the GICv3 distributor register window.
+pub const GICV3_GICD_SIZE: usize = 0x10000;
+/// Size of the redistributor region of one vCPU: the RD_base and SGI_base frames.
+pub const GICR_SIZE_PER_VCPU: usize = 0x20000;
+/// Number of software generated interrupts.
+pub const GIC_SGIS_NUM: usize = 16;
+/// Number of banked (SGI and PPI) interrupts of each CPU interface.
+pub const GIC_PRIVATE_INT_NUM: usize = 32;
+/// Number of interrupt IDs implemented by the virtual distributor.
+pub const VGIC_INTS_NUM: usize = 256;
+/// The PPI signalled by the virtual CPU interface for maintenance interrupts.
+pub const GIC_MAINTENANCE_IRQ: usize = 25;
+
+const GICD_CTLR: usize = 0x000;
+const GICD_TYPER: usize = 0x004;
+const GICD_IIDR: usize = 0x008;
+const GICD_IGROUPR: usize = 0x080;
+const GICD_ISENABLER: usize = 0x100;
+const GICD_ICENABLER: usize = 0x180;
+const GICD_ISPENDR: usize = 0x200;
+const GICD_ICPENDR: usize = 0x280;
+const GICD_ISACTIVER: usize = 0x300;
+const GICD_ICACTIVER: usize = 0x380;
+const GICD_IPRIORITYR: usize = 0x400;
+const GICD_ITARGETSR: usize = 0x800;
+const GICD_ICFGR: usize = 0xc00;
+const GICD_SGIR: usize = 0xf00;
+const GICD_CPENDSGIR: usize = 0xf10;
+const GICD_SPENDSGIR: usize = 0xf20;
+const GICD_PIDR2: usize = 0xfe8;
+const GICD_IROUTER: usize = 0x6000;
+const GICV3_PIDR2: usize = 0xffe8;
+
+const GICD_CTLR_ARE_NS: u32 = 1 << 4;
+const GICD_CTLR_DS: u32 = 1 << 6;
+
+const GICR_CTLR: usize = 0x0000;
+const GICR_IIDR: usize = 0x0004;
+const GICR_TYPER: usize = 0x0008;
+const GICR_WAKER: usize = 0x0014;
+const GICR_SYNCR: usize = 0x00c0;
+const GICR_SGI_BASE: usize = 0x10000;
+
+const GICR_WAKER_PROCESSOR_SLEEP: u32 = 1 << 1;
+const GICR_WAKER_CHILDREN_ASLEEP: u32 = 1 << 2;
+
+/// State of a single virtual interrupt.
+#[derive(Debug, Clone, Copy, Default)]
+struct VgicIrq {
+    enabled: bool,
+    pending: bool,
+    active: bool,
+    /// Edge-triggered rather than level-sensitive.
+    edge: bool,
+    group1: bool,
+    priority: u8,
+    targets: u8,
+    /// Backed by the physical interrupt of the same ID, which the guest
+    /// deactivates directly through the list register.
+    hw: bool,
+    /// The level of the line driven by [`Vgic::set_irq_level`].
+    level: bool,
+    /// For SGIs, the requesting vCPUs not yet loaded into a list register.
+    /// GICv3 does not track SGI sources and only uses bit 0.
+    sgi_sources: u8,
+    /// The vCPU and the index of the list register holding this interrupt.
+    lr: Option<(usize, usize)>,
+}
+
+impl_snapshot_fields!(VgicIrq {
+    enabled,
+    pending,
+    active,
+    edge,
+    group1,
+    priority,
+    targets,
+    hw,
+    level,
+    sgi_sources,
+    lr,
+});
+
+impl VgicIrq {
+    fn is_pending(&self) -> bool {
+        self.pending || self.sgi_sources != 0
+    }
+}
+
+type IrqGetter = fn(&VgicIrq) -> u32;
+type IrqSetter = fn(usize, &mut VgicIrq, u32);
+
+/// Per vCPU state: the banked interrupts, the list register usage and the
+/// GICv3 redistributor.
+struct VgicCpu {
+    private: [VgicIrq; GIC_PRIVATE_INT_NUM],
+    /// The interrupt loaded into each list register.
+    lr_irqs: [Option<usize>; GIC_LIST_REGS_NUM],
+    waker: u32,
+}
+
+impl_snapshot_fields!(VgicCpu {
+    private,
+    lr_irqs,
+    waker,
+});
+
+impl VgicCpu {
+    fn new(vcpu_id: usize, version: GicVersion) -> Self {
+        let mut private = [VgicIrq::default(); GIC_PRIVATE_INT_NUM];
+        for irq in private.iter_mut() {
+            irq.targets = 1 << vcpu_id;
+        }
+        // SGIs are always edge-triggered, and enabled at reset in GICv2.
+        for sgi in private[..GIC_SGIS_NUM].iter_mut() {
+            sgi.edge = true;
+            sgi.enabled = version == GicVersion::V2;
+        }
+        Self {
+            private,
+            lr_irqs: [None; GIC_LIST_REGS_NUM],
+            waker: GICR_WAKER_PROCESSOR_SLEEP | GICR_WAKER_CHILDREN_ASLEEP,
+        }
+    }
+}
+
+/// A virtual GICv2 or GICv3 distributor, with interrupts delivered to the
+/// guest through the list registers of the GIC virtualization extensions.
+///
+/// For GICv2 the guest's GICC window must be backed by the physical GICV
+/// frame. For GICv3 the guest uses the virtual system register CPU interface,
+/// and the redistributors are emulated by [`VgicRedistributors`]. In both
+/// cases `HCR_EL2.{IMO,FMO}` must be set for the VM's vCPUs. Interrupts which
+/// do not fit into the list registers stay pending here, and the underflow
+/// maintenance interrupt is enabled to load them once list registers free up.
+pub struct Vgic {
+    version: GicVersion,
+    base: GuestPhysAddr,
+    ctlr: u32,
+    cpus: Vec<VgicCpu>,
+    spis: Vec<VgicIrq>,
+}
+
+impl Vgic {
+    /// Create a distributor of GIC `version` at guest physical address `base`
+    /// for `nr_vcpus` vCPUs.
+    pub fn new(version: GicVersion, base: GuestPhysAddr, nr_vcpus: usize) -> Self {
+        Self {
+            version,
+            base,
+            ctlr: 0,
+            cpus: (0..nr_vcpus).map(|id| VgicCpu::new(id, version)).collect(),
+            spis: alloc::vec![VgicIrq::default(); VGIC_INTS_NUM - GIC_PRIVATE_INT_NUM],
+        }
+    }
+
+    /// The GIC architecture version presented to the guest.
+    pub fn version(&self) -> GicVersion {
+        self.version
+    }
+
+    fn irq_mut(&mut self, vcpu_id: usize, irq: usize) -> Option<&mut VgicIrq> {
+        if irq < GIC_PRIVATE_INT_NUM {
+            self.cpus.get_mut(vcpu_id).map(|cpu| &mut cpu.private[irq])
+        } else {
+            self.spis.get_mut(irq - GIC_PRIVATE_INT_NUM)
+        }
+    }
+
+    /// Make `irq` pending. `vcpu_id` selects the banked SGI or PPI, SPIs go
+    /// to the vCPUs set in their `GICD_ITARGETSR` or `GICD_IROUTER`.
+    pub fn inject_irq(&mut self, vcpu_id: usize, irq: usize) -> HyperResult {
+        let virq = self.irq_mut(vcpu_id, irq).ok_or(HyperError::InvalidParam)?;
+        if irq < GIC_SGIS_NUM {
+            // The hypervisor is reported as the SGI source of vCPU 0.
+            virq.sgi_sources |= 1;
+        } else {
+            virq.pending = true;
+        }
+        Ok(())
+    }
+
+    /// Make the physical interrupt `irq` pending in the guest. The guest's
+    /// EOI deactivates the physical interrupt, so the host must have
+    /// acknowledged it with `EOImode` set, without deactivating.
+    pub fn inject_hw_irq(&mut self, vcpu_id: usize, irq: usize) -> HyperResult {
+        if irq < GIC_SGIS_NUM {
+            return Err(HyperError::InvalidParam);
+        }
+        let virq = self.irq_mut(vcpu_id, irq).ok_or(HyperError::InvalidParam)?;
+        virq.hw = true;
+        virq.pending = true;
+        Ok(())
+    }
+
+    /// Drive the level-sensitive interrupt `irq` with the interrupt line of
+    /// an emulated device. It is pending while the line is high, and again
+    /// after the guest completes it if the line is still high then.
+    pub fn set_irq_level(&mut self, vcpu_id: usize, irq: usize, level: bool) -> HyperResult {
+        if irq < GIC_SGIS_NUM {
+            return Err(HyperError::InvalidParam);
+        }
+        let virq = self.irq_mut(vcpu_id, irq).ok_or(HyperError::InvalidParam)?;
+        virq.level = level;
+        // An interrupt in a list register is resampled when it retires.
+        virq.pending = level && virq.lr.is_none();
+        Ok(())
+    }
+
+    /// Synchronize the list registers of the current physical CPU, which
+    /// runs `vcpu_id`, with the distributor state: retire completed
+    /// interrupts and load the highest priority pending ones.
+    pub fn flush_lrs(&mut self, vcpu_id: usize) {
+        match self.version {
+            GicVersion::V2 => self.flush_lrs_with::<GicState>(vcpu_id),
+            GicVersion::V3 => self.flush_lrs_with::<GicV3State>(vcpu_id),
+        }
+    }
+
+    fn flush_lrs_with<C: VgicCpuInterface>(&mut self, vcpu_id: usize) {
+        if vcpu_id >= self.cpus.len() {
+            return;
+        }
+        let lrs_num = C::lrs_num().min(GIC_LIST_REGS_NUM);
+
+        // Retire interrupts the guest has completed.
+        for lr_idx in 0..lrs_num {
+            let irq = match self.cpus[vcpu_id].lr_irqs[lr_idx] {
+                Some(irq) => irq,
+                None => continue,
+            };
+            let state = C::lr_state(lr_idx);
+            let virq = self.irq_mut(vcpu_id, irq).unwrap();
+            virq.active = state & LR_STATE_ACTIVE != 0;
+            if state == 0 {
+                virq.lr = None;
+                virq.pending |= virq.level;
+                self.cpus[vcpu_id].lr_irqs[lr_idx] = None;
+            }
+        }
+
+        // Collect deliverable interrupts, highest priority first. This runs
+        // on every guest entry at EL2, so they are sorted on the stack.
+        let mut candidates = [(0u8, 0u16); VGIC_INTS_NUM];
+        let mut count = 0;
+        if self.ctlr & 0x3 != 0 {
+            let private = self.cpus[vcpu_id].private.iter().enumerate();
+            let spis = self
+                .spis
+                .iter()
+                .enumerate()
+                .map(|(i, virq)| (i + GIC_PRIVATE_INT_NUM, virq))
+                .filter(|(_, virq)| virq.targets & (1 << vcpu_id) != 0);
+            for (irq, virq) in private.chain(spis) {
+                if virq.enabled && virq.is_pending() {
+                    candidates[count] = (virq.priority, irq as u16);
+                    count += 1;
+                }
+            }
+        }
+        let candidates = &mut candidates[..count];
+        candidates.sort_unstable();
+
+        let mut overflow = false;
+        for &(_, irq) in candidates.iter() {
+            let irq = irq as usize;
+            let in_lr = self.irq_mut(vcpu_id, irq).unwrap().lr;
+            let lr_idx = match in_lr {
+                // Only an active interrupt may become pending again in its
+                // LR, and an SPI is only loaded on one vCPU at a time.
+                Some((lr_vcpu, lr_idx)) => {
+                    if lr_vcpu != vcpu_id
+                        || C::lr_state(lr_idx) != LR_STATE_ACTIVE
+                        || irq < GIC_SGIS_NUM
+                    {
+                        continue;
+                    }
+                    lr_idx
+                }
+                None => match (0..lrs_num).find(|&i| self.cpus[vcpu_id].lr_irqs[i].is_none()) {
+                    Some(lr_idx) => lr_idx,
+                    None => {
+                        overflow = true;
+                        break;
+                    }
+                },
+            };
+            let virq = self.irq_mut(vcpu_id, irq).unwrap();
+            let mut lr = ListRegister {
+                vintid: irq as u32,
+                pintid: None,
+                source: 0,
+                priority: virq.priority,
+                state: LR_STATE_PENDING,
+                group1: virq.group1,
+            };
+            if virq.active {
+                lr.state |= LR_STATE_ACTIVE;
+            }
+            if irq < GIC_SGIS_NUM {
+                lr.source = virq.sgi_sources.trailing_zeros();
+                virq.sgi_sources &= !(1 << lr.source);
+            } else {
+                virq.pending = false;
+                if virq.hw {
+                    lr.pintid = Some(irq as u32);
+                }
+            }
+            virq.lr = Some((vcpu_id, lr_idx));
+            self.cpus[vcpu_id].lr_irqs[lr_idx] = Some(irq);
+            C::write_lr(lr_idx, &lr);
+        }
+
+        let mut hcr = C::hcr() | GICH_HCR_EN;
+        if overflow {
+            hcr |= GICH_HCR_UIE;
+        } else {
+            hcr &= !GICH_HCR_UIE;
+        }
+        C::set_hcr(hcr);
+    }
+
+    /// Handle a write of `GICD_SGIR` (GICv2).
+    fn send_sgi(&mut self, sender: usize, value: u32) {
+        let sgi = (value & 0xf) as usize;
+        let targets = match (value >> 24) & 0b11 {
+            0 => (value >> 16) & 0xff,
+            // All but self
+            1 => ((1 << self.cpus.len()) - 1) & !(1 << sender),
+            // Self only
+            2 => 1 << sender,
+            _ => return,
+        };
+        for (vcpu_id, cpu) in self.cpus.iter_mut().enumerate() {
+            if targets & (1 << vcpu_id) != 0 {
+                cpu.private[sgi].sgi_sources |= 1 << sender;
+            }
+        }
+    }
+
+    /// Handle a write of `ICC_SGI1R_EL1` by vCPU `sender` (GICv3). vCPUs are
+    /// addressed by affinity level 0 only, as set in their `VMPIDR_EL2`.
+    pub fn send_sgi_v3(&mut self, sender: usize, value: u64) {
+        let sgi = ((value >> 24) & 0xf) as usize;
+        let all = (1u64 << self.cpus.len()) - 1;
+        let targets = if value & (1 << 40) != 0 {
+            // IRM: all but self
+            all & !(1 << sender)
+        } else if value & 0x00ff_00ff_00ff_0000 != 0 {
+            // Non-zero Aff1, Aff2, Aff3 or range selector
+            0
+        } else {
+            value & 0xffff & all
+        };
+        for (vcpu_id, cpu) in self.cpus.iter_mut().enumerate() {
+            if targets & (1 << vcpu_id) != 0 {
+                cpu.private[sgi].sgi_sources |= 1;
+            }
+        }
+    }
+
+    /// The register holding `bits` bits per interrupt at `offset`, with the
+    /// accessors of the interrupt state it reflects.
+    fn irq_register(offset: usize) -> Option<(usize, usize, IrqGetter, IrqSetter)> {
+        let reg: (usize, usize, IrqGetter, IrqSetter) = match offset {
+            GICD_IGROUPR..=0x0fc => (
+                GICD_IGROUPR,
+                1,
+                |v| v.group1 as u32,
+                |_, v, bit| v.group1 = bit != 0,
+            ),
+            GICD_ISENABLER..=0x17c => (
+                GICD_ISENABLER,
+                1,
+                |v| v.enabled as u32,
+                |_, v, bit| v.enabled |= bit != 0,
+            ),
+            GICD_ICENABLER..=0x1fc => (
+                GICD_ICENABLER,
+                1,
+                |v| v.enabled as u32,
+                |_, v, bit| {
+                    if bit != 0 {
+                        v.enabled = false;
+                    }
+                },
+            ),
+            // A pending SGI set here is reported as coming from vCPU 0.
+            GICD_ISPENDR..=0x27c => (
+                GICD_ISPENDR,
+                1,
+                |v| v.is_pending() as u32,
+                |irq, v, bit| {
+                    if bit != 0 && irq < GIC_SGIS_NUM {
+                        v.sgi_sources |= 1;
+                    } else if bit != 0 {
+                        v.pending = true;
+                    }
+                },
+            ),
+            GICD_ICPENDR..=0x2fc => (
+                GICD_ICPENDR,
+                1,
+                |v| v.is_pending() as u32,
+                |_, v, bit| {
+                    if bit != 0 {
+                        v.pending = false;
+                        v.sgi_sources = 0;
+                    }
+                },
+            ),
+            GICD_ISACTIVER..=0x37c => (
+                GICD_ISACTIVER,
+                1,
+                |v| v.active as u32,
+                |_, v, bit| v.active |= bit != 0,
+            ),
+            GICD_ICACTIVER..=0x3fc => (
+                GICD_ICACTIVER,
+                1,
+                |v| v.active as u32,
+                |_, v, bit| {
+                    if bit != 0 {
+                        v.active = false;
+                    }
+                },
+            ),
+            GICD_IPRIORITYR..=0x7fb => (
+                GICD_IPRIORITYR,
+                8,
+                |v| v.priority as u32,
+                |_, v, prio| v.priority = prio as u8,
+            ),
+            GICD_ITARGETSR..=0xbfb => (
+                GICD_ITARGETSR,
+                8,
+                |v| v.targets as u32,
+                |irq, v, targets| {
+                    // Targets of SGIs and PPIs are read-only.
+                    if irq >= GIC_PRIVATE_INT_NUM {
+                        v.targets = targets as u8;
+                    }
+                },
+            ),
+            GICD_ICFGR..=0xcfc => (
+                GICD_ICFGR,
+                2,
+                |v| (v.edge as u32) << 1,
+                |irq, v, cfg| {
+                    if irq >= GIC_SGIS_NUM {
+                        v.edge = cfg & 0b10 != 0;
+                    }
+                },
+            ),
+            _ => return None,
+        };
+        Some(reg)
+    }
+
+    fn read_register(&mut self, vcpu_id: usize, offset: usize, width: usize) -> u64 {
+        let v3 = self.version == GicVersion::V3;
+        if let Some((reg, bits, get, _)) = Self::irq_register(offset) {
+            let first = (offset - reg) * 8 / bits;
+            // With affinity routing, SGIs and PPIs are in the redistributors
+            // and GICD_ITARGETSR is not used.
+            if v3 && (first < GIC_PRIVATE_INT_NUM || reg == GICD_ITARGETSR) {
+                return 0;
+            }
+            return self.read_irq_bits(vcpu_id, first, bits, width, get);
+        }
+        match offset {
+            GICD_CTLR if v3 => (self.ctlr | GICD_CTLR_ARE_NS | GICD_CTLR_DS) as u64,
+            GICD_CTLR => self.ctlr as u64,
+            GICD_TYPER => {
+                // ITLinesNumber and CPUNumber, and IDbits for GICv3
+                let mut typer = (VGIC_INTS_NUM / 32 - 1) | ((self.cpus.len() - 1) << 5);
+                if v3 {
+                    typer |= 9 << 19;
+                }
+                typer as u64
+            }
+            // Implemented by ARM
+            GICD_IIDR => 0x43b,
+            GICD_CPENDSGIR..=0xf1f | GICD_SPENDSGIR..=0xf2f if !v3 => {
+                let first = offset & 0xf;
+                let mut value = 0;
+                for i in 0..width.min(GIC_SGIS_NUM - first) {
+                    let sources = self.cpus[vcpu_id].private[first + i].sgi_sources;
+                    value |= (sources as u64) << (i * 8);
+                }
+                value
+            }
+            GICD_IROUTER..=0x7fdc if v3 => {
+                let irq = (offset - GICD_IROUTER) / 8;
+                let router = match self.irq_mut(vcpu_id, irq) {
+                    // Aff0 of the first target vCPU
+                    Some(virq) if irq >= GIC_PRIVATE_INT_NUM => {
+                        (virq.targets.trailing_zeros() % 8) as u64
+                    }
+                    _ => 0,
+                };
+                router >> ((offset & 0x4) * 8)
+            }
+            // Architecture version 2 or 3
+            GICD_PIDR2 if !v3 => 0x2 << 4,
+            GICV3_PIDR2 if v3 => 0x3 << 4,
+            _ => {
+                debug!("vGICD: read of unknown register {:#x}", offset);
+                0
+            }
+        }
+    }
+
+    fn write_register(&mut self, vcpu_id: usize, offset: usize, width: usize, value: u64) {
+        let v3 = self.version == GicVersion::V3;
+        if let Some((reg, bits, _, set)) = Self::irq_register(offset) {
+            let first = (offset - reg) * 8 / bits;
+            if v3 && (first < GIC_PRIVATE_INT_NUM || reg == GICD_ITARGETSR) {
+                return;
+            }
+            self.write_irq_bits(vcpu_id, first, bits, width, value as u32, set);
+            return;
+        }
+        match offset {
+            GICD_CTLR => self.ctlr = value as u32 & 0x3,
+            GICD_SGIR if !v3 => self.send_sgi(vcpu_id, value as u32),
+            GICD_CPENDSGIR..=0xf1f | GICD_SPENDSGIR..=0xf2f if !v3 => {
+                let set = offset >= GICD_SPENDSGIR;
+                let first = offset & 0xf;
+                for i in 0..width.min(GIC_SGIS_NUM - first) {
+                    let sources = (value >> (i * 8)) as u8;
+                    let sgi = &mut self.cpus[vcpu_id].private[first + i];
+                    if set {
+                        sgi.sgi_sources |= sources;
+                    } else {
+                        sgi.sgi_sources &= !sources;
+                    }
+                }
+            }
+            // The upper word only holds Aff3.
+            GICD_IROUTER..=0x7fdc if v3 && offset & 0x4 == 0 => {
+                let irq = (offset - GICD_IROUTER) / 8;
+                let nr_vcpus = self.cpus.len();
+                if let Some(virq) = self.irq_mut(vcpu_id, irq) {
+                    if irq < GIC_PRIVATE_INT_NUM {
+                        return;
+                    }
+                    let aff0 = (value & 0xff) as usize;
+                    // Interrupts routed to any PE (IRM) are delivered to vCPU 0.
+                    virq.targets = if value & (1 << 31) != 0 {
+                        1
+                    } else if value & 0xff_ff00 == 0 && aff0 < nr_vcpus {
+                        1 << aff0
+                    } else {
+                        0
+                    };
+                }
+            }
+            _ => debug!(
+                "vGICD: write to read-only or unknown register {:#x}",
+                offset
+            ),
+        }
+    }
+
+    fn read_irq_bits(
+        &mut self,
+        vcpu_id: usize,
+        first: usize,
+        bits: usize,
+        width: usize,
+        get: IrqGetter,
+    ) -> u64 {
+        let mut value = 0;
+        for i in 0..(width * 8 / bits) {
+            if let Some(virq) = self.irq_mut(vcpu_id, first + i) {
+                value |= ((get(virq) & ((1 << bits) - 1)) as u64) << (i * bits);
+            }
+        }
+        value
+    }
+
+    fn write_irq_bits(
+        &mut self,
+        vcpu_id: usize,
+        first: usize,
+        bits: usize,
+        width: usize,
+        value: u32,
+        set: IrqSetter,
+    ) {
+        for i in 0..(width * 8 / bits) {
+            let field = (value >> (i * bits)) & ((1 << bits) - 1);
+            if let Some(virq) = self.irq_mut(vcpu_id, first + i) {
+                set(first + i, virq, field);
+            }
+        }
+    }
+
+    fn read_redist_register(&mut self, vcpu_id: usize, offset: usize, width: usize) -> u64 {
+        if offset >= GICR_SGI_BASE {
+            let offset = offset - GICR_SGI_BASE;
+            return match Self::irq_register(offset) {
+                Some((reg, bits, get, _)) if reg != GICD_ITARGETSR => {
+                    let first = (offset - reg) * 8 / bits;
+                    if first >= GIC_PRIVATE_INT_NUM {
+                        return 0;
+                    }
+                    self.read_irq_bits(vcpu_id, first, bits, width, get)
+                }
+                _ => {
+                    debug!("vGICR: read of unknown register {:#x}", offset);
+                    0
+                }
+            };
+        }
+        match offset {
+            GICR_CTLR | GICR_SYNCR => 0,
+            GICR_IIDR => 0x43b,
+            GICR_TYPER | 0x000c => {
+                // Affinity value, processor number and Last
+                let mut typer = ((vcpu_id as u64) << 32) | ((vcpu_id as u64) << 8);
+                if vcpu_id == self.cpus.len() - 1 {
+                    typer |= 1 << 4;
+                }
+                typer >> ((offset - GICR_TYPER) * 8)
+            }
+            GICR_WAKER => self.cpus[vcpu_id].waker as u64,
+            GICV3_PIDR2 => 0x3 << 4,
+            _ => {
+                debug!("vGICR: read of unknown register {:#x}", offset);
+                0
+            }
+        }
+    }
+
+    fn write_redist_register(&mut self, vcpu_id: usize, offset: usize, width: usize, value: u64) {
+        if offset >= GICR_SGI_BASE {
+            let offset = offset - GICR_SGI_BASE;
+            match Self::irq_register(offset) {
+                Some((reg, bits, _, set)) if reg != GICD_ITARGETSR => {
+                    let first = (offset - reg) * 8 / bits;
+                    if first < GIC_PRIVATE_INT_NUM {
+                        self.write_irq_bits(vcpu_id, first, bits, width, value as u32, set);
+                    }
+                }
+                _ => debug!("vGICR: write to unknown register {:#x}", offset),
+            }
+            return;
+        }
+        match offset {
+            GICR_WAKER => {
+                // The redistributor goes to sleep or wakes up immediately.
+                let sleep = value as u32 & GICR_WAKER_PROCESSOR_SLEEP;
+                let asleep = if sleep != 0 {
+                    GICR_WAKER_CHILDREN_ASLEEP
+                } else {
+                    0
+                };
+                self.cpus[vcpu_id].waker = sleep | asleep;
+            }
+            _ => debug!(
+                "vGICR: write to read-only or unknown register {:#x}",
+                offset
+            ),
+        }
+    }
+}
+
+/// Only the byte-per-interrupt registers allow byte accesses, and only the
+/// 64-bit registers of GICv3 allow doubleword accesses. All registers allow
+/// aligned word accesses.
+fn check_access(offset: usize, width: usize, version: GicVersion) -> HyperResult {
+    let v3 = version == GicVersion::V3;
+    let byte_ok = matches!(offset, GICD_IPRIORITYR..=0x7fb)
+        || (!v3 && matches!(offset, GICD_ITARGETSR..=0xbfb | GICD_CPENDSGIR..=0xf2f));
+    let dword_ok = v3 && matches!(offset, GICD_IROUTER..=0x7fdc);
+    let width_ok = width == 4 || (width == 1 && byte_ok) || (width == 8 && dword_ok);
+    if !width_ok || offset & (width - 1) != 0 {
+        return Err(HyperError::InvalidParam);
+    }
+    Ok(())
+}
+
+impl MmioDevice for Vgic {
+    fn mmio_range(&self) -> Range<GuestPhysAddr> {
+        let size = match self.version {
+            GicVersion::V2 => GICD_SIZE,
+            GicVersion::V3 => GICV3_GICD_SIZE,
+        };
+        self.base..self.base + size
+    }
+
+    fn read(&mut self, addr: GuestPhysAddr, width: usize) -> HyperResult<u64> {
+        let offset = addr - self.base;
+        check_access(offset, width, self.version)?;
+        let vcpu_id = current_vcpu_id().min(self.cpus.len() - 1);
+        Ok(self.read_register(vcpu_id, offset, width))
+    }
+
+    fn write(&mut self, addr: GuestPhysAddr, width: usize, value: u64) -> HyperResult {
+        let offset = addr - self.base;
+        check_access(offset, width, self.version)?;
+        let vcpu_id = current_vcpu_id().min(self.cpus.len() - 1);
+        self.write_register(vcpu_id, offset, width, value);
+        Ok(())
+    }
+}
+
+/// The distributor state can only be restored into one with the same
+/// version, address and number of vCPUs.
+impl Snapshot for Vgic {
+    fn save(&self, w: &mut SnapshotWriter) {
+        (self.version as u8).save(w);
+        self.base.save(w);
+        self.ctlr.save(w);
+        self.cpus.len().save(w);
+        self.cpus.iter().for_each(|cpu| cpu.save(w));
+        self.spis.save(w);
+    }
+
+    fn restore(&mut self, r: &mut SnapshotReader<'_>) -> HyperResult {
+        let version = r.read::<u8>()?;
+        let base = r.read::<GuestPhysAddr>()?;
+        if version != self.version as u8 || base != self.base {
+            return Err(HyperError::InvalidParam);
+        }
+        self.ctlr.restore(r)?;
+        if r.read::<usize>()? != self.cpus.len() {
+            return Err(HyperError::InvalidParam);
+        }
+        self.cpus.iter_mut().try_for_each(|cpu| cpu.restore(r))?;
+        self.spis.restore(r)
+    }
+}
+
+/// The GICv3 redistributor regions of a [`Vgic`], one
+/// [`GICR_SIZE_PER_VCPU`] region for each vCPU in order of vCPU id.
+pub struct VgicRedistributors {
+    base: GuestPhysAddr,
+    nr_vcpus: usize,
+    vgic: Arc<Mutex<Vgic>>,
+}
+
+impl VgicRedistributors {
+    /// Create the redistributors of `vgic` at guest physical address `base`.
+    pub fn new(base: GuestPhysAddr, vgic: Arc<Mutex<Vgic>>) -> Self {
+        let nr_vcpus = vgic.lock().cpus.len();
+        Self {
+            base,
+            nr_vcpus,
+            vgic,
+        }
+    }
+
+    /// The vCPU and the offset in its region of `addr`, after checking the access.
+    fn decode(&self, addr: GuestPhysAddr, width: usize) -> HyperResult<(usize, usize)> {
+        let offset = (addr - self.base) % GICR_SIZE_PER_VCPU;
+        let byte_ok = matches!(offset, 0x10400..=0x1041f);
+        let dword_ok = offset == GICR_TYPER;
+        let width_ok = width == 4 || (width == 1 && byte_ok) || (width == 8 && dword_ok);
+        if !width_ok || offset & (width - 1) != 0 {
+            return Err(HyperError::InvalidParam);
+        }
+        Ok(((addr - self.base) / GICR_SIZE_PER_VCPU, offset))
+    }
+}
+
+impl MmioDevice for VgicRedistributors {
+    fn mmio_range(&self) -> Range<GuestPhysAddr> {
+        self.base..self.base + self.nr_vcpus * GICR_SIZE_PER_VCPU
+    }
+
+    fn read(&mut self, addr: GuestPhysAddr, width: usize) -> HyperResult<u64> {
+        let (vcpu_id, offset) = self.decode(addr, width)?;
+        Ok(self
+            .vgic
+            .lock()
+            .read_redist_register(vcpu_id, offset, width))
+    }
+
+    fn write(&mut self, addr: GuestPhysAddr, width: usize, value: u64) -> HyperResult {
+        let (vcpu_id, offset) = self.decode(addr, width)?;
+        self.vgic
+            .lock()
+            .write_redist_register(vcpu_id, offset, width, value);
+        Ok(())
+    }
+}
+
+/// The virtual GICs of the VMs, by VM id.
+static VGICS: Mutex<Vec<(usize, Arc<Mutex<Vgic>>)>> = Mutex::new(Vec::new());
+
+pub(crate) fn vgic_register(vm_id: usize, vgic: Arc<Mutex<Vgic>>) {
+    let mut vgics = VGICS.lock();
+    vgics.retain(|(id, _)| *id != vm_id);
+    vgics.push((vm_id, vgic));
+}
+
+pub(crate) fn vgic_remove(vm_id: usize) {
+    VGICS.lock().retain(|(id, _)| *id != vm_id);
+}
+
+fn current_vgic() -> Option<Arc<Mutex<Vgic>>> {
+    let vm_id = active_vm_id();
+    VGICS
+        .lock()
+        .iter()
+        .find(|(id, _)| *id == vm_id)
+        .map(|(_, vgic)| vgic.clone())
+}
+
+/// Load pending virtual interrupts of the running vCPU into the list
+/// registers. Called before returning to the guest from EL2.
+pub fn vgic_flush_current() {
+    if let Some(vgic) = current_vgic() {
+        vgic.lock().flush_lrs(current_vcpu_id());
+    }
+}
+
+/// Handle a trapped write of `ICC_SGI1R_EL1` by the running vCPU.
+pub(crate) fn vgic_send_sgi_current(value: u64) {
+    if let Some(vgic) = current_vgic() {
+        vgic.lock().send_sgi_v3(current_vcpu_id(), value);
+    }
+}
+
+/// Make `irq` pending in the running vCPU and load it into the list
+/// registers. See [`Vgic::inject_irq`].
+pub fn vgic_inject_current(irq: usize) -> HyperResult {
+    let vgic = current_vgic().ok_or(HyperError::NotFound)?;
+    let mut vgic = vgic.lock();
+    let vcpu_id = current_vcpu_id();
+    vgic.inject_irq(vcpu_id, irq)?;
+    vgic.flush_lrs(vcpu_id);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Enable the SPIs `irqs` and target them to vCPU 0.
+    fn enable_spis(vgic: &mut Vgic, irqs: &[usize]) {
+        for &irq in irqs {
+            vgic.write_register(0, GICD_ISENABLER + irq / 32 * 4, 4, 1 << (irq % 32));
+            vgic.write_register(0, GICD_ITARGETSR + irq, 1, 1);
+        }
+    }
+
+    #[test]
+    fn irq_registers() {
+        let reg = |offset| Vgic::irq_register(offset).map(|(reg, bits, ..)| (reg, bits));
+        assert_eq!(reg(0x104), Some((GICD_ISENABLER, 1)));
+        assert_eq!(reg(0x2fc), Some((GICD_ICPENDR, 1)));
+        assert_eq!(reg(0x421), Some((GICD_IPRIORITYR, 8)));
+        assert_eq!(reg(0x820), Some((GICD_ITARGETSR, 8)));
+        assert_eq!(reg(0xc08), Some((GICD_ICFGR, 2)));
+        assert_eq!(reg(GICD_CTLR), None);
+        assert_eq!(reg(GICD_SGIR), None);
+        assert_eq!(reg(0xd00), None);
+
+        let mut vgic = Vgic::new(GicVersion::V2, 0, 2);
+        // IRQ 33 is bit 1 of the second word.
+        vgic.write_register(0, 0x104, 4, 1 << 1);
+        assert_eq!(vgic.read_register(0, 0x104, 4), 1 << 1);
+        assert_eq!(vgic.read_register(0, 0x184, 4), 1 << 1);
+        vgic.write_register(0, 0x184, 4, 1 << 1);
+        assert_eq!(vgic.read_register(0, 0x104, 4), 0);
+        // One byte per interrupt.
+        vgic.write_register(0, 0x421, 1, 0xa0);
+        assert_eq!(vgic.read_register(0, 0x420, 4), 0xa000);
+        vgic.write_register(0, 0x820, 4, 0x0201);
+        assert_eq!(vgic.read_register(0, 0x821, 1), 0x02);
+        // Two bits per interrupt, and the configuration of SGIs is fixed.
+        vgic.write_register(0, 0xc08, 4, 0b10 << 2);
+        assert_eq!(vgic.read_register(0, 0xc08, 4), 0b10 << 2);
+        vgic.write_register(0, 0xc00, 4, 0);
+        assert_eq!(vgic.read_register(0, 0xc00, 4), 0xaaaa_aaaa);
+        // The banked interrupts of each vCPU.
+        assert_eq!(vgic.read_register(0, 0x820 - 4, 4), 0x0101_0101);
+        assert_eq!(vgic.read_register(1, 0x820 - 4, 4), 0x0202_0202);
+    }
+
+    #[test]
+    fn access_widths() {
+        let check = |offset, width, version| check_access(offset, width, version).is_ok();
+        assert!(check(GICD_CTLR, 4, GicVersion::V2));
+        assert!(!check(GICD_CTLR, 1, GicVersion::V2));
+        assert!(!check(GICD_CTLR, 2, GicVersion::V2));
+        assert!(!check(0x104 + 2, 4, GicVersion::V2));
+        assert!(check(0x401, 1, GicVersion::V2));
+        assert!(check(0x401, 1, GicVersion::V3));
+        assert!(check(0x801, 1, GicVersion::V2));
+        assert!(!check(0x801, 1, GicVersion::V3));
+        assert!(check(GICD_SPENDSGIR + 3, 1, GicVersion::V2));
+        assert!(check(GICD_IROUTER + 0x100, 8, GicVersion::V3));
+        assert!(check(GICD_IROUTER + 0x104, 4, GicVersion::V3));
+        assert!(!check(GICD_IROUTER + 0x104, 8, GicVersion::V3));
+        assert!(!check(GICD_IROUTER + 0x100, 8, GicVersion::V2));
+    }
+
+    #[test]
+    fn sgi_targets() {
+        let sources = |vgic: &Vgic, sgi: usize| -> Vec<u8> {
+            vgic.cpus
+                .iter()
+                .map(|cpu| cpu.private[sgi].sgi_sources)
+                .collect()
+        };
+        let mut vgic = Vgic::new(GicVersion::V2, 0, 4);
+        // Target list
+        vgic.send_sgi(1, 0b0101 << 16 | 3);
+        assert_eq!(sources(&vgic, 3), [0b10, 0, 0b10, 0]);
+        // All but self
+        vgic.send_sgi(1, 1 << 24 | 4);
+        assert_eq!(sources(&vgic, 4), [0b10, 0, 0b10, 0b10]);
+        // Self only
+        vgic.send_sgi(2, 2 << 24 | 5);
+        assert_eq!(sources(&vgic, 5), [0, 0, 0b100, 0]);
+        vgic.send_sgi(2, 3 << 24 | 6);
+        assert_eq!(sources(&vgic, 6), [0; 4]);
+        // GICD_SPENDSGIR holds a byte of sources per SGI.
+        assert_eq!(vgic.read_register(0, GICD_SPENDSGIR, 4), 0b10 << 24);
+
+        let mut vgic = Vgic::new(GicVersion::V3, 0, 4);
+        vgic.send_sgi_v3(0, 2 << 24 | 0b1010);
+        assert_eq!(sources(&vgic, 2), [0, 1, 0, 1]);
+        // IRM
+        vgic.send_sgi_v3(1, 1 << 40 | 6 << 24);
+        assert_eq!(sources(&vgic, 6), [1, 0, 1, 1]);
+        // vCPUs only have affinity level 0, and targets past them are ignored.
+        vgic.send_sgi_v3(0, 1 << 16 | 7 << 24 | 1);
+        vgic.send_sgi_v3(0, 7 << 24 | 0b11_0000);
+        assert_eq!(sources(&vgic, 7), [0; 4]);
+    }
+
+    #[test]
+    fn irouter() {
+        let mut vgic = Vgic::new(GicVersion::V3, 0, 4);
+        let router = GICD_IROUTER + 8 * 40;
+        vgic.write_register(0, router, 8, 2);
+        assert_eq!(vgic.spis[40 - GIC_PRIVATE_INT_NUM].targets, 1 << 2);
+        assert_eq!(vgic.read_register(0, router, 8), 2);
+        assert_eq!(vgic.read_register(0, router + 4, 4), 0);
+        // Routed to any PE, which is vCPU 0.
+        vgic.write_register(0, router, 8, 1 << 31);
+        assert_eq!(vgic.spis[40 - GIC_PRIVATE_INT_NUM].targets, 1);
+        // No such vCPU
+        vgic.write_register(0, router, 8, 0x100 | 1);
+        assert_eq!(vgic.spis[40 - GIC_PRIVATE_INT_NUM].targets, 0);
+        vgic.write_register(0, router, 8, 4);
+        assert_eq!(vgic.spis[40 - GIC_PRIVATE_INT_NUM].targets, 0);
+        // The routes of SGIs and PPIs are fixed.
+        vgic.write_register(1, GICD_IROUTER + 8 * 20, 8, 3);
+        assert_eq!(vgic.cpus[1].private[20].targets, 1 << 1);
+        assert_eq!(
+            vgic.read_register(0, GICD_CTLR, 4),
+            (GICD_CTLR_ARE_NS | GICD_CTLR_DS) as u64
+        );
+    }
+
+    /// List registers in memory: their states and interrupt IDs, and the HCR.
+    static LRS: Mutex<([u8; 2], [u32; 2], u32)> = Mutex::new(([0; 2], [0; 2], 0));
+
+    struct MockCpuInterface;
+
+    impl VgicCpuInterface for MockCpuInterface {
+        fn save_state(&mut self) {}
+
+        fn restore_state(&self) {}
+
+        fn lrs_num() -> usize {
+            2
+        }
+
+        fn lr_state(idx: usize) -> u8 {
+            LRS.lock().0[idx]
+        }
+
+        fn write_lr(idx: usize, lr: &ListRegister) {
+            let mut lrs = LRS.lock();
+            lrs.0[idx] = lr.state;
+            lrs.1[idx] = lr.vintid;
+        }
+
+        fn hcr() -> u32 {
+            LRS.lock().2
+        }
+
+        fn set_hcr(hcr: u32) {
+            LRS.lock().2 = hcr;
+        }
+    }
+
+    #[test]
+    fn list_registers_load_by_priority() {
+        let mut vgic = Vgic::new(GicVersion::V2, 0, 1);
+        vgic.write_register(0, GICD_CTLR, 4, 1);
+        enable_spis(&mut vgic, &[40, 41, 42]);
+        for (irq, priority) in [(40, 0x80), (41, 0x40), (42, 0x60)] {
+            vgic.write_register(0, GICD_IPRIORITYR + irq, 1, priority);
+            vgic.inject_irq(0, irq).unwrap();
+        }
+        vgic.flush_lrs_with::<MockCpuInterface>(0);
+        assert_eq!(LRS.lock().1, [41, 42]);
+        // The third waits for a free list register.
+        assert_eq!(LRS.lock().2, GICH_HCR_EN | GICH_HCR_UIE);
+
+        // The guest completes 41.
+        LRS.lock().0[0] = 0;
+        vgic.flush_lrs_with::<MockCpuInterface>(0);
+        assert_eq!(*LRS.lock(), ([LR_STATE_PENDING; 2], [40, 42], GICH_HCR_EN));
+
+        // A level-sensitive line still high when completed is loaded again.
+        enable_spis(&mut vgic, &[43]);
+        vgic.set_irq_level(0, 43, true).unwrap();
+        LRS.lock().0 = [0; 2];
+        vgic.flush_lrs_with::<MockCpuInterface>(0);
+        assert_eq!(LRS.lock().1[0], 43);
+        LRS.lock().0[0] = 0;
+        vgic.flush_lrs_with::<MockCpuInterface>(0);
+        assert_eq!(LRS.lock().0[0], LR_STATE_PENDING);
+        vgic.set_irq_level(0, 43, false).unwrap();
+        LRS.lock().0[0] = 0;
+        vgic.flush_lrs_with::<MockCpuInterface>(0);
+        assert_eq!(LRS.lock().0[0], 0);
+        assert!(!vgic.spis[43 - GIC_PRIVATE_INT_NUM].is_pending());
+    }
+}
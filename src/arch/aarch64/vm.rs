@@ -1,4 +1,39 @@
-use crate::{HyperCraftHal, GuestPageTableTrait, VmCpus, HyperResult};
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::Range;
+use page_table_entry::MappingFlags;
+use spin::Mutex;
+
+use crate::arch::emu::{
+    emu_init, emu_overlaps, emu_register_dev, emu_register_lazy_ram, emu_remove_vm_devs, EmuContext,
+    EmuDevice,
+};
+use crate::arch::gic::{GicVersion, VgicCpuState};
+use crate::arch::hvc::flush_guest_tlb_by_trap2el2;
+use crate::arch::psci::{
+    psci_pop_event, psci_register_vm, psci_remove_vm, psci_restore_vm, psci_save_vm, psci_set_vcpu_on,
+    VmLifecycleEvent,
+};
+use crate::arch::vgic::{vgic_register, vgic_remove, Vgic, VgicRedistributors};
+use crate::arch::vmexit::VmExitInfo;
+use crate::arch::vtimer::{physical_counter, CNTV_CTL_IMASK, VTIMER_IRQ};
+use crate::arch::{Pl011, VCpu};
+use crate::cow::CowRam;
+use crate::devices::{CharBackend, MmioDevice};
+use crate::dirty_log::{DirtyLog, DirtyTracking};
+use crate::lazy_ram::LazyRam;
+use crate::migration::Migratable;
+use crate::snapshot::{
+    restore_memory, save_memory, Snapshot, SnapshotReader, SnapshotWriter, SECTION_IRQCHIP,
+    SECTION_VCPUS, SECTION_VM,
+};
+use crate::vcpus::VM_CPUS_MAX;
+use crate::{GuestMemory, GuestPhysAddr, HyperCraftHal, GuestPageTableTrait, VmCpus, HyperError, HyperResult, Uart16550, UartAttachment, VirtioMmio};
+
+/// HCR_EL2.FMO and HCR_EL2.IMO: route physical FIQs and IRQs to EL2 and
+/// enable the virtual ones.
+const HCR_EL2_FMO_IMO: u64 = (1 << 3) | (1 << 4);
 
 /// The guest VM
 #[repr(align(4096))]
@@ -9,30 +44,588 @@ pub struct VM<H: HyperCraftHal, G: GuestPageTableTrait> {
     gpt: G,
     /// VM id
     vm_id: usize,
+    /// The GIC version presented to the guest
+    gic_version: GicVersion,
+    /// The virtual interrupt controller, if enabled
+    vgic: Option<Arc<Mutex<Vgic>>>,
+    /// The virtual counter offset (`CNTVOFF_EL2`) shared by all vCPUs
+    cntvoff: u64,
+    /// The pages written by the guest, while dirty logging is enabled
+    dirty_log: Option<DirtyLog>,
+    /// The RAM backed on demand
+    lazy_ram: LazyRam<H>,
+    /// The RAM shared with the template VM, if this is a clone
+    cow_ram: CowRam<H>,
+    /// The 16550 UART and its SPI, emulated by `run`
+    uart: Option<(Uart16550, usize)>,
+    /// The PL011 UART and its SPI, emulated by `run`
+    pl011: Option<(Pl011, usize)>,
+    /// The virtio-mmio devices and their SPIs, emulated by `run`
+    virtio: Vec<(VirtioMmio, usize)>,
 }
 
 impl <H: HyperCraftHal, G: GuestPageTableTrait> VM<H, G> {
     /// Create a new VM
     pub fn new(vcpus: VmCpus<H>, gpt: G, id: usize)-> HyperResult<Self> {
+        Self::new_with_gic_version(vcpus, gpt, id, GicVersion::V2)
+    }
+
+    /// Create a new VM whose vCPUs use the virtual CPU interface of GIC
+    /// `gic_version`. GICv3 requires the host to have enabled the system
+    /// register interface in `ICC_SRE_EL2`.
+    pub fn new_with_gic_version(mut vcpus: VmCpus<H>, gpt: G, id: usize, gic_version: GicVersion) -> HyperResult<Self> {
+        emu_init::<H>();
+        let nr_vcpus = (0..VM_CPUS_MAX).take_while(|&i| vcpus.get_vcpu(i).is_ok()).count();
+        psci_register_vm(id, nr_vcpus);
         Ok(Self { 
                 vcpus: vcpus, 
                 gpt: gpt, 
-                vm_id: id
+                vm_id: id,
+                gic_version,
+                vgic: None,
+                // The virtual counter starts from 0 when the VM is created.
+                cntvoff: physical_counter(),
+                dirty_log: None,
+                lazy_ram: LazyRam::new(),
+                cow_ram: CowRam::new(),
+                uart: None,
+                pl011: None,
+                virtio: Vec::new(),
             }
         )
     }
 
     /// Init VM vcpu by vcpu id. Set kernel entry point.
+    ///
+    /// Also starts a secondary vCPU on [`VmLifecycleEvent::CpuOn`], with the
+    /// context ID in place of the device tree address.
     pub fn init_vm_vcpu(&mut self, vcpu_id:usize, kernel_entry_point: usize, device_tree_ipa: usize) {
+        psci_set_vcpu_on(self.vm_id, vcpu_id);
         let vcpu = self.vcpus.get_vcpu(vcpu_id).unwrap();
         vcpu.init(kernel_entry_point, device_tree_ipa);
+        vcpu.regs.vm_system_regs.gic_state = VgicCpuState::new(self.gic_version);
+        vcpu.regs.vm_system_regs.cntvoff_el2 = self.cntvoff;
+        if self.vgic.is_some() {
+            Self::route_virtual_irqs(vcpu);
+        }
     }
 
-    /// Run this VM.
-    pub fn run(&mut self, vcpu_id: usize) {
-        let vcpu = self.vcpus.get_vcpu(vcpu_id).unwrap();
-        let vttbr_token = (self.vm_id << 48) | self.gpt.token();
+    fn route_virtual_irqs(vcpu: &mut VCpu<H>) {
+        vcpu.regs.vm_system_regs.hcr_el2 |= HCR_EL2_FMO_IMO;
+    }
+
+    /// Emulate a distributor of the VM's GIC version for `nr_vcpus` vCPUs at
+    /// guest physical address `gicd_ipa`, and deliver interrupts through the
+    /// list registers.
+    ///
+    /// For GICv2 the guest's GICC window should be mapped to the physical
+    /// GICV frame. For GICv3 `gicr_ipa` is the base of the redistributor
+    /// regions, of [`GICR_SIZE_PER_VCPU`](crate::GICR_SIZE_PER_VCPU) each.
+    pub fn enable_vgic(&mut self, gicd_ipa: GuestPhysAddr, gicr_ipa: Option<GuestPhysAddr>, nr_vcpus: usize) -> HyperResult {
+        if self.vgic.is_some() || nr_vcpus == 0 || nr_vcpus > VM_CPUS_MAX.min(8) {
+            return Err(HyperError::InvalidParam);
+        }
+        let vgic = Arc::new(Mutex::new(Vgic::new(self.gic_version, gicd_ipa, nr_vcpus)));
+        if self.gic_version == GicVersion::V3 {
+            let gicr_ipa = gicr_ipa.ok_or(HyperError::InvalidParam)?;
+            let redists = VgicRedistributors::new(gicr_ipa, vgic.clone());
+            emu_register_dev(self.vm_id, Arc::new(Mutex::new(redists)))?;
+        }
+        emu_register_dev(self.vm_id, vgic.clone())?;
+        vgic_register(self.vm_id, vgic.clone());
+        for vcpu_id in 0..nr_vcpus {
+            if let Ok(vcpu) = self.vcpus.get_vcpu(vcpu_id) {
+                Self::route_virtual_irqs(vcpu);
+            }
+        }
+        self.vgic = Some(vgic);
+        Ok(())
+    }
+
+    /// Make interrupt `irq` pending in this VM. `vcpu_id` selects the
+    /// target of SGIs and PPIs. The interrupt is delivered on the next entry
+    /// of a target vCPU.
+    pub fn inject_irq(&mut self, vcpu_id: usize, irq: usize) -> HyperResult {
+        let vgic = self.vgic.as_ref().ok_or(HyperError::NotSupported)?;
+        vgic.lock().inject_irq(vcpu_id, irq)
+    }
+
+    /// Forward the physical interrupt `irq`, which the host acknowledged
+    /// without deactivating it, to this VM. See [`Vgic::inject_hw_irq`].
+    pub fn inject_hw_irq(&mut self, vcpu_id: usize, irq: usize) -> HyperResult {
+        let vgic = self.vgic.as_ref().ok_or(HyperError::NotSupported)?;
+        vgic.lock().inject_hw_irq(vcpu_id, irq)
+    }
+
+    /// Nanoseconds until the virtual timer of the descheduled vCPU `vcpu_id`
+    /// fires, or `None` if it is disabled or masked. The host arms one of its
+    /// timers with it and calls [`VM::check_vtimer`] when that expires.
+    pub fn vtimer_deadline_nanos(&mut self, vcpu_id: usize) -> Option<u64> {
+        let vcpu = self.vcpus.get_vcpu(vcpu_id).ok()?;
+        vcpu.regs.vm_system_regs.vtimer_deadline_nanos()
+    }
+
+    /// Inject the virtual timer interrupt into the descheduled vCPU
+    /// `vcpu_id` if its timer expired. Returns whether it did.
+    ///
+    /// The timer is masked until the guest reprograms it, so that its
+    /// physical interrupt does not fire again on the next entry.
+    pub fn check_vtimer(&mut self, vcpu_id: usize) -> HyperResult<bool> {
+        let vcpu = self.vcpus.get_vcpu(vcpu_id)?;
+        if !vcpu.regs.vm_system_regs.vtimer_expired() {
+            return Ok(false);
+        }
+        vcpu.regs.vm_system_regs.cntv_ctl_el0 |= CNTV_CTL_IMASK;
+        self.inject_irq(vcpu_id, VTIMER_IRQ)?;
+        Ok(true)
+    }
+
+    /// Take the oldest PSCI request of the guest, such as starting a
+    /// secondary vCPU or powering the VM off.
+    pub fn pop_lifecycle_event(&mut self) -> Option<VmLifecycleEvent> {
+        psci_pop_event(self.vm_id)
+    }
+
+    /// Register an emulated MMIO device, which handles the accesses of the
+    /// guest at EL2. Its range must not be mapped in the guest page table,
+    /// so that accesses trap as stage-2 data aborts, and must not overlap
+    /// another device of the VM.
+    pub fn register_mmio_device(&mut self, dev: EmuDevice) -> HyperResult {
+        let range = dev.lock().mmio_range();
+        self.check_mmio_range_free(&range)?;
+        emu_register_dev(self.vm_id, dev)
+    }
+
+    /// Attach a 16550 UART at guest physical address `base`, with registers
+    /// `1 << reg_shift` bytes apart, raising SPI `irq` through the virtual
+    /// GIC. Its range must not be mapped in the guest page table, so that its
+    /// accesses exit to [`VM::run`], which emulates them at EL1, and must not
+    /// overlap another device of the VM.
+    pub fn attach_uart(&mut self, base: GuestPhysAddr, reg_shift: u32, irq: usize, backend: Box<dyn CharBackend>) -> HyperResult {
+        let uart = Uart16550::new(UartAttachment::Mmio { base, reg_shift }, backend);
+        self.check_mmio_range_free(&uart.mmio_range())?;
+        self.uart = Some((uart, irq));
+        Ok(())
+    }
+
+    /// Attach a PL011 UART at guest physical address `base`, connected to
+    /// `backend` and raising SPI `irq` through the virtual GIC. Like the
+    /// 16550 UART, it is emulated by [`VM::run`].
+    pub fn attach_pl011(&mut self, base: GuestPhysAddr, irq: usize, backend: Box<dyn CharBackend>) -> HyperResult {
+        let pl011 = Pl011::new(base, backend);
+        self.check_mmio_range_free(&pl011.mmio_range())?;
+        self.pl011 = Some((pl011, irq));
+        Ok(())
+    }
+
+    /// Attach a virtio-mmio device raising SPI `irq` through the virtual GIC.
+    /// Like the UARTs, it is emulated by [`VM::run`].
+    pub fn attach_virtio_mmio(&mut self, device: VirtioMmio, irq: usize) -> HyperResult {
+        self.check_mmio_range_free(&device.mmio_range())?;
+        self.virtio.push((device, irq));
+        Ok(())
+    }
+
+    /// Let the UARTs and the virtio-mmio devices make progress on the host
+    /// side, e.g. for received input, and drive their SPIs from the levels
+    /// of their interrupt outputs.
+    /// [`VM::run`] does it on interrupt exits.
+    pub fn poll_devices(&mut self, vcpu_id: usize) -> HyperResult {
+        if self.vgic.is_none() {
+            return Err(HyperError::NotSupported);
+        }
+        if let Some((uart, _)) = &mut self.uart {
+            uart.poll();
+        }
+        if let Some((pl011, _)) = &mut self.pl011 {
+            pl011.poll();
+        }
+        for (virtio, _) in &mut self.virtio {
+            virtio.poll();
+        }
+        self.update_irq_lines(vcpu_id);
+        Ok(())
+    }
+
+    /// Forward the interrupt outputs of the UARTs and the virtio-mmio devices
+    /// to the virtual GIC, with `vcpu_id` the vCPU running. Without one, the
+    /// guest has to poll the devices.
+    fn update_irq_lines(&mut self, vcpu_id: usize) {
+        let Some(vgic) = &self.vgic else {
+            return;
+        };
+        let mut vgic = vgic.lock();
+        if let Some((uart, irq)) = &self.uart {
+            let _ = vgic.set_irq_level(vcpu_id, *irq, uart.irq_level());
+        }
+        if let Some((pl011, irq)) = &self.pl011 {
+            let _ = vgic.set_irq_level(vcpu_id, *irq, pl011.irq_level());
+        }
+        for (virtio, irq) in &self.virtio {
+            let _ = vgic.set_irq_level(vcpu_id, *irq, virtio.irq_level());
+        }
+    }
+
+    /// Fail with [`HyperError::InvalidParam`] if `range` overlaps a device of
+    /// the VM, emulated at EL2 or by [`VM::run`].
+    fn check_mmio_range_free(&self, range: &Range<GuestPhysAddr>) -> HyperResult {
+        let overlaps = |other: Range<GuestPhysAddr>| range.start < other.end && other.start < range.end;
+        let taken = self.uart.iter().any(|(uart, _)| overlaps(uart.mmio_range()))
+            || self.pl011.iter().any(|(pl011, _)| overlaps(pl011.mmio_range()))
+            || self.virtio.iter().any(|(virtio, _)| overlaps(virtio.mmio_range()))
+            || emu_overlaps(self.vm_id, range);
+        if taken {
+            warn!("MMIO range {:#x?} of VM {} already in use", range, self.vm_id);
+            return Err(HyperError::InvalidParam);
+        }
+        Ok(())
+    }
+
+    /// The device emulated by [`VM::run`] at `addr`, if any.
+    fn mmio_device_mut(&mut self, addr: GuestPhysAddr) -> Option<&mut dyn MmioDevice> {
+        if let Some((uart, _)) = self.uart.as_mut().filter(|(uart, _)| uart.mmio_range().contains(&addr)) {
+            Some(uart)
+        } else if let Some((pl011, _)) = self.pl011.as_mut().filter(|(pl011, _)| pl011.mmio_range().contains(&addr)) {
+            Some(pl011)
+        } else if let Some((virtio, _)) = self.virtio.iter_mut().find(|(virtio, _)| virtio.mmio_range().contains(&addr)) {
+            Some(virtio)
+        } else {
+            None
+        }
+    }
+
+    /// Emulate the load of `access` if it targets a device emulated by
+    /// [`VM::run`], and return the value read.
+    fn device_mmio_read(&mut self, vcpu_id: usize, access: &EmuContext) -> Option<u64> {
+        let device = self.mmio_device_mut(access.address)?;
+        let value = device.read(access.address, access.width).unwrap_or(0);
+        // Reads change the interrupt state too, e.g. the 16550's IIR.
+        self.update_irq_lines(vcpu_id);
+        Some(value)
+    }
+
+    /// Emulate the store of `value` of `access` if it targets a device
+    /// emulated by [`VM::run`], e.g. a queue notification. Returns whether it
+    /// did.
+    fn device_mmio_write(&mut self, vcpu_id: usize, access: &EmuContext, value: u64) -> bool {
+        let Some(device) = self.mmio_device_mut(access.address) else {
+            return false;
+        };
+        let _ = device.write(access.address, access.width, value);
+        self.update_irq_lines(vcpu_id);
+        true
+    }
+
+    /// Add the `size` bytes at `ipa` to `memory`, the RAM of the guest,
+    /// as [`LazyRam`] backed on demand with `flags`.
+    pub fn add_lazy_region(&mut self, memory: &mut GuestMemory, ipa: GuestPhysAddr, size: usize, flags: MappingFlags) -> HyperResult {
+        self.lazy_ram.add_region(memory, ipa, size, flags)?;
+        emu_register_lazy_ram(self.vm_id, ipa..ipa + size);
+        Ok(())
+    }
+
+    /// The number of pages of the RAM backed on demand allocated so far.
+    pub fn lazy_ram_pages(&self) -> usize {
+        self.lazy_ram.nr_pages()
+    }
+
+    /// Free the pages of the [`LazyRam`] in the `size` bytes at `ipa`, and
+    /// return their number.
+    pub fn reclaim_pages(&mut self, ipa: GuestPhysAddr, size: usize) -> HyperResult<usize> {
+        let vttbr_token = self.vttbr_token();
+        self.lazy_ram.reclaim(&mut self.gpt, ipa, size, || {
+            flush_guest_tlb_by_trap2el2(vttbr_token);
+            Ok(())
+        })
+    }
+
+    /// Make this VM a [`CowRam`] clone of a template VM, whose RAM is
+    /// `template_memory` and whose state is `template_state`, and return the
+    /// RAM of the clone.
+    pub fn clone_from_template(&mut self, template_state: &[u8], template_memory: &GuestMemory) -> HyperResult<GuestMemory> {
+        let memory = self.cow_ram.share(template_memory, &mut self.gpt)?;
+        flush_guest_tlb_by_trap2el2(self.vttbr_token());
+        for (virtio, _) in &mut self.virtio {
+            virtio.set_memory(memory.clone());
+        }
+        self.import_state(template_state)?;
+        Ok(memory)
+    }
+
+    /// The number of pages shared with the template VM copied so far.
+    pub fn cow_pages(&self) -> usize {
+        self.cow_ram.nr_pages()
+    }
+
+    fn vttbr_token(&self) -> usize {
+        (self.vm_id << 48) | self.gpt.token()
+    }
+
+    /// Start logging the pages written in `memory`, as described on
+    /// [`DirtyLog`], by write-protecting them in the stage-2 page table.
+    pub fn enable_dirty_log(&mut self, memory: &GuestMemory) -> HyperResult {
+        if self.dirty_log.is_some() {
+            return Err(HyperError::BadState);
+        }
+        if self.cow_ram.is_shared() {
+            return Err(HyperError::NotSupported);
+        }
+        let log = DirtyLog::start(memory, &mut self.gpt, DirtyTracking::WriteProtect)?;
+        self.dirty_log = Some(log);
+        flush_guest_tlb_by_trap2el2(self.vttbr_token());
+        Ok(())
+    }
+
+    /// Take the dirty bitmap of the `size` bytes at `gpa`.
+    pub fn get_and_clear_dirty_log(&mut self, gpa: GuestPhysAddr, size: usize) -> HyperResult<Vec<usize>> {
+        let log = self.dirty_log.as_mut().ok_or(HyperError::BadState)?;
+        let dirty = log.get_and_clear(&mut self.gpt, gpa, size)?;
+        flush_guest_tlb_by_trap2el2(self.vttbr_token());
+        Ok(dirty)
+    }
+
+    /// Stop dirty logging, and give the guest its write permissions back.
+    pub fn disable_dirty_log(&mut self) -> HyperResult {
+        let log = self.dirty_log.take().ok_or(HyperError::BadState)?;
+        log.stop(&mut self.gpt)?;
+        flush_guest_tlb_by_trap2el2(self.vttbr_token());
+        Ok(())
+    }
+
+    /// Save the state of this VM: the registers of its vCPUs, its virtual GIC,
+    /// UARTs, virtio-mmio transports and PSCI power states, and the contents of
+    /// `memory`, its RAM. The vCPUs must not be running, and the host must
+    /// have taken the pending lifecycle events.
+    pub fn snapshot(&mut self, memory: &GuestMemory) -> HyperResult<Vec<u8>> {
+        let mut w = SnapshotWriter::new();
+        self.save_state(&mut w)?;
+        save_memory(memory, &mut w)?;
+        Ok(w.finish())
+    }
+
+    /// Restore a snapshot taken by [`VM::snapshot`] into this VM, which must
+    /// be set up like the saved one: the same vCPUs, virtual GIC, UARTs and
+    /// virtio devices, and `memory` with the same regions, mapped in the guest page
+    /// table. The vCPUs then resume where the saved ones stopped, and the
+    /// virtual counter continues from its saved value.
+    pub fn restore(&mut self, data: &[u8], memory: &GuestMemory) -> HyperResult {
+        let mut r = SnapshotReader::new(data)?;
+        self.restore_state(&mut r)?;
+        restore_memory(memory, &mut r)?;
+        r.finish()
+    }
+
+    fn nr_vcpus(&mut self) -> usize {
+        (0..VM_CPUS_MAX).take_while(|&i| self.vcpus.get_vcpu(i).is_ok()).count()
+    }
+
+    /// Save everything but the RAM.
+    fn save_state(&mut self, w: &mut SnapshotWriter) -> HyperResult {
+        let vm_id = self.vm_id;
+        // The guest's view of the counter, which the restored VM continues.
+        let vcounter = physical_counter().wrapping_sub(self.cntvoff);
+        w.section(SECTION_VM, |w| {
+            vcounter.save(w);
+            psci_save_vm(vm_id, w);
+            Ok(())
+        })?;
+        let nr_vcpus = self.nr_vcpus();
+        w.section(SECTION_VCPUS, |w| {
+            nr_vcpus.save(w);
+            for vcpu_id in 0..nr_vcpus {
+                self.vcpus.get_vcpu(vcpu_id)?.regs.save(w);
+            }
+            Ok(())
+        })?;
+        w.section(SECTION_IRQCHIP, |w| {
+            self.vgic.is_some().save(w);
+            if let Some(vgic) = &self.vgic {
+                vgic.lock().save(w);
+            }
+            self.uart.is_some().save(w);
+            if let Some((uart, _)) = &self.uart {
+                uart.save(w);
+            }
+            self.pl011.is_some().save(w);
+            if let Some((pl011, _)) = &self.pl011 {
+                pl011.save(w);
+            }
+            self.virtio.len().save(w);
+            self.virtio.iter().for_each(|(virtio, _)| virtio.save(w));
+            Ok(())
+        })
+    }
+
+    fn restore_state(&mut self, r: &mut SnapshotReader<'_>) -> HyperResult {
+        let vm_id = self.vm_id;
+        let mut vcounter = 0u64;
+        r.section(SECTION_VM, |r| {
+            vcounter.restore(r)?;
+            psci_restore_vm(vm_id, r)
+        })?;
+        self.cntvoff = physical_counter().wrapping_sub(vcounter);
+        let cntvoff = self.cntvoff;
+        let nr_vcpus = self.nr_vcpus();
+        r.section(SECTION_VCPUS, |r| {
+            if r.read::<usize>()? != nr_vcpus {
+                return Err(HyperError::InvalidParam);
+            }
+            for vcpu_id in 0..nr_vcpus {
+                let regs = &mut self.vcpus.get_vcpu(vcpu_id)?.regs;
+                regs.restore(r)?;
+                regs.vm_system_regs.cntvoff_el2 = cntvoff;
+            }
+            Ok(())
+        })?;
+        r.section(SECTION_IRQCHIP, |r| {
+            if r.read::<bool>()? != self.vgic.is_some() {
+                return Err(HyperError::InvalidParam);
+            }
+            if let Some(vgic) = &self.vgic {
+                vgic.lock().restore(r)?;
+            }
+            if r.read::<bool>()? != self.uart.is_some() {
+                return Err(HyperError::InvalidParam);
+            }
+            if let Some((uart, _)) = &mut self.uart {
+                uart.restore(r)?;
+            }
+            if r.read::<bool>()? != self.pl011.is_some() {
+                return Err(HyperError::InvalidParam);
+            }
+            if let Some((pl011, _)) = &mut self.pl011 {
+                pl011.restore(r)?;
+            }
+            if r.read::<usize>()? != self.virtio.len() {
+                return Err(HyperError::InvalidParam);
+            }
+            self.virtio.iter_mut().try_for_each(|(virtio, _)| virtio.restore(r))
+        })
+    }
+
+    /// Handle the write of the guest to the read-only `ipa`. Returns whether
+    /// the guest can resume.
+    fn handle_write_protect_fault(&mut self, ipa: GuestPhysAddr) -> HyperResult<bool> {
+        if self.cow_ram.handle_write_fault(&mut self.gpt, ipa)? {
+            flush_guest_tlb_by_trap2el2(self.vttbr_token());
+            return Ok(true);
+        }
+        let Some(log) = self.dirty_log.as_mut() else {
+            return Ok(false);
+        };
+        if !log.handle_write_fault(&mut self.gpt, ipa)? {
+            return Ok(false);
+        }
+        flush_guest_tlb_by_trap2el2(self.vttbr_token());
+        Ok(true)
+    }
+
+    /// Map the pages of the RAM shared with the template VM which the host
+    /// copied, e.g. for the DMA of the virtio devices, to their copies.
+    fn sync_cow_ram(&mut self) -> HyperResult {
+        if self.cow_ram.sync(&mut self.gpt)? {
+            flush_guest_tlb_by_trap2el2(self.vttbr_token());
+        }
+        Ok(())
+    }
+
+    /// Back the page of `ipa` if it is in the RAM backed on demand. Returns
+    /// whether the guest can resume.
+    fn handle_lazy_ram_fault(&mut self, ipa: GuestPhysAddr) -> HyperResult<bool> {
+        let write_protect = self.dirty_log.is_some();
+        if !self.lazy_ram.handle_fault(&mut self.gpt, ipa, write_protect)? {
+            return Ok(false);
+        }
+        flush_guest_tlb_by_trap2el2(self.vttbr_token());
+        Ok(true)
+    }
+
+    /// Run vCPU `vcpu_id` of this VM until it exits to the host, and return
+    /// why. Calling it again resumes the guest where it stopped.
+    ///
+    /// An expired virtual timer is injected on interrupt exits. Write faults
+    /// of dirty logging and of the RAM shared with a template VM, the first
+    /// accesses to the RAM backed on demand and the accesses to the UARTs and
+    /// the virtio-mmio devices are handled without returning.
+    pub fn run(&mut self, vcpu_id: usize) -> VmExitInfo {
+        let vttbr_token = self.vttbr_token();
         debug!("vttbr_token: 0x{:X}", self.gpt.token());
-        vcpu.run(vttbr_token);
+        loop {
+            if let Err(err) = self.sync_cow_ram() {
+                warn!("Failed to map the copied pages: {:?}", err);
+            }
+            let vcpu = self.vcpus.get_vcpu(vcpu_id).unwrap();
+            let exit = vcpu.run(vttbr_token);
+            match exit {
+                VmExitInfo::Irq if self.vgic.is_some() => {
+                    let _ = self.check_vtimer(vcpu_id);
+                    let _ = self.poll_devices(vcpu_id);
+                }
+                VmExitInfo::WriteProtectFault { ipa } => {
+                    if let Ok(true) = self.handle_write_protect_fault(ipa) {
+                        continue;
+                    }
+                }
+                VmExitInfo::MmioRead(ref access) => {
+                    if let Some(value) = self.device_mmio_read(vcpu_id, access) {
+                        let vcpu = self.vcpus.get_vcpu(vcpu_id).unwrap();
+                        vcpu.complete_mmio_read(access, value);
+                        continue;
+                    }
+                }
+                VmExitInfo::MmioWrite { ref access, value } => {
+                    if self.device_mmio_write(vcpu_id, access, value) {
+                        continue;
+                    }
+                }
+                VmExitInfo::LazyRamFault { ipa } | VmExitInfo::InstructionAbort { ipa, .. } => {
+                    match self.handle_lazy_ram_fault(ipa) {
+                        Ok(true) => continue,
+                        Ok(false) => {}
+                        Err(err) => warn!("Failed to back lazy RAM at {:#x}: {:?}", ipa, err),
+                    }
+                }
+                _ => {}
+            }
+            return exit;
+        }
+    }
+}
+
+impl<H: HyperCraftHal, G: GuestPageTableTrait> Migratable for VM<H, G> {
+    fn enable_dirty_log(&mut self, memory: &GuestMemory) -> HyperResult {
+        self.enable_dirty_log(memory)
+    }
+
+    fn get_and_clear_dirty_log(
+        &mut self,
+        gpa: GuestPhysAddr,
+        size: usize,
+    ) -> HyperResult<Vec<usize>> {
+        self.get_and_clear_dirty_log(gpa, size)
+    }
+
+    fn disable_dirty_log(&mut self) -> HyperResult {
+        self.disable_dirty_log()
+    }
+
+    fn export_state(&mut self) -> HyperResult<Vec<u8>> {
+        let mut w = SnapshotWriter::new();
+        self.save_state(&mut w)?;
+        Ok(w.finish())
+    }
+
+    fn import_state(&mut self, state: &[u8]) -> HyperResult {
+        let mut r = SnapshotReader::new(state)?;
+        self.restore_state(&mut r)?;
+        r.finish()
+    }
+}
+
+impl <H: HyperCraftHal, G: GuestPageTableTrait> Drop for VM<H, G> {
+    fn drop(&mut self) {
+        emu_remove_vm_devs(self.vm_id);
+        vgic_remove(self.vm_id);
+        psci_remove_vm(self.vm_id);
     }
 }
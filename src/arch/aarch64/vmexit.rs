@@ -0,0 +1,58 @@
+use crate::arch::emu::EmuContext;
+use crate::{GuestPhysAddr, GuestVirtAddr};
+
+/// Identifies the reason a vCPU returned to the host.
+#[derive(Debug, Clone, Copy)]
+pub enum VmExitInfo {
+    /// A physical interrupt arrived while the guest ran. It stays pending
+    /// and is taken by the host once it unmasks interrupts.
+    Irq,
+    /// The guest executed WFI. The host runs the vCPU again when it has an
+    /// interrupt to take, e.g. after `VM::vtimer_deadline_nanos`.
+    WaitForInterrupt,
+    /// The guest fetched an instruction from `ipa`, which is not mapped. The
+    /// PC still points at the faulting instruction.
+    InstructionAbort {
+        /// The faulting intermediate physical address.
+        ipa: GuestPhysAddr,
+        /// The guest virtual address of the instruction.
+        pc: GuestVirtAddr,
+    },
+    /// The guest made a data access to `ipa` which cannot be emulated: the
+    /// abort is not a translation fault, its instruction cannot be decoded,
+    /// or the device model failed. The PC still points at the faulting
+    /// instruction, and the host stops the VM or injects an abort.
+    DataAbort {
+        /// The faulting intermediate physical address.
+        ipa: GuestPhysAddr,
+        /// The guest virtual address of the instruction.
+        pc: GuestVirtAddr,
+        /// The exception syndrome.
+        esr: usize,
+    },
+    /// The guest wrote to `ipa`, which is mapped read-only in the stage-2
+    /// page table. The PC still points at the faulting instruction.
+    WriteProtectFault {
+        /// The faulting intermediate physical address.
+        ipa: GuestPhysAddr,
+    },
+    /// The guest accessed `ipa`, in RAM backed on demand, but no page could
+    /// be allocated for it. The PC still points at the faulting instruction.
+    LazyRamFault {
+        /// The faulting intermediate physical address.
+        ipa: GuestPhysAddr,
+    },
+    /// The guest stopped this vCPU or the whole VM through PSCI. The host
+    /// takes the requests with `VM::pop_lifecycle_event`.
+    Lifecycle,
+    /// A load from an MMIO address no registered device emulates. The host
+    /// completes it with `VCpu::complete_mmio_read`.
+    MmioRead(EmuContext),
+    /// A store of `value` to an MMIO address no registered device emulates.
+    MmioWrite {
+        /// The trapped access.
+        access: EmuContext,
+        /// The stored value, truncated to the access width.
+        value: u64,
+    },
+}
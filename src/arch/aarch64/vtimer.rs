@@ -0,0 +1,54 @@
+use crate::arch::context_frame::VmContext;
+use crate::mrs;
+
+/// The PPI of the EL1 virtual timer.
+pub const VTIMER_IRQ: usize = 27;
+
+// CNTV_CTL_EL0 bits
+pub const CNTV_CTL_ENABLE: u32 = 1 << 0;
+pub const CNTV_CTL_IMASK: u32 = 1 << 1;
+pub const CNTV_CTL_ISTATUS: u32 = 1 << 2;
+
+/// The physical counter, `CNTPCT_EL0`.
+pub fn physical_counter() -> u64 {
+    let cnt: u64;
+    mrs!(cnt, CNTPCT_EL0);
+    cnt
+}
+
+/// The counter frequency in Hz, `CNTFRQ_EL0`.
+pub fn counter_frequency() -> u64 {
+    let freq: u64;
+    mrs!(freq, CNTFRQ_EL0);
+    freq
+}
+
+fn ticks_to_nanos(ticks: u64) -> u64 {
+    let freq = counter_frequency().max(1);
+    (ticks as u128 * 1_000_000_000 / freq as u128) as u64
+}
+
+impl VmContext {
+    /// The physical counter value at which the saved virtual timer fires,
+    /// or `None` if the timer is disabled or masked.
+    pub fn vtimer_deadline(&self) -> Option<u64> {
+        let ctl = self.cntv_ctl_el0;
+        if ctl & CNTV_CTL_ENABLE == 0 || ctl & CNTV_CTL_IMASK != 0 {
+            return None;
+        }
+        Some(self.cntv_cval_el0.wrapping_add(self.cntvoff_el2))
+    }
+
+    /// Nanoseconds until the saved virtual timer fires, 0 if it already
+    /// expired. The host arms a timer with this while the vCPU is descheduled.
+    pub fn vtimer_deadline_nanos(&self) -> Option<u64> {
+        let deadline = self.vtimer_deadline()?;
+        Some(ticks_to_nanos(deadline.saturating_sub(physical_counter())))
+    }
+
+    /// Whether the saved virtual timer condition is met.
+    pub fn vtimer_expired(&self) -> bool {
+        self.vtimer_deadline()
+            .is_some_and(|deadline| physical_counter() >= deadline)
+    }
+}
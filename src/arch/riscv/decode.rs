@@ -0,0 +1,53 @@
+use riscv_decode::Instruction;
+
+use super::regs::GprIndex;
+use crate::{HyperError, HyperResult};
+
+/// A load or store of the guest to an emulated device.
+pub(crate) enum MmioAccess {
+    /// A load of `width` bytes to `rd`, sign-extended if `signed`.
+    Load {
+        rd: GprIndex,
+        width: usize,
+        signed: bool,
+    },
+    /// A store of the low `width` bytes of `rs2`.
+    Store { rs2: GprIndex, width: usize },
+}
+
+/// Decode the load or store `inst` of the guest which faulted on an
+/// emulated device. Returns the access and the length of the instruction.
+pub(crate) fn decode_mmio_instruction(inst: u32) -> HyperResult<(MmioAccess, usize)> {
+    let len = riscv_decode::instruction_length(inst as u16);
+    let inst = match len {
+        2 => inst as u16 as u32,
+        4 => inst,
+        _ => return Err(HyperError::InvalidInstruction),
+    };
+    let (is_load, reg, width, signed) =
+        match riscv_decode::decode(inst).map_err(|_| HyperError::DecodeError)? {
+            Instruction::Lb(i) => (true, i.rd(), 1, true),
+            Instruction::Lbu(i) => (true, i.rd(), 1, false),
+            Instruction::Lh(i) => (true, i.rd(), 2, true),
+            Instruction::Lhu(i) => (true, i.rd(), 2, false),
+            Instruction::Lw(i) => (true, i.rd(), 4, true),
+            Instruction::Lwu(i) => (true, i.rd(), 4, false),
+            Instruction::Ld(i) => (true, i.rd(), 8, false),
+            Instruction::Sb(i) => (false, i.rs2(), 1, false),
+            Instruction::Sh(i) => (false, i.rs2(), 2, false),
+            Instruction::Sw(i) => (false, i.rs2(), 4, false),
+            Instruction::Sd(i) => (false, i.rs2(), 8, false),
+            _ => return Err(HyperError::InvalidInstruction),
+        };
+    let reg = GprIndex::from_raw(reg).ok_or(HyperError::DecodeError)?;
+    let access = if is_load {
+        MmioAccess::Load {
+            rd: reg,
+            width,
+            signed,
+        }
+    } else {
+        MmioAccess::Store { rs2: reg, width }
+    };
+    Ok((access, len))
+}
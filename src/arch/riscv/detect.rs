@@ -19,6 +19,27 @@ pub fn detect_h_extension() -> bool {
     ans != 2
 }
 
+// Detect if vector extension exists on current hart environment
+//
+// This function enables sstatus.VS, tries to read vlenb and returns false if the read operation
+// failed. sstatus.VS is restored afterwards.
+pub fn detect_v_extension() -> bool {
+    const SSTATUS_VS: usize = 0x3 << 9;
+    let sstatus: usize;
+    unsafe {
+        asm!("csrr {}, sstatus", out(reg) sstatus, options(nomem, nostack));
+        asm!("csrs sstatus, {}", in(reg) 1usize << 9, options(nomem, nostack)); // VS = Initial
+    }
+    let ans = with_detect_trap(0, || unsafe {
+        asm!("csrr  {}, 0xc22", out(reg) _, options(nomem, nostack)); // 0xc22 => vlenb
+    });
+    unsafe {
+        asm!("csrc sstatus, {}", in(reg) SSTATUS_VS, options(nomem, nostack));
+        asm!("csrs sstatus, {}", in(reg) sstatus & SSTATUS_VS, options(nomem, nostack));
+    }
+    ans != 2
+}
+
 // Tries to execute all instructions defined in clojure `f`.
 // If resulted in an exception, this function returns its exception id.
 //
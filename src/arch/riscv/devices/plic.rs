@@ -1,30 +1,100 @@
+use core::ops::Range;
+
+use crate::memory::PAGE_SIZE_4K;
 use crate::{
-    arch::csrs::{traps, RiscvCsrTrait, CSR},
-    vcpus::MAX_CPUS,
+    vcpus::MAX_CPUS, GuestPageTableTrait, GuestPhysAddr, HyperError, HyperResult, MmioDevice,
 };
 
 /// Number of contexts for the PLIC. Value is twice the max number of harts because each hart will
 /// have one M-mode context and one S-mode context.
 pub const MAX_CONTEXTS: usize = 2 * MAX_CPUS;
 
+/// The number of interrupt sources, including the reserved source 0.
+const NUM_SOURCES: usize = 1024;
+/// The number of 32-bit words of a bitmap of the sources.
+const NUM_WORDS: usize = NUM_SOURCES / 32;
+
+/// The size of the register space of the PLIC.
+pub const PLIC_SIZE: usize = 0x400_0000;
+
+const PRIORITY_BASE: usize = 0;
+const PENDING_BASE: usize = 0x1000;
+const ENABLE_BASE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT_BASE: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+
+/// The PLIC of a guest.
+///
+/// The sources of the emulated devices are virtual: their pending bits are
+/// driven by [`PlicState::set_irq_level`], and they are claimed and completed
+/// here without touching the physical PLIC. The other sources are passed
+/// through: their priority, enable and threshold writes go to the physical
+/// PLIC, the interrupt the hypervisor claimed for the guest is recorded with
+/// [`PlicState::set_hw_claimed`], and its completion is forwarded.
+///
+/// The whole window of [`PLIC_SIZE`] bytes must be left unmapped in the guest
+/// page table, so that every access faults and is emulated here, which
+/// [`PlicState::check_unmapped`] verifies.
 pub struct PlicState {
+    /// The guest physical address of the PLIC.
+    gpa: GuestPhysAddr,
+    /// The address of the host's PLIC.
     base: usize,
-    source_priority: [u32; 512],
-    pending: [u32; 16],
-    enable: [[u32; 32]; MAX_CONTEXTS],
+    source_priority: [u32; NUM_SOURCES],
+    pending: [u32; NUM_WORDS],
+    enable: [[u32; NUM_WORDS]; MAX_CONTEXTS],
     thresholds: [u32; MAX_CONTEXTS],
-    pub claim_complete: [u32; MAX_CONTEXTS],
+    /// The sources of the emulated devices.
+    virtual_sources: [u32; NUM_WORDS],
+    /// The lines of the virtual sources.
+    levels: [u32; NUM_WORDS],
+    /// The virtual sources claimed and not completed by the guest.
+    in_service: [u32; NUM_WORDS],
+    /// The physical interrupt claimed for each context, not claimed by the
+    /// guest yet.
+    hw_claimed: [u32; MAX_CONTEXTS],
+}
+
+// `gpa` and `base` are the addresses of the PLIC, and the virtual sources
+// are registered when the devices are attached, so they are not saved.
+impl_snapshot_fields!(PlicState {
+    source_priority,
+    pending,
+    enable,
+    thresholds,
+    levels,
+    in_service,
+    hw_claimed,
+});
+
+fn test_bit(words: &[u32], id: usize) -> bool {
+    words[id / 32] & (1 << (id % 32)) != 0
+}
+
+fn set_bit(words: &mut [u32], id: usize, value: bool) {
+    if value {
+        words[id / 32] |= 1 << (id % 32);
+    } else {
+        words[id / 32] &= !(1 << (id % 32));
+    }
 }
 
 impl PlicState {
-    pub fn new(base: usize) -> Self {
+    /// Create the PLIC of a guest at `gpa`, passing the physical sources
+    /// through to the host's PLIC at address `base`.
+    pub fn new(gpa: GuestPhysAddr, base: usize) -> Self {
         Self {
+            gpa,
             base,
-            source_priority: [0; 512],
-            pending: [0; 16],
-            enable: [[0; 32]; MAX_CONTEXTS],
+            source_priority: [0; NUM_SOURCES],
+            pending: [0; NUM_WORDS],
+            enable: [[0; NUM_WORDS]; MAX_CONTEXTS],
             thresholds: [0; MAX_CONTEXTS],
-            claim_complete: [0; MAX_CONTEXTS],
+            virtual_sources: [0; NUM_WORDS],
+            levels: [0; NUM_WORDS],
+            in_service: [0; NUM_WORDS],
+            hw_claimed: [0; MAX_CONTEXTS],
         }
     }
 
@@ -32,46 +102,318 @@ impl PlicState {
         self.base
     }
 
-    pub fn read_u32(&mut self, addr: usize) -> u32 {
-        let offset = addr.wrapping_sub(self.base);
-        if (0x20_0000..0x20_0000 + 0x1000 * MAX_CONTEXTS).contains(&offset) {
-            // threshold/claim/complete
-            let hart = (offset - 0x200000) / 0x1000;
-            let index = ((offset - 0x200000) & 0xfff) >> 2;
-            if index == 1 {
-                // debug!("PLIC read@{:#x} -> {:#x}", addr, self.claim_complete[hart]);
-                return self.claim_complete[hart];
+    /// Check that no page of the window of the PLIC is mapped in `gpt`.
+    /// Returns `InvalidParam` otherwise.
+    pub fn check_unmapped<G: GuestPageTableTrait>(&self, gpt: &G) -> HyperResult {
+        let mut pages = self.mmio_range().step_by(PAGE_SIZE_4K);
+        if pages.any(|gpa| gpt.translate(gpa).is_ok()) {
+            return Err(HyperError::InvalidParam);
+        }
+        Ok(())
+    }
+
+    /// Make `irq` the source of an emulated device, driven by
+    /// [`PlicState::set_irq_level`].
+    pub fn add_virtual_source(&mut self, irq: u32) {
+        if (1..NUM_SOURCES).contains(&(irq as usize)) {
+            set_bit(&mut self.virtual_sources, irq as usize, true);
+        }
+    }
+
+    /// Set the line of the virtual source `irq`. It is pending while high,
+    /// unless the guest is handling it.
+    pub fn set_irq_level(&mut self, irq: u32, level: bool) {
+        let id = irq as usize;
+        if id >= NUM_SOURCES || !test_bit(&self.virtual_sources, id) {
+            return;
+        }
+        set_bit(&mut self.levels, id, level);
+        let pending = level && !test_bit(&self.in_service, id);
+        set_bit(&mut self.pending, id, pending);
+    }
+
+    /// Record `irq`, claimed from the host's PLIC for `context`, to be
+    /// claimed by the guest.
+    pub fn set_hw_claimed(&mut self, context: usize, irq: u32) {
+        self.hw_claimed[context] = irq;
+    }
+
+    /// Whether `context` has an interrupt to claim.
+    pub fn has_interrupt(&self, context: usize) -> bool {
+        self.hw_claimed[context] != 0 || self.best_virtual(context).is_some()
+    }
+
+    /// The priority and ID of the pending and enabled virtual source with the
+    /// highest priority above the threshold of `context`. Ties go to the
+    /// lowest ID.
+    fn best_virtual(&self, context: usize) -> Option<(u32, usize)> {
+        let threshold = self.thresholds[context];
+        let mut best: Option<(u32, usize)> = None;
+        for word in 0..NUM_WORDS {
+            let mut bits =
+                self.pending[word] & self.enable[context][word] & self.virtual_sources[word];
+            while bits != 0 {
+                let id = word * 32 + bits.trailing_zeros() as usize;
+                bits &= bits - 1;
+                let priority = self.source_priority[id];
+                if priority > threshold && best.map_or(true, |(best, _)| priority > best) {
+                    best = Some((priority, id));
+                }
+            }
+        }
+        best
+    }
+
+    fn claim(&mut self, context: usize) -> u32 {
+        let virt = self.best_virtual(context);
+        let hw = self.hw_claimed[context];
+        let take_hw = hw != 0
+            && virt.map_or(true, |(priority, id)| {
+                let hw_priority = self.source_priority[hw as usize % NUM_SOURCES];
+                hw_priority > priority || (hw_priority == priority && (hw as usize) < id)
+            });
+        if take_hw {
+            self.hw_claimed[context] = 0;
+            return hw;
+        }
+        match virt {
+            Some((_, id)) => {
+                set_bit(&mut self.pending, id, false);
+                set_bit(&mut self.in_service, id, true);
+                id as u32
             }
-            todo!()
+            None => 0,
+        }
+    }
+
+    fn complete(&mut self, context: usize, irq: u32) {
+        let id = irq as usize;
+        if id == 0 || id >= NUM_SOURCES {
+            return;
+        }
+        if test_bit(&self.virtual_sources, id) {
+            set_bit(&mut self.in_service, id, false);
+            if test_bit(&self.levels, id) {
+                set_bit(&mut self.pending, id, true);
+            }
+        } else {
+            self.write_host(CONTEXT_BASE + CONTEXT_STRIDE * context + 4, irq);
         }
-        todo!()
-    }
-
-    pub fn write_u32(&mut self, addr: usize, val: u32) {
-        // debug!("PLIC write@{:#x} -> {:#x}", addr, val);
-        let offset = addr.wrapping_sub(self.base);
-        // threshold/claim/complete
-        if (0x200000..0x200000 + 0x1000 * MAX_CONTEXTS).contains(&offset) {
-            let hart = (offset - 0x200000) / 0x1000;
-            let index = ((offset - 0x200000) & 0xfff) >> 2;
-            if index == 0 {
-                // threshold
-                self.thresholds[hart] = val;
-                unsafe {
-                    core::ptr::write_volatile(addr as *mut u32, val);
+    }
+
+    fn read_host(&self, offset: usize) -> u32 {
+        unsafe { core::ptr::read_volatile((self.base + offset) as *const u32) }
+    }
+
+    fn write_host(&self, offset: usize, val: u32) {
+        unsafe { core::ptr::write_volatile((self.base + offset) as *mut u32, val) }
+    }
+
+    fn read_u32(&mut self, offset: usize) -> u32 {
+        match offset {
+            PRIORITY_BASE..PENDING_BASE => self.source_priority[offset / 4],
+            PENDING_BASE..ENABLE_BASE if offset - PENDING_BASE < 4 * NUM_WORDS => {
+                let word = (offset - PENDING_BASE) / 4;
+                self.pending[word] | (self.read_host(offset) & !self.virtual_sources[word])
+            }
+            ENABLE_BASE..CONTEXT_BASE => {
+                let context = (offset - ENABLE_BASE) / ENABLE_STRIDE;
+                let word = (offset - ENABLE_BASE) % ENABLE_STRIDE / 4;
+                match self.enable.get(context) {
+                    Some(enable) => enable[word],
+                    None => 0,
+                }
+            }
+            _ if offset >= CONTEXT_BASE => {
+                let context = (offset - CONTEXT_BASE) / CONTEXT_STRIDE;
+                if context >= MAX_CONTEXTS {
+                    return 0;
                 }
-            } else if index == 1 {
-                // claim
-                unsafe {
-                    core::ptr::write_volatile(addr as *mut u32, val);
+                match (offset - CONTEXT_BASE) % CONTEXT_STRIDE {
+                    0 => self.thresholds[context],
+                    4 => self.claim(context),
+                    _ => 0,
                 }
-                self.claim_complete[hart] = 0;
-                // Send Interrupt to the hart
-                CSR.hvip
-                    .read_and_clear_bits(traps::interrupt::VIRTUAL_SUPERVISOR_EXTERNAL);
             }
-        } else {
-            todo!()
+            _ => 0,
         }
     }
+
+    fn write_u32(&mut self, offset: usize, val: u32) {
+        match offset {
+            PRIORITY_BASE..PENDING_BASE => {
+                let id = offset / 4;
+                self.source_priority[id] = val;
+                if !test_bit(&self.virtual_sources, id) {
+                    self.write_host(offset, val);
+                }
+            }
+            // The pending bits are read-only.
+            PENDING_BASE..ENABLE_BASE => {}
+            ENABLE_BASE..CONTEXT_BASE => {
+                let context = (offset - ENABLE_BASE) / ENABLE_STRIDE;
+                let word = (offset - ENABLE_BASE) % ENABLE_STRIDE / 4;
+                if context < MAX_CONTEXTS {
+                    self.enable[context][word] = val;
+                    self.write_host(offset, val & !self.virtual_sources[word]);
+                }
+            }
+            _ if offset >= CONTEXT_BASE => {
+                let context = (offset - CONTEXT_BASE) / CONTEXT_STRIDE;
+                if context >= MAX_CONTEXTS {
+                    return;
+                }
+                match (offset - CONTEXT_BASE) % CONTEXT_STRIDE {
+                    0 => {
+                        self.thresholds[context] = val;
+                        self.write_host(offset, val);
+                    }
+                    4 => self.complete(context, val),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl MmioDevice for PlicState {
+    fn mmio_range(&self) -> Range<GuestPhysAddr> {
+        self.gpa..self.gpa + PLIC_SIZE
+    }
+
+    // The registers are 32 bits wide, other accesses read as zero and are
+    // ignored.
+    fn read(&mut self, addr: GuestPhysAddr, width: usize) -> HyperResult<u64> {
+        if width != 4 {
+            return Ok(0);
+        }
+        Ok(self.read_u32(addr - self.gpa) as u64)
+    }
+
+    fn write(&mut self, addr: GuestPhysAddr, width: usize, value: u64) -> HyperResult {
+        if width == 4 {
+            self.write_u32(addr - self.gpa, value as u32);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+    use crate::memory::tests::{rw, MockPageTable};
+
+    const GPA: GuestPhysAddr = 0xC00_0000;
+    const CONTEXT: usize = 1;
+
+    /// A PLIC over `host`, which stands for the registers of the host's PLIC.
+    fn plic(host: &mut Vec<u32>) -> PlicState {
+        host.resize((CONTEXT_BASE + CONTEXT_STRIDE * MAX_CONTEXTS) / 4, 0);
+        let mut plic = PlicState::new(GPA, host.as_mut_ptr() as usize);
+        plic.add_virtual_source(10);
+        plic.add_virtual_source(11);
+        // Enable sources 5, 10 and 11 above threshold 1.
+        write(
+            &mut plic,
+            ENABLE_BASE + ENABLE_STRIDE * CONTEXT,
+            1 << 5 | 1 << 10 | 1 << 11,
+        );
+        write(&mut plic, CONTEXT_BASE + CONTEXT_STRIDE * CONTEXT, 1);
+        plic
+    }
+
+    fn read(plic: &mut PlicState, offset: usize) -> u32 {
+        plic.read(GPA + offset, 4).unwrap() as u32
+    }
+
+    fn write(plic: &mut PlicState, offset: usize, value: u32) {
+        plic.write(GPA + offset, 4, value as u64).unwrap();
+    }
+
+    fn claim(plic: &mut PlicState) -> u32 {
+        read(plic, CONTEXT_BASE + CONTEXT_STRIDE * CONTEXT + 4)
+    }
+
+    fn complete(plic: &mut PlicState, irq: u32) {
+        write(plic, CONTEXT_BASE + CONTEXT_STRIDE * CONTEXT + 4, irq);
+    }
+
+    #[test]
+    fn virtual_sources_follow_their_lines() {
+        let mut host = Vec::new();
+        let mut plic = plic(&mut host);
+        write(&mut plic, 4 * 10, 2);
+        write(&mut plic, 4 * 11, 3);
+        plic.set_irq_level(10, true);
+        plic.set_irq_level(11, true);
+        assert!(plic.has_interrupt(CONTEXT));
+        assert_eq!(read(&mut plic, PENDING_BASE), 1 << 10 | 1 << 11);
+
+        // Highest priority first, and each source once until completed.
+        assert_eq!(claim(&mut plic), 11);
+        assert_eq!(claim(&mut plic), 10);
+        assert_eq!(claim(&mut plic), 0);
+        assert!(!plic.has_interrupt(CONTEXT));
+
+        // A line still high at completion is pending again.
+        complete(&mut plic, 11);
+        assert_eq!(claim(&mut plic), 11);
+        plic.set_irq_level(11, false);
+        complete(&mut plic, 11);
+        plic.set_irq_level(10, false);
+        complete(&mut plic, 10);
+        assert_eq!(claim(&mut plic), 0);
+
+        // Below the threshold, the source is held.
+        plic.set_irq_level(10, true);
+        write(&mut plic, CONTEXT_BASE + CONTEXT_STRIDE * CONTEXT, 2);
+        assert!(!plic.has_interrupt(CONTEXT));
+
+        // The virtual sources are not programmed into the host's PLIC.
+        assert_eq!(host[10], 0);
+        assert_eq!(host[(ENABLE_BASE + ENABLE_STRIDE * CONTEXT) / 4], 1 << 5);
+    }
+
+    #[test]
+    fn hardware_sources_are_passed_through() {
+        let mut host = Vec::new();
+        let mut plic = plic(&mut host);
+        write(&mut plic, 4 * 5, 2);
+        write(&mut plic, 4 * 10, 3);
+        write(&mut plic, 4 * 11, 2);
+        assert_eq!(host[5], 2);
+
+        plic.set_hw_claimed(CONTEXT, 5);
+        plic.set_irq_level(10, true);
+        plic.set_irq_level(11, true);
+        host[PENDING_BASE / 4] = 1 << 5 | 1 << 11;
+        assert_eq!(read(&mut plic, PENDING_BASE), 1 << 5 | 1 << 10 | 1 << 11);
+
+        // The higher priority virtual source goes first, then the hardware
+        // one wins the tie of priority 2 with its lower ID.
+        assert_eq!(claim(&mut plic), 10);
+        assert_eq!(claim(&mut plic), 5);
+        assert_eq!(claim(&mut plic), 11);
+
+        // Only the completion of the hardware source reaches the host.
+        let host_claim = (CONTEXT_BASE + CONTEXT_STRIDE * CONTEXT + 4) / 4;
+        complete(&mut plic, 10);
+        assert_eq!(host[host_claim], 0);
+        complete(&mut plic, 5);
+        assert_eq!(host[host_claim], 5);
+    }
+
+    #[test]
+    fn window_must_be_unmapped() {
+        let mut host = Vec::new();
+        let plic = plic(&mut host);
+        let mut gpt = MockPageTable::default();
+        gpt.map(GPA - PAGE_SIZE_4K, 0, rw()).unwrap();
+        assert_eq!(plic.check_unmapped(&gpt), Ok(()));
+        gpt.map(GPA + CONTEXT_BASE, 0, rw()).unwrap();
+        assert_eq!(plic.check_unmapped(&gpt), Err(HyperError::InvalidParam));
+    }
 }
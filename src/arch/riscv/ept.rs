@@ -1,5 +1,8 @@
-use page_table::{PageTable64, PagingMetaData};
-use page_table_entry::riscv::Rv64PTE;
+use page_table::{PageSize, PageTable64, PagingIf, PagingMetaData};
+use page_table_entry::{riscv::Rv64PTE, MappingFlags};
+
+use crate::memory::{map_page_in, query_page_in};
+use crate::{GuestPhysAddr, HostPhysAddr, HyperResult, NestedPageTableExt};
 
 pub struct Sv39GuestMetaData;
 
@@ -12,3 +15,23 @@ impl PagingMetaData for Sv39GuestMetaData {
 
 /// Nested page table define.
 pub type NestedPageTable<I> = PageTable64<Sv39GuestMetaData, Rv64PTE, I>;
+
+/// Sv39x4 maps 2M megapages and 1G gigapages.
+impl<I: PagingIf> NestedPageTableExt for NestedPageTable<I> {
+    fn map_nested_page(
+        &mut self,
+        gpa: GuestPhysAddr,
+        hpa: HostPhysAddr,
+        page_size: PageSize,
+        flags: MappingFlags,
+    ) -> HyperResult {
+        map_page_in(self, gpa, hpa, page_size, PageSize::Size1G, flags)
+    }
+
+    fn query_nested_page(
+        &self,
+        gpa: GuestPhysAddr,
+    ) -> HyperResult<(HostPhysAddr, MappingFlags, PageSize)> {
+        query_page_in(self, gpa)
+    }
+}
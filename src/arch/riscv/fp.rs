@@ -0,0 +1,448 @@
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::Mutex;
+
+use super::detect::detect_v_extension;
+use crate::snapshot::{Snapshot, SnapshotReader, SnapshotWriter};
+use crate::{HyperError, HyperResult};
+
+/// Bit offset of `sstatus.FS`.
+const SSTATUS_FS_SHIFT: usize = 13;
+/// Bit offset of `sstatus.VS`.
+const SSTATUS_VS_SHIFT: usize = 9;
+
+/// The state of the FP or vector unit in `sstatus.FS` or `sstatus.VS`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExtensionState {
+    Off = 0,
+    Initial = 1,
+    Clean = 2,
+    Dirty = 3,
+}
+
+impl ExtensionState {
+    fn read(sstatus: usize, shift: usize) -> Self {
+        match (sstatus >> shift) & 0x3 {
+            0 => Self::Off,
+            1 => Self::Initial,
+            2 => Self::Clean,
+            _ => Self::Dirty,
+        }
+    }
+
+    fn write(self, sstatus: usize, shift: usize) -> usize {
+        (sstatus & !(0x3 << shift)) | ((self as usize) << shift)
+    }
+
+    /// The state of the FP unit in `sstatus`.
+    pub fn fs(sstatus: usize) -> Self {
+        Self::read(sstatus, SSTATUS_FS_SHIFT)
+    }
+
+    /// `sstatus` with the FP unit in this state.
+    pub fn set_fs(self, sstatus: usize) -> usize {
+        self.write(sstatus, SSTATUS_FS_SHIFT)
+    }
+
+    /// The state of the vector unit in `sstatus`.
+    pub fn vs(sstatus: usize) -> Self {
+        Self::read(sstatus, SSTATUS_VS_SHIFT)
+    }
+
+    /// `sstatus` with the vector unit in this state.
+    pub fn set_vs(self, sstatus: usize) -> usize {
+        self.write(sstatus, SSTATUS_VS_SHIFT)
+    }
+}
+
+fn read_sstatus() -> usize {
+    let sstatus: usize;
+    unsafe { asm!("csrr {}, sstatus", out(reg) sstatus) };
+    sstatus
+}
+
+fn write_sstatus(sstatus: usize) {
+    unsafe { asm!("csrw sstatus, {}", in(reg) sstatus) };
+}
+
+/// The next ID of a [`GuestFpState`]. 0 stands for the hypervisor.
+static NEXT_FP_STATE_ID: AtomicU64 = AtomicU64::new(1);
+
+/// The IDs of the [`GuestFpState`] whose FP and vector registers each hart
+/// holds, by `tp`.
+static HART_FP_OWNERS: Mutex<Vec<(usize, u64, u64)>> = Mutex::new(Vec::new());
+
+fn next_fp_state_id() -> u64 {
+    NEXT_FP_STATE_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+fn this_hart() -> usize {
+    let tp: usize;
+    unsafe { asm!("mv {}, tp", out(reg) tp) };
+    tp
+}
+
+/// The IDs of the states whose FP and vector registers this hart holds.
+fn hart_fp_owners() -> (u64, u64) {
+    let hart = this_hart();
+    HART_FP_OWNERS
+        .lock()
+        .iter()
+        .find(|&&(tp, _, _)| tp == hart)
+        .map_or((0, 0), |&(_, fp, vector)| (fp, vector))
+}
+
+fn set_hart_fp_owners(fp: u64, vector: u64) {
+    let hart = this_hart();
+    let mut owners = HART_FP_OWNERS.lock();
+    owners.retain(|&(tp, _, _)| tp != hart);
+    owners.push((hart, fp, vector));
+}
+
+/// The F and D extension registers.
+#[derive(Default, Clone)]
+#[repr(C)]
+pub struct FpRegisters {
+    f: [u64; 32],
+    fcsr: usize,
+}
+
+impl_snapshot_fields!(FpRegisters { f, fcsr });
+
+impl FpRegisters {
+    /// Save the FP registers of the hart. `sstatus.FS` must not be Off.
+    pub fn save(&mut self) {
+        unsafe {
+            asm!(
+                ".option push",
+                ".option arch, +d",
+            "fsd f0, 0({f})",
+            "fsd f1, 8({f})",
+            "fsd f2, 16({f})",
+            "fsd f3, 24({f})",
+            "fsd f4, 32({f})",
+            "fsd f5, 40({f})",
+            "fsd f6, 48({f})",
+            "fsd f7, 56({f})",
+            "fsd f8, 64({f})",
+            "fsd f9, 72({f})",
+            "fsd f10, 80({f})",
+            "fsd f11, 88({f})",
+            "fsd f12, 96({f})",
+            "fsd f13, 104({f})",
+            "fsd f14, 112({f})",
+            "fsd f15, 120({f})",
+            "fsd f16, 128({f})",
+            "fsd f17, 136({f})",
+            "fsd f18, 144({f})",
+            "fsd f19, 152({f})",
+            "fsd f20, 160({f})",
+            "fsd f21, 168({f})",
+            "fsd f22, 176({f})",
+            "fsd f23, 184({f})",
+            "fsd f24, 192({f})",
+            "fsd f25, 200({f})",
+            "fsd f26, 208({f})",
+            "fsd f27, 216({f})",
+            "fsd f28, 224({f})",
+            "fsd f29, 232({f})",
+            "fsd f30, 240({f})",
+            "fsd f31, 248({f})",
+                "frcsr {fcsr}",
+                ".option pop",
+                f = in(reg) self.f.as_mut_ptr(),
+                fcsr = out(reg) self.fcsr,
+            );
+        }
+    }
+
+    /// Load these FP registers into the hart. `sstatus.FS` must not be Off.
+    pub fn restore(&self) {
+        unsafe {
+            asm!(
+                ".option push",
+                ".option arch, +d",
+            "fld f0, 0({f})",
+            "fld f1, 8({f})",
+            "fld f2, 16({f})",
+            "fld f3, 24({f})",
+            "fld f4, 32({f})",
+            "fld f5, 40({f})",
+            "fld f6, 48({f})",
+            "fld f7, 56({f})",
+            "fld f8, 64({f})",
+            "fld f9, 72({f})",
+            "fld f10, 80({f})",
+            "fld f11, 88({f})",
+            "fld f12, 96({f})",
+            "fld f13, 104({f})",
+            "fld f14, 112({f})",
+            "fld f15, 120({f})",
+            "fld f16, 128({f})",
+            "fld f17, 136({f})",
+            "fld f18, 144({f})",
+            "fld f19, 152({f})",
+            "fld f20, 160({f})",
+            "fld f21, 168({f})",
+            "fld f22, 176({f})",
+            "fld f23, 184({f})",
+            "fld f24, 192({f})",
+            "fld f25, 200({f})",
+            "fld f26, 208({f})",
+            "fld f27, 216({f})",
+            "fld f28, 224({f})",
+            "fld f29, 232({f})",
+            "fld f30, 240({f})",
+            "fld f31, 248({f})",
+                "fscsr {fcsr}",
+                ".option pop",
+                f = in(reg) self.f.as_ptr(),
+                fcsr = in(reg) self.fcsr,
+            );
+        }
+    }
+}
+
+/// The V extension registers: v0-v31 of `vlenb` bytes each, and the vector
+/// CSRs. Empty if the hart does not implement V.
+#[derive(Default, Clone)]
+pub struct VectorRegisters {
+    v: Vec<u8>,
+    vstart: usize,
+    vtype: usize,
+    vl: usize,
+    vcsr: usize,
+}
+
+impl Snapshot for VectorRegisters {
+    fn save(&self, w: &mut SnapshotWriter) {
+        self.v.save(w);
+        self.vstart.save(w);
+        self.vtype.save(w);
+        self.vl.save(w);
+        self.vcsr.save(w);
+    }
+
+    /// The registers can only be restored on a hart with the same `vlenb`,
+    /// and only on a hart which implements V if the snapshot has them.
+    fn restore(&mut self, r: &mut SnapshotReader<'_>) -> HyperResult {
+        let mut v = Vec::new();
+        v.restore(r)?;
+        if v.len() != self.v.len() {
+            return Err(HyperError::NotSupported);
+        }
+        self.v = v;
+        self.vstart.restore(r)?;
+        self.vtype.restore(r)?;
+        self.vl.restore(r)?;
+        self.vcsr.restore(r)
+    }
+}
+
+impl VectorRegisters {
+    /// Allocate the register file if the hart implements V.
+    pub fn new() -> Self {
+        let mut regs = Self::default();
+        if detect_v_extension() {
+            let vlenb: usize;
+            let sstatus = read_sstatus();
+            write_sstatus(ExtensionState::Initial.set_vs(sstatus));
+            unsafe { asm!("csrr {}, vlenb", out(reg) vlenb) };
+            write_sstatus(sstatus);
+            regs.v = alloc::vec![0; vlenb * 32];
+        }
+        regs
+    }
+
+    fn is_present(&self) -> bool {
+        !self.v.is_empty()
+    }
+
+    /// Save the vector registers of the hart. `sstatus.VS` must not be Off.
+    pub fn save(&mut self) {
+        if !self.is_present() {
+            return;
+        }
+        let step = self.v.len() / 4;
+        unsafe {
+            asm!(
+                ".option push",
+                ".option arch, +v",
+                "csrr {vstart}, vstart",
+                "csrr {vtype}, vtype",
+                "csrr {vl}, vl",
+                "csrr {vcsr}, vcsr",
+            "vs8r.v v0, ({v})",
+            "add {v}, {v}, {step}",
+            "vs8r.v v8, ({v})",
+            "add {v}, {v}, {step}",
+            "vs8r.v v16, ({v})",
+            "add {v}, {v}, {step}",
+            "vs8r.v v24, ({v})",
+            "add {v}, {v}, {step}",
+                ".option pop",
+                v = inout(reg) self.v.as_mut_ptr() => _,
+                step = in(reg) step,
+                vstart = out(reg) self.vstart,
+                vtype = out(reg) self.vtype,
+                vl = out(reg) self.vl,
+                vcsr = out(reg) self.vcsr,
+            );
+        }
+    }
+
+    /// Load these vector registers into the hart. `sstatus.VS` must not be
+    /// Off.
+    pub fn restore(&self) {
+        if !self.is_present() {
+            return;
+        }
+        let step = self.v.len() / 4;
+        unsafe {
+            asm!(
+                ".option push",
+                ".option arch, +v",
+            "vl8re8.v v0, ({v})",
+            "add {v}, {v}, {step}",
+            "vl8re8.v v8, ({v})",
+            "add {v}, {v}, {step}",
+            "vl8re8.v v16, ({v})",
+            "add {v}, {v}, {step}",
+            "vl8re8.v v24, ({v})",
+            "add {v}, {v}, {step}",
+                "vsetvl x0, {vl}, {vtype}",
+                "csrw vstart, {vstart}",
+                "csrw vcsr, {vcsr}",
+                ".option pop",
+                v = inout(reg) self.v.as_ptr() => _,
+                step = in(reg) step,
+                vstart = in(reg) self.vstart,
+                vtype = in(reg) self.vtype,
+                vl = in(reg) self.vl,
+                vcsr = in(reg) self.vcsr,
+            );
+        }
+    }
+}
+
+/// The FP and vector state of a vCPU, switched lazily: a unit is only
+/// loaded if the guest may use it (`sstatus.FS`/`sstatus.VS` not Off) and
+/// only saved back if the guest dirtied it. The hypervisor's own registers
+/// are kept aside meanwhile, unless its unit is Off.
+///
+/// When the hypervisor's unit is Off, the hart keeps the guest's registers
+/// after the exit, and they are not loaded again if the same vCPU is the
+/// next to run there. This relies on the hypervisor not enabling its units
+/// between vCPU runs, as with soft-float targets.
+#[derive(Default)]
+pub struct GuestFpState {
+    /// Tells this state apart in the owners of the harts' registers.
+    id: u64,
+    guest_fp: FpRegisters,
+    guest_vector: VectorRegisters,
+    hyp_fp: FpRegisters,
+    hyp_vector: VectorRegisters,
+    hyp_fp_saved: bool,
+    hyp_vector_saved: bool,
+}
+
+// The hypervisor's registers are only kept aside while the guest's are
+// loaded, and are not part of the vCPU state.
+impl Snapshot for GuestFpState {
+    fn save(&self, w: &mut SnapshotWriter) {
+        self.guest_fp.save(w);
+        self.guest_vector.save(w);
+    }
+
+    /// The restored registers differ from those a hart may hold for this
+    /// state, so they are loaded on the next entry.
+    fn restore(&mut self, r: &mut SnapshotReader<'_>) -> HyperResult {
+        self.guest_fp.restore(r)?;
+        self.guest_vector.restore(r)?;
+        self.id = next_fp_state_id();
+        Ok(())
+    }
+}
+
+impl GuestFpState {
+    /// Create the state of a vCPU on this hart.
+    pub fn new() -> Self {
+        Self {
+            id: next_fp_state_id(),
+            guest_vector: VectorRegisters::new(),
+            hyp_vector: VectorRegisters::new(),
+            ..Default::default()
+        }
+    }
+
+    /// Load the guest's units before entering it with `guest_sstatus`,
+    /// unless the hart still holds them.
+    pub fn enter(&mut self, guest_sstatus: usize) {
+        let sstatus = read_sstatus();
+        let mut enabled = sstatus;
+        let (mut fp_owner, mut vector_owner) = hart_fp_owners();
+        let held = |owner: u64| self.id != 0 && owner == self.id;
+        if ExtensionState::fs(guest_sstatus) != ExtensionState::Off {
+            self.hyp_fp_saved = ExtensionState::fs(sstatus) != ExtensionState::Off;
+            enabled = ExtensionState::Initial.set_fs(enabled);
+            write_sstatus(enabled);
+            if self.hyp_fp_saved {
+                self.hyp_fp.save();
+            }
+            if !held(fp_owner) {
+                self.guest_fp.restore();
+            }
+            fp_owner = self.id;
+        }
+        if self.guest_vector.is_present()
+            && ExtensionState::vs(guest_sstatus) != ExtensionState::Off
+        {
+            self.hyp_vector_saved = ExtensionState::vs(sstatus) != ExtensionState::Off;
+            enabled = ExtensionState::Initial.set_vs(enabled);
+            write_sstatus(enabled);
+            if self.hyp_vector_saved {
+                self.hyp_vector.save();
+            }
+            if !held(vector_owner) {
+                self.guest_vector.restore();
+            }
+            vector_owner = self.id;
+        }
+        write_sstatus(sstatus);
+        set_hart_fp_owners(fp_owner, vector_owner);
+    }
+
+    /// Save the units the guest dirtied after it exited, give the
+    /// hypervisor its own back, and return the guest's `sstatus` with them
+    /// marked Clean.
+    pub fn exit(&mut self, mut guest_sstatus: usize) -> usize {
+        let sstatus = read_sstatus();
+        write_sstatus(ExtensionState::Initial.set_vs(ExtensionState::Initial.set_fs(sstatus)));
+        if ExtensionState::fs(guest_sstatus) == ExtensionState::Dirty {
+            self.guest_fp.save();
+            guest_sstatus = ExtensionState::Clean.set_fs(guest_sstatus);
+        }
+        let (mut fp_owner, mut vector_owner) = hart_fp_owners();
+        if self.hyp_fp_saved {
+            self.hyp_fp.restore();
+            self.hyp_fp_saved = false;
+            fp_owner = 0;
+        }
+        if self.guest_vector.is_present() {
+            if ExtensionState::vs(guest_sstatus) == ExtensionState::Dirty {
+                self.guest_vector.save();
+                guest_sstatus = ExtensionState::Clean.set_vs(guest_sstatus);
+            }
+            if self.hyp_vector_saved {
+                self.hyp_vector.restore();
+                self.hyp_vector_saved = false;
+                vector_owner = 0;
+            }
+        }
+        write_sstatus(sstatus);
+        set_hart_fp_owners(fp_owner, vector_owner);
+        guest_sstatus
+    }
+}
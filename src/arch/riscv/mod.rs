@@ -1,7 +1,9 @@
 mod csrs;
+mod decode;
 mod detect;
 mod devices;
 mod ept;
+mod fp;
 mod gdb;
 mod regs;
 mod sbi;
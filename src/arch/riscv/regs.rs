@@ -2,6 +2,8 @@
 #[repr(C)]
 pub struct GeneralPurposeRegisters([usize; 32]);
 
+impl_snapshot_fields!(GeneralPurposeRegisters { 0 });
+
 /// Index of risc-v general purpose registers in `GeneralPurposeRegisters`.
 #[allow(missing_docs)]
 #[repr(u32)]
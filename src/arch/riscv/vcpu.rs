@@ -10,12 +10,14 @@ use riscv::register::{htinst, htval, hvip, scause, sstatus, stval, vsatp};
 
 use crate::arch::vmexit::PrivilegeLevel;
 use crate::arch::{traps, RiscvCsrTrait, CSR};
+use crate::snapshot::{Snapshot, SnapshotReader, SnapshotWriter};
 use crate::{
     arch::sbi::SbiMessage, GuestPageTableTrait, GuestPhysAddr, GuestVirtAddr, HostPhysAddr,
-    HyperCraftHal, VmExitInfo,
+    HyperCraftHal, HyperResult, VmExitInfo,
 };
 
 use super::csrs::defs::hstatus;
+use super::fp::{ExtensionState, GuestFpState};
 use super::regs::{GeneralPurposeRegisters, GprIndex};
 // use super::Guest;
 
@@ -42,6 +44,14 @@ struct GuestCpuState {
     sepc: usize,
 }
 
+impl_snapshot_fields!(GuestCpuState {
+    gprs,
+    sstatus,
+    hstatus,
+    scounteren,
+    sepc,
+});
+
 /// The CSRs that are only in effect when virtualization is enabled (V=1) and must be saved and
 /// restored whenever we switch between VMs.
 #[derive(Default)]
@@ -59,6 +69,75 @@ pub struct GuestVsCsrs {
     vstimecmp: usize,
 }
 
+// `vstimecmp` is not used, the guest's timer is set through the SBI.
+impl_snapshot_fields!(GuestVsCsrs {
+    htimedelta,
+    vsstatus,
+    vsie,
+    vstvec,
+    vsscratch,
+    vsepc,
+    vscause,
+    vstval,
+    vsatp,
+});
+
+impl GuestVsCsrs {
+    /// Read the CSRs from the hart, which keeps them for the vCPU it ran
+    /// last.
+    fn save_from_hart(&mut self) {
+        unsafe {
+            core::arch::asm!(
+                "csrr {htimedelta}, htimedelta",
+                "csrr {vsstatus}, vsstatus",
+                "csrr {vsie}, vsie",
+                "csrr {vstvec}, vstvec",
+                "csrr {vsscratch}, vsscratch",
+                "csrr {vsepc}, vsepc",
+                "csrr {vscause}, vscause",
+                "csrr {vstval}, vstval",
+                "csrr {vsatp}, vsatp",
+                htimedelta = out(reg) self.htimedelta,
+                vsstatus = out(reg) self.vsstatus,
+                vsie = out(reg) self.vsie,
+                vstvec = out(reg) self.vstvec,
+                vsscratch = out(reg) self.vsscratch,
+                vsepc = out(reg) self.vsepc,
+                vscause = out(reg) self.vscause,
+                vstval = out(reg) self.vstval,
+                vsatp = out(reg) self.vsatp,
+            );
+        }
+    }
+
+    /// Write the CSRs to the hart.
+    fn restore_to_hart(&self) {
+        unsafe {
+            core::arch::asm!(
+                "csrw htimedelta, {htimedelta}",
+                "csrw vsstatus, {vsstatus}",
+                "csrw vsie, {vsie}",
+                "csrw vstvec, {vstvec}",
+                "csrw vsscratch, {vsscratch}",
+                "csrw vsepc, {vsepc}",
+                "csrw vscause, {vscause}",
+                "csrw vstval, {vstval}",
+                "csrw vsatp, {vsatp}",
+                "hfence.vvma",
+                htimedelta = in(reg) self.htimedelta,
+                vsstatus = in(reg) self.vsstatus,
+                vsie = in(reg) self.vsie,
+                vstvec = in(reg) self.vstvec,
+                vsscratch = in(reg) self.vsscratch,
+                vsepc = in(reg) self.vsepc,
+                vscause = in(reg) self.vscause,
+                vstval = in(reg) self.vstval,
+                vsatp = in(reg) self.vsatp,
+            );
+        }
+    }
+}
+
 /// Virtualized HS-level CSRs that are used to emulate (part of) the hypervisor extension for the
 /// guest.
 #[derive(Default)]
@@ -69,6 +148,9 @@ pub struct GuestVirtualHsCsrs {
     hgatp: usize,
 }
 
+// `hgatp` points to the page table of the VM, which is not saved.
+impl_snapshot_fields!(GuestVirtualHsCsrs { hie, hgeie });
+
 /// CSRs written on an exit from virtualization that are used by the hypervisor to determine the cause
 /// of the trap.
 #[derive(Default, Clone)]
@@ -102,6 +184,9 @@ pub struct VmCpuRegisters {
 
     // Page table root
     page_table_root: usize,
+
+    // FP and vector state, switched in Rust around `_run_guest`.
+    fp_state: GuestFpState,
 }
 
 #[allow(dead_code)]
@@ -241,7 +326,10 @@ impl<H: HyperCraftHal> VCpu<H> {
         // Set sstatus
         let mut sstatus = sstatus::read();
         sstatus.set_spp(sstatus::SPP::Supervisor);
-        regs.guest_regs.sstatus = sstatus.bits();
+        // Let the guest use the FP and vector units, starting from their initial state.
+        let sstatus = ExtensionState::Initial.set_fs(sstatus.bits());
+        regs.guest_regs.sstatus = ExtensionState::Initial.set_vs(sstatus);
+        regs.fp_state = GuestFpState::new();
 
         regs.guest_regs.gprs.set_reg(GprIndex::A0, 0);
         regs.guest_regs.gprs.set_reg(GprIndex::A1, 0x9000_0000);
@@ -296,11 +384,13 @@ impl<H: HyperCraftHal> VCpu<H> {
     /// Runs this vCPU until traps.
     pub fn run(&mut self) -> VmExitInfo {
         let regs = &mut self.regs;
+        regs.fp_state.enter(regs.guest_regs.sstatus);
         unsafe {
             // Safe to run the guest as it only touches memory assigned to it by being owned
             // by its page table
             _run_guest(regs);
         }
+        regs.guest_regs.sstatus = regs.fp_state.exit(regs.guest_regs.sstatus);
         // Save off the trap information
         regs.trap_csrs.scause = scause::read().bits();
         regs.trap_csrs.stval = stval::read();
@@ -321,7 +411,8 @@ impl<H: HyperCraftHal> VCpu<H> {
                 VmExitInfo::ExternalInterruptEmulation
             }
             Trap::Exception(Exception::LoadGuestPageFault)
-            | Trap::Exception(Exception::StoreGuestPageFault) => {
+            | Trap::Exception(Exception::StoreGuestPageFault)
+            | Trap::Exception(Exception::InstructionGuestPageFault) => {
                 let fault_addr = regs.trap_csrs.htval << 2 | regs.trap_csrs.stval & 0x3;
                 // debug!(
                 //     "fault_addr: {:#x}, htval: {:#x}, stval: {:#x}, sepc: {:#x}, scause: {:?}",
@@ -395,6 +486,33 @@ impl<H: HyperCraftHal> VCpu<H> {
     pub fn regs(&mut self) -> &mut VmCpuRegisters {
         &mut self.regs
     }
+
+    /// Save the guest state of this vCPU. It must be the vCPU that ran last
+    /// on this hart, which holds its VS-level CSRs and pending virtual
+    /// interrupts.
+    pub(crate) fn save_state(&mut self, w: &mut SnapshotWriter) {
+        let regs = &mut self.regs;
+        regs.vs_csrs.save_from_hart();
+        regs.guest_regs.save(w);
+        regs.vs_csrs.save(w);
+        regs.virtual_hs_csrs.save(w);
+        regs.fp_state.save(w);
+        CSR.hvip.get_value().save(w);
+    }
+
+    /// Restore the guest state saved by [`VCpu::save_state`], and load its
+    /// CSRs into this hart.
+    pub(crate) fn restore_state(&mut self, r: &mut SnapshotReader<'_>) -> HyperResult {
+        let regs = &mut self.regs;
+        regs.guest_regs.restore(r)?;
+        regs.vs_csrs.restore(r)?;
+        regs.virtual_hs_csrs.restore(r)?;
+        regs.fp_state.restore(r)?;
+        let hvip = r.read::<usize>()?;
+        regs.vs_csrs.restore_to_hart();
+        CSR.hvip.write_value(hvip);
+        Ok(())
+    }
 }
 
 // Private methods implements
@@ -1,7 +1,8 @@
 use core::panic;
 
 use super::{
-    devices::plic::{PlicState, MAX_CONTEXTS},
+    decode::{decode_mmio_instruction, MmioAccess},
+    devices::plic::PlicState,
     regs::GeneralPurposeRegisters,
     sbi::PmuFunction,
     sbi::{BaseFunction, RemoteFenceFunction},
@@ -11,34 +12,74 @@ use super::{
     HyperCallMsg, RiscvCsrTrait, CSR,
 };
 use crate::{
-    arch::sbi::SBI_ERR_NOT_SUPPORTED, vcpus::VM_CPUS_MAX, GprIndex, GuestPageTableTrait,
-    GuestPhysAddr, GuestVirtAddr, HyperCraftHal, HyperError, HyperResult, VCpu, VmCpus, VmExitInfo,
+    arch::sbi::SBI_ERR_NOT_SUPPORTED,
+    cow::CowRam,
+    dirty_log::{DirtyLog, DirtyTracking},
+    lazy_ram::LazyRam,
+    migration::Migratable,
+    snapshot::{
+        restore_memory, save_memory, Snapshot, SnapshotReader, SnapshotWriter, SECTION_IRQCHIP,
+        SECTION_VCPUS, SECTION_VM,
+    },
+    vcpus::VM_CPUS_MAX,
+    CharBackend, GprIndex, GuestMemory, GuestPageTableTrait, GuestPhysAddr, GuestVirtAddr,
+    HyperCraftHal, HyperError, HyperResult, MmioDevice, Uart16550, UartAttachment, VCpu,
+    VirtioMmio, VmCpus, VmExitInfo,
 };
+use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
 use gdbstub::{conn::ConnectionExt, stub::state_machine::GdbStubStateMachine};
-use riscv_decode::Instruction;
+use page_table_entry::MappingFlags;
 use sbi_rt::{pmu_counter_get_info, pmu_counter_stop};
 
+/// The guest physical address of the PLIC, the same as the host's.
+const PLIC_BASE: GuestPhysAddr = 0xC00_0000;
+/// The PLIC context of the guest's supervisor mode.
+const GUEST_CONTEXT: usize = 1;
+
 /// A VM that is being run.
 pub struct VM<H: HyperCraftHal, G: GuestPageTableTrait, C: ConnectionExt> {
     vcpus: VmCpus<H>,
     pub(crate) gpt: G,
     pub(crate) vm_pages: VmPages,
     plic: PlicState,
+    /// The emulated UART and its PLIC interrupt source.
+    uart: Option<(Uart16550, u32)>,
+    /// The virtio-mmio devices and their PLIC interrupt sources.
+    virtio: Vec<(VirtioMmio, u32)>,
     pub(crate) gdbstub: Option<GdbStubStateMachine<'static, Self, C>>,
     pub(crate) breakpoints: BTreeMap<usize, (usize, [u8; 2])>,
+    /// The pages written by the guest, while dirty logging is enabled.
+    dirty_log: Option<DirtyLog>,
+    /// The deadline of the guest's timer, once it set one.
+    timer_deadline: Option<u64>,
+    /// The RAM backed on demand.
+    lazy_ram: LazyRam<H>,
+    /// The RAM shared with the template VM, if this is a clone.
+    cow_ram: CowRam<H>,
 }
 
 impl<H: HyperCraftHal, G: GuestPageTableTrait, C: ConnectionExt> VM<H, G, C> {
     /// Create a new VM with `vcpus` vCPUs and `gpt` as the guest page table.
+    /// The PLIC is emulated at the host's address, which must not be mapped
+    /// in `gpt`. Otherwise it is `InvalidParam`.
     pub fn new(vcpus: VmCpus<H>, gpt: G) -> HyperResult<Self> {
+        let plic = PlicState::new(PLIC_BASE, H::phys_to_virt(PLIC_BASE));
+        plic.check_unmapped(&gpt)?;
         Ok(Self {
             vcpus,
             gpt,
             vm_pages: VmPages::default(),
-            plic: PlicState::new(H::phys_to_virt(0xC00_0000)),
+            plic,
+            uart: None,
+            virtio: Vec::new(),
             gdbstub: None,
             breakpoints: BTreeMap::new(),
+            dirty_log: None,
+            timer_deadline: None,
+            lazy_ram: LazyRam::new(),
+            cow_ram: CowRam::new(),
         })
     }
 
@@ -48,6 +89,187 @@ impl<H: HyperCraftHal, G: GuestPageTableTrait, C: ConnectionExt> VM<H, G, C> {
         vcpu.init_page_map(self.gpt.token());
     }
 
+    /// Attach a 16550 UART at guest physical address `base`, with registers
+    /// `1 << reg_shift` bytes apart, raising PLIC interrupt source `irq`. The
+    /// range must not be mapped in the guest page table.
+    pub fn attach_uart(
+        &mut self,
+        base: GuestPhysAddr,
+        reg_shift: u32,
+        irq: u32,
+        backend: Box<dyn CharBackend>,
+    ) {
+        let uart = Uart16550::new(UartAttachment::Mmio { base, reg_shift }, backend);
+        self.plic.add_virtual_source(irq);
+        self.uart = Some((uart, irq));
+    }
+
+    /// Attach a virtio-mmio device raising PLIC interrupt source `irq`. Its
+    /// range must not be mapped in the guest page table.
+    pub fn attach_virtio_mmio(&mut self, device: VirtioMmio, irq: u32) {
+        self.plic.add_virtual_source(irq);
+        self.virtio.push((device, irq));
+    }
+
+    /// Start logging the pages written in `memory`, as described on
+    /// [`DirtyLog`], by write-protecting them in the G-stage page table.
+    pub fn enable_dirty_log(&mut self, memory: &GuestMemory) -> HyperResult {
+        if self.dirty_log.is_some() {
+            return Err(HyperError::BadState);
+        }
+        if self.cow_ram.is_shared() {
+            return Err(HyperError::NotSupported);
+        }
+        let log = DirtyLog::start(memory, &mut self.gpt, DirtyTracking::WriteProtect)?;
+        self.dirty_log = Some(log);
+        flush_guest_tlb();
+        Ok(())
+    }
+
+    /// Take the dirty bitmap of the `size` bytes at `gpa`.
+    pub fn get_and_clear_dirty_log(
+        &mut self,
+        gpa: GuestPhysAddr,
+        size: usize,
+    ) -> HyperResult<Vec<usize>> {
+        let log = self.dirty_log.as_mut().ok_or(HyperError::BadState)?;
+        let dirty = log.get_and_clear(&mut self.gpt, gpa, size)?;
+        flush_guest_tlb();
+        Ok(dirty)
+    }
+
+    /// Stop dirty logging, and give the guest its write permissions back.
+    pub fn disable_dirty_log(&mut self) -> HyperResult {
+        let log = self.dirty_log.take().ok_or(HyperError::BadState)?;
+        log.stop(&mut self.gpt)?;
+        flush_guest_tlb();
+        Ok(())
+    }
+
+    /// Add the `size` bytes at `gpa` to `memory`, the RAM of the guest,
+    /// as [`LazyRam`] backed on demand with `flags`.
+    pub fn add_lazy_region(
+        &mut self,
+        memory: &mut GuestMemory,
+        gpa: GuestPhysAddr,
+        size: usize,
+        flags: MappingFlags,
+    ) -> HyperResult {
+        self.lazy_ram.add_region(memory, gpa, size, flags)
+    }
+
+    /// The number of pages of the RAM backed on demand allocated so far.
+    pub fn lazy_ram_pages(&self) -> usize {
+        self.lazy_ram.nr_pages()
+    }
+
+    /// Free the pages of the [`LazyRam`] in the `size` bytes at `gpa`, and
+    /// return their number.
+    pub fn reclaim_pages(&mut self, gpa: GuestPhysAddr, size: usize) -> HyperResult<usize> {
+        self.lazy_ram.reclaim(&mut self.gpt, gpa, size, || {
+            flush_guest_tlb();
+            Ok(())
+        })
+    }
+
+    /// Make this VM a [`CowRam`] clone of a template VM, whose RAM is
+    /// `template_memory` and whose state is `template_state`, and return the
+    /// RAM of the clone. Like [`VM::restore`], the vCPUs then resume on this
+    /// hart.
+    pub fn clone_from_template(
+        &mut self,
+        template_state: &[u8],
+        template_memory: &GuestMemory,
+    ) -> HyperResult<GuestMemory> {
+        let memory = self.cow_ram.share(template_memory, &mut self.gpt)?;
+        flush_guest_tlb();
+        for (virtio, _) in &mut self.virtio {
+            virtio.set_memory(memory.clone());
+        }
+        self.import_state(template_state)?;
+        Ok(memory)
+    }
+
+    /// The number of pages shared with the template VM copied so far.
+    pub fn cow_pages(&self) -> usize {
+        self.cow_ram.nr_pages()
+    }
+
+    /// Save the state of this VM: the registers of its vCPUs, its PLIC, timer
+    /// and virtio-mmio transports, and the contents of `memory`, its RAM. It must not be running,
+    /// and this hart must be the one that ran it last.
+    pub fn snapshot(&mut self, memory: &GuestMemory) -> HyperResult<Vec<u8>> {
+        let mut w = SnapshotWriter::new();
+        self.save_state(&mut w)?;
+        save_memory(memory, &mut w)?;
+        Ok(w.finish())
+    }
+
+    /// Restore a snapshot taken by [`VM::snapshot`] into this VM, which must
+    /// be set up like the saved one: the same vCPUs and virtio devices, and
+    /// `memory` with the same regions, mapped in the guest page table. The vCPUs then resume
+    /// on this hart where the saved ones stopped.
+    pub fn restore(&mut self, data: &[u8], memory: &GuestMemory) -> HyperResult {
+        let mut r = SnapshotReader::new(data)?;
+        self.restore_state(&mut r)?;
+        restore_memory(memory, &mut r)?;
+        r.finish()
+    }
+
+    fn nr_vcpus(&mut self) -> usize {
+        (0..VM_CPUS_MAX)
+            .take_while(|&i| self.vcpus.get_vcpu(i).is_ok())
+            .count()
+    }
+
+    /// Save everything but the RAM.
+    fn save_state(&mut self, w: &mut SnapshotWriter) -> HyperResult {
+        let timer_deadline = self.timer_deadline;
+        w.section(SECTION_VM, |w| {
+            timer_deadline.save(w);
+            Ok(())
+        })?;
+        let nr_vcpus = self.nr_vcpus();
+        w.section(SECTION_VCPUS, |w| {
+            nr_vcpus.save(w);
+            for vcpu_id in 0..nr_vcpus {
+                self.vcpus.get_vcpu(vcpu_id)?.save_state(w);
+            }
+            Ok(())
+        })?;
+        w.section(SECTION_IRQCHIP, |w| {
+            self.plic.save(w);
+            self.virtio.len().save(w);
+            self.virtio.iter().for_each(|(virtio, _)| virtio.save(w));
+            Ok(())
+        })
+    }
+
+    fn restore_state(&mut self, r: &mut SnapshotReader<'_>) -> HyperResult {
+        let mut timer_deadline = None;
+        r.section(SECTION_VM, |r| timer_deadline.restore(r))?;
+        let nr_vcpus = self.nr_vcpus();
+        r.section(SECTION_VCPUS, |r| {
+            if r.read::<usize>()? != nr_vcpus {
+                return Err(HyperError::InvalidParam);
+            }
+            (0..nr_vcpus).try_for_each(|vcpu_id| self.vcpus.get_vcpu(vcpu_id)?.restore_state(r))
+        })?;
+        r.section(SECTION_IRQCHIP, |r| {
+            self.plic.restore(r)?;
+            if r.read::<usize>()? != self.virtio.len() {
+                return Err(HyperError::InvalidParam);
+            }
+            self.virtio
+                .iter_mut()
+                .try_for_each(|(virtio, _)| virtio.restore(r))
+        })?;
+        if let Some(deadline) = timer_deadline {
+            self.set_guest_timer(deadline);
+        }
+        Ok(())
+    }
+
     /// Get current vcpu
     pub fn get_current_vcpu(&mut self) -> &mut VCpu<H> {
         self.vcpus.get_vcpu(0).unwrap()
@@ -63,6 +285,7 @@ impl<H: HyperCraftHal, G: GuestPageTableTrait, C: ConnectionExt> VM<H, G, C> {
         loop {
             let mut len = 4;
             let mut advance_pc = false;
+            self.sync_cow_ram();
             {
                 let vcpu = self.vcpus.get_vcpu(vcpu_id).unwrap();
                 vm_exit_info = vcpu.run();
@@ -84,14 +307,7 @@ impl<H: HyperCraftHal, G: GuestPageTableTrait, C: ConnectionExt> VM<H, G, C> {
                                 sbi_rt::legacy::console_putchar(c);
                             }
                             HyperCallMsg::SetTimer(timer) => {
-                                sbi_rt::set_timer(timer as u64);
-                                // Clear guest timer interrupt
-                                CSR.hvip.read_and_clear_bits(
-                                    traps::interrupt::VIRTUAL_SUPERVISOR_TIMER,
-                                );
-                                //  Enable host timer interrupt
-                                CSR.sie
-                                    .read_and_set_bits(traps::interrupt::SUPERVISOR_TIMER);
+                                self.set_guest_timer(timer as u64);
                             }
                             HyperCallMsg::Reset(_) => {
                                 sbi_rt::system_reset(sbi_rt::Shutdown, sbi_rt::SystemFailure);
@@ -109,6 +325,11 @@ impl<H: HyperCraftHal, G: GuestPageTableTrait, C: ConnectionExt> VM<H, G, C> {
                         panic!()
                     }
                 }
+                // Accesses to RAM backed on demand, and stores to pages shared
+                // with a template VM or write-protected for dirty logging, are
+                // retried.
+                VmExitInfo::PageFault { fault_addr, .. } if self.handle_ram_fault(fault_addr) => {}
+                VmExitInfo::PageFault { fault_addr, .. } if self.log_dirty_write(fault_addr) => {}
                 VmExitInfo::PageFault {
                     fault_addr,
                     falut_pc,
@@ -146,6 +367,9 @@ impl<H: HyperCraftHal, G: GuestPageTableTrait, C: ConnectionExt> VM<H, G, C> {
                 VmExitInfo::Breakpoint => self.gdbserver_report(),
                 _ => {}
             }
+            self.poll_uart();
+            self.poll_virtio();
+            self.update_external_irq();
 
             {
                 let vcpu = self.vcpus.get_vcpu(vcpu_id).unwrap();
@@ -158,71 +382,195 @@ impl<H: HyperCraftHal, G: GuestPageTableTrait, C: ConnectionExt> VM<H, G, C> {
     }
 }
 
+impl<H: HyperCraftHal, G: GuestPageTableTrait, C: ConnectionExt> Migratable for VM<H, G, C> {
+    fn enable_dirty_log(&mut self, memory: &GuestMemory) -> HyperResult {
+        self.enable_dirty_log(memory)
+    }
+
+    fn get_and_clear_dirty_log(
+        &mut self,
+        gpa: GuestPhysAddr,
+        size: usize,
+    ) -> HyperResult<Vec<usize>> {
+        self.get_and_clear_dirty_log(gpa, size)
+    }
+
+    fn disable_dirty_log(&mut self) -> HyperResult {
+        self.disable_dirty_log()
+    }
+
+    fn export_state(&mut self) -> HyperResult<Vec<u8>> {
+        let mut w = SnapshotWriter::new();
+        self.save_state(&mut w)?;
+        Ok(w.finish())
+    }
+
+    fn import_state(&mut self, state: &[u8]) -> HyperResult {
+        let mut r = SnapshotReader::new(state)?;
+        self.restore_state(&mut r)?;
+        r.finish()
+    }
+}
+
 // Privaie methods implementation
 impl<H: HyperCraftHal, G: GuestPageTableTrait, C: ConnectionExt> VM<H, G, C> {
-    fn handle_page_fault(
-        &mut self,
-        inst_addr: GuestVirtAddr,
-        inst: u32,
-        fault_addr: GuestPhysAddr,
-        gprs: &mut GeneralPurposeRegisters,
-    ) -> HyperResult<usize> {
-        //  plic
-        if fault_addr >= H::virt_to_phys(self.plic.base())
-            && fault_addr < H::virt_to_phys(self.plic.base() + 0x0400_0000)
+    /// Arm the timer of the guest for `deadline`.
+    fn set_guest_timer(&mut self, deadline: u64) {
+        sbi_rt::set_timer(deadline);
+        self.timer_deadline = Some(deadline);
+        // Clear guest timer interrupt
+        CSR.hvip
+            .read_and_clear_bits(traps::interrupt::VIRTUAL_SUPERVISOR_TIMER);
+        //  Enable host timer interrupt
+        CSR.sie
+            .read_and_set_bits(traps::interrupt::SUPERVISOR_TIMER);
+    }
+
+    /// Allocate and map the page of `fault_addr` if it is in the RAM backed
+    /// on demand, or copy it if it is shared with the template VM. Returns
+    /// whether it did.
+    fn handle_ram_fault(&mut self, fault_addr: GuestPhysAddr) -> bool {
+        let write_protect = self.dirty_log.is_some();
+        let result = match self
+            .lazy_ram
+            .handle_fault(&mut self.gpt, fault_addr, write_protect)
         {
-            self.handle_plic(inst_addr, inst, H::phys_to_virt(fault_addr), gprs)
-        } else {
-            error!("inst_addr: {:#x}, fault_addr: {:#x}", inst_addr, fault_addr);
-            Err(HyperError::PageFault)
+            Ok(false) => self.cow_ram.handle_write_fault(&mut self.gpt, fault_addr),
+            result => result,
+        };
+        match result {
+            Ok(handled) => {
+                if handled {
+                    unsafe { core::arch::riscv64::hfence_gvma_all() };
+                }
+                handled
+            }
+            Err(err) => {
+                error!("Failed to back guest RAM at {:#x}: {:?}", fault_addr, err);
+                false
+            }
         }
     }
 
-    #[allow(clippy::needless_late_init)]
-    fn handle_plic(
+    /// Map the pages of the RAM shared with the template VM which the host
+    /// copied, e.g. for the DMA of the virtio devices, to their copies.
+    fn sync_cow_ram(&mut self) {
+        match self.cow_ram.sync(&mut self.gpt) {
+            Ok(true) => flush_guest_tlb(),
+            Ok(false) => {}
+            Err(err) => error!("Failed to map the copied guest RAM: {:?}", err),
+        }
+    }
+
+    /// Record the guest's write to `fault_addr` if it is in a page
+    /// write-protected for dirty logging. Returns whether it was.
+    fn log_dirty_write(&mut self, fault_addr: GuestPhysAddr) -> bool {
+        let Some(log) = self.dirty_log.as_mut() else {
+            return false;
+        };
+        match log.handle_write_fault(&mut self.gpt, fault_addr) {
+            Ok(handled) => {
+                if handled {
+                    unsafe { core::arch::riscv64::hfence_gvma_all() };
+                }
+                handled
+            }
+            Err(err) => {
+                error!("Failed to log write to {:#x}: {:?}", fault_addr, err);
+                false
+            }
+        }
+    }
+
+    /// Emulate the load or store at `inst_addr` to the emulated device at
+    /// `fault_addr`. Returns the length of the instruction.
+    fn handle_page_fault(
         &mut self,
         inst_addr: GuestVirtAddr,
         mut inst: u32,
         fault_addr: GuestPhysAddr,
         gprs: &mut GeneralPurposeRegisters,
     ) -> HyperResult<usize> {
+        let device: &mut dyn MmioDevice = if self.plic.mmio_range().contains(&fault_addr) {
+            &mut self.plic
+        } else if let Some((uart, _)) = self
+            .uart
+            .as_mut()
+            .filter(|(uart, _)| uart.mmio_range().contains(&fault_addr))
+        {
+            uart
+        } else if let Some((virtio, _)) = self
+            .virtio
+            .iter_mut()
+            .find(|(virtio, _)| virtio.mmio_range().contains(&fault_addr))
+        {
+            virtio
+        } else {
+            error!("inst_addr: {:#x}, fault_addr: {:#x}", inst_addr, fault_addr);
+            return Err(HyperError::PageFault);
+        };
         if inst == 0 {
             // If hinst does not provide information about trap,
             // we must read the instruction from guest's memory maunally.
             inst = self.vm_pages.fetch_guest_instruction(inst_addr)?;
         }
-        let i1 = inst as u16;
-        let len = riscv_decode::instruction_length(i1);
-        let inst = match len {
-            2 => i1 as u32,
-            4 => inst,
-            _ => unreachable!(),
-        };
-        // assert!(len == 4);
-        let decode_inst = riscv_decode::decode(inst).map_err(|_| HyperError::DecodeError)?;
-        match decode_inst {
-            Instruction::Sw(i) => {
-                let val = gprs.reg(GprIndex::from_raw(i.rs2()).unwrap()) as u32;
-                self.plic.write_u32(fault_addr, val)
+        let (access, len) = decode_mmio_instruction(inst)?;
+        match access {
+            MmioAccess::Load { rd, width, signed } => {
+                let mut val = device.read(fault_addr, width)?;
+                if signed {
+                    let shift = 64 - width * 8;
+                    val = ((val << shift) as i64 >> shift) as u64;
+                }
+                gprs.set_reg(rd, val as usize);
             }
-            Instruction::Lw(i) => {
-                let val = self.plic.read_u32(fault_addr);
-                gprs.set_reg(GprIndex::from_raw(i.rd()).unwrap(), val as usize)
+            MmioAccess::Store { rs2, width } => {
+                let mask = u64::MAX >> (64 - width * 8);
+                device.write(fault_addr, width, gprs.reg(rs2) as u64 & mask)?;
             }
-            _ => return Err(HyperError::InvalidInstruction),
         }
         Ok(len)
     }
 
+    /// Move data between the UART and its backend, and drive its PLIC
+    /// source with its interrupt line.
+    fn poll_uart(&mut self) {
+        let Some((uart, irq)) = self.uart.as_mut() else {
+            return;
+        };
+        uart.poll();
+        self.plic.set_irq_level(*irq, uart.irq_level());
+    }
+
+    /// Let the virtio devices make progress, and drive their PLIC sources
+    /// with their interrupt lines.
+    fn poll_virtio(&mut self) {
+        for (virtio, irq) in &mut self.virtio {
+            virtio.poll();
+            self.plic.set_irq_level(*irq, virtio.irq_level());
+        }
+    }
+
+    /// Raise the virtual external interrupt while the guest's context of the
+    /// PLIC has an interrupt to claim.
+    fn update_external_irq(&mut self) {
+        if self.plic.has_interrupt(GUEST_CONTEXT) {
+            CSR.hvip
+                .read_and_set_bits(traps::interrupt::VIRTUAL_SUPERVISOR_EXTERNAL);
+        } else {
+            CSR.hvip
+                .read_and_clear_bits(traps::interrupt::VIRTUAL_SUPERVISOR_EXTERNAL);
+        }
+    }
+
+    /// Claim the physical interrupt from the host's PLIC for the guest to
+    /// claim from its own.
     fn handle_irq(&mut self) {
-        let context_id = 1;
-        let claim_and_complete_addr = self.plic.base() + 0x0020_0004 + 0x1000 * context_id;
+        let claim_and_complete_addr = self.plic.base() + 0x0020_0004 + 0x1000 * GUEST_CONTEXT;
         let irq = unsafe { core::ptr::read_volatile(claim_and_complete_addr as *const u32) };
-        assert!(irq != 0);
-        self.plic.claim_complete[context_id] = irq;
-
-        CSR.hvip
-            .read_and_set_bits(traps::interrupt::VIRTUAL_SUPERVISOR_EXTERNAL);
+        if irq != 0 {
+            self.plic.set_hw_claimed(GUEST_CONTEXT, irq);
+        }
     }
 
     fn handle_base_function(
@@ -332,3 +680,10 @@ impl<H: HyperCraftHal, G: GuestPageTableTrait, C: ConnectionExt> VM<H, G, C> {
         Ok(())
     }
 }
+
+/// Invalidate the G-stage TLB entries on all harts, after changing the
+/// guest page table.
+fn flush_guest_tlb() {
+    unsafe { core::arch::riscv64::hfence_gvma_all() };
+    sbi_rt::remote_hfence_gvma(0, usize::MAX, 0, 0);
+}
@@ -0,0 +1,182 @@
+use crate::{HyperError, HyperResult};
+
+/// Operand of an emulated MMIO store.
+#[derive(Debug, Clone, Copy)]
+pub enum MmioStoreSource {
+    /// A general-purpose register, numbered as in the ModRM `reg` field.
+    Register(usize),
+    /// An immediate operand.
+    Immediate(u64),
+}
+
+/// A decoded guest instruction which accesses MMIO.
+#[derive(Debug, Clone, Copy)]
+pub enum MmioInstructionKind {
+    /// `MOV reg, [mem]` or `MOVZX reg, [mem]`.
+    Load {
+        /// Destination register number.
+        reg: usize,
+        /// Whether the destination register is written as a whole
+        /// (32-bit and 64-bit destinations, or `MOVZX`).
+        zero_extend: bool,
+    },
+    /// `MOV [mem], reg` or `MOV [mem], imm`.
+    Store(MmioStoreSource),
+}
+
+/// A decoded MMIO access instruction.
+#[derive(Debug, Clone, Copy)]
+pub struct MmioInstruction {
+    /// The kind of the access.
+    pub kind: MmioInstructionKind,
+    /// Access width in bytes.
+    pub width: usize,
+    /// Length of the instruction in bytes.
+    pub length: usize,
+}
+
+/// Decodes the subset of `MOV` instructions compilers emit for volatile MMIO
+/// accesses. (SDM Vol. 2, Chapter 2)
+pub fn decode_mmio_instruction(bytes: &[u8]) -> HyperResult<MmioInstruction> {
+    let mut pos = 0;
+    let mut operand_16 = false;
+    let mut rex = 0u8;
+    let next = |pos: &mut usize| -> HyperResult<u8> {
+        let b = *bytes.get(*pos).ok_or(HyperError::DecodeError)?;
+        *pos += 1;
+        Ok(b)
+    };
+
+    // Legacy prefixes.
+    let mut opcode = next(&mut pos)?;
+    loop {
+        match opcode {
+            0x66 => operand_16 = true,
+            // Segment overrides, LOCK and address size are irrelevant here.
+            0x26 | 0x2e | 0x36 | 0x3e | 0x64 | 0x65 | 0x67 | 0xf0 => {}
+            _ => break,
+        }
+        opcode = next(&mut pos)?;
+    }
+    if (0x40..=0x4f).contains(&opcode) {
+        rex = opcode;
+        opcode = next(&mut pos)?;
+    }
+    let rex_w = rex & 0x8 != 0;
+    let rex_r = ((rex >> 2) & 1) as usize;
+    let full_width = if rex_w {
+        8
+    } else if operand_16 {
+        2
+    } else {
+        4
+    };
+
+    let (kind_fn, width, imm_size): (fn(usize) -> MmioInstructionKind, usize, usize) = match opcode
+    {
+        0x88 => (|reg| MmioInstructionKind::Store(MmioStoreSource::Register(reg)), 1, 0),
+        0x89 => (
+            |reg| MmioInstructionKind::Store(MmioStoreSource::Register(reg)),
+            full_width,
+            0,
+        ),
+        0x8a => (
+            |reg| MmioInstructionKind::Load {
+                reg,
+                zero_extend: false,
+            },
+            1,
+            0,
+        ),
+        0x8b => (
+            |reg| MmioInstructionKind::Load {
+                reg,
+                zero_extend: true,
+            },
+            full_width,
+            0,
+        ),
+        0xc6 => (|_| MmioInstructionKind::Store(MmioStoreSource::Immediate(0)), 1, 1),
+        0xc7 => (
+            |_| MmioInstructionKind::Store(MmioStoreSource::Immediate(0)),
+            full_width,
+            full_width.min(4),
+        ),
+        0x0f => {
+            let opcode2 = next(&mut pos)?;
+            let width = match opcode2 {
+                0xb6 => 1,
+                0xb7 => 2,
+                _ => return Err(HyperError::InvalidInstruction),
+            };
+            (
+                |reg| MmioInstructionKind::Load {
+                    reg,
+                    zero_extend: true,
+                },
+                width,
+                0,
+            )
+        }
+        _ => return Err(HyperError::InvalidInstruction),
+    };
+
+    // ModRM, SIB and displacement.
+    let modrm = next(&mut pos)?;
+    let md = modrm >> 6;
+    let reg = ((modrm >> 3) & 7) as usize | (rex_r << 3);
+    let rm = modrm & 7;
+    if rex == 0 && (opcode == 0x88 || opcode == 0x8a) && (4..8).contains(&reg) {
+        // AH, CH, DH and BH are not supported.
+        return Err(HyperError::NotSupported);
+    }
+    if md == 0b11 {
+        // Register operand, not a memory access.
+        return Err(HyperError::InvalidInstruction);
+    }
+    let mut disp_size = match md {
+        0b01 => 1,
+        0b10 => 4,
+        _ => 0,
+    };
+    if rm == 0b100 {
+        let sib = next(&mut pos)?;
+        if md == 0b00 && sib & 7 == 0b101 {
+            disp_size = 4;
+        }
+    } else if md == 0b00 && rm == 0b101 {
+        // RIP-relative
+        disp_size = 4;
+    }
+    pos += disp_size;
+
+    let mut kind = kind_fn(reg);
+    if imm_size != 0 {
+        let imm_bytes = bytes
+            .get(pos..pos + imm_size)
+            .ok_or(HyperError::DecodeError)?;
+        let mut imm = 0u64;
+        for (i, b) in imm_bytes.iter().enumerate() {
+            imm |= (*b as u64) << (i * 8);
+        }
+        if imm_size == 4 && width == 8 {
+            // imm32 is sign-extended to 64 bits.
+            imm = imm as u32 as i32 as i64 as u64;
+        }
+        kind = MmioInstructionKind::Store(MmioStoreSource::Immediate(imm));
+        pos += imm_size;
+    }
+    if let MmioInstructionKind::Load { reg, zero_extend } = kind {
+        // 8/16-bit MOV only writes the low part of the destination.
+        kind = MmioInstructionKind::Load {
+            reg,
+            zero_extend: zero_extend && (width >= 4 || opcode == 0x0f),
+        };
+    }
+
+    Ok(MmioInstruction {
+        kind,
+        width,
+        length: pos,
+    })
+}
@@ -0,0 +1,526 @@
+use bit_field::BitField;
+use core::marker::PhantomData;
+use core::ops::Range;
+
+use crate::devices::PortIoDevice;
+use crate::snapshot::{Snapshot, SnapshotReader, SnapshotWriter};
+use crate::{HyperCraftHal, HyperError, HyperResult};
+
+/// Counter and mode/command ports of the PIT.
+pub const PIT_PORTS: Range<u16> = 0x40..0x44;
+/// NMI status and control port, which also gates PIT channel 2 (speaker).
+pub const PIT_SPEAKER_PORT: u16 = 0x61;
+
+/// Input clock frequency of the PIT in Hz.
+const PIT_FREQ_HZ: u64 = 1_193_182;
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+const PIT_NUM_CHANNELS: usize = 3;
+
+/// Which bytes of the counter are accessed through the data port, with the
+/// values of the RW field of the control word.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AccessMode {
+    LoByte = 1,
+    HiByte = 2,
+    LoHiByte = 3,
+}
+
+/// A single counter of the 8254. (Intel 8254 datasheet)
+#[derive(Debug, Clone, Copy)]
+struct PitChannel {
+    /// Operating mode (0-5).
+    mode: u8,
+    access: AccessMode,
+    /// Reload value, 0 stands for 65536.
+    count: u32,
+    /// Time at which the counter was (re)loaded, in nanoseconds.
+    load_time_ns: u64,
+    gate: bool,
+    /// Time at which GATE went low, in nanoseconds.
+    gate_low_ns: u64,
+    /// The next read returns the high byte (for `LoHiByte` access).
+    read_high: bool,
+    /// The next write sets the high byte (for `LoHiByte` access).
+    write_high: bool,
+    write_latch: u8,
+    /// Latched counter value, valid until fully read.
+    count_latch: Option<u16>,
+    /// Latched status byte of a read-back command, valid until read.
+    status_latch: Option<u8>,
+    /// The counter has been loaded with a value since the last mode write.
+    armed: bool,
+    /// Time of the next interrupt (rising edge of OUT), 0 if none.
+    next_irq_ns: u64,
+}
+
+impl PitChannel {
+    const fn new() -> Self {
+        Self {
+            mode: 0,
+            access: AccessMode::LoHiByte,
+            count: 0x10000,
+            load_time_ns: 0,
+            gate: true,
+            gate_low_ns: 0,
+            read_high: false,
+            write_high: false,
+            write_latch: 0,
+            count_latch: None,
+            status_latch: None,
+            armed: false,
+            next_irq_ns: 0,
+        }
+    }
+
+    fn ns_to_ticks(ns: u64) -> u64 {
+        (ns as u128 * PIT_FREQ_HZ as u128 / NANOS_PER_SEC as u128) as u64
+    }
+
+    fn ticks_to_ns(ticks: u64) -> u64 {
+        (ticks as u128 * NANOS_PER_SEC as u128 / PIT_FREQ_HZ as u128) as u64
+    }
+
+    fn is_periodic(&self) -> bool {
+        matches!(self.mode, 2 | 3)
+    }
+
+    /// Whether counting is suspended: a low GATE stops modes 0, 2, 3 and 4.
+    fn is_stopped(&self) -> bool {
+        !self.gate && matches!(self.mode, 0 | 2 | 3 | 4)
+    }
+
+    fn elapsed_ticks(&self, now_ns: u64) -> u64 {
+        let now_ns = if self.is_stopped() {
+            now_ns.min(self.gate_low_ns)
+        } else {
+            now_ns
+        };
+        Self::ns_to_ticks(now_ns.saturating_sub(self.load_time_ns))
+    }
+
+    /// The current value of the counting element.
+    fn counter(&self, now_ns: u64) -> u16 {
+        if !self.armed {
+            return 0;
+        }
+        let d = self.elapsed_ticks(now_ns);
+        let count = self.count as u64;
+        let value = match self.mode {
+            2 => count - d % count,
+            // Mode 3 decrements by two each clock.
+            3 => count - (2 * d) % count,
+            // Modes 0, 1, 4 and 5 wrap around after the terminal count.
+            _ => count.wrapping_sub(d) & 0xffff,
+        };
+        value as u16
+    }
+
+    /// The level of the OUT pin.
+    fn output(&self, now_ns: u64) -> bool {
+        if !self.armed {
+            return self.mode != 0;
+        }
+        // A low GATE forces OUT high in modes 2 and 3.
+        if !self.gate && self.is_periodic() {
+            return true;
+        }
+        let d = self.elapsed_ticks(now_ns);
+        let count = self.count as u64;
+        match self.mode {
+            0 => d >= count,
+            1 => d >= count,
+            2 => d % count != count - 1,
+            3 => d % count < count.div_ceil(2),
+            // Modes 4 and 5 pulse low for one clock at the terminal count.
+            _ => d != count,
+        }
+    }
+
+    fn reload(&mut self, now_ns: u64) {
+        self.load_time_ns = now_ns;
+        self.armed = true;
+        self.next_irq_ns = match self.mode {
+            0 | 2 | 3 => now_ns + Self::ticks_to_ns(self.count as u64),
+            _ => 0,
+        };
+    }
+
+    fn set_gate(&mut self, gate: bool, now_ns: u64) {
+        if gate == self.gate {
+            return;
+        }
+        if !gate {
+            self.gate_low_ns = now_ns;
+        } else if self.armed {
+            match self.mode {
+                // A rising edge of GATE retriggers modes 1, 2, 3 and 5.
+                1 | 2 | 3 | 5 => self.reload(now_ns),
+                // Modes 0 and 4 go on counting from where they stopped.
+                _ => {
+                    let stopped_ns = now_ns - self.gate_low_ns.max(self.load_time_ns);
+                    self.load_time_ns += stopped_ns;
+                    if self.next_irq_ns != 0 {
+                        self.next_irq_ns += stopped_ns;
+                    }
+                }
+            }
+        }
+        self.gate = gate;
+    }
+
+    fn write_mode(&mut self, command: u8) {
+        let mut mode = command.get_bits(1..4);
+        if mode > 5 {
+            // Modes 6 and 7 are aliases of 2 and 3.
+            mode -= 4;
+        }
+        self.mode = mode;
+        self.access = match command.get_bits(4..6) {
+            1 => AccessMode::LoByte,
+            2 => AccessMode::HiByte,
+            _ => AccessMode::LoHiByte,
+        };
+        if command.get_bit(0) {
+            warn!("PIT: BCD counting is not supported");
+        }
+        self.read_high = false;
+        self.write_high = false;
+        self.count_latch = None;
+        self.armed = false;
+        self.next_irq_ns = 0;
+    }
+
+    fn latch_count(&mut self, now_ns: u64) {
+        if self.count_latch.is_none() {
+            self.count_latch = Some(self.counter(now_ns));
+            self.read_high = false;
+        }
+    }
+
+    fn latch_status(&mut self, now_ns: u64) {
+        if self.status_latch.is_none() {
+            let mut status = (self.mode << 1) | ((self.access as u8) << 4);
+            status.set_bit(6, !self.armed);
+            status.set_bit(7, self.output(now_ns));
+            self.status_latch = Some(status);
+        }
+    }
+
+    fn read_data(&mut self, now_ns: u64) -> u8 {
+        if let Some(status) = self.status_latch.take() {
+            return status;
+        }
+        let value = self.count_latch.unwrap_or_else(|| self.counter(now_ns));
+        let (byte, done) = match self.access {
+            AccessMode::LoByte => (value as u8, true),
+            AccessMode::HiByte => ((value >> 8) as u8, true),
+            AccessMode::LoHiByte => {
+                self.read_high = !self.read_high;
+                if self.read_high {
+                    (value as u8, false)
+                } else {
+                    ((value >> 8) as u8, true)
+                }
+            }
+        };
+        if done {
+            self.count_latch = None;
+        }
+        byte
+    }
+
+    fn write_data(&mut self, value: u8, now_ns: u64) {
+        let count = match self.access {
+            AccessMode::LoByte => value as u32,
+            AccessMode::HiByte => (value as u32) << 8,
+            AccessMode::LoHiByte => {
+                self.write_high = !self.write_high;
+                if self.write_high {
+                    self.write_latch = value;
+                    return;
+                }
+                self.write_latch as u32 | (value as u32) << 8
+            }
+        };
+        self.count = if count == 0 { 0x10000 } else { count };
+        self.reload(now_ns);
+    }
+
+    /// Save the channel, with its times relative to `now_ns`.
+    fn save(&self, w: &mut SnapshotWriter, now_ns: u64) {
+        self.mode.save(w);
+        (self.access as u8).save(w);
+        self.count.save(w);
+        now_ns.saturating_sub(self.load_time_ns).save(w);
+        self.gate.save(w);
+        now_ns.saturating_sub(self.gate_low_ns).save(w);
+        self.read_high.save(w);
+        self.write_high.save(w);
+        self.write_latch.save(w);
+        self.count_latch.save(w);
+        self.status_latch.save(w);
+        self.armed.save(w);
+        let next_irq = (self.next_irq_ns != 0).then(|| self.next_irq_ns.saturating_sub(now_ns));
+        next_irq.save(w);
+    }
+
+    /// Restore the channel saved by [`PitChannel::save`] at `now_ns`.
+    fn restore(&mut self, r: &mut SnapshotReader<'_>, now_ns: u64) -> HyperResult {
+        self.mode = r.read()?;
+        self.access = match r.read::<u8>()? {
+            1 => AccessMode::LoByte,
+            2 => AccessMode::HiByte,
+            3 => AccessMode::LoHiByte,
+            _ => return Err(HyperError::DecodeError),
+        };
+        self.count = r.read()?;
+        if self.mode > 5 || !(1..=0x10000).contains(&self.count) {
+            return Err(HyperError::DecodeError);
+        }
+        self.load_time_ns = now_ns.saturating_sub(r.read()?);
+        self.gate.restore(r)?;
+        self.gate_low_ns = now_ns.saturating_sub(r.read()?);
+        self.read_high.restore(r)?;
+        self.write_high.restore(r)?;
+        self.write_latch.restore(r)?;
+        self.count_latch.restore(r)?;
+        self.status_latch.restore(r)?;
+        self.armed.restore(r)?;
+        let next_irq = r.read::<Option<u64>>()?;
+        self.next_irq_ns = next_irq.map_or(0, |remaining| now_ns + remaining);
+        Ok(())
+    }
+
+    /// Whether OUT had a rising edge since the last check, i.e. an interrupt
+    /// should be raised on the connected IRQ line.
+    fn check_interrupt(&mut self, now_ns: u64) -> bool {
+        if self.next_irq_ns == 0 || now_ns < self.next_irq_ns || self.is_stopped() {
+            return false;
+        }
+        if self.is_periodic() {
+            // Coalesce missed ticks into a single interrupt.
+            let period_ns = Self::ticks_to_ns(self.count as u64).max(1);
+            let missed = (now_ns - self.next_irq_ns) / period_ns + 1;
+            self.next_irq_ns += missed * period_ns;
+        } else {
+            self.next_irq_ns = 0;
+        }
+        true
+    }
+}
+
+/// A virtual Intel 8254 programmable interval timer.
+///
+/// Channel 0 is connected to ISA IRQ 0, channel 2 is gated by port 0x61 and
+/// its output can be read back there, which is used for TSC calibration.
+pub struct VirtPit<H: HyperCraftHal> {
+    channels: [PitChannel; PIT_NUM_CHANNELS],
+    speaker_data_on: bool,
+    _phantom: PhantomData<H>,
+}
+
+impl<H: HyperCraftHal> VirtPit<H> {
+    /// Create a PIT with all counters unprogrammed.
+    pub(crate) const fn new() -> Self {
+        Self {
+            channels: [PitChannel::new(); PIT_NUM_CHANNELS],
+            speaker_data_on: false,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Whether channel 0 has generated an interrupt since the last check.
+    pub fn check_interrupt(&mut self) -> bool {
+        self.channels[0].check_interrupt(H::current_time_nanos())
+    }
+
+    fn write_command(&mut self, command: u8, now_ns: u64) {
+        let channel = command.get_bits(6..8) as usize;
+        if channel == 3 {
+            // Read-back command
+            for (i, ch) in self.channels.iter_mut().enumerate() {
+                if command.get_bit(i + 1) {
+                    if !command.get_bit(5) {
+                        ch.latch_count(now_ns);
+                    }
+                    if !command.get_bit(4) {
+                        ch.latch_status(now_ns);
+                    }
+                }
+            }
+        } else if command.get_bits(4..6) == 0 {
+            // Counter latch command
+            self.channels[channel].latch_count(now_ns);
+        } else {
+            self.channels[channel].write_mode(command);
+        }
+    }
+
+    fn read_speaker_port(&mut self, now_ns: u64) -> u8 {
+        let ch2 = &self.channels[2];
+        let mut value = 0u8;
+        value.set_bit(0, ch2.gate);
+        value.set_bit(1, self.speaker_data_on);
+        // The refresh request bit toggles every 15.085us.
+        value.set_bit(4, (now_ns / 15_085) & 1 != 0);
+        value.set_bit(5, ch2.output(now_ns));
+        value
+    }
+}
+
+/// The times are saved relative to the current time, so that the restored
+/// counters go on counting from where the saved ones stopped.
+impl<H: HyperCraftHal> Snapshot for VirtPit<H> {
+    fn save(&self, w: &mut SnapshotWriter) {
+        let now_ns = H::current_time_nanos();
+        for channel in &self.channels {
+            channel.save(w, now_ns);
+        }
+        self.speaker_data_on.save(w);
+    }
+
+    fn restore(&mut self, r: &mut SnapshotReader<'_>) -> HyperResult {
+        let now_ns = H::current_time_nanos();
+        for channel in &mut self.channels {
+            channel.restore(r, now_ns)?;
+        }
+        self.speaker_data_on.restore(r)
+    }
+}
+
+impl<H: HyperCraftHal> PortIoDevice for VirtPit<H> {
+    fn port_range(&self) -> Range<u16> {
+        PIT_PORTS
+    }
+
+    fn read(&mut self, port: u16, access_size: u8) -> HyperResult<u32> {
+        if access_size != 1 {
+            return Err(HyperError::InvalidParam);
+        }
+        let now_ns = H::current_time_nanos();
+        let value = match port {
+            0x40..=0x42 => self.channels[(port - 0x40) as usize].read_data(now_ns),
+            // The mode/command register is write-only.
+            0x43 => 0xff,
+            PIT_SPEAKER_PORT => self.read_speaker_port(now_ns),
+            _ => return Err(HyperError::InvalidParam),
+        };
+        Ok(value as u32)
+    }
+
+    fn write(&mut self, port: u16, access_size: u8, value: u32) -> HyperResult {
+        if access_size != 1 {
+            return Err(HyperError::InvalidParam);
+        }
+        let now_ns = H::current_time_nanos();
+        let value = value as u8;
+        match port {
+            0x40..=0x42 => self.channels[(port - 0x40) as usize].write_data(value, now_ns),
+            0x43 => self.write_command(value, now_ns),
+            PIT_SPEAKER_PORT => {
+                self.channels[2].set_gate(value.get_bit(0), now_ns);
+                self.speaker_data_on = value.get_bit(1);
+            }
+            _ => return Err(HyperError::InvalidParam),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A time at which exactly `ticks` PIT clocks have elapsed since 0.
+    fn at(ticks: u64) -> u64 {
+        PitChannel::ticks_to_ns(ticks) + 1
+    }
+
+    /// A channel in `mode` loaded with `count` at time 0, low byte first.
+    fn channel(mode: u8, count: u16) -> PitChannel {
+        let mut ch = PitChannel::new();
+        ch.write_mode(0x30 | mode << 1);
+        ch.write_data(count as u8, 0);
+        ch.write_data((count >> 8) as u8, 0);
+        ch
+    }
+
+    fn read_count(ch: &mut PitChannel, now_ns: u64) -> u16 {
+        let low = ch.read_data(now_ns) as u16;
+        low | (ch.read_data(now_ns) as u16) << 8
+    }
+
+    #[test]
+    fn latched_count_holds_until_read() {
+        let mut ch = channel(2, 1000);
+        ch.latch_count(at(100));
+        // A second latch command before the read is ignored.
+        ch.latch_count(at(200));
+        assert_eq!(read_count(&mut ch, at(300)), 900);
+        assert_eq!(read_count(&mut ch, at(300)), 700);
+
+        ch.latch_status(at(300));
+        // Status: OUT high, count loaded, lobyte/hibyte access, mode 2.
+        assert_eq!(ch.read_data(at(300)), 0x80 | 0x30 | 2 << 1);
+        assert_eq!(read_count(&mut ch, at(300)), 700);
+    }
+
+    #[test]
+    fn rate_generator_reloads() {
+        let mut ch = channel(2, 1000);
+        assert_eq!(ch.counter(at(250)), 750);
+        assert_eq!(ch.counter(at(1250)), 750);
+        assert!(!ch.output(at(999)));
+        assert!(ch.output(at(1000)));
+
+        assert!(!ch.check_interrupt(at(999)));
+        assert!(ch.check_interrupt(at(1000)));
+        assert!(!ch.check_interrupt(at(1500)));
+        // Missed periods are coalesced into one interrupt.
+        assert!(ch.check_interrupt(at(3500)));
+        assert!(!ch.check_interrupt(at(3900)));
+    }
+
+    #[test]
+    fn square_wave_counts_by_two() {
+        let mut ch = channel(3, 1000);
+        assert_eq!(ch.counter(at(100)), 800);
+        assert_eq!(ch.counter(at(600)), 800);
+        assert!(ch.output(at(499)));
+        assert!(!ch.output(at(500)));
+        assert!(ch.output(at(1000)));
+        assert!(ch.check_interrupt(at(1000)));
+    }
+
+    #[test]
+    fn one_shot_wraps_after_terminal_count() {
+        let mut ch = channel(0, 100);
+        assert!(!ch.output(at(99)));
+        assert!(ch.output(at(100)));
+        assert_eq!(ch.counter(at(101)), 0xffff);
+        assert!(ch.check_interrupt(at(100)));
+        assert!(!ch.check_interrupt(at(300)));
+    }
+
+    #[test]
+    fn gate_stops_and_retriggers_counting() {
+        let mut ch = channel(0, 1000);
+        ch.set_gate(false, at(100));
+        assert_eq!(ch.counter(at(500)), 900);
+        assert!(!ch.check_interrupt(at(1000)));
+        // Counting resumes where it stopped.
+        ch.set_gate(true, at(500));
+        assert_eq!(ch.counter(at(600)), 800);
+        assert!(!ch.check_interrupt(at(1399)));
+        assert!(ch.check_interrupt(at(1401)));
+
+        let mut ch = channel(2, 1000);
+        ch.set_gate(false, at(300));
+        assert!(ch.output(at(999)));
+        assert_eq!(ch.counter(at(800)), 700);
+        // A rising edge reloads the count.
+        ch.set_gate(true, at(800));
+        assert_eq!(ch.counter(at(800) + at(100)), 900);
+    }
+}
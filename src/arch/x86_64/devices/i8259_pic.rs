@@ -0,0 +1,541 @@
+use bit_field::BitField;
+use core::ops::Range;
+
+use crate::devices::PortIoDevice;
+use crate::snapshot::{Snapshot, SnapshotReader, SnapshotWriter};
+use crate::{HyperError, HyperResult};
+
+/// Command/data ports of the master PIC.
+pub const PIC_MASTER_PORTS: Range<u16> = 0x20..0x22;
+/// Command/data ports of the slave PIC.
+pub const PIC_SLAVE_PORTS: Range<u16> = 0xA0..0xA2;
+/// Edge/level control registers (ELCR) of both PICs.
+pub const PIC_ELCR_PORTS: Range<u16> = 0x4D0..0x4D2;
+
+/// The IRQ line of the master PIC which the slave PIC is cascaded to.
+const CASCADE_IRQ: usize = 2;
+
+/// Initialization state of a single 8259A. (Intel 8259A datasheet, "Initialization Command Words")
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum InitState {
+    /// Normal operation, OCWs are accepted on the data port.
+    Ready,
+    /// Waiting for ICW2 (vector base).
+    Icw2,
+    /// Waiting for ICW3 (cascade configuration).
+    Icw3,
+    /// Waiting for ICW4 (mode).
+    Icw4,
+}
+
+impl Snapshot for InitState {
+    fn save(&self, w: &mut SnapshotWriter) {
+        (*self as u8).save(w);
+    }
+
+    fn restore(&mut self, r: &mut SnapshotReader<'_>) -> HyperResult {
+        *self = match r.read::<u8>()? {
+            0 => Self::Ready,
+            1 => Self::Icw2,
+            2 => Self::Icw3,
+            3 => Self::Icw4,
+            _ => return Err(HyperError::DecodeError),
+        };
+        Ok(())
+    }
+}
+
+/// State of a single 8259A programmable interrupt controller.
+#[derive(Debug, Clone)]
+struct I8259 {
+    /// Interrupt request register.
+    irr: u8,
+    /// In-service register.
+    isr: u8,
+    /// Interrupt mask register.
+    imr: u8,
+    /// Edge/level control register.
+    elcr: u8,
+    /// Allowed bits of `elcr`.
+    elcr_mask: u8,
+    /// Last sampled levels of the IR lines, used for edge detection.
+    last_irr: u8,
+    /// The vector number of IR0.
+    irq_base: u8,
+    /// The IR line with the lowest priority is `priority_add - 1`.
+    priority_add: u8,
+    /// Whether OCW3 selected ISR (instead of IRR) for reads of the command port.
+    read_isr: bool,
+    /// Whether the next read of the command port is a poll command.
+    poll: bool,
+    special_mask: bool,
+    auto_eoi: bool,
+    rotate_on_auto_eoi: bool,
+    special_fully_nested: bool,
+    init_state: InitState,
+    /// ICW4 is expected after ICW2/ICW3.
+    icw4_needed: bool,
+    /// Single mode, i.e. no ICW3.
+    single_mode: bool,
+}
+
+// `elcr_mask` is fixed by the wiring of the PIC.
+impl_snapshot_fields!(I8259 {
+    irr,
+    isr,
+    imr,
+    elcr,
+    last_irr,
+    irq_base,
+    priority_add,
+    read_isr,
+    poll,
+    special_mask,
+    auto_eoi,
+    rotate_on_auto_eoi,
+    special_fully_nested,
+    init_state,
+    icw4_needed,
+    single_mode,
+});
+
+impl I8259 {
+    const fn new(elcr_mask: u8) -> Self {
+        Self {
+            irr: 0,
+            isr: 0,
+            // Keep all lines masked until the guest programs the PIC.
+            imr: 0xff,
+            elcr: 0,
+            elcr_mask,
+            last_irr: 0,
+            irq_base: 0,
+            priority_add: 0,
+            read_isr: false,
+            poll: false,
+            special_mask: false,
+            auto_eoi: false,
+            rotate_on_auto_eoi: false,
+            special_fully_nested: false,
+            init_state: InitState::Ready,
+            icw4_needed: false,
+            single_mode: false,
+        }
+    }
+
+    fn reset(&mut self) {
+        let elcr = self.elcr;
+        let elcr_mask = self.elcr_mask;
+        *self = Self::new(elcr_mask);
+        self.imr = 0;
+        self.elcr = elcr;
+    }
+
+    /// Returns the highest priority line set in `mask`, or `None`.
+    fn priority(&self, mask: u8) -> Option<u8> {
+        if mask == 0 {
+            return None;
+        }
+        (0..8).find(|&priority| mask & (1 << ((priority + self.priority_add) & 7)) != 0)
+    }
+
+    /// Returns the IR line that should be raised to the CPU, if any.
+    fn output_irq(&self) -> Option<u8> {
+        let priority = self.priority(self.irr & !self.imr)?;
+        // In special fully nested mode, the slave's line on the master is not
+        // blocked by its own in-service bit.
+        let mut isr = self.isr;
+        if self.special_fully_nested {
+            isr &= !(1 << CASCADE_IRQ);
+        }
+        if self.special_mask {
+            isr &= !self.imr;
+        }
+        match self.priority(isr) {
+            Some(cur) if priority >= cur => None,
+            _ => Some((priority + self.priority_add) & 7),
+        }
+    }
+
+    fn set_irq(&mut self, irq: usize, level: bool) {
+        let mask = 1 << irq;
+        if self.elcr & mask != 0 {
+            // Level triggered.
+            if level {
+                self.irr |= mask;
+                self.last_irr |= mask;
+            } else {
+                self.irr &= !mask;
+                self.last_irr &= !mask;
+            }
+        } else if level {
+            // Edge triggered.
+            if self.last_irr & mask == 0 {
+                self.irr |= mask;
+            }
+            self.last_irr |= mask;
+        } else {
+            self.last_irr &= !mask;
+        }
+    }
+
+    /// Acknowledge `irq`, moving it from IRR to ISR (the first INTA cycle).
+    fn acknowledge(&mut self, irq: u8) {
+        let mask = 1 << irq;
+        if self.auto_eoi {
+            if self.rotate_on_auto_eoi {
+                self.priority_add = (irq + 1) & 7;
+            }
+        } else {
+            self.isr |= mask;
+        }
+        // Level triggered interrupts stay pending while the line is asserted.
+        if self.elcr & mask == 0 {
+            self.irr &= !mask;
+        }
+    }
+
+    fn write_command(&mut self, value: u8) {
+        if value.get_bit(4) {
+            // ICW1
+            self.reset();
+            self.init_state = InitState::Icw2;
+            self.icw4_needed = value.get_bit(0);
+            self.single_mode = value.get_bit(1);
+            if value.get_bit(3) {
+                warn!("8259: level sensitive mode in ICW1 is not supported");
+            }
+        } else if value.get_bit(3) {
+            // OCW3
+            if value.get_bit(2) {
+                self.poll = true;
+            }
+            if value.get_bit(1) {
+                self.read_isr = value.get_bit(0);
+            }
+            if value.get_bit(6) {
+                self.special_mask = value.get_bit(5);
+            }
+        } else {
+            // OCW2
+            let level = value.get_bits(0..3);
+            match value >> 5 {
+                0b000 | 0b100 => self.rotate_on_auto_eoi = value >> 7 != 0,
+                // Non-specific EOI, optionally with rotation.
+                0b001 | 0b101 => {
+                    if let Some(priority) = self.priority(self.isr) {
+                        let irq = (priority + self.priority_add) & 7;
+                        self.isr &= !(1 << irq);
+                        if value >> 5 == 0b101 {
+                            self.priority_add = (irq + 1) & 7;
+                        }
+                    }
+                }
+                // Specific EOI, optionally with rotation.
+                0b011 | 0b111 => {
+                    self.isr &= !(1 << level);
+                    if value >> 5 == 0b111 {
+                        self.priority_add = (level + 1) & 7;
+                    }
+                }
+                // Set priority.
+                0b110 => self.priority_add = (level + 1) & 7,
+                _ => {}
+            }
+        }
+    }
+
+    fn write_data(&mut self, value: u8) {
+        match self.init_state {
+            InitState::Ready => self.imr = value, // OCW1
+            InitState::Icw2 => {
+                self.irq_base = value & 0xf8;
+                self.init_state = if !self.single_mode {
+                    InitState::Icw3
+                } else if self.icw4_needed {
+                    InitState::Icw4
+                } else {
+                    InitState::Ready
+                };
+            }
+            InitState::Icw3 => {
+                self.init_state = if self.icw4_needed {
+                    InitState::Icw4
+                } else {
+                    InitState::Ready
+                };
+            }
+            InitState::Icw4 => {
+                self.special_fully_nested = value.get_bit(4);
+                self.auto_eoi = value.get_bit(1);
+                self.init_state = InitState::Ready;
+            }
+        }
+    }
+
+    /// Poll command: acknowledges the highest priority request and returns
+    /// its number with bit 7 set, or 0 if there is none.
+    fn poll_read(&mut self) -> u8 {
+        match self.output_irq() {
+            Some(irq) => {
+                self.acknowledge(irq);
+                0x80 | irq
+            }
+            None => 0,
+        }
+    }
+
+    fn read_command(&mut self) -> u8 {
+        if self.poll {
+            self.poll = false;
+            self.poll_read()
+        } else if self.read_isr {
+            self.isr
+        } else {
+            self.irr
+        }
+    }
+
+    fn read_data(&mut self) -> u8 {
+        if self.poll {
+            self.poll = false;
+            self.poll_read()
+        } else {
+            self.imr
+        }
+    }
+}
+
+/// A pair of cascaded virtual 8259A PICs, as found in the PC/AT architecture.
+///
+/// The slave PIC is connected to IR2 of the master PIC. The output of the
+/// master PIC is consumed by the virtual local APIC as an ExtINT interrupt.
+pub struct VirtDualPic {
+    master: I8259,
+    slave: I8259,
+}
+
+impl_snapshot_fields!(VirtDualPic { master, slave });
+
+impl VirtDualPic {
+    /// Create a pair of PICs in their power-on state.
+    pub const fn new() -> Self {
+        Self {
+            // IRQ 0, 1 and 2 are always edge triggered.
+            master: I8259::new(0xf8),
+            // IRQ 8 and 13 are always edge triggered.
+            slave: I8259::new(0xde),
+        }
+    }
+
+    /// Set the level of the ISA interrupt line `irq` (0-15).
+    pub fn set_irq_line(&mut self, irq: usize, level: bool) {
+        match irq {
+            0..=7 => self.master.set_irq(irq, level),
+            8..=15 => self.slave.set_irq(irq - 8, level),
+            _ => warn!("8259: invalid irq {}", irq),
+        }
+        self.update_cascade();
+    }
+
+    fn update_cascade(&mut self) {
+        let slave_output = self.slave.output_irq().is_some();
+        self.master.set_irq(CASCADE_IRQ, slave_output);
+    }
+
+    /// Whether the master PIC is asserting its INT output.
+    pub fn has_interrupt(&self) -> bool {
+        self.master.output_irq().is_some()
+    }
+
+    /// Perform an interrupt acknowledge cycle, returning the vector of the
+    /// highest priority pending interrupt.
+    pub fn acknowledge(&mut self) -> Option<u8> {
+        let irq = self.master.output_irq()?;
+        self.master.acknowledge(irq);
+        let vector = if irq as usize == CASCADE_IRQ {
+            // If the slave request vanished meanwhile, report a spurious IRQ 15.
+            let slave_irq = match self.slave.output_irq() {
+                Some(slave_irq) => {
+                    self.slave.acknowledge(slave_irq);
+                    slave_irq
+                }
+                None => 7,
+            };
+            self.slave.irq_base + slave_irq
+        } else {
+            self.master.irq_base + irq
+        };
+        self.update_cascade();
+        Some(vector)
+    }
+}
+
+impl PortIoDevice for VirtDualPic {
+    fn port_range(&self) -> Range<u16> {
+        // Only the master range is reported, `VirtIrqChip` dispatches the others.
+        PIC_MASTER_PORTS
+    }
+
+    fn read(&mut self, port: u16, access_size: u8) -> HyperResult<u32> {
+        if access_size != 1 {
+            return Err(HyperError::InvalidParam);
+        }
+        let value = match port {
+            0x20 => self.master.read_command(),
+            0x21 => self.master.read_data(),
+            0xA0 => self.slave.read_command(),
+            0xA1 => self.slave.read_data(),
+            0x4D0 => self.master.elcr,
+            0x4D1 => self.slave.elcr,
+            _ => return Err(HyperError::InvalidParam),
+        };
+        self.update_cascade();
+        Ok(value as u32)
+    }
+
+    fn write(&mut self, port: u16, access_size: u8, value: u32) -> HyperResult {
+        if access_size != 1 {
+            return Err(HyperError::InvalidParam);
+        }
+        let value = value as u8;
+        match port {
+            0x20 => self.master.write_command(value),
+            0x21 => self.master.write_data(value),
+            0xA0 => self.slave.write_command(value),
+            0xA1 => self.slave.write_data(value),
+            0x4D0 => self.master.elcr = value & self.master.elcr_mask,
+            0x4D1 => self.slave.elcr = value & self.slave.elcr_mask,
+            _ => return Err(HyperError::InvalidParam),
+        }
+        self.update_cascade();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(pic: &mut VirtDualPic, port: u16, value: u8) {
+        pic.write(port, 1, value as u32).unwrap();
+    }
+
+    fn read(pic: &mut VirtDualPic, port: u16) -> u8 {
+        pic.read(port, 1).unwrap() as u8
+    }
+
+    /// Pulse the edge-triggered line `irq`.
+    fn pulse(pic: &mut VirtDualPic, irq: usize) {
+        pic.set_irq_line(irq, true);
+        pic.set_irq_line(irq, false);
+    }
+
+    /// PICs programmed as by a PC BIOS: vectors 0x20 and 0x28, cascaded on
+    /// IR2, with all lines unmasked.
+    fn programmed() -> VirtDualPic {
+        let mut pic = VirtDualPic::new();
+        for (command, data, icw2, icw3) in [(0x20, 0x21, 0x20, 0x04), (0xA0, 0xA1, 0x28, 0x02)] {
+            write(&mut pic, command, 0x11);
+            write(&mut pic, data, icw2);
+            write(&mut pic, data, icw3);
+            write(&mut pic, data, 0x01);
+        }
+        pic
+    }
+
+    #[test]
+    fn initialization_and_masking() {
+        let mut pic = VirtDualPic::new();
+        // Lines are masked until the guest programs the PICs.
+        pulse(&mut pic, 1);
+        assert!(!pic.has_interrupt());
+
+        let mut pic = programmed();
+        assert_eq!(read(&mut pic, 0x21), 0);
+        write(&mut pic, 0x21, 0x02);
+        assert_eq!(read(&mut pic, 0x21), 0x02);
+        pulse(&mut pic, 1);
+        assert!(!pic.has_interrupt());
+        // The request is kept in IRR while the line is masked.
+        write(&mut pic, 0x21, 0);
+        assert_eq!(pic.acknowledge(), Some(0x21));
+        assert_eq!(pic.acknowledge(), None);
+    }
+
+    #[test]
+    fn priority_and_eoi() {
+        let mut pic = programmed();
+        pulse(&mut pic, 3);
+        pulse(&mut pic, 1);
+        assert_eq!(read(&mut pic, 0x20), 0x0a);
+        assert_eq!(pic.acknowledge(), Some(0x21));
+        // IRQ 3 waits for the end of IRQ 1, IRQ 0 preempts it.
+        assert!(!pic.has_interrupt());
+        pulse(&mut pic, 0);
+        assert_eq!(pic.acknowledge(), Some(0x20));
+        // OCW3 selects the ISR for reads of the command port.
+        write(&mut pic, 0x20, 0x0b);
+        assert_eq!(read(&mut pic, 0x20), 0x03);
+
+        // Non-specific EOI ends the highest priority interrupt in service.
+        write(&mut pic, 0x20, 0x20);
+        assert_eq!(read(&mut pic, 0x20), 0x02);
+        assert!(!pic.has_interrupt());
+        // Specific EOI for IRQ 1.
+        write(&mut pic, 0x20, 0x61);
+        assert_eq!(pic.acknowledge(), Some(0x23));
+    }
+
+    #[test]
+    fn slave_interrupts_cascade() {
+        let mut pic = programmed();
+        pulse(&mut pic, 10);
+        assert!(pic.has_interrupt());
+        assert_eq!(pic.acknowledge(), Some(0x2a));
+        write(&mut pic, 0x20, 0x0b);
+        write(&mut pic, 0xA0, 0x0b);
+        assert_eq!(read(&mut pic, 0x20), 1 << CASCADE_IRQ);
+        assert_eq!(read(&mut pic, 0xA0), 1 << 2);
+
+        // The slave line waits until both PICs receive their EOI.
+        pulse(&mut pic, 9);
+        write(&mut pic, 0xA0, 0x20);
+        assert!(!pic.has_interrupt());
+        write(&mut pic, 0x20, 0x20);
+        assert_eq!(pic.acknowledge(), Some(0x29));
+    }
+
+    #[test]
+    fn rotation_and_poll() {
+        let mut pic = programmed();
+        // Make IRQ 3 the lowest priority, so IRQ 5 comes before IRQ 1.
+        write(&mut pic, 0x20, 0xc3);
+        pulse(&mut pic, 1);
+        pulse(&mut pic, 5);
+        // A poll command acknowledges the request.
+        write(&mut pic, 0x20, 0x0c);
+        assert_eq!(read(&mut pic, 0x20), 0x85);
+        // Rotate on non-specific EOI: IRQ 5 becomes the lowest priority.
+        write(&mut pic, 0x20, 0xa0);
+        pulse(&mut pic, 4);
+        assert_eq!(pic.acknowledge(), Some(0x21));
+    }
+
+    #[test]
+    fn level_triggered_lines_stay_pending() {
+        let mut pic = programmed();
+        // IRQ 0-2 cannot be level triggered.
+        write(&mut pic, 0x4D0, 0xff);
+        assert_eq!(read(&mut pic, 0x4D0), 0xf8);
+        write(&mut pic, 0x4D0, 0x08);
+
+        pic.set_irq_line(3, true);
+        assert_eq!(pic.acknowledge(), Some(0x23));
+        write(&mut pic, 0x20, 0x20);
+        assert_eq!(pic.acknowledge(), Some(0x23));
+        pic.set_irq_line(3, false);
+        write(&mut pic, 0x20, 0x20);
+        assert!(!pic.has_interrupt());
+    }
+}
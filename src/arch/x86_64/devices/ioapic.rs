@@ -0,0 +1,315 @@
+use bit_field::BitField;
+
+use crate::GuestPhysAddr;
+
+/// Guest physical base address of the IOAPIC MMIO window.
+pub const IOAPIC_BASE: GuestPhysAddr = 0xFEC0_0000;
+/// Size of the IOAPIC MMIO window.
+pub const IOAPIC_SIZE: usize = 0x1000;
+/// Number of interrupt input pins (and redirection table entries).
+pub const IOAPIC_NUM_PINS: usize = 24;
+
+const IOAPIC_VERSION: u32 = 0x11;
+
+// MMIO register offsets.
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+
+// Indirect register indices.
+const IOAPICID: u32 = 0x00;
+const IOAPICVER: u32 = 0x01;
+const IOAPICARB: u32 = 0x02;
+const IOREDTBL_BASE: u32 = 0x10;
+
+/// A redirection table entry. (82093AA IOAPIC datasheet, Section 3.2.4)
+#[derive(Debug, Clone, Copy)]
+struct RedirectionEntry(u64);
+
+impl_snapshot_fields!(RedirectionEntry { 0 });
+
+impl RedirectionEntry {
+    const fn new() -> Self {
+        Self(1 << 16) // masked
+    }
+
+    fn vector(&self) -> u8 {
+        self.0.get_bits(0..8) as u8
+    }
+
+    fn is_active_low(&self) -> bool {
+        self.0.get_bit(13)
+    }
+
+    fn remote_irr(&self) -> bool {
+        self.0.get_bit(14)
+    }
+
+    fn set_remote_irr(&mut self, value: bool) {
+        self.0.set_bit(14, value);
+    }
+
+    fn is_level_triggered(&self) -> bool {
+        self.0.get_bit(15)
+    }
+
+    fn is_masked(&self) -> bool {
+        self.0.get_bit(16)
+    }
+
+    /// Writes the low or high dword from the guest, preserving read-only bits
+    /// (delivery status and remote IRR).
+    fn write_dword(&mut self, high: bool, value: u32) {
+        if high {
+            self.0.set_bits(32..64, value as u64);
+        } else {
+            let ro_mask = (1 << 12) | (1 << 14);
+            let low = (self.0 & ro_mask) | (value as u64 & !ro_mask);
+            self.0.set_bits(0..32, low.get_bits(0..32));
+        }
+    }
+
+    fn read_dword(&self, high: bool) -> u32 {
+        if high {
+            self.0.get_bits(32..64) as u32
+        } else {
+            self.0.get_bits(0..32) as u32
+        }
+    }
+}
+
+/// A virtual I/O APIC. (82093AA IOAPIC datasheet)
+///
+/// Interrupts raised on the input pins are translated through the redirection
+/// table into vectors, which are queued by the caller into the virtual local APIC.
+pub struct VirtIoApic {
+    id: u32,
+    ioregsel: u32,
+    /// Current (active-high normalized) level of each pin.
+    pin_level: u32,
+    redtbl: [RedirectionEntry; IOAPIC_NUM_PINS],
+}
+
+impl_snapshot_fields!(VirtIoApic {
+    id,
+    ioregsel,
+    pin_level,
+    redtbl,
+});
+
+impl VirtIoApic {
+    /// Create a virtual IOAPIC with all pins masked.
+    pub const fn new() -> Self {
+        Self {
+            id: 0,
+            ioregsel: 0,
+            pin_level: 0,
+            redtbl: [RedirectionEntry::new(); IOAPIC_NUM_PINS],
+        }
+    }
+
+    /// Set the level of the input `pin`. Returns the vector to be delivered to
+    /// the local APIC, if any.
+    pub fn set_irq_line(&mut self, pin: usize, level: bool) -> Option<u8> {
+        if pin >= IOAPIC_NUM_PINS {
+            warn!("IOAPIC: invalid pin {}", pin);
+            return None;
+        }
+        let entry = self.redtbl[pin];
+        let asserted = level != entry.is_active_low();
+        let old = self.pin_level.get_bit(pin);
+        self.pin_level.set_bit(pin, asserted);
+
+        if entry.is_level_triggered() {
+            if asserted {
+                self.service_pin(pin)
+            } else {
+                None
+            }
+        } else if asserted && !old {
+            // Rising edge.
+            self.service_pin(pin)
+        } else {
+            None
+        }
+    }
+
+    /// Handle an end-of-interrupt broadcast from the local APIC for `vector`.
+    /// Level-triggered pins that are still asserted are re-delivered; the
+    /// resulting vectors are pushed into `delivered`.
+    pub fn end_of_interrupt(&mut self, vector: u8, mut delivered: impl FnMut(u8)) {
+        for pin in 0..IOAPIC_NUM_PINS {
+            let entry = &mut self.redtbl[pin];
+            if entry.vector() == vector && entry.is_level_triggered() && entry.remote_irr() {
+                entry.set_remote_irr(false);
+                if self.pin_level.get_bit(pin) {
+                    if let Some(vector) = self.service_pin(pin) {
+                        delivered(vector);
+                    }
+                }
+            }
+        }
+    }
+
+    fn service_pin(&mut self, pin: usize) -> Option<u8> {
+        let entry = &mut self.redtbl[pin];
+        if entry.is_masked() {
+            return None;
+        }
+        if entry.is_level_triggered() {
+            if entry.remote_irr() {
+                return None;
+            }
+            entry.set_remote_irr(true);
+        }
+        trace!("IOAPIC: pin {} -> vector {:#x}", pin, entry.vector());
+        Some(entry.vector())
+    }
+
+    fn read_indirect(&self) -> u32 {
+        match self.ioregsel {
+            IOAPICID | IOAPICARB => self.id << 24,
+            IOAPICVER => ((IOAPIC_NUM_PINS as u32 - 1) << 16) | IOAPIC_VERSION,
+            sel @ IOREDTBL_BASE.. => {
+                let index = ((sel - IOREDTBL_BASE) / 2) as usize;
+                match self.redtbl.get(index) {
+                    Some(entry) => entry.read_dword(sel & 1 != 0),
+                    None => 0,
+                }
+            }
+            _ => 0,
+        }
+    }
+
+    /// Writes an indirect register. Returns the pin whose redirection entry
+    /// was changed, so that a still-asserted level can be re-evaluated.
+    fn write_indirect(&mut self, value: u32) -> Option<usize> {
+        match self.ioregsel {
+            IOAPICID => {
+                self.id = value.get_bits(24..28);
+                None
+            }
+            sel @ IOREDTBL_BASE.. => {
+                let index = ((sel - IOREDTBL_BASE) / 2) as usize;
+                let entry = self.redtbl.get_mut(index)?;
+                entry.write_dword(sel & 1 != 0, value);
+                Some(index)
+            }
+            _ => None,
+        }
+    }
+
+    /// Handles a guest store to the MMIO window, returning a vector to deliver
+    /// if unmasking a pin made a pending level interrupt deliverable.
+    pub fn mmio_write(&mut self, offset: usize, value: u32) -> Option<u8> {
+        match offset {
+            IOREGSEL => {
+                self.ioregsel = value & 0xff;
+                None
+            }
+            IOWIN => {
+                let pin = self.write_indirect(value)?;
+                if self.redtbl[pin].is_level_triggered() && self.pin_level.get_bit(pin) {
+                    self.service_pin(pin)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Handles a guest load from the MMIO window.
+    pub fn mmio_read(&self, offset: usize) -> u32 {
+        match offset {
+            IOREGSEL => self.ioregsel,
+            IOWIN => self.read_indirect(),
+            _ => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    fn read_reg(ioapic: &mut VirtIoApic, index: u32) -> u32 {
+        ioapic.mmio_write(IOREGSEL, index);
+        ioapic.mmio_read(IOWIN)
+    }
+
+    fn write_reg(ioapic: &mut VirtIoApic, index: u32, value: u32) -> Option<u8> {
+        ioapic.mmio_write(IOREGSEL, index);
+        ioapic.mmio_write(IOWIN, value)
+    }
+
+    /// Route `pin` to `vector`, with the given flags of the low dword.
+    fn route(ioapic: &mut VirtIoApic, pin: u32, vector: u8, flags: u32) -> Option<u8> {
+        write_reg(ioapic, IOREDTBL_BASE + pin * 2, flags | vector as u32)
+    }
+
+    const LEVEL: u32 = 1 << 15;
+    const ACTIVE_LOW: u32 = 1 << 13;
+    const MASKED: u32 = 1 << 16;
+
+    #[test]
+    fn registers() {
+        let mut ioapic = VirtIoApic::new();
+        assert_eq!(read_reg(&mut ioapic, IOAPICVER), 0x17_0011);
+        write_reg(&mut ioapic, IOAPICID, 0x0f00_00ff);
+        assert_eq!(read_reg(&mut ioapic, IOAPICID), 0x0f00_0000);
+        assert_eq!(ioapic.mmio_read(IOREGSEL), IOAPICID);
+
+        // Entries start masked.
+        assert_eq!(read_reg(&mut ioapic, IOREDTBL_BASE + 2 * 5), MASKED);
+        route(&mut ioapic, 5, 0x35, LEVEL);
+        write_reg(&mut ioapic, IOREDTBL_BASE + 2 * 5 + 1, 0x0300_0000);
+        assert_eq!(read_reg(&mut ioapic, IOREDTBL_BASE + 2 * 5), LEVEL | 0x35);
+        assert_eq!(
+            read_reg(&mut ioapic, IOREDTBL_BASE + 2 * 5 + 1),
+            0x0300_0000
+        );
+        // Pins past the table read as zero.
+        assert_eq!(read_reg(&mut ioapic, IOREDTBL_BASE + 2 * 24), 0);
+    }
+
+    #[test]
+    fn edge_triggered_pins() {
+        let mut ioapic = VirtIoApic::new();
+        assert_eq!(ioapic.set_irq_line(4, true), None);
+        ioapic.set_irq_line(4, false);
+        route(&mut ioapic, 4, 0x34, 0);
+        assert_eq!(ioapic.set_irq_line(4, true), Some(0x34));
+        assert_eq!(ioapic.set_irq_line(4, true), None);
+        ioapic.set_irq_line(4, false);
+        assert_eq!(ioapic.set_irq_line(4, true), Some(0x34));
+    }
+
+    #[test]
+    fn level_triggered_pins_wait_for_eoi() {
+        let mut ioapic = VirtIoApic::new();
+        route(&mut ioapic, 9, 0x39, LEVEL | ACTIVE_LOW | MASKED);
+        // Active low: the line is asserted at level 0.
+        assert_eq!(ioapic.set_irq_line(9, false), None);
+        // Unmasking the asserted pin delivers it.
+        assert_eq!(route(&mut ioapic, 9, 0x39, LEVEL | ACTIVE_LOW), Some(0x39));
+        assert_eq!(ioapic.set_irq_line(9, false), None);
+
+        // Remote IRR is set until the EOI, and cannot be written.
+        let low = read_reg(&mut ioapic, IOREDTBL_BASE + 2 * 9);
+        assert_ne!(low & 1 << 14, 0);
+        route(&mut ioapic, 9, 0x39, LEVEL | ACTIVE_LOW);
+        assert_eq!(read_reg(&mut ioapic, IOREDTBL_BASE + 2 * 9), low);
+
+        // The EOI delivers the pin again while it is asserted.
+        let mut delivered = Vec::new();
+        ioapic.end_of_interrupt(0x39, |vector| delivered.push(vector));
+        assert_eq!(delivered, [0x39]);
+        ioapic.set_irq_line(9, true);
+        delivered.clear();
+        ioapic.end_of_interrupt(0x39, |vector| delivered.push(vector));
+        assert!(delivered.is_empty());
+        assert_eq!(read_reg(&mut ioapic, IOREDTBL_BASE + 2 * 9) & 1 << 14, 0);
+    }
+}
@@ -0,0 +1,502 @@
+mod i8254_pit;
+mod i8259_pic;
+mod ioapic;
+mod pm_timer;
+mod rtc;
+
+pub use i8254_pit::{VirtPit, PIT_PORTS, PIT_SPEAKER_PORT};
+pub use i8259_pic::{VirtDualPic, PIC_ELCR_PORTS, PIC_MASTER_PORTS, PIC_SLAVE_PORTS};
+pub use ioapic::{VirtIoApic, IOAPIC_BASE, IOAPIC_NUM_PINS, IOAPIC_SIZE};
+pub use pm_timer::{AcpiPmTimer, PM_TIMER_DEFAULT_PORT};
+pub use rtc::{VirtRtc, RTC_IRQ, RTC_PORTS};
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use crate::devices::{
+    CharBackend, MmioDevice, PortIoDevice, Uart16550, UartAttachment, VirtioMmio, UART_COM1_IRQ,
+    UART_COM1_PORT,
+};
+use crate::snapshot::{Snapshot, SnapshotReader, SnapshotWriter};
+use crate::{GuestMemory, GuestPhysAddr, HyperCraftHal, HyperError, HyperResult};
+
+/// Number of legacy ISA interrupt lines routed through the 8259 PICs.
+const ISA_NUM_IRQS: usize = 16;
+/// The ISA IRQ line of PIT channel 0.
+const PIT_IRQ: usize = 0;
+
+/// The guest's interrupt routing: a dual 8259 PIC and an IOAPIC, both
+/// delivering into the virtual local APIC of the vCPU.
+///
+/// Device models raise interrupts with [`VirtIrqChip::set_irq_line`]. Before
+/// each VM entry where the guest accepts interrupts, the vCPU acknowledges the
+/// highest priority interrupt the local APIC may deliver with
+/// [`VirtIrqChip::pop_pending_vector`] and injects it. The guest ends it by
+/// writing the x2APIC EOI register, which the vCPU forwards to
+/// [`VirtIrqChip::end_of_interrupt`].
+pub struct VirtIrqChip {
+    pic: VirtDualPic,
+    ioapic: VirtIoApic,
+    /// Vectors accepted by the virtual local APIC but not yet injected.
+    lapic_irr: [u64; 4],
+    /// Vectors injected whose end-of-interrupt the guest has not signalled.
+    lapic_isr: [u64; 4],
+    /// The task priority register of the virtual local APIC.
+    lapic_tpr: u8,
+}
+
+impl_snapshot_fields!(VirtIrqChip {
+    pic,
+    ioapic,
+    lapic_irr,
+    lapic_isr,
+    lapic_tpr,
+});
+
+impl VirtIrqChip {
+    pub(crate) const fn new() -> Self {
+        Self {
+            pic: VirtDualPic::new(),
+            ioapic: VirtIoApic::new(),
+            lapic_irr: [0; 4],
+            lapic_isr: [0; 4],
+            lapic_tpr: 0,
+        }
+    }
+
+    /// Set the level of the global system interrupt `gsi`.
+    ///
+    /// GSIs 0-15 are the ISA IRQs and are routed to both the PICs and the
+    /// IOAPIC. As described by the default MADT interrupt source override,
+    /// ISA IRQ 0 is wired to IOAPIC pin 2.
+    pub fn set_irq_line(&mut self, gsi: usize, level: bool) {
+        if gsi < ISA_NUM_IRQS {
+            self.pic.set_irq_line(gsi, level);
+        }
+        let pin = match gsi {
+            0 => 2,
+            // IOAPIC pin 2 belongs to ISA IRQ 0, the cascade line has no pin.
+            2 => return,
+            _ => gsi,
+        };
+        if let Some(vector) = self.ioapic.set_irq_line(pin, level) {
+            self.accept_vector(vector);
+        }
+    }
+
+    /// Signal an end-of-interrupt, as written by the guest to the local APIC
+    /// EOI register: the highest priority vector in service ends, and the
+    /// level-triggered IOAPIC pins which raised it may fire again.
+    pub fn end_of_interrupt(&mut self) {
+        let Some(vector) = highest_vector(&self.lapic_isr) else {
+            return;
+        };
+        clear_vector(&mut self.lapic_isr, vector);
+        let mut redelivered = [0u8; IOAPIC_NUM_PINS];
+        let mut count = 0;
+        self.ioapic.end_of_interrupt(vector, |v| {
+            redelivered[count] = v;
+            count += 1;
+        });
+        for &v in &redelivered[..count] {
+            self.accept_vector(v);
+        }
+    }
+
+    /// The task priority register of the virtual local APIC.
+    pub fn task_priority(&self) -> u8 {
+        self.lapic_tpr
+    }
+
+    /// Set the task priority register of the virtual local APIC. Interrupts
+    /// whose priority class is not above it are held.
+    pub fn set_task_priority(&mut self, tpr: u8) {
+        self.lapic_tpr = tpr;
+    }
+
+    /// The processor priority: the task priority, or the priority class of
+    /// the highest vector in service if higher. (SDM Vol. 3A, Section 10.8.3.1)
+    fn processor_priority(&self) -> u8 {
+        let isrv = highest_vector(&self.lapic_isr).unwrap_or(0);
+        if self.lapic_tpr >> 4 >= isrv >> 4 {
+            self.lapic_tpr
+        } else {
+            isrv & 0xf0
+        }
+    }
+
+    /// The highest pending vector of the local APIC, if the processor
+    /// priority lets it be delivered.
+    fn deliverable_vector(&self) -> Option<u8> {
+        highest_vector(&self.lapic_irr).filter(|v| v >> 4 > self.processor_priority() >> 4)
+    }
+
+    /// Whether there is an interrupt the guest may be interrupted by.
+    pub fn has_pending_interrupt(&self) -> bool {
+        self.deliverable_vector().is_some() || self.pic.has_interrupt()
+    }
+
+    /// Acknowledge the interrupt to inject next, and return its vector. The
+    /// highest pending vector of the local APIC moves in service if the
+    /// processor priority allows it; otherwise the ExtINT output of the
+    /// master PIC, which is not subject to it, is acknowledged.
+    pub fn pop_pending_vector(&mut self) -> Option<u8> {
+        if let Some(vector) = self.deliverable_vector() {
+            clear_vector(&mut self.lapic_irr, vector);
+            self.lapic_isr[vector as usize / 64] |= 1 << (vector as usize % 64);
+            return Some(vector);
+        }
+        self.pic.acknowledge()
+    }
+
+    /// Make `vector` pending in the virtual local APIC, e.g. for its timer.
+    pub(crate) fn accept_vector(&mut self, vector: u8) {
+        if vector < 16 {
+            // Vectors 0-15 are reserved and would be rejected by a real local APIC.
+            warn!("IrqChip: illegal vector {:#x} ignored", vector);
+            return;
+        }
+        self.lapic_irr[vector as usize / 64] |= 1 << (vector as usize % 64);
+    }
+
+    /// Whether `port` belongs to one of the PICs.
+    pub fn claims_port(&self, port: u16) -> bool {
+        PIC_MASTER_PORTS.contains(&port)
+            || PIC_SLAVE_PORTS.contains(&port)
+            || PIC_ELCR_PORTS.contains(&port)
+    }
+
+    /// Returns the mutable reference of the [`VirtDualPic`].
+    pub fn pic_mut(&mut self) -> &mut VirtDualPic {
+        &mut self.pic
+    }
+
+    /// Returns the mutable reference of the [`VirtIoApic`].
+    pub fn ioapic_mut(&mut self) -> &mut VirtIoApic {
+        &mut self.ioapic
+    }
+}
+
+impl PortIoDevice for VirtIrqChip {
+    fn port_range(&self) -> Range<u16> {
+        PIC_MASTER_PORTS
+    }
+
+    fn read(&mut self, port: u16, access_size: u8) -> HyperResult<u32> {
+        self.pic.read(port, access_size)
+    }
+
+    fn write(&mut self, port: u16, access_size: u8, value: u32) -> HyperResult {
+        self.pic.write(port, access_size, value)
+    }
+}
+
+impl MmioDevice for VirtIrqChip {
+    fn mmio_range(&self) -> Range<GuestPhysAddr> {
+        IOAPIC_BASE..IOAPIC_BASE + IOAPIC_SIZE
+    }
+
+    fn read(&mut self, addr: GuestPhysAddr, width: usize) -> HyperResult<u64> {
+        if width != 4 {
+            return Err(HyperError::InvalidParam);
+        }
+        Ok(self.ioapic.mmio_read(addr - IOAPIC_BASE) as u64)
+    }
+
+    fn write(&mut self, addr: GuestPhysAddr, width: usize, value: u64) -> HyperResult {
+        if width != 4 {
+            return Err(HyperError::InvalidParam);
+        }
+        if let Some(vector) = self.ioapic.mmio_write(addr - IOAPIC_BASE, value as u32) {
+            self.accept_vector(vector);
+        }
+        Ok(())
+    }
+}
+
+fn highest_vector(bits: &[u64; 4]) -> Option<u8> {
+    bits.iter()
+        .enumerate()
+        .rev()
+        .find(|(_, word)| **word != 0)
+        .map(|(i, word)| (i * 64 + 63 - word.leading_zeros() as usize) as u8)
+}
+
+fn clear_vector(bits: &mut [u64; 4], vector: u8) {
+    bits[vector as usize / 64] &= !(1 << (vector as usize % 64));
+}
+
+/// The legacy platform devices emulated in the crate: the interrupt
+/// controllers, the 8254 PIT, the RTC/CMOS, the ACPI PM timer, optionally
+/// a 16550 UART as COM1, and the virtio-mmio devices.
+pub struct VirtPlatform<H: HyperCraftHal> {
+    irq_chip: VirtIrqChip,
+    pit: VirtPit<H>,
+    rtc: VirtRtc<H>,
+    pm_timer: AcpiPmTimer<H>,
+    serial: Option<Uart16550>,
+    /// The virtio-mmio devices and their GSIs.
+    virtio: Vec<(VirtioMmio, usize)>,
+}
+
+/// The devices are attached by the host, so the snapshot must be restored
+/// on a platform with the same devices. Otherwise it is `InvalidParam`.
+impl<H: HyperCraftHal> Snapshot for VirtPlatform<H> {
+    fn save(&self, w: &mut SnapshotWriter) {
+        self.irq_chip.save(w);
+        self.pit.save(w);
+        self.rtc.save(w);
+        self.pm_timer.save(w);
+        self.serial.is_some().save(w);
+        if let Some(serial) = &self.serial {
+            serial.save(w);
+        }
+        self.virtio.len().save(w);
+        self.virtio.iter().for_each(|(virtio, _)| virtio.save(w));
+    }
+
+    fn restore(&mut self, r: &mut SnapshotReader<'_>) -> HyperResult {
+        self.irq_chip.restore(r)?;
+        self.pit.restore(r)?;
+        self.rtc.restore(r)?;
+        self.pm_timer.restore(r)?;
+        if r.read::<bool>()? != self.serial.is_some() {
+            return Err(HyperError::InvalidParam);
+        }
+        if let Some(serial) = &mut self.serial {
+            serial.restore(r)?;
+        }
+        if r.read::<usize>()? != self.virtio.len() {
+            return Err(HyperError::InvalidParam);
+        }
+        for (virtio, _) in &mut self.virtio {
+            virtio.restore(r)?;
+        }
+        Ok(())
+    }
+}
+
+impl<H: HyperCraftHal> VirtPlatform<H> {
+    pub(crate) const fn new() -> Self {
+        Self {
+            irq_chip: VirtIrqChip::new(),
+            pit: VirtPit::new(),
+            rtc: VirtRtc::new(),
+            pm_timer: AcpiPmTimer::new(PM_TIMER_DEFAULT_PORT),
+            serial: None,
+            virtio: Vec::new(),
+        }
+    }
+
+    /// Returns the device handling I/O port `port`, if any.
+    pub(crate) fn port_device_mut(&mut self, port: u16) -> Option<&mut dyn PortIoDevice> {
+        if self.irq_chip.claims_port(port) {
+            Some(&mut self.irq_chip)
+        } else if PIT_PORTS.contains(&port) || port == PIT_SPEAKER_PORT {
+            Some(&mut self.pit)
+        } else if RTC_PORTS.contains(&port) {
+            Some(&mut self.rtc)
+        } else if self.pm_timer.port_range().contains(&port) {
+            Some(&mut self.pm_timer)
+        } else if let Some(serial) = self
+            .serial
+            .as_mut()
+            .filter(|serial| serial.port_range().contains(&port))
+        {
+            Some(serial)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the device handling guest physical address `addr`, if any.
+    pub(crate) fn mmio_device_mut(&mut self, addr: GuestPhysAddr) -> Option<&mut dyn MmioDevice> {
+        if self.irq_chip.mmio_range().contains(&addr) {
+            Some(&mut self.irq_chip)
+        } else if let Some((virtio, _)) = self
+            .virtio
+            .iter_mut()
+            .find(|(virtio, _)| virtio.mmio_range().contains(&addr))
+        {
+            Some(virtio)
+        } else {
+            None
+        }
+    }
+
+    /// Update the interrupt lines of the devices. Called on every VM exit.
+    ///
+    /// Timer interrupts which have expired since the last check are edge
+    /// triggered, so the line is pulsed.
+    pub(crate) fn poll_devices(&mut self) {
+        if self.pit.check_interrupt() {
+            self.irq_chip.set_irq_line(PIT_IRQ, true);
+            self.irq_chip.set_irq_line(PIT_IRQ, false);
+        }
+        if self.rtc.check_interrupt() {
+            self.irq_chip.set_irq_line(RTC_IRQ, true);
+            self.irq_chip.set_irq_line(RTC_IRQ, false);
+        }
+        if let Some(serial) = self.serial.as_mut() {
+            serial.poll();
+            self.irq_chip.set_irq_line(UART_COM1_IRQ, serial.irq_level());
+        }
+        for (virtio, gsi) in &mut self.virtio {
+            virtio.poll();
+            self.irq_chip.set_irq_line(*gsi, virtio.irq_level());
+        }
+    }
+
+    /// Attach a 16550 UART as COM1 (port 0x3F8, IRQ 4), connected to `backend`.
+    pub fn attach_serial(&mut self, backend: Box<dyn CharBackend>) {
+        self.serial = Some(Uart16550::new(UartAttachment::PortIo(UART_COM1_PORT), backend));
+    }
+
+    /// Attach a virtio-mmio device raising global system interrupt `gsi`. Its
+    /// range must not be mapped in the EPT.
+    pub fn attach_virtio_mmio(&mut self, device: VirtioMmio, gsi: usize) {
+        self.virtio.push((device, gsi));
+    }
+
+    /// Make the virtio-mmio devices reach the guest RAM through `memory`.
+    pub(crate) fn set_virtio_memory(&mut self, memory: &GuestMemory) {
+        for (virtio, _) in &mut self.virtio {
+            virtio.set_memory(memory.clone());
+        }
+    }
+
+    /// Returns the mutable reference of the COM1 UART, if attached.
+    pub fn serial_mut(&mut self) -> Option<&mut Uart16550> {
+        self.serial.as_mut()
+    }
+
+    /// Returns the mutable reference of the [`VirtIrqChip`].
+    pub fn irq_chip_mut(&mut self) -> &mut VirtIrqChip {
+        &mut self.irq_chip
+    }
+
+    /// Returns the mutable reference of the [`VirtPit`].
+    pub fn pit_mut(&mut self) -> &mut VirtPit<H> {
+        &mut self.pit
+    }
+
+    /// Returns the mutable reference of the [`VirtRtc`].
+    pub fn rtc_mut(&mut self) -> &mut VirtRtc<H> {
+        &mut self.rtc
+    }
+
+    /// Move the ACPI PM timer to `port`, as advertised in the guest's FADT.
+    pub fn set_pm_timer_port(&mut self, port: u16) {
+        self.pm_timer = AcpiPmTimer::new(port);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Route IOAPIC `pin` to `vector`, with the given flags of the low dword.
+    fn route(chip: &mut VirtIrqChip, pin: u64, vector: u8, flags: u64) {
+        MmioDevice::write(chip, IOAPIC_BASE, 4, 0x10 + pin * 2).unwrap();
+        MmioDevice::write(chip, IOAPIC_BASE + 0x10, 4, flags | vector as u64).unwrap();
+    }
+
+    const LEVEL: u64 = 1 << 15;
+
+    #[test]
+    fn task_priority_holds_lower_classes() {
+        let mut chip = VirtIrqChip::new();
+        chip.accept_vector(0x45);
+        chip.accept_vector(0x62);
+        chip.set_task_priority(0x60);
+        assert!(!chip.has_pending_interrupt());
+        assert_eq!(chip.pop_pending_vector(), None);
+
+        chip.set_task_priority(0x50);
+        assert_eq!(chip.task_priority(), 0x50);
+        assert_eq!(chip.pop_pending_vector(), Some(0x62));
+        // The vector in service raises the processor priority above 0x45.
+        assert!(!chip.has_pending_interrupt());
+        chip.end_of_interrupt();
+        // 0x45 is still held by the task priority.
+        assert_eq!(chip.pop_pending_vector(), None);
+        chip.set_task_priority(0);
+        assert_eq!(chip.pop_pending_vector(), Some(0x45));
+        chip.end_of_interrupt();
+        assert!(!chip.has_pending_interrupt());
+    }
+
+    #[test]
+    fn acknowledge_nests_higher_classes() {
+        let mut chip = VirtIrqChip::new();
+        chip.accept_vector(0x30);
+        chip.accept_vector(0x80);
+        assert_eq!(chip.pop_pending_vector(), Some(0x80));
+        assert_eq!(chip.lapic_irr, [1 << 0x30, 0, 0, 0]);
+        assert_eq!(chip.lapic_isr, [0, 0, 1, 0]);
+
+        // The same priority class waits, a higher one nests.
+        chip.accept_vector(0x81);
+        assert_eq!(chip.pop_pending_vector(), None);
+        chip.accept_vector(0x90);
+        assert_eq!(chip.pop_pending_vector(), Some(0x90));
+
+        // Each EOI ends the highest vector in service.
+        chip.end_of_interrupt();
+        assert_eq!(chip.lapic_isr, [0, 0, 1, 0]);
+        assert_eq!(chip.pop_pending_vector(), None);
+        chip.end_of_interrupt();
+        assert_eq!(chip.pop_pending_vector(), Some(0x81));
+        chip.end_of_interrupt();
+        assert_eq!(chip.pop_pending_vector(), Some(0x30));
+        chip.end_of_interrupt();
+        assert_eq!(chip.lapic_isr, [0; 4]);
+        // Reserved vectors are ignored.
+        chip.accept_vector(0x0f);
+        assert!(!chip.has_pending_interrupt());
+    }
+
+    #[test]
+    fn level_triggered_gsi_is_redelivered_on_eoi() {
+        let mut chip = VirtIrqChip::new();
+        route(&mut chip, 20, 0x50, LEVEL);
+        chip.set_irq_line(20, true);
+        assert_eq!(chip.pop_pending_vector(), Some(0x50));
+        // Still asserted at the EOI: the pin fires again.
+        chip.end_of_interrupt();
+        assert_eq!(chip.pop_pending_vector(), Some(0x50));
+        chip.set_irq_line(20, false);
+        chip.end_of_interrupt();
+        assert!(!chip.has_pending_interrupt());
+    }
+
+    #[test]
+    fn isa_irqs_reach_the_ioapic_and_the_pic() {
+        let mut chip = VirtIrqChip::new();
+        // ISA IRQ 0 is IOAPIC pin 2, and the cascade line has no pin.
+        route(&mut chip, 2, 0x22, 0);
+        chip.set_irq_line(2, true);
+        assert!(!chip.has_pending_interrupt());
+        chip.set_irq_line(0, true);
+        assert_eq!(chip.pop_pending_vector(), Some(0x22));
+        chip.end_of_interrupt();
+
+        // Program the master PIC at vector 0x20 and unmask IRQ 1 only.
+        for (port, value) in [
+            (0x20, 0x11),
+            (0x21, 0x20),
+            (0x21, 0x04),
+            (0x21, 0x01),
+            (0x21, 0xfd),
+        ] {
+            PortIoDevice::write(&mut chip, port, 1, value).unwrap();
+        }
+        // The ExtINT output is not subject to the task priority.
+        chip.set_task_priority(0xff);
+        chip.set_irq_line(1, true);
+        assert!(chip.has_pending_interrupt());
+        assert_eq!(chip.pop_pending_vector(), Some(0x21));
+        assert!(!chip.has_pending_interrupt());
+    }
+}
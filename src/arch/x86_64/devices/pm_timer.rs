@@ -0,0 +1,109 @@
+use core::marker::PhantomData;
+use core::ops::Range;
+
+use crate::devices::PortIoDevice;
+use crate::snapshot::{Snapshot, SnapshotReader, SnapshotWriter};
+use crate::{HyperCraftHal, HyperResult};
+
+/// Default PM timer port, `PMBASE + 8` with the ICH9 `PMBASE` of 0x600.
+///
+/// The address must match the `PM_TMR_BLK` field of the FADT provided to
+/// the guest.
+pub const PM_TIMER_DEFAULT_PORT: u16 = 0x608;
+
+/// Frequency of the ACPI power management timer in Hz.
+const PM_TIMER_FREQ_HZ: u64 = 3_579_545;
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// A virtual ACPI power management timer. (ACPI Spec, Section 4.8.3.3)
+///
+/// The timer is a free-running 24-bit counter at 3.579545 MHz, which is
+/// read with a 32-bit `IN` from its port. Overflow interrupts (`TMR_STS`)
+/// are not generated.
+pub struct AcpiPmTimer<H: HyperCraftHal> {
+    port: u16,
+    /// Added to the host time to get the time the counter runs from.
+    offset_ns: u64,
+    _phantom: PhantomData<H>,
+}
+
+impl<H: HyperCraftHal> AcpiPmTimer<H> {
+    /// Create a PM timer at `port`.
+    pub(crate) const fn new(port: u16) -> Self {
+        Self {
+            port,
+            offset_ns: 0,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// The current value of the counter.
+    pub fn counter(&self) -> u32 {
+        let ticks = self.time_nanos() as u128 * PM_TIMER_FREQ_HZ as u128 / NANOS_PER_SEC as u128;
+        (ticks as u32) & 0xff_ffff
+    }
+
+    fn time_nanos(&self) -> u64 {
+        H::current_time_nanos().wrapping_add(self.offset_ns)
+    }
+}
+
+/// The port is set up by the host as advertised in the FADT, only the time
+/// of the counter is saved, so that the restored one goes on from the saved
+/// value.
+impl<H: HyperCraftHal> Snapshot for AcpiPmTimer<H> {
+    fn save(&self, w: &mut SnapshotWriter) {
+        self.time_nanos().save(w);
+    }
+
+    fn restore(&mut self, r: &mut SnapshotReader<'_>) -> HyperResult {
+        self.offset_ns = r.read::<u64>()?.wrapping_sub(H::current_time_nanos());
+        Ok(())
+    }
+}
+
+impl<H: HyperCraftHal> PortIoDevice for AcpiPmTimer<H> {
+    fn port_range(&self) -> Range<u16> {
+        self.port..self.port + 4
+    }
+
+    fn read(&mut self, port: u16, _access_size: u8) -> HyperResult<u32> {
+        // The register is 32-bit; narrower or unaligned reads get the bytes
+        // at the port, which the caller truncates to the access size.
+        let shift = (port - self.port) as u32 * 8;
+        Ok(self.counter() >> shift)
+    }
+
+    fn write(&mut self, _port: u16, _access_size: u8, _value: u32) -> HyperResult {
+        // The timer is read-only, writes are ignored.
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::tests::TestHal;
+
+    #[test]
+    fn restored_counter_goes_on_from_saved_time() {
+        let mut timer = AcpiPmTimer::<TestHal>::new(PM_TIMER_DEFAULT_PORT);
+        assert_eq!(timer.read(PM_TIMER_DEFAULT_PORT, 4), Ok(0));
+
+        let mut w = SnapshotWriter::new();
+        // Ten seconds, which wraps the 24-bit counter twice.
+        (10 * NANOS_PER_SEC).save(&mut w);
+        let data = w.finish();
+        let mut r = SnapshotReader::new(&data).unwrap();
+        timer.restore(&mut r).unwrap();
+        r.finish().unwrap();
+
+        let counter = (10 * PM_TIMER_FREQ_HZ) as u32 & 0xff_ffff;
+        assert_eq!(timer.counter(), counter);
+        assert_eq!(timer.read(PM_TIMER_DEFAULT_PORT, 4), Ok(counter));
+        assert_eq!(timer.read(PM_TIMER_DEFAULT_PORT + 2, 1), Ok(counter >> 16));
+        // Writes are ignored.
+        timer.write(PM_TIMER_DEFAULT_PORT, 4, 0).unwrap();
+        assert_eq!(timer.counter(), counter);
+    }
+}
@@ -0,0 +1,538 @@
+use bit_field::BitField;
+use core::marker::PhantomData;
+use core::ops::Range;
+
+use crate::devices::PortIoDevice;
+use crate::snapshot::{Snapshot, SnapshotReader, SnapshotWriter};
+use crate::{HyperCraftHal, HyperError, HyperResult};
+
+/// Index and data ports of the RTC/CMOS.
+pub const RTC_PORTS: Range<u16> = 0x70..0x72;
+/// The ISA IRQ line of the RTC.
+pub const RTC_IRQ: usize = 8;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+const CMOS_SIZE: usize = 128;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_SECONDS_ALARM: u8 = 0x01;
+const REG_MINUTES: u8 = 0x02;
+const REG_MINUTES_ALARM: u8 = 0x03;
+const REG_HOURS: u8 = 0x04;
+const REG_HOURS_ALARM: u8 = 0x05;
+const REG_DAY_OF_WEEK: u8 = 0x06;
+const REG_DAY_OF_MONTH: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_A: u8 = 0x0a;
+const REG_B: u8 = 0x0b;
+const REG_C: u8 = 0x0c;
+const REG_D: u8 = 0x0d;
+const REG_CENTURY: u8 = 0x32;
+
+const REG_A_UIP: usize = 7;
+const REG_B_SET: usize = 7;
+const REG_B_PIE: usize = 6;
+const REG_B_AIE: usize = 5;
+const REG_B_UIE: usize = 4;
+const REG_B_DM_BINARY: usize = 2;
+const REG_B_24H: usize = 1;
+const REG_C_IRQF: usize = 7;
+const REG_C_PF: usize = 6;
+const REG_C_AF: usize = 5;
+const REG_C_UF: usize = 4;
+const REG_D_VRT: usize = 7;
+
+/// Duration of the update cycle during which UIP is set, in nanoseconds.
+const UPDATE_CYCLE_NS: u64 = 244_000;
+
+/// Broken-down calendar time.
+#[derive(Debug, Clone, Copy)]
+struct DateTime {
+    year: u32,
+    month: u32,
+    day: u32,
+    hour: u32,
+    minute: u32,
+    second: u32,
+    /// 1 = Sunday, ..., 7 = Saturday.
+    weekday: u32,
+}
+
+impl DateTime {
+    /// Converts seconds since the Unix epoch to calendar time.
+    fn from_unix_secs(secs: u64) -> Self {
+        let days = (secs / 86400) as i64;
+        let rem = secs % 86400;
+        // Days to civil date, see http://howardhinnant.github.io/date_algorithms.html
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z.rem_euclid(146097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        let year = (yoe + era * 400 + (month <= 2) as i64) as u32;
+        Self {
+            year,
+            month,
+            day,
+            hour: (rem / 3600) as u32,
+            minute: (rem / 60 % 60) as u32,
+            second: (rem % 60) as u32,
+            // 1970-01-01 was a Thursday.
+            weekday: ((days + 4).rem_euclid(7) + 1) as u32,
+        }
+    }
+
+    /// Converts calendar time to seconds since the Unix epoch.
+    fn to_unix_secs(self) -> u64 {
+        let y = self.year as i64 - (self.month <= 2) as i64;
+        let m = self.month as i64;
+        let era = y.div_euclid(400);
+        let yoe = y.rem_euclid(400);
+        let doy = (153 * if m > 2 { m - 3 } else { m + 9 } + 2) / 5 + self.day as i64 - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = era * 146097 + doe - 719468;
+        (days.max(0) as u64) * 86400
+            + self.hour as u64 * 3600
+            + self.minute as u64 * 60
+            + self.second as u64
+    }
+}
+
+/// A virtual MC146818 real-time clock with its battery-backed CMOS memory.
+///
+/// The wall clock is kept as an offset to [`HyperCraftHal::current_time_nanos`],
+/// so it only needs to be set once with [`VirtRtc::set_time`]. Periodic,
+/// alarm and update-ended interrupts are raised on ISA IRQ 8.
+pub struct VirtRtc<H: HyperCraftHal> {
+    cmos: [u8; CMOS_SIZE],
+    index: u8,
+    /// Unix time in nanoseconds at `current_time_nanos() == 0`.
+    base_ns: u64,
+    /// Time of the next periodic interrupt, 0 if disabled.
+    next_periodic_ns: u64,
+    /// Second (since the Unix epoch) of the last update cycle handled.
+    last_update_sec: u64,
+    _phantom: PhantomData<H>,
+}
+
+impl<H: HyperCraftHal> VirtRtc<H> {
+    /// Create an RTC reading the Unix epoch, in 24-hour BCD mode.
+    pub(crate) const fn new() -> Self {
+        let mut cmos = [0u8; CMOS_SIZE];
+        // 32.768 kHz time base, 1024 Hz periodic rate.
+        cmos[REG_A as usize] = 0x26;
+        cmos[REG_B as usize] = 1 << REG_B_24H;
+        cmos[REG_D as usize] = 1 << REG_D_VRT;
+        Self {
+            cmos,
+            index: 0,
+            base_ns: 0,
+            next_periodic_ns: 0,
+            last_update_sec: 0,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Set the wall clock to `unix_secs` seconds since the Unix epoch.
+    pub fn set_time(&mut self, unix_secs: u64) {
+        let now_ns = H::current_time_nanos();
+        self.base_ns = (unix_secs * NANOS_PER_SEC).wrapping_sub(now_ns);
+        self.last_update_sec = unix_secs;
+    }
+
+    /// Set byte `offset` of the CMOS memory, e.g. the memory size fields read
+    /// by firmware.
+    pub fn set_cmos_byte(&mut self, offset: usize, value: u8) {
+        if offset > REG_D as usize && offset < CMOS_SIZE {
+            self.cmos[offset] = value;
+        }
+    }
+
+    fn unix_nanos(&self, now_ns: u64) -> u64 {
+        self.base_ns.wrapping_add(now_ns)
+    }
+
+    /// Whether an interrupt has been generated since the last check.
+    pub fn check_interrupt(&mut self) -> bool {
+        let now_ns = H::current_time_nanos();
+        let mut flags = 0u8;
+
+        if self.next_periodic_ns != 0 && now_ns >= self.next_periodic_ns {
+            let period_ns = self.periodic_interval_ns();
+            let missed = (now_ns - self.next_periodic_ns) / period_ns + 1;
+            self.next_periodic_ns += missed * period_ns;
+            flags.set_bit(REG_C_PF, true);
+        }
+
+        let reg_b = self.cmos[REG_B as usize];
+        let sec = self.unix_nanos(now_ns) / NANOS_PER_SEC;
+        if !reg_b.get_bit(REG_B_SET) && sec != self.last_update_sec {
+            self.last_update_sec = sec;
+            flags.set_bit(REG_C_UF, true);
+            if self.alarm_matches(&DateTime::from_unix_secs(sec)) {
+                flags.set_bit(REG_C_AF, true);
+            }
+        }
+
+        let reg_c = &mut self.cmos[REG_C as usize];
+        *reg_c |= flags;
+        let enabled = flags & reg_b & 0x70;
+        if enabled != 0 && !reg_c.get_bit(REG_C_IRQF) {
+            reg_c.set_bit(REG_C_IRQF, true);
+            return true;
+        }
+        false
+    }
+
+    fn periodic_interval_ns(&self) -> u64 {
+        let rate = self.cmos[REG_A as usize].get_bits(0..4) as u32;
+        // Rates 1 and 2 are aliases of 8 and 9 with the 32.768 kHz time base.
+        let rate = if rate <= 2 { rate + 7 } else { rate };
+        (NANOS_PER_SEC << (rate - 1)) / 32768
+    }
+
+    fn update_periodic_timer(&mut self) {
+        let rate = self.cmos[REG_A as usize].get_bits(0..4);
+        self.next_periodic_ns = if rate != 0 && self.cmos[REG_B as usize].get_bit(REG_B_PIE) {
+            H::current_time_nanos() + self.periodic_interval_ns()
+        } else {
+            0
+        };
+    }
+
+    fn alarm_matches(&self, now: &DateTime) -> bool {
+        // Values with the two high bits set are "don't care".
+        let matches = |reg: u8, value: u32| {
+            let alarm = self.cmos[reg as usize];
+            alarm & 0xc0 == 0xc0 || self.decode_rtc_value(alarm, reg == REG_HOURS_ALARM) == value
+        };
+        matches(REG_SECONDS_ALARM, now.second)
+            && matches(REG_MINUTES_ALARM, now.minute)
+            && matches(REG_HOURS_ALARM, now.hour)
+    }
+
+    fn encode_rtc_value(&self, value: u32, is_hour: bool) -> u8 {
+        let reg_b = self.cmos[REG_B as usize];
+        let mut value = value;
+        let mut pm = false;
+        if is_hour && !reg_b.get_bit(REG_B_24H) {
+            pm = value >= 12;
+            value %= 12;
+            if value == 0 {
+                value = 12;
+            }
+        }
+        let mut byte = if reg_b.get_bit(REG_B_DM_BINARY) {
+            value as u8
+        } else {
+            (((value / 10) << 4) | (value % 10)) as u8
+        };
+        byte.set_bit(7, pm);
+        byte
+    }
+
+    fn decode_rtc_value(&self, byte: u8, is_hour: bool) -> u32 {
+        let reg_b = self.cmos[REG_B as usize];
+        let pm = is_hour && !reg_b.get_bit(REG_B_24H) && byte.get_bit(7);
+        let byte = if is_hour { byte & 0x7f } else { byte };
+        let mut value = if reg_b.get_bit(REG_B_DM_BINARY) {
+            byte as u32
+        } else {
+            (byte >> 4) as u32 * 10 + (byte & 0xf) as u32
+        };
+        if is_hour && !reg_b.get_bit(REG_B_24H) {
+            value %= 12;
+            if pm {
+                value += 12;
+            }
+        }
+        value
+    }
+
+    fn read_register(&mut self, index: u8) -> u8 {
+        if self.cmos[REG_B as usize].get_bit(REG_B_SET) && Self::is_time_register(index) {
+            // The clock is stopped while SET is asserted.
+            return self.cmos[index as usize];
+        }
+        let now_ns = H::current_time_nanos();
+        let unix_ns = self.unix_nanos(now_ns);
+        let now = DateTime::from_unix_secs(unix_ns / NANOS_PER_SEC);
+        match index {
+            REG_SECONDS => self.encode_rtc_value(now.second, false),
+            REG_MINUTES => self.encode_rtc_value(now.minute, false),
+            REG_HOURS => self.encode_rtc_value(now.hour, true),
+            REG_DAY_OF_WEEK => self.encode_rtc_value(now.weekday, false),
+            REG_DAY_OF_MONTH => self.encode_rtc_value(now.day, false),
+            REG_MONTH => self.encode_rtc_value(now.month, false),
+            REG_YEAR => self.encode_rtc_value(now.year % 100, false),
+            REG_CENTURY => self.encode_rtc_value(now.year / 100, false),
+            REG_A => {
+                let mut value = self.cmos[REG_A as usize];
+                let in_update = unix_ns % NANOS_PER_SEC >= NANOS_PER_SEC - UPDATE_CYCLE_NS;
+                value.set_bit(REG_A_UIP, in_update && !self.cmos[REG_B as usize].get_bit(REG_B_SET));
+                value
+            }
+            REG_C => {
+                // Reading register C acknowledges all interrupts.
+                let value = self.cmos[REG_C as usize];
+                self.cmos[REG_C as usize] = 0;
+                value
+            }
+            _ => self.cmos[index as usize],
+        }
+    }
+
+    fn is_time_register(index: u8) -> bool {
+        matches!(
+            index,
+            REG_SECONDS
+                | REG_MINUTES
+                | REG_HOURS
+                | REG_DAY_OF_WEEK
+                | REG_DAY_OF_MONTH
+                | REG_MONTH
+                | REG_YEAR
+                | REG_CENTURY
+        )
+    }
+
+    /// Reads the time registers as the guest last wrote them.
+    fn time_from_registers(&self) -> DateTime {
+        let reg = |index: u8| self.decode_rtc_value(self.cmos[index as usize], index == REG_HOURS);
+        DateTime {
+            year: reg(REG_CENTURY) * 100 + reg(REG_YEAR),
+            month: reg(REG_MONTH),
+            day: reg(REG_DAY_OF_MONTH),
+            hour: reg(REG_HOURS),
+            minute: reg(REG_MINUTES),
+            second: reg(REG_SECONDS),
+            weekday: reg(REG_DAY_OF_WEEK),
+        }
+    }
+
+    /// Latch the current time into the time registers, so that the guest can
+    /// modify individual fields while SET is asserted.
+    fn latch_time(&mut self) {
+        for index in [
+            REG_SECONDS,
+            REG_MINUTES,
+            REG_HOURS,
+            REG_DAY_OF_WEEK,
+            REG_DAY_OF_MONTH,
+            REG_MONTH,
+            REG_YEAR,
+            REG_CENTURY,
+        ] {
+            self.cmos[index as usize] = self.read_register(index);
+        }
+    }
+
+    fn write_register(&mut self, index: u8, value: u8) {
+        match index {
+            index if Self::is_time_register(index) => {
+                if !self.cmos[REG_B as usize].get_bit(REG_B_SET) {
+                    // Writes outside of SET mode take effect immediately.
+                    self.latch_time();
+                    self.cmos[index as usize] = value;
+                    self.set_time(self.time_from_registers().to_unix_secs());
+                } else {
+                    self.cmos[index as usize] = value;
+                }
+            }
+            REG_A => {
+                // UIP is read-only.
+                self.cmos[REG_A as usize] = value & 0x7f;
+                self.update_periodic_timer();
+            }
+            REG_B => {
+                let old = self.cmos[REG_B as usize];
+                if value.get_bit(REG_B_SET) && !old.get_bit(REG_B_SET) {
+                    self.latch_time();
+                }
+                let mut value = value;
+                if value.get_bit(REG_B_SET) {
+                    // Setting SET clears UIE.
+                    value.set_bit(REG_B_UIE, false);
+                }
+                self.cmos[REG_B as usize] = value;
+                if !value.get_bit(REG_B_SET) && old.get_bit(REG_B_SET) {
+                    self.set_time(self.time_from_registers().to_unix_secs());
+                }
+                self.update_periodic_timer();
+            }
+            // Registers C and D are read-only.
+            REG_C | REG_D => {}
+            _ => self.cmos[index as usize] = value,
+        }
+    }
+}
+
+/// The wall clock and the next periodic interrupt are saved relative to the
+/// current time, so that the restored clock goes on from the saved time.
+impl<H: HyperCraftHal> Snapshot for VirtRtc<H> {
+    fn save(&self, w: &mut SnapshotWriter) {
+        let now_ns = H::current_time_nanos();
+        self.cmos.save(w);
+        self.index.save(w);
+        self.unix_nanos(now_ns).save(w);
+        let next_periodic =
+            (self.next_periodic_ns != 0).then(|| self.next_periodic_ns.saturating_sub(now_ns));
+        next_periodic.save(w);
+        self.last_update_sec.save(w);
+    }
+
+    fn restore(&mut self, r: &mut SnapshotReader<'_>) -> HyperResult {
+        let now_ns = H::current_time_nanos();
+        self.cmos.restore(r)?;
+        self.index.restore(r)?;
+        if self.index as usize >= CMOS_SIZE {
+            return Err(HyperError::DecodeError);
+        }
+        self.base_ns = r.read::<u64>()?.wrapping_sub(now_ns);
+        let next_periodic = r.read::<Option<u64>>()?;
+        self.next_periodic_ns = next_periodic.map_or(0, |remaining| now_ns + remaining);
+        self.last_update_sec.restore(r)
+    }
+}
+
+impl<H: HyperCraftHal> PortIoDevice for VirtRtc<H> {
+    fn port_range(&self) -> Range<u16> {
+        RTC_PORTS
+    }
+
+    fn read(&mut self, port: u16, access_size: u8) -> HyperResult<u32> {
+        if access_size != 1 {
+            return Err(HyperError::InvalidParam);
+        }
+        match port {
+            // The index register is write-only.
+            0x70 => Ok(0xff),
+            0x71 => Ok(self.read_register(self.index) as u32),
+            _ => Err(HyperError::InvalidParam),
+        }
+    }
+
+    fn write(&mut self, port: u16, access_size: u8, value: u32) -> HyperResult {
+        if access_size != 1 {
+            return Err(HyperError::InvalidParam);
+        }
+        match port {
+            // Bit 7 is the NMI disable bit, which is ignored.
+            0x70 => self.index = value as u8 & 0x7f,
+            0x71 => self.write_register(self.index, value as u8),
+            _ => return Err(HyperError::InvalidParam),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::tests::TestHal;
+
+    /// 2024-03-09 13:45:30, a Saturday.
+    const TIME: u64 = 1_709_991_930;
+
+    fn read(rtc: &mut VirtRtc<TestHal>, index: u8) -> u8 {
+        rtc.write(0x70, 1, index as u32).unwrap();
+        rtc.read(0x71, 1).unwrap() as u8
+    }
+
+    fn write(rtc: &mut VirtRtc<TestHal>, index: u8, value: u8) {
+        rtc.write(0x70, 1, index as u32).unwrap();
+        rtc.write(0x71, 1, value as u32).unwrap();
+    }
+
+    #[test]
+    fn calendar_conversion() {
+        let epoch = DateTime::from_unix_secs(0);
+        assert_eq!(
+            (epoch.year, epoch.month, epoch.day, epoch.weekday),
+            (1970, 1, 1, 5)
+        );
+        let leap = DateTime::from_unix_secs(951_782_400);
+        assert_eq!((leap.year, leap.month, leap.day), (2000, 2, 29));
+        let time = DateTime::from_unix_secs(TIME);
+        assert_eq!(
+            (
+                time.year,
+                time.month,
+                time.day,
+                time.hour,
+                time.minute,
+                time.second,
+                time.weekday
+            ),
+            (2024, 3, 9, 13, 45, 30, 7)
+        );
+        for secs in [0, 951_782_400, TIME, 4_102_444_799] {
+            assert_eq!(DateTime::from_unix_secs(secs).to_unix_secs(), secs);
+        }
+    }
+
+    #[test]
+    fn time_registers_follow_data_mode() {
+        let mut rtc = VirtRtc::<TestHal>::new();
+        rtc.set_time(TIME);
+        let regs = [
+            REG_SECONDS,
+            REG_MINUTES,
+            REG_HOURS,
+            REG_DAY_OF_WEEK,
+            REG_YEAR,
+            REG_CENTURY,
+        ];
+        assert_eq!(
+            regs.map(|index| read(&mut rtc, index)),
+            [0x30, 0x45, 0x13, 0x07, 0x24, 0x20]
+        );
+
+        // Binary, 12-hour mode: 13:00 is 1 PM.
+        write(&mut rtc, REG_B, 1 << REG_B_DM_BINARY);
+        assert_eq!(
+            regs.map(|index| read(&mut rtc, index)),
+            [30, 45, 0x81, 7, 24, 20]
+        );
+    }
+
+    #[test]
+    fn set_mode_latches_written_time() {
+        let mut rtc = VirtRtc::<TestHal>::new();
+        rtc.set_time(TIME);
+        write(&mut rtc, REG_B, 1 << REG_B_SET | 1 << REG_B_24H);
+        write(&mut rtc, REG_HOURS, 0x08);
+        write(&mut rtc, REG_MINUTES, 0x05);
+        // The clock is stopped and reads back the written fields.
+        assert_eq!(read(&mut rtc, REG_HOURS), 0x08);
+        write(&mut rtc, REG_B, 1 << REG_B_24H);
+        assert_eq!(read(&mut rtc, REG_HOURS), 0x08);
+        assert_eq!(read(&mut rtc, REG_MINUTES), 0x05);
+        assert_eq!(read(&mut rtc, REG_SECONDS), 0x30);
+
+        // Writes outside of SET mode change a single field.
+        write(&mut rtc, REG_DAY_OF_MONTH, 0x10);
+        assert_eq!(read(&mut rtc, REG_DAY_OF_WEEK), 0x01);
+        assert_eq!(read(&mut rtc, REG_HOURS), 0x08);
+    }
+
+    #[test]
+    fn register_c_is_cleared_on_read() {
+        let mut rtc = VirtRtc::<TestHal>::new();
+        rtc.set_time(TIME);
+        write(&mut rtc, REG_B, 1 << REG_B_UIE | 1 << REG_B_24H);
+        // The previous second ended, which raises the update-ended interrupt.
+        rtc.last_update_sec = TIME - 1;
+        assert!(rtc.check_interrupt());
+        assert!(!rtc.check_interrupt());
+        assert_eq!(read(&mut rtc, REG_C), 1 << REG_C_IRQF | 1 << REG_C_UF);
+        assert_eq!(read(&mut rtc, REG_C), 0);
+        // Register D is read-only.
+        write(&mut rtc, REG_D, 0);
+        assert_eq!(read(&mut rtc, REG_D), 1 << REG_D_VRT);
+    }
+}
@@ -1,5 +1,10 @@
 use page_table_entry::x86_64::EPTEntry;
-use page_table::{PagingMetaData, PageTable64};
+use page_table_entry::MappingFlags;
+use page_table::{PagingIf, PagingMetaData, PageTable64, PageSize};
+
+use super::msr::Msr;
+use crate::memory::{map_page_in, query_page_in};
+use crate::{GuestPhysAddr, HostPhysAddr, HyperResult, NestedPageTableExt};
 
 pub struct ExtendedPageTableMetadata;
 
@@ -11,3 +16,39 @@ impl PagingMetaData for ExtendedPageTableMetadata {
 
 /// The VMX extended page table. (SDM Vol. 3C, Section 28.3)
 pub type ExtendedPageTable<I> = PageTable64<ExtendedPageTableMetadata, EPTEntry, I>;
+
+/// The largest leaf page the processor supports in extended page tables,
+/// from `IA32_VMX_EPT_VPID_CAP`. A [`GuestPageTableTrait::map_page`]
+/// implementation should reject the larger ones.
+///
+/// [`GuestPageTableTrait::map_page`]: crate::GuestPageTableTrait::map_page
+pub fn ept_max_page_size() -> PageSize {
+    let cap = Msr::IA32_VMX_EPT_VPID_CAP.read();
+    if cap & (1 << 17) != 0 {
+        PageSize::Size1G
+    } else if cap & (1 << 16) != 0 {
+        PageSize::Size2M
+    } else {
+        PageSize::Size4K
+    }
+}
+
+impl<I: PagingIf> NestedPageTableExt for ExtendedPageTable<I> {
+    /// Rejects the pages larger than [`ept_max_page_size`].
+    fn map_nested_page(
+        &mut self,
+        gpa: GuestPhysAddr,
+        hpa: HostPhysAddr,
+        page_size: PageSize,
+        flags: MappingFlags,
+    ) -> HyperResult {
+        map_page_in(self, gpa, hpa, page_size, ept_max_page_size(), flags)
+    }
+
+    fn query_nested_page(
+        &self,
+        gpa: GuestPhysAddr,
+    ) -> HyperResult<(HostPhysAddr, MappingFlags, PageSize)> {
+        query_page_in(self, gpa)
+    }
+}
@@ -95,7 +95,7 @@ where
     G: GuestPageTableTrait,
     C: ConnectionExt,
 {
-    fn get_page(&self, addr: usize) -> PagingResult<(PhysAddr, MappingFlags, PageSize)> {
+    pub(crate) fn get_page(&self, addr: usize) -> PagingResult<(PhysAddr, MappingFlags, PageSize)> {
         let mut paging = PagingIfCallback::new();
         paging.set_callback(|guest_addr| {
             let host_addr = self.ept.translate(guest_addr.into()).unwrap();
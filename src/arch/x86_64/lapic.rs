@@ -1,6 +1,7 @@
 use bit_field::BitField;
 use core::marker::PhantomData;
 
+use crate::snapshot::{Snapshot, SnapshotReader, SnapshotWriter};
 use crate::{HyperCraftHal, HyperResult, HyperError};
 
 const APIC_FREQ_MHZ: u64 = 1000; // 1000 MHz
@@ -143,3 +144,29 @@ impl<H: HyperCraftHal> ApicTimer<H> {
         }
     }
 }
+
+/// The times are saved relative to the current time, so that a restored
+/// timer goes on counting from where the saved one stopped.
+impl<H: HyperCraftHal> Snapshot for ApicTimer<H> {
+    fn save(&self, w: &mut SnapshotWriter) {
+        let now = H::current_time_nanos();
+        self.lvt_timer_bits.save(w);
+        self.divide_shift.save(w);
+        self.initial_count.save(w);
+        now.saturating_sub(self.last_start_ns).save(w);
+        let remaining = (self.deadline_ns != 0).then(|| self.deadline_ns.saturating_sub(now));
+        remaining.save(w);
+    }
+
+    fn restore(&mut self, r: &mut SnapshotReader<'_>) -> HyperResult {
+        self.lvt_timer_bits.restore(r)?;
+        self.divide_shift.restore(r)?;
+        self.initial_count.restore(r)?;
+        let elapsed = r.read::<u64>()?;
+        let remaining = r.read::<Option<u64>>()?;
+        let now = H::current_time_nanos();
+        self.last_start_ns = now.saturating_sub(elapsed);
+        self.deadline_ns = remaining.map_or(0, |remaining| now + remaining);
+        Ok(())
+    }
+}
@@ -3,6 +3,8 @@ mod regs;
 
 // Codes in this module come mainly from https://github.com/rcore-os/RVM-Tutorial
 
+mod decode;
+mod devices;
 mod ept;
 mod gdb;
 mod lapic;
@@ -24,6 +26,7 @@ pub fn init_hv_runtime() {
 
 /// Nested page table define.
 pub use ept::ExtendedPageTable as NestedPageTable;
+pub use ept::ept_max_page_size;
 
 /// VCpu define.
 pub use vmx::VmxVcpu as VCpu;
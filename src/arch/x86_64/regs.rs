@@ -20,6 +20,58 @@ pub struct GeneralRegisters {
     pub r15: u64,
 }
 
+impl_snapshot_fields!(GeneralRegisters {
+    rax, rcx, rdx, rbx, rbp, rsi, rdi, r8, r9, r10, r11, r12, r13, r14, r15,
+});
+
+impl GeneralRegisters {
+    /// Returns the value of the register numbered `index`, in the order used by
+    /// instruction encodings (`RAX`, `RCX`, `RDX`, `RBX`, `RSP`, ...). `RSP` is
+    /// kept in the VMCS and is not available here.
+    pub fn get_reg_of_index(&self, index: usize) -> u64 {
+        match index {
+            0 => self.rax,
+            1 => self.rcx,
+            2 => self.rdx,
+            3 => self.rbx,
+            5 => self.rbp,
+            6 => self.rsi,
+            7 => self.rdi,
+            8 => self.r8,
+            9 => self.r9,
+            10 => self.r10,
+            11 => self.r11,
+            12 => self.r12,
+            13 => self.r13,
+            14 => self.r14,
+            15 => self.r15,
+            _ => panic!("Illegal index of GeneralRegisters {}", index),
+        }
+    }
+
+    /// Sets the value of the register numbered `index`. See [`Self::get_reg_of_index`].
+    pub fn set_reg_of_index(&mut self, index: usize, value: u64) {
+        match index {
+            0 => self.rax = value,
+            1 => self.rcx = value,
+            2 => self.rdx = value,
+            3 => self.rbx = value,
+            5 => self.rbp = value,
+            6 => self.rsi = value,
+            7 => self.rdi = value,
+            8 => self.r8 = value,
+            9 => self.r9 = value,
+            10 => self.r10 = value,
+            11 => self.r11 = value,
+            12 => self.r12 = value,
+            13 => self.r13 = value,
+            14 => self.r14 = value,
+            15 => self.r15 = value,
+            _ => panic!("Illegal index of GeneralRegisters {}", index),
+        }
+    }
+}
+
 macro_rules! save_regs_to_stack {
     () => {
         "
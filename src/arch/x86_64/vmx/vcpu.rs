@@ -1,4 +1,5 @@
 use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
 use core::fmt::{Debug, Formatter, Result};
 use core::{arch::asm, mem::size_of};
 
@@ -9,6 +10,10 @@ use x86::irq::BREAKPOINT_VECTOR;
 use x86::segmentation::SegmentSelector;
 use x86_64::registers::control::{Cr0, Cr0Flags, Cr3, Cr4, Cr4Flags};
 
+use crate::arch::decode::{decode_mmio_instruction, MmioInstructionKind, MmioStoreSource};
+use crate::arch::devices::{VirtIrqChip, VirtPlatform};
+use crate::devices::{MmioDevice, PortIoDevice};
+
 use super::definitions::VmxExitReason;
 use super::region::{MsrBitmap, VmxRegion};
 use super::vmcs::{
@@ -17,8 +22,118 @@ use super::vmcs::{
 };
 use super::VmxPerCpuState;
 use crate::arch::lapic::ApicTimer;
-use crate::arch::{memory::NestedPageFaultInfo, msr::Msr, regs::GeneralRegisters};
-use crate::{GuestPageTableTrait, GuestPhysAddr, HostPhysAddr, HyperCraftHal, HyperResult};
+use crate::arch::memory::{NestedPageFaultInfo, PhysFrame};
+use crate::arch::{msr::Msr, regs::GeneralRegisters};
+use crate::cow::CowRam;
+use crate::dirty_log::{DirtyLog, DirtyTracking};
+use crate::lazy_ram::LazyRam;
+use crate::migration::Migratable;
+use crate::snapshot::{
+    restore_memory, save_memory, Snapshot, SnapshotReader, SnapshotWriter, SECTION_IRQCHIP,
+    SECTION_VCPUS,
+};
+use crate::{
+    GuestMemory, GuestPageTableTrait, GuestPhysAddr, HostPhysAddr, HyperCraftHal, HyperError,
+    HyperResult,
+};
+use page_table::MappingFlags;
+
+/// The number of entries of the page-modification log.
+const PML_ENTRIES: u16 = 512;
+
+/// The x2APIC task priority register.
+const X2APIC_TPR: u32 = 0x808;
+/// The x2APIC end-of-interrupt register.
+const X2APIC_EOI: u32 = 0x80b;
+
+/// The guest-state fields of the VMCS saved in snapshots, besides the
+/// control registers and the read shadows.
+const SNAPSHOT_GUEST16: [VmcsGuest16; 8] = {
+    use VmcsGuest16::*;
+    [
+        ES_SELECTOR,
+        CS_SELECTOR,
+        SS_SELECTOR,
+        DS_SELECTOR,
+        FS_SELECTOR,
+        GS_SELECTOR,
+        LDTR_SELECTOR,
+        TR_SELECTOR,
+    ]
+};
+const SNAPSHOT_GUEST32: [VmcsGuest32; 22] = {
+    use VmcsGuest32::*;
+    [
+        ES_LIMIT,
+        CS_LIMIT,
+        SS_LIMIT,
+        DS_LIMIT,
+        FS_LIMIT,
+        GS_LIMIT,
+        LDTR_LIMIT,
+        TR_LIMIT,
+        GDTR_LIMIT,
+        IDTR_LIMIT,
+        ES_ACCESS_RIGHTS,
+        CS_ACCESS_RIGHTS,
+        SS_ACCESS_RIGHTS,
+        DS_ACCESS_RIGHTS,
+        FS_ACCESS_RIGHTS,
+        GS_ACCESS_RIGHTS,
+        LDTR_ACCESS_RIGHTS,
+        TR_ACCESS_RIGHTS,
+        INTERRUPTIBILITY_STATE,
+        ACTIVITY_STATE,
+        SMBASE,
+        IA32_SYSENTER_CS,
+    ]
+};
+const SNAPSHOT_GUEST64: [VmcsGuest64; 7] = {
+    use VmcsGuest64::*;
+    [
+        IA32_DEBUGCTL,
+        IA32_PAT,
+        IA32_EFER,
+        PDPTE0,
+        PDPTE1,
+        PDPTE2,
+        PDPTE3,
+    ]
+};
+const SNAPSHOT_GUEST_NW: [VmcsGuestNW; 20] = {
+    use VmcsGuestNW::*;
+    [
+        CR0,
+        CR3,
+        CR4,
+        ES_BASE,
+        CS_BASE,
+        SS_BASE,
+        DS_BASE,
+        FS_BASE,
+        GS_BASE,
+        LDTR_BASE,
+        TR_BASE,
+        GDTR_BASE,
+        IDTR_BASE,
+        DR7,
+        RSP,
+        RIP,
+        RFLAGS,
+        PENDING_DBG_EXCEPTIONS,
+        IA32_SYSENTER_ESP,
+        IA32_SYSENTER_EIP,
+    ]
+};
+
+/// The MSRs the guest accesses directly, whose values are its own.
+const SNAPSHOT_MSRS: [Msr; 5] = [
+    Msr::IA32_STAR,
+    Msr::IA32_LSTAR,
+    Msr::IA32_CSTAR,
+    Msr::IA32_FMASK,
+    Msr::IA32_KERNEL_GSBASE,
+];
 
 use gdbstub::conn::ConnectionExt;
 use gdbstub::stub::state_machine::GdbStubStateMachine;
@@ -31,10 +146,19 @@ pub struct VmxVcpu<H: HyperCraftHal, G: GuestPageTableTrait, C: ConnectionExt> {
     vmcs: VmxRegion<H>,
     msr_bitmap: MsrBitmap<H>,
     apic_timer: ApicTimer<H>,
+    devices: VirtPlatform<H>,
     pending_events: VecDeque<(u8, Option<u32>)>,
     pub(crate) ept: G,
     pub(crate) gdbstub: Option<GdbStubStateMachine<'static, Self, C>>,
     pub(crate) breakpoints: BTreeMap<usize, (usize, [u8; 1])>,
+    /// The pages written by the guest, while dirty logging is enabled.
+    dirty_log: Option<DirtyLog>,
+    /// The page-modification log, if the processor supports it.
+    pml: Option<PhysFrame<H>>,
+    /// The RAM backed on demand.
+    lazy_ram: LazyRam<H>,
+    /// The RAM shared with the template VM, if this is a clone.
+    cow_ram: CowRam<H>,
 }
 
 impl<H: HyperCraftHal, G: GuestPageTableTrait, C: ConnectionExt> VmxVcpu<H, G, C> {
@@ -49,10 +173,15 @@ impl<H: HyperCraftHal, G: GuestPageTableTrait, C: ConnectionExt> VmxVcpu<H, G, C
             vmcs: VmxRegion::new(percpu.vmcs_revision_id, false)?,
             msr_bitmap: MsrBitmap::passthrough_all()?,
             apic_timer: ApicTimer::new(),
+            devices: VirtPlatform::new(),
             pending_events: VecDeque::with_capacity(8),
             ept,
             gdbstub: None,
             breakpoints: BTreeMap::new(),
+            dirty_log: None,
+            pml: None,
+            lazy_ram: LazyRam::new(),
+            cow_ram: CowRam::new(),
         };
         vcpu.setup_msr_bitmap()?;
         vcpu.setup_vmcs(entry)?;
@@ -63,6 +192,7 @@ impl<H: HyperCraftHal, G: GuestPageTableTrait, C: ConnectionExt> VmxVcpu<H, G, C
     /// Run the guest, never return.
     pub fn run(&mut self) -> ! {
         self.gdbserver_loop();
+        self.sync_cow_ram().unwrap();
         VmcsHostNW::RSP
             .write(&self.host_stack_top as *const _ as usize)
             .unwrap();
@@ -134,6 +264,101 @@ impl<H: HyperCraftHal, G: GuestPageTableTrait, C: ConnectionExt> VmxVcpu<H, G, C
         VmcsGuestNW::CR3.read().unwrap()
     }
 
+    /// Start logging the pages written in `memory`, as described on
+    /// [`DirtyLog`]. The processor logs them in the page-modification log if
+    /// it supports PML, otherwise each first write is an EPT violation.
+    ///
+    /// Like the other dirty logging methods, it must be called while the
+    /// VMCS of this vCPU is loaded, e.g. from the VM-exit handler.
+    pub fn enable_dirty_log(&mut self, memory: &GuestMemory) -> HyperResult {
+        if self.dirty_log.is_some() {
+            return Err(HyperError::BadState);
+        }
+        if self.cow_ram.is_shared() {
+            return Err(HyperError::NotSupported);
+        }
+        let tracking = match self.enable_pml() {
+            Ok(()) => DirtyTracking::Hardware,
+            Err(HyperError::NotSupported) => DirtyTracking::WriteProtect,
+            Err(err) => return Err(err),
+        };
+        let log = DirtyLog::start(memory, &mut self.ept, tracking)?;
+        self.dirty_log = Some(log);
+        vmcs::flush_ept()
+    }
+
+    /// Take the dirty bitmap of the `size` bytes at `gpa`, after draining
+    /// the page-modification log.
+    pub fn get_and_clear_dirty_log(
+        &mut self,
+        gpa: GuestPhysAddr,
+        size: usize,
+    ) -> HyperResult<Vec<usize>> {
+        self.drain_pml()?;
+        let log = self.dirty_log.as_mut().ok_or(HyperError::BadState)?;
+        let dirty = log.get_and_clear(&mut self.ept, gpa, size)?;
+        vmcs::flush_ept()?;
+        Ok(dirty)
+    }
+
+    /// Stop dirty logging, and give the guest its write permissions back.
+    pub fn disable_dirty_log(&mut self) -> HyperResult {
+        let log = self.dirty_log.take().ok_or(HyperError::BadState)?;
+        log.stop(&mut self.ept)?;
+        if self.pml.take().is_some() {
+            let mut ctrl = VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS.read()?;
+            ctrl &= !vmcs::controls::SecondaryControls::ENABLE_PML.bits();
+            VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS.write(ctrl)?;
+        }
+        vmcs::flush_ept()
+    }
+
+    /// Add the `size` bytes at `gpa` to `memory`, the RAM of the guest,
+    /// as [`LazyRam`] backed on demand with `flags`.
+    pub fn add_lazy_region(
+        &mut self,
+        memory: &mut GuestMemory,
+        gpa: GuestPhysAddr,
+        size: usize,
+        flags: MappingFlags,
+    ) -> HyperResult {
+        self.lazy_ram.add_region(memory, gpa, size, flags)
+    }
+
+    /// The number of pages of the RAM backed on demand allocated so far.
+    pub fn lazy_ram_pages(&self) -> usize {
+        self.lazy_ram.nr_pages()
+    }
+
+    /// Free the pages of the [`LazyRam`] in the `size` bytes at `gpa`, and
+    /// return their number.
+    ///
+    /// It must be called while the VMCS of this vCPU is loaded.
+    pub fn reclaim_pages(&mut self, gpa: GuestPhysAddr, size: usize) -> HyperResult<usize> {
+        self.lazy_ram.reclaim(&mut self.ept, gpa, size, vmcs::flush_ept)
+    }
+
+    /// Make this vCPU a [`CowRam`] clone of the vCPU of a template VM, whose
+    /// RAM is `template_memory` and whose state is `template_state`, and
+    /// return the RAM of the clone. It must be called while the VMCS of this
+    /// vCPU is loaded.
+    pub fn clone_from_template(
+        &mut self,
+        template_state: &[u8],
+        template_memory: &GuestMemory,
+    ) -> HyperResult<GuestMemory> {
+        let memory = self.cow_ram.share(template_memory, &mut self.ept)?;
+        vmcs::flush_ept()?;
+        self.devices.set_virtio_memory(&memory);
+        self.import_state(template_state)?;
+        Ok(memory)
+    }
+
+    /// The number of pages shared with the template VM copied so far.
+    pub fn cow_pages(&self) -> usize {
+        self.cow_ram.nr_pages()
+    }
+
     /// Add a virtual interrupt or exception to the pending events list,
     /// and try to inject it before later VM entries.
     pub fn inject_event(&mut self, vector: u8, err_code: Option<u32>) {
@@ -172,6 +397,73 @@ impl<H: HyperCraftHal, G: GuestPageTableTrait, C: ConnectionExt> VmxVcpu<H, G, C
     pub fn apic_timer_mut(&mut self) -> &mut ApicTimer<H> {
         &mut self.apic_timer
     }
+
+    /// Returns the mutable reference of [`VirtIrqChip`], which device models
+    /// use to raise interrupts through the PIC and IOAPIC.
+    pub fn irq_chip_mut(&mut self) -> &mut VirtIrqChip {
+        self.devices.irq_chip_mut()
+    }
+
+    /// Returns the mutable reference of [`VirtPlatform`], the legacy devices
+    /// emulated for this vCPU.
+    pub fn devices_mut(&mut self) -> &mut VirtPlatform<H> {
+        &mut self.devices
+    }
+
+    /// Save the state of this vCPU: its registers, VMCS guest state, APIC
+    /// timer and pending events, the state of the legacy devices, and the
+    /// contents of `memory`, the guest's RAM.
+    ///
+    /// Like [`VmxVcpu::restore`], it must be called while the VMCS of this
+    /// vCPU is loaded, e.g. from the VM-exit handler.
+    pub fn snapshot(&mut self, memory: &GuestMemory) -> HyperResult<Vec<u8>> {
+        let mut w = SnapshotWriter::new();
+        self.save_state(&mut w)?;
+        save_memory(memory, &mut w)?;
+        Ok(w.finish())
+    }
+
+    /// Restore a snapshot taken by [`VmxVcpu::snapshot`] into this vCPU,
+    /// whose `memory` must have the same regions as the saved one, mapped in
+    /// the EPT. The guest then resumes where the saved one stopped.
+    pub fn restore(&mut self, data: &[u8], memory: &GuestMemory) -> HyperResult {
+        let mut r = SnapshotReader::new(data)?;
+        self.restore_state(&mut r)?;
+        restore_memory(memory, &mut r)?;
+        r.finish()
+    }
+}
+
+impl<H: HyperCraftHal, G: GuestPageTableTrait, C: ConnectionExt> Migratable
+    for VmxVcpu<H, G, C>
+{
+    fn enable_dirty_log(&mut self, memory: &GuestMemory) -> HyperResult {
+        self.enable_dirty_log(memory)
+    }
+
+    fn get_and_clear_dirty_log(
+        &mut self,
+        gpa: GuestPhysAddr,
+        size: usize,
+    ) -> HyperResult<Vec<usize>> {
+        self.get_and_clear_dirty_log(gpa, size)
+    }
+
+    fn disable_dirty_log(&mut self) -> HyperResult {
+        self.disable_dirty_log()
+    }
+
+    fn export_state(&mut self) -> HyperResult<Vec<u8>> {
+        let mut w = SnapshotWriter::new();
+        self.save_state(&mut w)?;
+        Ok(w.finish())
+    }
+
+    fn import_state(&mut self, state: &[u8]) -> HyperResult {
+        let mut r = SnapshotReader::new(state)?;
+        self.restore_state(&mut r)?;
+        r.finish()
+    }
 }
 
 // Implementation of private methods
@@ -424,7 +716,171 @@ impl<H: HyperCraftHal, G: GuestPageTableTrait, C: ConnectionExt> VmxVcpu<H, G, C
             && block_state == 0
     }
 
-    /// Try to inject a pending event before next VM entry.
+    /// Enable the page-modification log. Returns [`HyperError::NotSupported`]
+    /// if the processor does not support it.
+    fn enable_pml(&mut self) -> HyperResult {
+        let frame = PhysFrame::alloc_zero()?;
+        let old = VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS.read()?;
+        vmcs::set_control(
+            VmcsControl32::SECONDARY_PROCBASED_EXEC_CONTROLS,
+            Msr::IA32_VMX_PROCBASED_CTLS2,
+            old,
+            vmcs::controls::SecondaryControls::ENABLE_PML.bits(),
+            0,
+        )
+        .map_err(|_| HyperError::NotSupported)?;
+        VmcsControl64::PML_ADDR.write(frame.start_paddr() as u64)?;
+        VmcsGuest16::PML_INDEX.write(PML_ENTRIES - 1)?;
+        self.pml = Some(frame);
+        Ok(())
+    }
+
+    /// Move the guest physical addresses of the page-modification log to the
+    /// dirty log, and empty it.
+    fn drain_pml(&mut self) -> HyperResult {
+        let (Some(frame), Some(log)) = (self.pml.as_ref(), self.dirty_log.as_mut()) else {
+            return Ok(());
+        };
+        // The index is decremented from 511 after each entry, and wraps
+        // around to 0xffff when the log is full.
+        let index = VmcsGuest16::PML_INDEX.read()?;
+        let first = if index >= PML_ENTRIES {
+            0
+        } else {
+            index as usize + 1
+        };
+        let entries = frame.as_mut_ptr() as *const u64;
+        for i in first..PML_ENTRIES as usize {
+            let gpa = unsafe { entries.add(i).read_volatile() } as usize;
+            log.mark_dirty(gpa);
+        }
+        VmcsGuest16::PML_INDEX.write(PML_ENTRIES - 1)?;
+        Ok(())
+    }
+
+    /// Handle EPT violations of the writes to the pages write-protected for
+    /// dirty logging. Returns `None` if the violation is not one of them.
+    fn handle_dirty_log_write(&mut self) -> Option<HyperResult> {
+        let fault_info = self.nested_page_fault_info().ok()?;
+        if !fault_info.access_flags.contains(MappingFlags::WRITE) {
+            return None;
+        }
+        let log = self.dirty_log.as_mut()?;
+        match log.handle_write_fault(&mut self.ept, fault_info.fault_guest_paddr) {
+            Ok(true) => Some(vmcs::flush_ept()),
+            Ok(false) => None,
+            Err(err) => Some(Err(err)),
+        }
+    }
+
+    /// Handle EPT violations of the writes to the RAM shared with the
+    /// template VM. Returns `None` if the violation is not one of them.
+    fn handle_cow_write(&mut self) -> Option<HyperResult> {
+        let fault_info = self.nested_page_fault_info().ok()?;
+        if !fault_info.access_flags.contains(MappingFlags::WRITE) {
+            return None;
+        }
+        match self
+            .cow_ram
+            .handle_write_fault(&mut self.ept, fault_info.fault_guest_paddr)
+        {
+            Ok(true) => Some(vmcs::flush_ept()),
+            Ok(false) => None,
+            Err(err) => Some(Err(err)),
+        }
+    }
+
+    /// Map the pages of the RAM shared with the template VM which the host
+    /// copied, e.g. for the DMA of the virtio devices, to their copies.
+    fn sync_cow_ram(&mut self) -> HyperResult {
+        if self.cow_ram.sync(&mut self.ept)? {
+            vmcs::flush_ept()?;
+        }
+        Ok(())
+    }
+
+    /// Handle EPT violations of the first accesses to the RAM backed on
+    /// demand. Returns `None` if the violation is not one of them.
+    fn handle_lazy_ram_fault(&mut self) -> Option<HyperResult> {
+        let fault_info = self.nested_page_fault_info().ok()?;
+        let write_protect = self
+            .dirty_log
+            .as_ref()
+            .is_some_and(|log| log.tracking() == DirtyTracking::WriteProtect);
+        match self.lazy_ram.handle_fault(
+            &mut self.ept,
+            fault_info.fault_guest_paddr,
+            write_protect,
+        ) {
+            Ok(true) => Some(vmcs::flush_ept()),
+            Ok(false) => None,
+            Err(err) => Some(Err(err)),
+        }
+    }
+
+    /// Save everything but the RAM.
+    fn save_state(&mut self, w: &mut SnapshotWriter) -> HyperResult {
+        w.section(SECTION_VCPUS, |w| {
+            self.guest_regs.save(w);
+            for field in SNAPSHOT_GUEST16 {
+                field.read()?.save(w);
+            }
+            for field in SNAPSHOT_GUEST32 {
+                field.read()?.save(w);
+            }
+            for field in SNAPSHOT_GUEST64 {
+                field.read()?.save(w);
+            }
+            for field in SNAPSHOT_GUEST_NW {
+                field.read()?.save(w);
+            }
+            VmcsControlNW::CR0_READ_SHADOW.read()?.save(w);
+            VmcsControlNW::CR4_READ_SHADOW.read()?.save(w);
+            for msr in SNAPSHOT_MSRS {
+                msr.read().save(w);
+            }
+            self.pending_events.save(w);
+            Ok(())
+        })?;
+        w.section(SECTION_IRQCHIP, |w| {
+            self.apic_timer.save(w);
+            self.devices.save(w);
+            Ok(())
+        })
+    }
+
+    fn restore_state(&mut self, r: &mut SnapshotReader<'_>) -> HyperResult {
+        r.section(SECTION_VCPUS, |r| {
+            self.guest_regs.restore(r)?;
+            for field in SNAPSHOT_GUEST16 {
+                field.write(r.read()?)?;
+            }
+            for field in SNAPSHOT_GUEST32 {
+                field.write(r.read()?)?;
+            }
+            for field in SNAPSHOT_GUEST64 {
+                field.write(r.read()?)?;
+            }
+            for field in SNAPSHOT_GUEST_NW {
+                field.write(r.read()?)?;
+            }
+            VmcsControlNW::CR0_READ_SHADOW.write(r.read()?)?;
+            VmcsControlNW::CR4_READ_SHADOW.write(r.read()?)?;
+            for msr in SNAPSHOT_MSRS {
+                unsafe { msr.write(r.read()?) };
+            }
+            self.pending_events.restore(r)
+        })?;
+        r.section(SECTION_IRQCHIP, |r| {
+            self.apic_timer.restore(r)?;
+            self.devices.restore(r)
+        })
+    }
+
+    /// Try to inject a pending event before next VM entry. The events added
+    /// by [`VmxVcpu::inject_event`] come first, then the interrupts of the
+    /// [`VirtIrqChip`], which are only acknowledged when the guest can take
+    /// them.
     fn check_pending_events(&mut self) -> HyperResult {
         if let Some(event) = self.pending_events.front() {
             if event.0 < 32 || self.allow_interrupt() {
@@ -435,10 +891,137 @@ impl<H: HyperCraftHal, G: GuestPageTableTrait, C: ConnectionExt> VmxVcpu<H, G, C
                 // interrupts are blocked, enable interrupt-window exiting.
                 self.set_interrupt_window(true)?;
             }
+        } else if self.devices.irq_chip_mut().has_pending_interrupt() {
+            if self.allow_interrupt() {
+                if let Some(vector) = self.devices.irq_chip_mut().pop_pending_vector() {
+                    vmcs::inject_event(vector, None)?;
+                }
+            } else {
+                self.set_interrupt_window(true)?;
+            }
         }
         Ok(())
     }
 
+    /// Handle the accesses to the x2APIC registers emulated by the
+    /// [`VirtIrqChip`]: the task priority and the end-of-interrupt. Returns
+    /// `None` for the other MSRs.
+    fn handle_apic_msr(&mut self, is_write: bool, instr_len: u32) -> Option<HyperResult> {
+        let msr = self.guest_regs.rcx as u32;
+        let irq_chip = self.devices.irq_chip_mut();
+        match (msr, is_write) {
+            (X2APIC_TPR, false) => {
+                self.guest_regs.rax = irq_chip.task_priority() as u64;
+                self.guest_regs.rdx = 0;
+            }
+            (X2APIC_TPR, true) => irq_chip.set_task_priority(self.guest_regs.rax as u8),
+            (X2APIC_EOI, true) => irq_chip.end_of_interrupt(),
+            _ => return None,
+        }
+        Some(self.advance_rip(instr_len as u8))
+    }
+
+    /// Read the bytes of the instruction at guest `CS:RIP`.
+    fn read_guest_instruction(&self, buf: &mut [u8]) -> HyperResult<usize> {
+        let mut addr = VmcsGuestNW::CS_BASE.read()? + self.rip();
+        let mut count = buf.len();
+        let cr0 = Cr0Flags::from_bits_truncate(self.cr0() as u64);
+        if cr0.contains(Cr0Flags::PAGING) {
+            let (paddr, _, size) = self.get_page(addr).map_err(|_| HyperError::PageFault)?;
+            let size = size as usize;
+            addr = paddr.as_usize();
+            count = count.min(size - addr % size);
+        }
+        self.ept.read_guest_phys_addrs(addr, buf.as_mut_ptr(), count)
+    }
+
+    /// Handle port I/O to the in-crate device models. Returns `None` if the
+    /// port is not emulated here.
+    fn handle_emulated_io(&mut self, instr_len: u32) -> Option<HyperResult> {
+        let io_info = self.io_exit_info().ok()?;
+        if io_info.is_string || self.devices.port_device_mut(io_info.port).is_none() {
+            return None;
+        }
+        let mask = match io_info.access_size {
+            1 => 0xff,
+            2 => 0xffff,
+            _ => 0xffff_ffff,
+        };
+        Some((|| {
+            if io_info.is_in {
+                let device = self.devices.port_device_mut(io_info.port).unwrap();
+                let value = device.read(io_info.port, io_info.access_size)? as u64;
+                let rax = &mut self.guest_regs.rax;
+                // `IN EAX` zero-extends, `IN AL/AX` only writes the low bits.
+                *rax = if io_info.access_size == 4 {
+                    value & mask
+                } else {
+                    (*rax & !mask) | (value & mask)
+                };
+            } else {
+                let value = (self.guest_regs.rax & mask) as u32;
+                let device = self.devices.port_device_mut(io_info.port).unwrap();
+                device.write(io_info.port, io_info.access_size, value)?;
+            }
+            self.advance_rip(instr_len as u8)
+        })())
+    }
+
+    /// Handle EPT violations of the in-crate MMIO device models. Returns `None`
+    /// if the address is not emulated here.
+    fn handle_emulated_mmio(&mut self) -> Option<HyperResult> {
+        let fault_info = self.nested_page_fault_info().ok()?;
+        let gpa = fault_info.fault_guest_paddr;
+        self.devices.mmio_device_mut(gpa)?;
+        Some((|| {
+            let mut bytes = [0u8; 15];
+            let len = self.read_guest_instruction(&mut bytes)?;
+            let inst = decode_mmio_instruction(&bytes[..len])?;
+            let mask = match inst.width {
+                8 => u64::MAX,
+                width => (1 << (width * 8)) - 1,
+            };
+            match inst.kind {
+                MmioInstructionKind::Load { reg, zero_extend } => {
+                    let device = self.devices.mmio_device_mut(gpa).unwrap();
+                    let value = device.read(gpa, inst.width)? & mask;
+                    let old = self.gpr_of_index(reg);
+                    let new = if zero_extend {
+                        value
+                    } else {
+                        (old & !mask) | value
+                    };
+                    self.set_gpr_of_index(reg, new);
+                }
+                MmioInstructionKind::Store(src) => {
+                    let value = match src {
+                        MmioStoreSource::Register(reg) => self.gpr_of_index(reg),
+                        MmioStoreSource::Immediate(imm) => imm,
+                    };
+                    let device = self.devices.mmio_device_mut(gpa).unwrap();
+                    device.write(gpa, inst.width, value & mask)?;
+                }
+            }
+            self.advance_rip(inst.length as u8)
+        })())
+    }
+
+    fn gpr_of_index(&self, index: usize) -> u64 {
+        if index == 4 {
+            self.stack_pointer() as u64
+        } else {
+            self.guest_regs.get_reg_of_index(index)
+        }
+    }
+
+    fn set_gpr_of_index(&mut self, index: usize, value: u64) {
+        if index == 4 {
+            self.set_stack_pointer(value as usize)
+        } else {
+            self.guest_regs.set_reg_of_index(index, value)
+        }
+    }
+
     fn handle_monitor_trap_flag(&mut self) -> HyperResult {
         self.set_monitor_trap_flag(false)?;
         self.gdbserver_report();
@@ -465,6 +1048,22 @@ impl<H: HyperCraftHal, G: GuestPageTableTrait, C: ConnectionExt> VmxVcpu<H, G, C
                 self.gdbserver_report();
                 Ok(())
             }
+            VmxExitReason::IO_INSTRUCTION => self
+                .handle_emulated_io(exit_info.exit_instruction_length)
+                .unwrap_or_else(|| H::vmexit_handler(self)),
+            VmxExitReason::EPT_VIOLATION => self
+                .handle_cow_write()
+                .or_else(|| self.handle_dirty_log_write())
+                .or_else(|| self.handle_lazy_ram_fault())
+                .or_else(|| self.handle_emulated_mmio())
+                .unwrap_or_else(|| H::vmexit_handler(self)),
+            VmxExitReason::PML_FULL => self.drain_pml(),
+            VmxExitReason::MSR_READ | VmxExitReason::MSR_WRITE => self
+                .handle_apic_msr(
+                    exit_info.exit_reason == VmxExitReason::MSR_WRITE,
+                    exit_info.exit_instruction_length,
+                )
+                .unwrap_or_else(|| H::vmexit_handler(self)),
             _ => H::vmexit_handler(self),
         };
 
@@ -477,8 +1076,13 @@ impl<H: HyperCraftHal, G: GuestPageTableTrait, C: ConnectionExt> VmxVcpu<H, G, C
 
         // Check if there is an APIC timer interrupt
         if self.apic_timer.check_interrupt() {
-            self.inject_event(self.apic_timer.vector(), None);
+            let vector = self.apic_timer.vector();
+            self.devices.irq_chip_mut().accept_vector(vector);
         }
+        // Raise the interrupts of the emulated devices through the PIC and
+        // IOAPIC
+        self.devices.poll_devices();
+        self.sync_cow_ram().unwrap();
         self.check_pending_events().unwrap();
     }
 }
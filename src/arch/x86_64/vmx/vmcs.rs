@@ -618,6 +618,13 @@ pub fn set_ept_pointer(pml4_paddr: HostPhysAddr) -> HyperResult {
     Ok(())
 }
 
+/// Invalidate the cached translations of the current EPT, after changing it.
+pub fn flush_ept() -> HyperResult {
+    let eptp = VmcsControl64::EPTP.read()?;
+    unsafe { invept(InvEptType::SingleContext, eptp)? };
+    Ok(())
+}
+
 pub fn instruction_error() -> VmxInstructionError {
     VmcsReadOnly32::VM_INSTRUCTION_ERROR.read().unwrap().into()
 }
@@ -0,0 +1,190 @@
+use core::marker::PhantomData;
+use page_table_entry::MappingFlags;
+
+use crate::memory::{RegionBacking, PAGE_SIZE_4K};
+use crate::{
+    GuestMemory, GuestPageTableTrait, GuestPhysAddr, HyperCraftHal, HyperError, HyperResult,
+};
+
+/// The RAM of a VM cloned from a template VM: the clone's nested page table
+/// maps the template's RAM read-only, and the first write of the clone to
+/// each page copies it to a private page. The host reaches the clone's RAM
+/// through `memory`, whose writes copy the pages too, and whose last clone
+/// frees the copies.
+///
+/// A clone is set up like its template, with the same devices attached and
+/// nothing mapped at its RAM, then takes the vCPU and device state of the
+/// template, as exported by [`Migratable::export_state`]. The template must
+/// stay paused, and its RAM must outlive the clone. The virtio devices of
+/// the clone use the RAM of the clone, and so must the host, so that its
+/// writes copy the pages too.
+///
+/// [`Migratable::export_state`]: crate::Migratable::export_state
+pub(crate) struct CowRam<H: HyperCraftHal> {
+    memory: Option<GuestMemory>,
+    _phantom: PhantomData<H>,
+}
+
+impl<H: HyperCraftHal> CowRam<H> {
+    pub const fn new() -> Self {
+        Self {
+            memory: None,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Whether the RAM of a template is shared.
+    pub fn is_shared(&self) -> bool {
+        self.memory.is_some()
+    }
+
+    /// Map the regions of `template`, the RAM of the template VM, read-only
+    /// in `gpt`, the nested page table of the clone, and return the RAM of
+    /// the clone, which reads the template's pages until they are copied.
+    /// The RAM backed on demand cannot be shared.
+    pub fn share<G: GuestPageTableTrait>(
+        &mut self,
+        template: &GuestMemory,
+        gpt: &mut G,
+    ) -> HyperResult<GuestMemory> {
+        if self.is_shared() {
+            return Err(HyperError::BadState);
+        }
+        let mut shared = GuestMemory::new();
+        let mut memory = GuestMemory::new();
+        for region in template.regions() {
+            if region.backing != RegionBacking::Host {
+                return Err(HyperError::NotSupported);
+            }
+            let flags = region.flags - MappingFlags::WRITE;
+            shared.add_region(region.gpa, region.hva, region.size, flags)?;
+            memory.add_cow_region::<H>(region.gpa, region.hva, region.size, region.flags)?;
+        }
+        shared.map_into::<H, G>(gpt)?;
+        self.memory = Some(memory.clone());
+        Ok(memory)
+    }
+
+    /// Handle a write of the clone to the read-only `gpa`: copy its page of
+    /// the template to a private page, and map that writable in `gpt`.
+    /// Returns `false` if `gpa` is not in a shared writable region, or its
+    /// page is already private. The caller flushes the stale translations of
+    /// `gpt` afterwards.
+    pub fn handle_write_fault<G: GuestPageTableTrait>(
+        &mut self,
+        gpt: &mut G,
+        gpa: GuestPhysAddr,
+    ) -> HyperResult<bool> {
+        let Some(memory) = &self.memory else {
+            return Ok(false);
+        };
+        match memory.find_region(gpa) {
+            Some(region) if region.flags.contains(MappingFlags::WRITE) => {
+                map_private::<H, G>(memory, gpt, gpa & !(PAGE_SIZE_4K - 1))
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Map the pages the host copied by writing to the RAM of the clone,
+    /// e.g. for the DMA of its devices, to their copies in `gpt`, so that
+    /// the guest sees the writes. Called before each guest entry. Returns
+    /// whether a mapping changed, and the caller flushes the stale
+    /// translations of `gpt`.
+    pub fn sync<G: GuestPageTableTrait>(&mut self, gpt: &mut G) -> HyperResult<bool> {
+        let Some(memory) = &self.memory else {
+            return Ok(false);
+        };
+        let mut changed = false;
+        for page in memory.take_copied() {
+            changed |= map_private::<H, G>(memory, gpt, page)?;
+        }
+        Ok(changed)
+    }
+
+    /// The number of pages copied so far.
+    pub fn nr_pages(&self) -> usize {
+        self.memory.as_ref().map_or(0, GuestMemory::nr_backed_pages)
+    }
+}
+
+/// Map `page` of `memory` to its private copy in `gpt`, with the flags of its
+/// region, copying it first if need be. Returns `false` if it is mapped so
+/// already.
+fn map_private<H: HyperCraftHal, G: GuestPageTableTrait>(
+    memory: &GuestMemory,
+    gpt: &mut G,
+    page: GuestPhysAddr,
+) -> HyperResult<bool> {
+    let flags = memory
+        .find_region(page)
+        .ok_or(HyperError::OutOfRange)?
+        .flags;
+    let hpa = H::virt_to_phys(memory.back_page(page)?);
+    if gpt.translate(page).ok() == Some(hpa) {
+        return Ok(false);
+    }
+    gpt.unmap_region(page, PAGE_SIZE_4K)?;
+    gpt.map(page, hpa, flags)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::tests::{host_backed, rw, MockPageTable, TestHal};
+
+    const RAM: GuestPhysAddr = 0x8000_0000;
+
+    fn clone_of(template: &GuestMemory) -> (CowRam<TestHal>, GuestMemory, MockPageTable) {
+        let mut cow = CowRam::new();
+        let mut gpt = MockPageTable::default();
+        let memory = cow.share(template, &mut gpt).unwrap();
+        (cow, memory, gpt)
+    }
+
+    #[test]
+    fn guest_write_copies_page() {
+        let (host, template) = host_backed(RAM, 2);
+        template.write_obj(RAM + 0x1010, &0xaau8).unwrap();
+        let (mut cow, memory, mut gpt) = clone_of(&template);
+        assert_eq!(gpt.flags(RAM + 0x1000), MappingFlags::READ);
+
+        assert_eq!(cow.handle_write_fault(&mut gpt, RAM + 0x1010), Ok(true));
+        let hpa = gpt.translate(RAM + 0x1000).unwrap();
+        assert_ne!(hpa, host[1].0.as_ptr() as usize);
+        assert_eq!(gpt.flags(RAM + 0x1000), rw());
+        assert_eq!(gpt.flags(RAM), MappingFlags::READ);
+        assert_eq!(unsafe { *((hpa + 0x10) as *const u8) }, 0xaa);
+        assert_eq!(cow.nr_pages(), 1);
+
+        // The page is private now, so the fault has another cause.
+        assert_eq!(cow.handle_write_fault(&mut gpt, RAM + 0x1010), Ok(false));
+        assert_eq!(cow.handle_write_fault(&mut gpt, RAM + 0x2000), Ok(false));
+        unsafe { *((hpa + 0x10) as *mut u8) = 0x55 };
+        assert_eq!(memory.read_obj::<u8>(RAM + 0x1010), Ok(0x55));
+        assert_eq!(host[1].0[0x10], 0xaa);
+        assert_eq!(cow.sync(&mut gpt), Ok(false));
+    }
+
+    #[test]
+    fn host_write_copies_page_and_sync_maps_it() {
+        let (host, template) = host_backed(RAM, 2);
+        template.write_obj(RAM + 0x8, &0x1234u32).unwrap();
+        let (mut cow, memory, mut gpt) = clone_of(&template);
+        assert_eq!(memory.read_obj::<u32>(RAM + 0x8), Ok(0x1234));
+
+        memory.write_obj(RAM + 0xc, &0x5678u32).unwrap();
+        assert_eq!(memory.read_obj::<u32>(RAM + 0x8), Ok(0x1234));
+        assert_eq!(memory.read_obj::<u32>(RAM + 0xc), Ok(0x5678));
+        assert_eq!(template.read_obj::<u32>(RAM + 0xc), Ok(0));
+        assert_eq!(gpt.translate(RAM), Ok(host[0].0.as_ptr() as usize));
+
+        assert_eq!(cow.sync(&mut gpt), Ok(true));
+        let hpa = gpt.translate(RAM).unwrap();
+        assert_eq!(hpa, memory.gpa_to_hva(RAM).unwrap());
+        assert_eq!(gpt.flags(RAM), rw());
+        assert_eq!(cow.sync(&mut gpt), Ok(false));
+        assert_eq!(cow.handle_write_fault(&mut gpt, RAM), Ok(false));
+    }
+}
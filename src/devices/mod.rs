@@ -0,0 +1,52 @@
+mod uart16550;
+mod virtio;
+
+pub use uart16550::{Uart16550, UartAttachment, UART_COM1_IRQ, UART_COM1_PORT};
+pub use virtio::{
+    BlockBackend, ConsoleHandler, Descriptor, DescriptorChain, MemoryBlockBackend, NetBackend,
+    NetSwitch, SwitchPort, VirtioBalloon, VirtioBlock, VirtioConsole, VirtioDevice, VirtioMmio,
+    VirtioNet, VirtioVsock, Virtqueue, VsockConn, VsockHandler, ETH_ALEN,
+    VIRTIO_BALLOON_F_DEFLATE_ON_OOM, VIRTIO_BALLOON_F_REPORTING, VIRTIO_BLK_SECTOR_SIZE,
+    VIRTIO_CONSOLE_MAX_PORTS, VIRTIO_F_INDIRECT_DESC, VIRTIO_F_VERSION_1, VIRTIO_ID_BALLOON,
+    VIRTIO_ID_BLOCK, VIRTIO_ID_CONSOLE, VIRTIO_ID_NET, VIRTIO_ID_VSOCK, VIRTIO_MMIO_SIZE,
+    VIRTQUEUE_MAX_SIZE, VSOCK_HOST_CID,
+};
+
+use core::ops::Range;
+
+use crate::{GuestPhysAddr, HyperResult};
+
+/// A device model which is accessed through x86 port I/O instructions.
+pub trait PortIoDevice {
+    /// The range of I/O ports claimed by this device.
+    fn port_range(&self) -> Range<u16>;
+
+    /// Handles an `IN` instruction of `access_size` bytes from `port`.
+    fn read(&mut self, port: u16, access_size: u8) -> HyperResult<u32>;
+
+    /// Handles an `OUT` instruction of `access_size` bytes to `port`.
+    fn write(&mut self, port: u16, access_size: u8, value: u32) -> HyperResult;
+}
+
+/// A device model which is accessed through trapped guest physical memory.
+pub trait MmioDevice {
+    /// The range of guest physical addresses claimed by this device.
+    fn mmio_range(&self) -> Range<GuestPhysAddr>;
+
+    /// Handles a load of `width` bytes from `addr`.
+    fn read(&mut self, addr: GuestPhysAddr, width: usize) -> HyperResult<u64>;
+
+    /// Handles a store of `width` bytes to `addr`.
+    fn write(&mut self, addr: GuestPhysAddr, width: usize, value: u64) -> HyperResult;
+}
+
+/// A host-side byte stream connected to an emulated serial device.
+pub trait CharBackend: Send {
+    /// Writes bytes sent by the guest. Returns the number of bytes accepted,
+    /// the rest is retried later.
+    fn write(&mut self, data: &[u8]) -> usize;
+
+    /// Reads bytes for the guest into `buf` without blocking. Returns the
+    /// number of bytes read.
+    fn read(&mut self, buf: &mut [u8]) -> usize;
+}
@@ -0,0 +1,446 @@
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use core::ops::Range;
+
+use bit_field::BitField;
+
+use super::{CharBackend, MmioDevice, PortIoDevice};
+use crate::{GuestPhysAddr, HyperError, HyperResult};
+
+/// I/O port base of COM1 on PC-compatible machines.
+pub const UART_COM1_PORT: u16 = 0x3F8;
+/// ISA IRQ line of COM1 on PC-compatible machines.
+pub const UART_COM1_IRQ: usize = 4;
+
+const UART_NUM_REGS: usize = 8;
+const UART_FIFO_SIZE: usize = 16;
+
+const REG_RBR_THR: u8 = 0;
+const REG_IER: u8 = 1;
+const REG_IIR_FCR: u8 = 2;
+const REG_LCR: u8 = 3;
+const REG_MCR: u8 = 4;
+const REG_LSR: u8 = 5;
+const REG_MSR: u8 = 6;
+const REG_SCR: u8 = 7;
+
+const IER_RDA: usize = 0;
+const IER_THRE: usize = 1;
+const IER_RLS: usize = 2;
+const IER_MS: usize = 3;
+
+const IIR_NO_INT: u8 = 0x01;
+const IIR_MSR: u8 = 0x00;
+const IIR_THRE: u8 = 0x02;
+const IIR_RDA: u8 = 0x04;
+const IIR_RLS: u8 = 0x06;
+const IIR_CTI: u8 = 0x0c;
+const IIR_FIFO_ENABLED: u8 = 0xc0;
+
+const FCR_ENABLE: usize = 0;
+const FCR_CLEAR_RX: usize = 1;
+const FCR_CLEAR_TX: usize = 2;
+
+const LCR_DLAB: usize = 7;
+
+const MCR_DTR: usize = 0;
+const MCR_RTS: usize = 1;
+const MCR_OUT1: usize = 2;
+const MCR_OUT2: usize = 3;
+const MCR_LOOP: usize = 4;
+
+const LSR_DR: usize = 0;
+const LSR_OE: usize = 1;
+const LSR_THRE: usize = 5;
+const LSR_TEMT: usize = 6;
+
+const MSR_TERI: usize = 2;
+const MSR_CTS: usize = 4;
+const MSR_DSR: usize = 5;
+const MSR_RI: usize = 6;
+const MSR_DCD: usize = 7;
+
+/// How the registers of a [`Uart16550`] are mapped into the guest.
+#[derive(Debug, Clone, Copy)]
+pub enum UartAttachment {
+    /// Eight consecutive I/O ports starting at the given port (x86).
+    PortIo(u16),
+    /// A guest physical MMIO window, with register `n` at `base + (n << reg_shift)`,
+    /// matching the `reg-shift` device tree property.
+    Mmio {
+        /// Base address of the register window.
+        base: GuestPhysAddr,
+        /// log2 of the register stride.
+        reg_shift: u32,
+    },
+}
+
+/// A virtual 16550A UART with 16-byte transmit and receive FIFOs.
+///
+/// Transmitted bytes are written to a [`CharBackend`], which is also polled
+/// for received bytes by [`Uart16550::poll`]. The interrupt output is
+/// reported by [`Uart16550::irq_level`], and should be forwarded by the
+/// caller to its interrupt controller after each access or poll.
+pub struct Uart16550 {
+    attachment: UartAttachment,
+    backend: Box<dyn CharBackend>,
+    rx_fifo: VecDeque<u8>,
+    tx_fifo: VecDeque<u8>,
+    ier: u8,
+    fcr: u8,
+    lcr: u8,
+    mcr: u8,
+    lsr: u8,
+    msr: u8,
+    scr: u8,
+    dll: u8,
+    dlm: u8,
+    /// The THR empty interrupt is pending, cleared by reading IIR or writing THR.
+    thre_pending: bool,
+    /// Received data sits below the trigger level without being read.
+    timeout_pending: bool,
+}
+
+// The attachment and the backend are set up by the host.
+impl_snapshot_fields!(Uart16550 {
+    rx_fifo,
+    tx_fifo,
+    ier,
+    fcr,
+    lcr,
+    mcr,
+    lsr,
+    msr,
+    scr,
+    dll,
+    dlm,
+    thre_pending,
+    timeout_pending,
+});
+
+impl Uart16550 {
+    /// Create a UART mapped by `attachment` and connected to `backend`.
+    pub fn new(attachment: UartAttachment, backend: Box<dyn CharBackend>) -> Self {
+        let mut msr = 0u8;
+        msr.set_bit(MSR_CTS, true);
+        msr.set_bit(MSR_DSR, true);
+        msr.set_bit(MSR_DCD, true);
+        let mut lsr = 0u8;
+        lsr.set_bit(LSR_THRE, true);
+        lsr.set_bit(LSR_TEMT, true);
+        Self {
+            attachment,
+            backend,
+            rx_fifo: VecDeque::with_capacity(UART_FIFO_SIZE),
+            tx_fifo: VecDeque::with_capacity(UART_FIFO_SIZE),
+            ier: 0,
+            fcr: 0,
+            lcr: 0,
+            mcr: 0,
+            lsr,
+            msr,
+            scr: 0,
+            // 115200 baud
+            dll: 1,
+            dlm: 0,
+            thre_pending: false,
+            timeout_pending: false,
+        }
+    }
+
+    /// Returns how the registers are mapped into the guest.
+    pub fn attachment(&self) -> UartAttachment {
+        self.attachment
+    }
+
+    /// Returns the mutable reference of the [`CharBackend`].
+    pub fn backend_mut(&mut self) -> &mut dyn CharBackend {
+        self.backend.as_mut()
+    }
+
+    fn fifo_enabled(&self) -> bool {
+        self.fcr.get_bit(FCR_ENABLE)
+    }
+
+    fn rx_capacity(&self) -> usize {
+        if self.fifo_enabled() {
+            UART_FIFO_SIZE
+        } else {
+            1
+        }
+    }
+
+    fn rx_trigger_level(&self) -> usize {
+        if !self.fifo_enabled() {
+            return 1;
+        }
+        match self.fcr >> 6 {
+            0 => 1,
+            1 => 4,
+            2 => 8,
+            _ => 14,
+        }
+    }
+
+    fn is_loopback(&self) -> bool {
+        self.mcr.get_bit(MCR_LOOP)
+    }
+
+    fn receive_byte(&mut self, byte: u8) {
+        if self.rx_fifo.len() >= self.rx_capacity() {
+            self.lsr.set_bit(LSR_OE, true);
+        } else {
+            self.rx_fifo.push_back(byte);
+        }
+        self.lsr.set_bit(LSR_DR, true);
+    }
+
+    /// Move transmitted bytes to the backend (or to the receiver in loopback
+    /// mode), and fetch received bytes from the backend.
+    pub fn poll(&mut self) {
+        self.flush_tx();
+        if self.is_loopback() {
+            return;
+        }
+        let room = self.rx_capacity().saturating_sub(self.rx_fifo.len());
+        if room == 0 {
+            return;
+        }
+        let mut buf = [0u8; UART_FIFO_SIZE];
+        let count = self.backend.read(&mut buf[..room]);
+        for &byte in &buf[..count] {
+            self.receive_byte(byte);
+        }
+        // A character timeout is indicated if nothing arrived since the last poll.
+        self.timeout_pending =
+            count == 0 && !self.rx_fifo.is_empty() && self.rx_fifo.len() < self.rx_trigger_level();
+    }
+
+    fn flush_tx(&mut self) {
+        if self.tx_fifo.is_empty() {
+            return;
+        }
+        if self.is_loopback() {
+            while let Some(byte) = self.tx_fifo.pop_front() {
+                self.receive_byte(byte);
+            }
+        } else {
+            let (data, _) = self.tx_fifo.as_slices();
+            let written = self.backend.write(data);
+            self.tx_fifo.drain(..written);
+        }
+        if self.tx_fifo.is_empty() {
+            self.lsr.set_bit(LSR_THRE, true);
+            self.lsr.set_bit(LSR_TEMT, true);
+            self.thre_pending = true;
+        }
+    }
+
+    fn transmit_byte(&mut self, byte: u8) {
+        if self.tx_fifo.len() >= UART_FIFO_SIZE {
+            // Overrunning the transmitter drops the byte.
+            return;
+        }
+        self.tx_fifo.push_back(byte);
+        self.thre_pending = false;
+        self.lsr.set_bit(LSR_THRE, false);
+        self.lsr.set_bit(LSR_TEMT, false);
+        self.flush_tx();
+    }
+
+    /// The interrupt identification of the highest priority pending interrupt.
+    fn interrupt_id(&self) -> u8 {
+        if self.ier.get_bit(IER_RLS) && self.lsr.get_bit(LSR_OE) {
+            IIR_RLS
+        } else if self.ier.get_bit(IER_RDA) && self.timeout_pending {
+            IIR_CTI
+        } else if self.ier.get_bit(IER_RDA) && self.rx_fifo.len() >= self.rx_trigger_level() {
+            IIR_RDA
+        } else if self.ier.get_bit(IER_THRE) && self.thre_pending {
+            IIR_THRE
+        } else if self.ier.get_bit(IER_MS) && self.msr & 0x0f != 0 {
+            IIR_MSR
+        } else {
+            IIR_NO_INT
+        }
+    }
+
+    /// The level of the interrupt output.
+    ///
+    /// As on PC hardware, the output is gated by `OUT2` in port I/O mode.
+    pub fn irq_level(&self) -> bool {
+        let gated =
+            matches!(self.attachment, UartAttachment::PortIo(_)) && !self.mcr.get_bit(MCR_OUT2);
+        !gated && self.interrupt_id() != IIR_NO_INT
+    }
+
+    fn update_msr(&mut self, new_msr: u8) {
+        let old = self.msr;
+        // The delta bits 0, 1 and 3 track changes of CTS, DSR and DCD.
+        let mut deltas = ((old ^ new_msr) >> 4) & 0x0b;
+        // TERI is set on the trailing edge of RI.
+        deltas.set_bit(MSR_TERI, old.get_bit(MSR_RI) && !new_msr.get_bit(MSR_RI));
+        let msr = (new_msr & 0xf0) | (old & 0x0f) | deltas;
+        self.msr = msr;
+    }
+
+    fn mmio_register(&self, addr: GuestPhysAddr) -> HyperResult<u8> {
+        match self.attachment {
+            UartAttachment::Mmio { base, reg_shift } if self.mmio_range().contains(&addr) => {
+                let offset = addr - base;
+                if offset & ((1 << reg_shift) - 1) != 0 {
+                    return Err(HyperError::InvalidParam);
+                }
+                Ok((offset >> reg_shift) as u8)
+            }
+            _ => Err(HyperError::InvalidParam),
+        }
+    }
+
+    /// Read register `reg` (0-7).
+    pub fn read_register(&mut self, reg: u8) -> u8 {
+        let dlab = self.lcr.get_bit(LCR_DLAB);
+        match reg {
+            REG_RBR_THR if dlab => self.dll,
+            REG_RBR_THR => {
+                let byte = self.rx_fifo.pop_front().unwrap_or(0);
+                self.lsr.set_bit(LSR_DR, !self.rx_fifo.is_empty());
+                self.timeout_pending = false;
+                byte
+            }
+            REG_IER if dlab => self.dlm,
+            REG_IER => self.ier,
+            REG_IIR_FCR => {
+                let id = self.interrupt_id();
+                if id == IIR_THRE {
+                    self.thre_pending = false;
+                }
+                if self.fifo_enabled() {
+                    id | IIR_FIFO_ENABLED
+                } else {
+                    id
+                }
+            }
+            REG_LCR => self.lcr,
+            REG_MCR => self.mcr,
+            REG_LSR => {
+                let lsr = self.lsr;
+                // Reading LSR clears the error bits.
+                self.lsr.set_bit(LSR_OE, false);
+                lsr
+            }
+            REG_MSR => {
+                let msr = self.msr;
+                // Reading MSR clears the delta bits.
+                self.msr &= 0xf0;
+                msr
+            }
+            REG_SCR => self.scr,
+            _ => 0,
+        }
+    }
+
+    /// Write `value` to register `reg` (0-7).
+    pub fn write_register(&mut self, reg: u8, value: u8) {
+        let dlab = self.lcr.get_bit(LCR_DLAB);
+        match reg {
+            REG_RBR_THR if dlab => self.dll = value,
+            REG_RBR_THR => self.transmit_byte(value),
+            REG_IER if dlab => self.dlm = value,
+            REG_IER => {
+                let old = self.ier;
+                self.ier = value & 0x0f;
+                // Enabling the THRE interrupt with an empty THR raises it immediately.
+                if !old.get_bit(IER_THRE)
+                    && self.ier.get_bit(IER_THRE)
+                    && self.lsr.get_bit(LSR_THRE)
+                {
+                    self.thre_pending = true;
+                }
+            }
+            REG_IIR_FCR => {
+                if value.get_bit(FCR_ENABLE) != self.fifo_enabled() {
+                    // Changing the FIFO mode clears both FIFOs.
+                    self.rx_fifo.clear();
+                    self.tx_fifo.clear();
+                }
+                if value.get_bit(FCR_CLEAR_RX) {
+                    self.rx_fifo.clear();
+                    self.timeout_pending = false;
+                }
+                if value.get_bit(FCR_CLEAR_TX) {
+                    self.tx_fifo.clear();
+                    self.lsr.set_bit(LSR_THRE, true);
+                    self.lsr.set_bit(LSR_TEMT, true);
+                }
+                self.lsr.set_bit(LSR_DR, !self.rx_fifo.is_empty());
+                self.fcr = value & 0xc9;
+            }
+            REG_LCR => self.lcr = value,
+            REG_MCR => {
+                self.mcr = value & 0x1f;
+                if self.is_loopback() {
+                    // In loopback mode the modem outputs are connected to the inputs.
+                    let mut msr = 0u8;
+                    msr.set_bit(MSR_CTS, value.get_bit(MCR_RTS));
+                    msr.set_bit(MSR_DSR, value.get_bit(MCR_DTR));
+                    msr.set_bit(MSR_RI, value.get_bit(MCR_OUT1));
+                    msr.set_bit(MSR_DCD, value.get_bit(MCR_OUT2));
+                    self.update_msr(msr);
+                } else {
+                    self.update_msr(0xb0);
+                }
+            }
+            // LSR and MSR are read-only.
+            REG_LSR | REG_MSR => {}
+            REG_SCR => self.scr = value,
+            _ => {}
+        }
+    }
+}
+
+impl PortIoDevice for Uart16550 {
+    fn port_range(&self) -> Range<u16> {
+        match self.attachment {
+            UartAttachment::PortIo(base) => base..base + UART_NUM_REGS as u16,
+            UartAttachment::Mmio { .. } => 0..0,
+        }
+    }
+
+    fn read(&mut self, port: u16, access_size: u8) -> HyperResult<u32> {
+        if access_size != 1 || !self.port_range().contains(&port) {
+            return Err(HyperError::InvalidParam);
+        }
+        let reg = (port - self.port_range().start) as u8;
+        Ok(self.read_register(reg) as u32)
+    }
+
+    fn write(&mut self, port: u16, access_size: u8, value: u32) -> HyperResult {
+        if access_size != 1 || !self.port_range().contains(&port) {
+            return Err(HyperError::InvalidParam);
+        }
+        let reg = (port - self.port_range().start) as u8;
+        self.write_register(reg, value as u8);
+        Ok(())
+    }
+}
+
+impl MmioDevice for Uart16550 {
+    fn mmio_range(&self) -> Range<GuestPhysAddr> {
+        match self.attachment {
+            UartAttachment::Mmio { base, reg_shift } => base..base + (UART_NUM_REGS << reg_shift),
+            UartAttachment::PortIo(_) => 0..0,
+        }
+    }
+
+    fn read(&mut self, addr: GuestPhysAddr, _width: usize) -> HyperResult<u64> {
+        let reg = self.mmio_register(addr)?;
+        Ok(self.read_register(reg) as u64)
+    }
+
+    fn write(&mut self, addr: GuestPhysAddr, _width: usize, value: u64) -> HyperResult {
+        let reg = self.mmio_register(addr)?;
+        self.write_register(reg, value as u8);
+        Ok(())
+    }
+}
@@ -0,0 +1,359 @@
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+use super::{read_config_bytes, VirtioDevice, Virtqueue, VIRTIO_ID_BALLOON};
+use crate::memory::PAGE_SIZE_4K;
+use crate::{GuestMemory, GuestPhysAddr, HyperError, HyperResult};
+
+/// The guest may take back deflated pages when it runs out of memory.
+pub const VIRTIO_BALLOON_F_DEFLATE_ON_OOM: u64 = 1 << 2;
+/// The guest reports the free pages of its allocator on the reporting queue.
+pub const VIRTIO_BALLOON_F_REPORTING: u64 = 1 << 5;
+
+/// The page frame numbers of the inflate and deflate queues are in units of
+/// 4K, whatever the page size of the guest.
+const VIRTIO_BALLOON_PFN_SHIFT: u32 = 12;
+/// The largest number of page frame numbers in a buffer of the inflate and
+/// deflate queues, as Linux sends.
+const MAX_PFNS: usize = 256;
+
+const CONFIG_NUM_PAGES: usize = 0;
+const CONFIG_ACTUAL: usize = 4;
+const CONFIG_SIZE: usize = 8;
+
+const QUEUE_INFLATE: usize = 0;
+const QUEUE_DEFLATE: usize = 1;
+/// The reporting queue follows the deflate queue, as the statistics and free
+/// page hinting queues are not offered.
+const QUEUE_REPORTING: usize = 2;
+
+/// A virtio memory balloon, through which the host asks the guest to give
+/// pages back, and the guest reports its free pages.
+///
+/// It is plugged into a transport such as [`VirtioMmio`](super::VirtioMmio),
+/// and shared with the host through an `Arc<Mutex<_>>`. It collects the
+/// ranges given up on the inflate and reporting queues, and the host frees
+/// them with the `reclaim_pages` method of the VM. The buffers of the
+/// reporting queue are only returned to the guest, which may use the pages
+/// again afterwards, once the host has taken all the ranges. Only RAM backed
+/// on demand gives host memory back: `reclaim_pages` rejects the other
+/// ranges, so a balloon is only of use to VMs whose RAM is backed on demand.
+/// The pages the guest touches again are backed on demand anew, so
+/// deflating needs no work from the host.
+pub struct VirtioBalloon {
+    features: u64,
+    /// The number of pages the host wants the guest to give up.
+    num_pages: u32,
+    /// The number of pages the guest has given up, as it last reported.
+    actual: u32,
+    /// The ranges given up by the guest, not yet taken by the host.
+    reclaimed: VecDeque<(GuestPhysAddr, usize)>,
+    /// The chains of the reporting queue held until the host takes the
+    /// ranges.
+    held_reports: Vec<u16>,
+    config_changed: bool,
+}
+
+impl VirtioBalloon {
+    /// Create a balloon offering `features`, a set of the
+    /// `VIRTIO_BALLOON_F_*` bits, and holding no pages.
+    pub fn new(features: u64) -> Self {
+        Self {
+            features: features & (VIRTIO_BALLOON_F_DEFLATE_ON_OOM | VIRTIO_BALLOON_F_REPORTING),
+            num_pages: 0,
+            actual: 0,
+            reclaimed: VecDeque::new(),
+            held_reports: Vec::new(),
+            config_changed: false,
+        }
+    }
+
+    /// The device features offered to the guest.
+    pub fn features(&self) -> u64 {
+        self.features
+    }
+
+    /// Ask the guest to hold `num_pages` 4K pages in the balloon. The
+    /// transport signals the configuration change.
+    pub fn set_target(&mut self, num_pages: u32) {
+        self.num_pages = num_pages;
+        self.config_changed = true;
+    }
+
+    /// The number of pages the host asked the guest to hold.
+    pub fn target(&self) -> u32 {
+        self.num_pages
+    }
+
+    /// The number of pages the guest holds in the balloon, as it reported.
+    pub fn actual(&self) -> u32 {
+        self.actual
+    }
+
+    /// Read `width` bytes at `offset` of the configuration space.
+    pub fn config_read(&self, offset: usize, width: usize) -> HyperResult<u64> {
+        read_config_bytes(&self.config(), offset, width)
+    }
+
+    /// Write `width` bytes of `value` at `offset` of the configuration
+    /// space. Only `actual` is writable by the guest.
+    pub fn config_write(&mut self, offset: usize, width: usize, value: u64) -> HyperResult {
+        let mut config = self.config();
+        let bytes = config
+            .get_mut(offset..offset + width)
+            .ok_or(HyperError::InvalidParam)?;
+        bytes.copy_from_slice(&value.to_le_bytes()[..width]);
+        let actual = &config[CONFIG_ACTUAL..CONFIG_ACTUAL + 4];
+        self.actual = u32::from_le_bytes(actual.try_into().unwrap());
+        Ok(())
+    }
+
+    fn config(&self) -> [u8; CONFIG_SIZE] {
+        let mut config = [0; CONFIG_SIZE];
+        config[CONFIG_NUM_PAGES..CONFIG_NUM_PAGES + 4]
+            .copy_from_slice(&self.num_pages.to_le_bytes());
+        config[CONFIG_ACTUAL..CONFIG_ACTUAL + 4].copy_from_slice(&self.actual.to_le_bytes());
+        config
+    }
+
+    /// Handle a buffer of the inflate queue: the little-endian page frame
+    /// numbers of the pages the guest gave up.
+    pub fn inflate(&mut self, pfns: &[u8]) {
+        for pfn in pfns.chunks_exact(4) {
+            let pfn = u32::from_le_bytes(pfn.try_into().unwrap()) as usize;
+            self.give_up(pfn << VIRTIO_BALLOON_PFN_SHIFT, PAGE_SIZE_4K);
+        }
+    }
+
+    /// Handle a buffer of the deflate queue: the page frame numbers of the
+    /// pages the guest takes back. They are backed on demand when it touches
+    /// them, and are only dropped from the ranges the host has not freed yet.
+    pub fn deflate(&mut self, pfns: &[u8]) {
+        for pfn in pfns.chunks_exact(4) {
+            let pfn = u32::from_le_bytes(pfn.try_into().unwrap()) as usize;
+            self.take_back(pfn << VIRTIO_BALLOON_PFN_SHIFT, PAGE_SIZE_4K);
+        }
+    }
+
+    /// Handle a buffer of the reporting queue: the `len` free bytes of the
+    /// guest at `gpa`. The guest may use them again at any time.
+    pub fn report_free(&mut self, gpa: GuestPhysAddr, len: usize) {
+        let start = (gpa + PAGE_SIZE_4K - 1) & !(PAGE_SIZE_4K - 1);
+        let end = (gpa + len) & !(PAGE_SIZE_4K - 1);
+        if start < end {
+            self.give_up(start, end - start);
+        }
+    }
+
+    /// Whether the guest gave up ranges the host has not taken yet.
+    pub fn has_reclaimed(&self) -> bool {
+        !self.reclaimed.is_empty()
+    }
+
+    /// Take the next range given up by the guest, as its guest physical
+    /// address and size, for the host to free.
+    pub fn pop_reclaimed(&mut self) -> Option<(GuestPhysAddr, usize)> {
+        self.reclaimed.pop_front()
+    }
+
+    /// Reset the device, when the guest's driver does. The guest takes all
+    /// the pages back, so the ranges not taken by the host are dropped.
+    pub fn reset(&mut self) {
+        self.actual = 0;
+        self.reclaimed.clear();
+        self.held_reports.clear();
+    }
+
+    fn give_up(&mut self, gpa: GuestPhysAddr, size: usize) {
+        // The guest usually gives up runs of contiguous pages.
+        if let Some((last_gpa, last_size)) = self.reclaimed.back_mut() {
+            if *last_gpa + *last_size == gpa {
+                *last_size += size;
+                return;
+            }
+        }
+        self.reclaimed.push_back((gpa, size));
+    }
+
+    fn take_back(&mut self, gpa: GuestPhysAddr, size: usize) {
+        let end = gpa + size;
+        let mut index = 0;
+        while index < self.reclaimed.len() {
+            let (start, len) = self.reclaimed[index];
+            let stop = start + len;
+            if stop <= gpa || end <= start {
+                index += 1;
+                continue;
+            }
+            // Keep the parts of the range around the pages taken back.
+            self.reclaimed.remove(index);
+            if end < stop {
+                self.reclaimed.insert(index, (end, stop - end));
+            }
+            if start < gpa {
+                self.reclaimed.insert(index, (start, gpa - start));
+            }
+            index += (start < gpa) as usize + (end < stop) as usize;
+        }
+    }
+}
+
+impl VirtioDevice for VirtioBalloon {
+    fn device_type(&self) -> u32 {
+        VIRTIO_ID_BALLOON
+    }
+
+    fn features(&self) -> u64 {
+        self.features
+    }
+
+    fn num_queues(&self) -> usize {
+        if self.features & VIRTIO_BALLOON_F_REPORTING != 0 {
+            3
+        } else {
+            2
+        }
+    }
+
+    fn read_config(&self, offset: usize, width: usize) -> HyperResult<u64> {
+        self.config_read(offset, width)
+    }
+
+    fn write_config(&mut self, offset: usize, width: usize, value: u64) -> HyperResult {
+        self.config_write(offset, width, value)
+    }
+
+    fn queue_notify(
+        &mut self,
+        index: usize,
+        queues: &mut [Virtqueue],
+        memory: &GuestMemory,
+    ) -> HyperResult {
+        let queue = &mut queues[index];
+        while let Some(chain) = queue.pop(memory)? {
+            match index {
+                QUEUE_INFLATE | QUEUE_DEFLATE => match chain.read_to_vec(memory, MAX_PFNS * 4) {
+                    Ok(pfns) if index == QUEUE_INFLATE => self.inflate(&pfns),
+                    Ok(pfns) => self.deflate(&pfns),
+                    Err(HyperError::InvalidParam) => {
+                        debug!("virtio-balloon: too many page frame numbers, ignored");
+                    }
+                    Err(err) => return Err(err),
+                },
+                _ => {
+                    for desc in chain.descriptors() {
+                        self.report_free(desc.addr, desc.len as usize);
+                    }
+                    self.held_reports.push(chain.head());
+                    continue;
+                }
+            }
+            queue.add_used(memory, chain.head(), 0)?;
+        }
+        Ok(())
+    }
+
+    fn poll(&mut self, queues: &mut [Virtqueue], memory: &GuestMemory) -> HyperResult {
+        if self.has_reclaimed() {
+            return Ok(());
+        }
+        for head in self.held_reports.drain(..) {
+            queues[QUEUE_REPORTING].add_used(memory, head, 0)?;
+        }
+        Ok(())
+    }
+
+    fn take_config_change(&mut self) -> bool {
+        core::mem::take(&mut self.config_changed)
+    }
+
+    fn reset(&mut self) {
+        self.reset()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::virtio::queue::tests::Driver;
+    use crate::memory::tests::host_backed;
+
+    const RAM: GuestPhysAddr = 0x4000_0000;
+    const PFN_BUF: GuestPhysAddr = RAM + 0x1000;
+
+    fn pfns(pfns: &[u32]) -> Vec<u8> {
+        pfns.iter().flat_map(|pfn| pfn.to_le_bytes()).collect()
+    }
+
+    fn reclaimed(balloon: &mut VirtioBalloon) -> Vec<(GuestPhysAddr, usize)> {
+        core::iter::from_fn(|| balloon.pop_reclaimed()).collect()
+    }
+
+    #[test]
+    fn config_holds_target_and_actual() {
+        let mut balloon = VirtioBalloon::new(VIRTIO_BALLOON_F_DEFLATE_ON_OOM);
+        assert!(!balloon.take_config_change());
+        balloon.set_target(16);
+        assert!(balloon.take_config_change());
+        assert_eq!(balloon.read_config(CONFIG_NUM_PAGES, 4), Ok(16));
+
+        // Only the guest's count is writable.
+        balloon.write_config(CONFIG_NUM_PAGES, 4, 1).unwrap();
+        balloon.write_config(CONFIG_ACTUAL, 4, 8).unwrap();
+        assert_eq!(balloon.target(), 16);
+        assert_eq!(balloon.actual(), 8);
+        assert_eq!(balloon.read_config(0, 8), Ok(8 << 32 | 16));
+        assert!(balloon.write_config(CONFIG_SIZE - 2, 4, 0).is_err());
+    }
+
+    #[test]
+    fn deflated_pages_are_kept_out_of_the_ranges() {
+        let mut balloon = VirtioBalloon::new(0);
+        balloon.inflate(&pfns(&[0x40000, 0x40001, 0x40002, 0x40005]));
+        balloon.deflate(&pfns(&[0x40001, 0x40005, 0x40009]));
+        assert_eq!(
+            reclaimed(&mut balloon),
+            [(0x4000_0000, 0x1000), (0x4000_2000, 0x1000)]
+        );
+    }
+
+    #[test]
+    fn inflated_pages_come_through_the_queue() {
+        let (_host, memory) = host_backed(RAM, 2);
+        let mut driver = Driver::new(RAM);
+        let mut queues = [driver.queue(), Driver::new(RAM + 0x400).queue()];
+        let mut balloon = VirtioBalloon::new(0);
+        let buf = pfns(&[0x40000, 0x40001]);
+        memory.write(PFN_BUF, &buf).unwrap();
+        driver.add(&memory, &[(PFN_BUF, buf.len() as u32, false)]);
+        balloon
+            .queue_notify(QUEUE_INFLATE, &mut queues, &memory)
+            .unwrap();
+        assert_eq!(driver.used(&memory).map(|(_, len)| len), Some(0));
+        assert_eq!(reclaimed(&mut balloon), [(0x4000_0000, 0x2000)]);
+    }
+
+    #[test]
+    fn reports_are_held_until_the_ranges_are_taken() {
+        let (_host, memory) = host_backed(RAM, 4);
+        let mut driver = Driver::new(RAM + 0x800);
+        let mut queues = [
+            Driver::new(RAM).queue(),
+            Driver::new(RAM + 0x400).queue(),
+            driver.queue(),
+        ];
+        let mut balloon = VirtioBalloon::new(VIRTIO_BALLOON_F_REPORTING);
+        assert_eq!(balloon.num_queues(), 3);
+        // Only the whole pages are given up.
+        driver.add(&memory, &[(RAM + 0x1800, 0x2800, true)]);
+        balloon
+            .queue_notify(QUEUE_REPORTING, &mut queues, &memory)
+            .unwrap();
+        balloon.poll(&mut queues, &memory).unwrap();
+        assert_eq!(driver.used(&memory), None);
+
+        assert_eq!(reclaimed(&mut balloon), [(RAM + 0x2000, 0x2000)]);
+        balloon.poll(&mut queues, &memory).unwrap();
+        assert_eq!(driver.used(&memory).map(|(_, len)| len), Some(0));
+    }
+}
@@ -0,0 +1,500 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use super::{read_config_bytes, DescriptorChain, VirtioDevice, Virtqueue, VIRTIO_ID_BLOCK};
+use crate::{GuestMemory, HyperError, HyperResult};
+
+/// The unit of the sector numbers of virtio-blk requests.
+pub const VIRTIO_BLK_SECTOR_SIZE: usize = 512;
+
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+const VIRTIO_BLK_F_DISCARD: u64 = 1 << 13;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+const VIRTIO_BLK_T_DISCARD: u32 = 11;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+/// The length of the device ID returned by `VIRTIO_BLK_T_GET_ID`.
+const VIRTIO_BLK_ID_BYTES: usize = 20;
+
+/// The request header: type, reserved and sector.
+const HEADER_SIZE: usize = 16;
+/// A discard segment: sector, number of sectors and flags.
+const DISCARD_SEGMENT_SIZE: usize = 16;
+const MAX_DISCARD_SEGMENTS: u32 = 16;
+/// Reads and writes are copied through a buffer of at most this size, as
+/// their length is chosen by the guest.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+const CONFIG_CAPACITY: usize = 0;
+const CONFIG_MAX_DISCARD_SECTORS: usize = 36;
+const CONFIG_MAX_DISCARD_SEG: usize = 40;
+const CONFIG_DISCARD_SECTOR_ALIGNMENT: usize = 44;
+const CONFIG_SIZE: usize = 48;
+
+/// The host storage behind a [`VirtioBlock`], such as a RAM disk, a file or
+/// a real disk. Offsets and lengths are in units of
+/// [`VIRTIO_BLK_SECTOR_SIZE`], and requests are within the capacity.
+pub trait BlockBackend: Send {
+    /// The capacity, in sectors.
+    fn num_sectors(&self) -> u64;
+
+    /// Whether the guest may not write.
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    /// Read the sectors from `sector` into `buf`.
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> HyperResult;
+
+    /// Write `data` to the sectors from `sector`.
+    fn write(&mut self, sector: u64, data: &[u8]) -> HyperResult;
+
+    /// Make the completed writes durable.
+    fn flush(&mut self) -> HyperResult {
+        Ok(())
+    }
+
+    /// Whether [`BlockBackend::discard`] is implemented.
+    fn supports_discard(&self) -> bool {
+        false
+    }
+
+    /// Drop the contents of `num_sectors` sectors from `sector`, which read
+    /// back as unspecified data afterwards.
+    fn discard(&mut self, sector: u64, num_sectors: u64) -> HyperResult {
+        let _ = (sector, num_sectors);
+        Err(HyperError::NotSupported)
+    }
+}
+
+/// A [`BlockBackend`] holding the disk in host memory. Discarded sectors
+/// read back as zeros.
+pub struct MemoryBlockBackend {
+    data: Vec<u8>,
+}
+
+impl MemoryBlockBackend {
+    /// Create a zeroed disk of `num_sectors` sectors.
+    pub fn new(num_sectors: usize) -> Self {
+        Self {
+            data: vec![0; num_sectors * VIRTIO_BLK_SECTOR_SIZE],
+        }
+    }
+
+    /// Create a disk holding `data`, which is padded with zeros to a whole
+    /// number of sectors.
+    pub fn from_vec(mut data: Vec<u8>) -> Self {
+        let len = data.len().next_multiple_of(VIRTIO_BLK_SECTOR_SIZE);
+        data.resize(len, 0);
+        Self { data }
+    }
+
+    /// The contents of the disk.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn range(&self, sector: u64, len: usize) -> HyperResult<core::ops::Range<usize>> {
+        let start = (sector as usize)
+            .checked_mul(VIRTIO_BLK_SECTOR_SIZE)
+            .ok_or(HyperError::OutOfRange)?;
+        let end = start.checked_add(len).ok_or(HyperError::OutOfRange)?;
+        if end > self.data.len() {
+            return Err(HyperError::OutOfRange);
+        }
+        Ok(start..end)
+    }
+}
+
+impl BlockBackend for MemoryBlockBackend {
+    fn num_sectors(&self) -> u64 {
+        (self.data.len() / VIRTIO_BLK_SECTOR_SIZE) as u64
+    }
+
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> HyperResult {
+        let range = self.range(sector, buf.len())?;
+        buf.copy_from_slice(&self.data[range]);
+        Ok(())
+    }
+
+    fn write(&mut self, sector: u64, data: &[u8]) -> HyperResult {
+        let range = self.range(sector, data.len())?;
+        self.data[range].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn supports_discard(&self) -> bool {
+        true
+    }
+
+    fn discard(&mut self, sector: u64, num_sectors: u64) -> HyperResult {
+        let len = (num_sectors as usize)
+            .checked_mul(VIRTIO_BLK_SECTOR_SIZE)
+            .ok_or(HyperError::OutOfRange)?;
+        let range = self.range(sector, len)?;
+        self.data[range].fill(0);
+        Ok(())
+    }
+}
+
+/// A virtio block device, with one request queue served synchronously by a
+/// [`BlockBackend`].
+///
+/// It implements reads, writes, flushes, discards if the backend supports
+/// them, and returns its device ID. Requests outside of the disk or failed
+/// by the backend complete with an I/O error.
+pub struct VirtioBlock {
+    backend: Box<dyn BlockBackend>,
+    id: [u8; VIRTIO_BLK_ID_BYTES],
+}
+
+impl VirtioBlock {
+    /// Create a block device on `backend`, whose device ID is `id`,
+    /// truncated to 20 bytes.
+    pub fn new(backend: Box<dyn BlockBackend>, id: &str) -> Self {
+        let mut bytes = [0; VIRTIO_BLK_ID_BYTES];
+        let len = id.len().min(VIRTIO_BLK_ID_BYTES);
+        bytes[..len].copy_from_slice(&id.as_bytes()[..len]);
+        Self { backend, id: bytes }
+    }
+
+    /// The storage behind the disk, e.g. to inspect what the guest wrote.
+    pub fn backend_mut(&mut self) -> &mut dyn BlockBackend {
+        self.backend.as_mut()
+    }
+
+    fn config(&self) -> [u8; CONFIG_SIZE] {
+        let mut config = [0; CONFIG_SIZE];
+        let mut put = |offset: usize, bytes: &[u8]| {
+            config[offset..offset + bytes.len()].copy_from_slice(bytes);
+        };
+        let capacity = self.backend.num_sectors();
+        put(CONFIG_CAPACITY, &capacity.to_le_bytes());
+        if self.backend.supports_discard() {
+            let max_sectors = capacity.min(u32::MAX as u64) as u32;
+            put(CONFIG_MAX_DISCARD_SECTORS, &max_sectors.to_le_bytes());
+            put(CONFIG_MAX_DISCARD_SEG, &MAX_DISCARD_SEGMENTS.to_le_bytes());
+            put(CONFIG_DISCARD_SECTOR_ALIGNMENT, &1u32.to_le_bytes());
+        }
+        config
+    }
+
+    /// Check that the `len` bytes at `sector` are within the disk and a
+    /// whole number of sectors.
+    fn check_range(&self, sector: u64, len: usize) -> HyperResult<u64> {
+        if len % VIRTIO_BLK_SECTOR_SIZE != 0 {
+            return Err(HyperError::InvalidParam);
+        }
+        let num_sectors = (len / VIRTIO_BLK_SECTOR_SIZE) as u64;
+        match sector.checked_add(num_sectors) {
+            Some(end) if end <= self.backend.num_sectors() => Ok(num_sectors),
+            _ => Err(HyperError::OutOfRange),
+        }
+    }
+
+    /// Serve the request of `chain`. Returns the number of bytes written to
+    /// its data buffers.
+    fn execute(
+        &mut self,
+        request: u32,
+        sector: u64,
+        chain: &DescriptorChain,
+        memory: &GuestMemory,
+    ) -> HyperResult<usize> {
+        // The last writable byte is the status.
+        let in_len = chain.writable_len() - 1;
+        let out_len = chain.readable_len() - HEADER_SIZE;
+        match request {
+            VIRTIO_BLK_T_IN => {
+                self.check_range(sector, in_len)?;
+                let mut buf = vec![0; in_len.min(CHUNK_SIZE)];
+                let mut done = 0;
+                while done < in_len {
+                    let chunk = &mut buf[..(in_len - done).min(CHUNK_SIZE)];
+                    let chunk_sector = sector + (done / VIRTIO_BLK_SECTOR_SIZE) as u64;
+                    self.backend.read(chunk_sector, chunk)?;
+                    done += chain.write(memory, done, chunk)?;
+                }
+                Ok(done)
+            }
+            VIRTIO_BLK_T_OUT => {
+                if self.backend.read_only() {
+                    return Err(HyperError::Disabled);
+                }
+                self.check_range(sector, out_len)?;
+                let mut buf = vec![0; out_len.min(CHUNK_SIZE)];
+                let mut done = 0;
+                while done < out_len {
+                    let chunk = &mut buf[..(out_len - done).min(CHUNK_SIZE)];
+                    chain.read(memory, HEADER_SIZE + done, chunk)?;
+                    let chunk_sector = sector + (done / VIRTIO_BLK_SECTOR_SIZE) as u64;
+                    self.backend.write(chunk_sector, chunk)?;
+                    done += chunk.len();
+                }
+                Ok(0)
+            }
+            VIRTIO_BLK_T_FLUSH => {
+                self.backend.flush()?;
+                Ok(0)
+            }
+            VIRTIO_BLK_T_GET_ID => chain.write(memory, 0, &self.id[..in_len.min(self.id.len())]),
+            VIRTIO_BLK_T_DISCARD if self.backend.supports_discard() => {
+                if self.backend.read_only() {
+                    return Err(HyperError::Disabled);
+                }
+                let count = out_len / DISCARD_SEGMENT_SIZE;
+                if count == 0 || count > MAX_DISCARD_SEGMENTS as usize {
+                    return Err(HyperError::InvalidParam);
+                }
+                for i in 0..count {
+                    let mut segment = [0u8; DISCARD_SEGMENT_SIZE];
+                    chain.read(memory, HEADER_SIZE + i * DISCARD_SEGMENT_SIZE, &mut segment)?;
+                    let sector = u64::from_le_bytes(segment[0..8].try_into().unwrap());
+                    let num_sectors = u32::from_le_bytes(segment[8..12].try_into().unwrap());
+                    let len = num_sectors as usize * VIRTIO_BLK_SECTOR_SIZE;
+                    self.check_range(sector, len)?;
+                    self.backend.discard(sector, num_sectors as u64)?;
+                }
+                Ok(0)
+            }
+            _ => Err(HyperError::NotSupported),
+        }
+    }
+}
+
+impl VirtioDevice for VirtioBlock {
+    fn device_type(&self) -> u32 {
+        VIRTIO_ID_BLOCK
+    }
+
+    fn features(&self) -> u64 {
+        let mut features = VIRTIO_BLK_F_FLUSH;
+        if self.backend.read_only() {
+            features |= VIRTIO_BLK_F_RO;
+        }
+        if self.backend.supports_discard() {
+            features |= VIRTIO_BLK_F_DISCARD;
+        }
+        features
+    }
+
+    fn num_queues(&self) -> usize {
+        1
+    }
+
+    fn read_config(&self, offset: usize, width: usize) -> HyperResult<u64> {
+        read_config_bytes(&self.config(), offset, width)
+    }
+
+    fn queue_notify(
+        &mut self,
+        index: usize,
+        queues: &mut [Virtqueue],
+        memory: &GuestMemory,
+    ) -> HyperResult {
+        let queue = &mut queues[index];
+        while let Some(chain) = queue.pop(memory)? {
+            let mut header = [0u8; HEADER_SIZE];
+            if chain.read(memory, 0, &mut header)? < HEADER_SIZE || chain.writable_len() == 0 {
+                return Err(HyperError::InvalidParam);
+            }
+            let request = u32::from_le_bytes(header[0..4].try_into().unwrap());
+            let sector = u64::from_le_bytes(header[8..16].try_into().unwrap());
+            let (status, len) = match self.execute(request, sector, &chain, memory) {
+                Ok(len) => (VIRTIO_BLK_S_OK, len),
+                Err(HyperError::NotSupported) => (VIRTIO_BLK_S_UNSUPP, 0),
+                Err(err) => {
+                    debug!(
+                        "virtio-blk request {} at sector {} failed: {:?}",
+                        request, sector, err
+                    );
+                    (VIRTIO_BLK_S_IOERR, 0)
+                }
+            };
+            chain.write(memory, chain.writable_len() - 1, &[status])?;
+            queue.add_used(memory, chain.head(), len as u32 + 1)?;
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::virtio::queue::tests::Driver;
+    use crate::memory::tests::{host_backed, HostPage};
+    use crate::GuestPhysAddr;
+
+    const RAM: GuestPhysAddr = 0x4000_0000;
+    const HEADER: GuestPhysAddr = RAM + 0x1000;
+    const STATUS: GuestPhysAddr = RAM + 0x1100;
+    const DATA: GuestPhysAddr = RAM + 0x2000;
+
+    /// A disk of 8 sectors in host memory, with a guest driving its queue.
+    struct Disk {
+        _host: Vec<HostPage>,
+        memory: GuestMemory,
+        driver: Driver,
+        queues: [Virtqueue; 1],
+        block: VirtioBlock,
+    }
+
+    impl Disk {
+        fn new() -> Self {
+            let (host, memory) = host_backed(RAM, 4);
+            let driver = Driver::new(RAM);
+            let queues = [driver.queue()];
+            let block = VirtioBlock::new(Box::new(MemoryBlockBackend::new(8)), "disk0");
+            Self {
+                _host: host,
+                memory,
+                driver,
+                queues,
+                block,
+            }
+        }
+
+        /// Submit a request with `len` bytes of data at `DATA`, written by the
+        /// device if `device_writes`. Returns the status and the number of
+        /// bytes written.
+        fn request(
+            &mut self,
+            request: u32,
+            sector: u64,
+            len: u32,
+            device_writes: bool,
+        ) -> (u8, u32) {
+            let memory = &self.memory;
+            memory.write_obj(HEADER, &request.to_le()).unwrap();
+            memory.write_obj(HEADER + 8, &sector.to_le()).unwrap();
+            let mut buffers = vec![(HEADER, HEADER_SIZE as u32, false)];
+            if len != 0 {
+                buffers.push((DATA, len, device_writes));
+            }
+            buffers.push((STATUS, 1, true));
+            let head = self.driver.add(memory, &buffers);
+            self.block
+                .queue_notify(0, &mut self.queues, memory)
+                .unwrap();
+            let (used_head, written) = self.driver.used(memory).unwrap();
+            assert_eq!(used_head, head);
+            (memory.read_obj(STATUS).unwrap(), written)
+        }
+    }
+
+    #[test]
+    fn write_then_read() {
+        let mut disk = Disk::new();
+        let data: Vec<u8> = (0..1024).map(|i| i as u8).collect();
+        disk.memory.write(DATA, &data).unwrap();
+        assert_eq!(
+            disk.request(VIRTIO_BLK_T_OUT, 3, 1024, false),
+            (VIRTIO_BLK_S_OK, 1)
+        );
+
+        disk.memory.write(DATA, &[0; 1024]).unwrap();
+        assert_eq!(
+            disk.request(VIRTIO_BLK_T_IN, 3, 1024, true),
+            (VIRTIO_BLK_S_OK, 1025)
+        );
+        let mut read = vec![0; 1024];
+        disk.memory.read(DATA, &mut read).unwrap();
+        assert_eq!(read, data);
+    }
+
+    #[test]
+    fn flush_and_get_id() {
+        let mut disk = Disk::new();
+        assert_eq!(
+            disk.request(VIRTIO_BLK_T_FLUSH, 0, 0, false),
+            (VIRTIO_BLK_S_OK, 1)
+        );
+
+        let id_len = VIRTIO_BLK_ID_BYTES as u32;
+        assert_eq!(
+            disk.request(VIRTIO_BLK_T_GET_ID, 0, id_len, true),
+            (VIRTIO_BLK_S_OK, id_len + 1)
+        );
+        let mut id = [0xff; VIRTIO_BLK_ID_BYTES];
+        disk.memory.read(DATA, &mut id).unwrap();
+        assert_eq!(&id[..6], b"disk0\0");
+    }
+
+    #[test]
+    fn discard_zeroes_sectors() {
+        let mut disk = Disk::new();
+        disk.memory.write(DATA, &[0xaa; 1024]).unwrap();
+        assert_eq!(
+            disk.request(VIRTIO_BLK_T_OUT, 0, 1024, false),
+            (VIRTIO_BLK_S_OK, 1)
+        );
+
+        // Discard the second sector only.
+        disk.memory.write_obj(DATA, &1u64.to_le()).unwrap();
+        disk.memory.write_obj(DATA + 8, &1u32.to_le()).unwrap();
+        disk.memory.write_obj(DATA + 12, &0u32).unwrap();
+        let segment_len = DISCARD_SEGMENT_SIZE as u32;
+        assert_eq!(
+            disk.request(VIRTIO_BLK_T_DISCARD, 0, segment_len, false),
+            (VIRTIO_BLK_S_OK, 1)
+        );
+
+        assert_eq!(
+            disk.request(VIRTIO_BLK_T_IN, 0, 1024, true),
+            (VIRTIO_BLK_S_OK, 1025)
+        );
+        let mut read = vec![0; 1024];
+        disk.memory.read(DATA, &mut read).unwrap();
+        assert!(read[..512].iter().all(|&byte| byte == 0xaa));
+        assert!(read[512..].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn bad_requests_fail() {
+        let mut disk = Disk::new();
+        // Past the end of the disk.
+        assert_eq!(
+            disk.request(VIRTIO_BLK_T_IN, 7, 1024, true),
+            (VIRTIO_BLK_S_IOERR, 1)
+        );
+        assert_eq!(
+            disk.request(VIRTIO_BLK_T_OUT, 8, 512, false),
+            (VIRTIO_BLK_S_IOERR, 1)
+        );
+        assert_eq!(
+            disk.request(VIRTIO_BLK_T_IN, u64::MAX, 512, true),
+            (VIRTIO_BLK_S_IOERR, 1)
+        );
+        // Not a whole number of sectors.
+        assert_eq!(
+            disk.request(VIRTIO_BLK_T_OUT, 0, 100, false),
+            (VIRTIO_BLK_S_IOERR, 1)
+        );
+        // An unknown request.
+        assert_eq!(disk.request(42, 0, 0, false), (VIRTIO_BLK_S_UNSUPP, 1));
+    }
+
+    #[test]
+    fn memory_backend_out_of_range() {
+        let mut backend = MemoryBlockBackend::from_vec(vec![1; 1000]);
+        assert_eq!(backend.num_sectors(), 2);
+        let mut buf = [0; 512];
+        assert_eq!(backend.read(1, &mut buf), Ok(()));
+        assert_eq!(&buf[..488], &[1; 488]);
+        assert_eq!(buf[488..], [0; 24]);
+        assert_eq!(backend.read(2, &mut buf), Err(HyperError::OutOfRange));
+        assert_eq!(backend.write(1, &[0; 1024]), Err(HyperError::OutOfRange));
+        assert_eq!(backend.discard(u64::MAX, 1), Err(HyperError::OutOfRange));
+        assert_eq!(backend.discard(0, u64::MAX), Err(HyperError::OutOfRange));
+    }
+}
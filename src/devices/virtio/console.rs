@@ -0,0 +1,505 @@
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::{read_config_bytes, VirtioDevice, Virtqueue, VIRTIO_ID_CONSOLE};
+use crate::{GuestMemory, HyperError, HyperResult};
+
+const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 1 << 1;
+const VIRTIO_CONSOLE_F_EMERG_WRITE: u64 = 1 << 2;
+
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
+
+/// A control message: port id, event and value.
+const CONTROL_SIZE: usize = 8;
+
+/// The largest number of ports of a [`VirtioConsole`], including the console
+/// port 0.
+pub const VIRTIO_CONSOLE_MAX_PORTS: u32 = 16;
+/// The input of each port the host may queue for the guest.
+const PORT_INPUT_SIZE: usize = 64 * 1024;
+/// The output of the guest is passed to the handler in pieces of at most
+/// this size.
+const OUTPUT_CHUNK_SIZE: usize = 4096;
+
+const QUEUE_CONTROL_RX: usize = 2;
+const QUEUE_CONTROL_TX: usize = 3;
+
+const CONFIG_MAX_NR_PORTS: usize = 4;
+const CONFIG_EMERG_WR: usize = 8;
+const CONFIG_SIZE: usize = 12;
+
+/// The host side of a [`VirtioConsole`], called with what the guest does on
+/// its ports.
+pub trait ConsoleHandler: Send {
+    /// The guest wrote `data` to port `port`.
+    fn on_data(&mut self, port: u32, data: &[u8]);
+
+    /// The guest opened or closed port `port`.
+    fn on_open(&mut self, port: u32, open: bool) {
+        let _ = (port, open);
+    }
+}
+
+struct Port {
+    name: String,
+    /// The bytes of the host not received by the guest yet.
+    input: VecDeque<u8>,
+    /// Whether a program of the guest has the port open.
+    guest_open: bool,
+}
+
+/// A virtio console with several ports, connecting host tools to programs
+/// of the guest, e.g. an agent answering health checks.
+///
+/// Port 0 is the console of the guest. The other ports, added with
+/// [`VirtioConsole::add_port`], appear in the guest under their name, e.g.
+/// as `/dev/virtio-ports/<name>` on Linux, when its driver supports several
+/// ports. The output of the guest is passed to a [`ConsoleHandler`], and the
+/// host sends input with [`VirtioConsole::send`], which the guest receives
+/// once it opens the port.
+pub struct VirtioConsole {
+    ports: Vec<Port>,
+    handler: Box<dyn ConsoleHandler>,
+    /// Whether the driver accepted several ports.
+    multiport: bool,
+    /// The control messages not received by the guest yet.
+    control: VecDeque<Vec<u8>>,
+    /// Whether the driver is ready for the ports to be added.
+    ready: bool,
+}
+
+impl VirtioConsole {
+    /// Create a console with only the console port, whose output goes to
+    /// `handler`.
+    pub fn new(handler: Box<dyn ConsoleHandler>) -> Self {
+        Self {
+            ports: vec![Port {
+                name: String::new(),
+                input: VecDeque::new(),
+                guest_open: true,
+            }],
+            handler,
+            multiport: false,
+            control: VecDeque::new(),
+            ready: false,
+        }
+    }
+
+    /// Add a port named `name`, and return its number. It is announced to the
+    /// guest at once if its driver is ready.
+    pub fn add_port(&mut self, name: &str) -> HyperResult<u32> {
+        let id = self.ports.len() as u32;
+        if id >= VIRTIO_CONSOLE_MAX_PORTS {
+            return Err(HyperError::OutOfRange);
+        }
+        self.ports.push(Port {
+            name: String::from(name),
+            input: VecDeque::new(),
+            guest_open: false,
+        });
+        if self.ready {
+            self.push_control(id, VIRTIO_CONSOLE_DEVICE_ADD, 1, &[]);
+        }
+        Ok(id)
+    }
+
+    /// Whether a program of the guest has port `port` open. The console port
+    /// is always open.
+    pub fn is_open(&self, port: u32) -> bool {
+        self.ports
+            .get(port as usize)
+            .is_some_and(|port| port.guest_open)
+    }
+
+    /// Queue `data` for the guest on port `port`. Returns the number of bytes
+    /// accepted, which is short when the input of the port is full.
+    pub fn send(&mut self, port: u32, data: &[u8]) -> HyperResult<usize> {
+        let port = self
+            .ports
+            .get_mut(port as usize)
+            .ok_or(HyperError::NotFound)?;
+        let len = data.len().min(PORT_INPUT_SIZE - port.input.len());
+        port.input.extend(&data[..len]);
+        Ok(len)
+    }
+
+    fn push_control(&mut self, id: u32, event: u16, value: u16, extra: &[u8]) {
+        let mut msg = Vec::with_capacity(CONTROL_SIZE + extra.len());
+        msg.extend_from_slice(&id.to_le_bytes());
+        msg.extend_from_slice(&event.to_le_bytes());
+        msg.extend_from_slice(&value.to_le_bytes());
+        msg.extend_from_slice(extra);
+        self.control.push_back(msg);
+    }
+
+    /// The port and direction of the virtqueue `index`.
+    fn port_of_queue(&self, index: usize) -> Option<(u32, bool)> {
+        let port = match index {
+            0 | 1 => 0,
+            QUEUE_CONTROL_RX | QUEUE_CONTROL_TX => return None,
+            _ if self.multiport => index / 2 - 1,
+            _ => return None,
+        };
+        Some((port as u32, index % 2 == 1))
+    }
+
+    /// The receive virtqueue of port `port`.
+    fn rx_queue(port: usize) -> usize {
+        match port {
+            0 => 0,
+            _ => 2 + port * 2,
+        }
+    }
+
+    /// Handle a control message of the driver.
+    fn control_message(&mut self, msg: &[u8]) {
+        if msg.len() < CONTROL_SIZE {
+            return;
+        }
+        let id = u32::from_le_bytes(msg[0..4].try_into().unwrap());
+        let event = u16::from_le_bytes(msg[4..6].try_into().unwrap());
+        let value = u16::from_le_bytes(msg[6..8].try_into().unwrap());
+        match event {
+            VIRTIO_CONSOLE_DEVICE_READY if value == 1 => {
+                self.ready = true;
+                for id in 0..self.ports.len() as u32 {
+                    self.push_control(id, VIRTIO_CONSOLE_DEVICE_ADD, 1, &[]);
+                }
+            }
+            VIRTIO_CONSOLE_PORT_READY if value == 1 && (id as usize) < self.ports.len() => {
+                if id == 0 {
+                    self.push_control(id, VIRTIO_CONSOLE_CONSOLE_PORT, 1, &[]);
+                }
+                let name = self.ports[id as usize].name.clone();
+                if !name.is_empty() {
+                    self.push_control(id, VIRTIO_CONSOLE_PORT_NAME, 1, name.as_bytes());
+                }
+                // The host side is always connected.
+                self.push_control(id, VIRTIO_CONSOLE_PORT_OPEN, 1, &[]);
+            }
+            VIRTIO_CONSOLE_PORT_OPEN => {
+                if let Some(port) = self.ports.get_mut(id as usize) {
+                    port.guest_open = value == 1;
+                    self.handler.on_open(id, value == 1);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Copy the pending control messages and input to the receive queues.
+    fn deliver(&mut self, queues: &mut [Virtqueue], memory: &GuestMemory) -> HyperResult {
+        if self.multiport {
+            let queue = &mut queues[QUEUE_CONTROL_RX];
+            while let Some(msg) = self.control.front() {
+                let Some(chain) = queue.pop(memory)? else {
+                    break;
+                };
+                let len = chain.write(memory, 0, msg)?;
+                queue.add_used(memory, chain.head(), len as u32)?;
+                self.control.pop_front();
+            }
+        }
+        let nr_ports = if self.multiport { self.ports.len() } else { 1 };
+        for (id, port) in self.ports[..nr_ports].iter_mut().enumerate() {
+            let queue = &mut queues[Self::rx_queue(id)];
+            while port.guest_open && !port.input.is_empty() {
+                let Some(chain) = queue.pop(memory)? else {
+                    break;
+                };
+                let len = chain.write(memory, 0, port.input.make_contiguous())?;
+                port.input.drain(..len);
+                queue.add_used(memory, chain.head(), len as u32)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl VirtioDevice for VirtioConsole {
+    fn device_type(&self) -> u32 {
+        VIRTIO_ID_CONSOLE
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_CONSOLE_F_MULTIPORT | VIRTIO_CONSOLE_F_EMERG_WRITE
+    }
+
+    fn num_queues(&self) -> usize {
+        2 + 2 * VIRTIO_CONSOLE_MAX_PORTS as usize
+    }
+
+    fn read_config(&self, offset: usize, width: usize) -> HyperResult<u64> {
+        let mut config = [0u8; CONFIG_SIZE];
+        config[CONFIG_MAX_NR_PORTS..CONFIG_MAX_NR_PORTS + 4]
+            .copy_from_slice(&VIRTIO_CONSOLE_MAX_PORTS.to_le_bytes());
+        read_config_bytes(&config, offset, width)
+    }
+
+    fn write_config(&mut self, offset: usize, width: usize, value: u64) -> HyperResult {
+        // An emergency write puts one character to the console, even before
+        // the driver is ready.
+        if offset == CONFIG_EMERG_WR && width == 4 {
+            self.handler.on_data(0, &[value as u8]);
+        }
+        Ok(())
+    }
+
+    fn activate(&mut self, driver_features: u64) -> HyperResult {
+        self.multiport = driver_features & VIRTIO_CONSOLE_F_MULTIPORT != 0;
+        Ok(())
+    }
+
+    fn queue_notify(
+        &mut self,
+        index: usize,
+        queues: &mut [Virtqueue],
+        memory: &GuestMemory,
+    ) -> HyperResult {
+        if index == QUEUE_CONTROL_TX && self.multiport {
+            let queue = &mut queues[index];
+            while let Some(chain) = queue.pop(memory)? {
+                let mut msg = [0; CONTROL_SIZE];
+                let len = chain.read(memory, 0, &mut msg)?;
+                queue.add_used(memory, chain.head(), 0)?;
+                self.control_message(&msg[..len]);
+            }
+        } else if let Some((port, true)) = self.port_of_queue(index) {
+            let queue = &mut queues[index];
+            let mut buf = [0; OUTPUT_CHUNK_SIZE];
+            while let Some(chain) = queue.pop(memory)? {
+                let mut offset = 0;
+                while (port as usize) < self.ports.len() {
+                    let len = chain.read(memory, offset, &mut buf)?;
+                    if len == 0 {
+                        break;
+                    }
+                    self.handler.on_data(port, &buf[..len]);
+                    offset += len;
+                }
+                queue.add_used(memory, chain.head(), 0)?;
+            }
+        }
+        self.deliver(queues, memory)
+    }
+
+    fn poll(&mut self, queues: &mut [Virtqueue], memory: &GuestMemory) -> HyperResult {
+        self.deliver(queues, memory)
+    }
+
+    fn reset(&mut self) {
+        self.multiport = false;
+        self.ready = false;
+        self.control.clear();
+        for (id, port) in self.ports.iter_mut().enumerate() {
+            port.guest_open = id == 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::sync::Arc;
+    use spin::Mutex;
+
+    use super::*;
+    use crate::devices::virtio::queue::tests::Driver;
+    use crate::memory::tests::{host_backed, HostPage};
+    use crate::GuestPhysAddr;
+
+    const RAM: GuestPhysAddr = 0x4000_0000;
+    const TX_BUF: GuestPhysAddr = RAM + 0x1000;
+    /// The receive buffers, one for each queue.
+    const RX_BUFS: GuestPhysAddr = RAM + 0x3000;
+    const RX_BUF_SIZE: u32 = 0x100;
+
+    const QUEUE_PORT1_RX: usize = 4;
+    const QUEUE_PORT1_TX: usize = 5;
+
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        Data(u32, Vec<u8>),
+        Open(u32, bool),
+    }
+
+    struct Recorder(Arc<Mutex<Vec<Event>>>);
+
+    impl ConsoleHandler for Recorder {
+        fn on_data(&mut self, port: u32, data: &[u8]) {
+            self.0.lock().push(Event::Data(port, data.to_vec()));
+        }
+
+        fn on_open(&mut self, port: u32, open: bool) {
+            self.0.lock().push(Event::Open(port, open));
+        }
+    }
+
+    /// A console with port 1 named "agent", driven by a guest which set up
+    /// the control queues and the queues of port 1.
+    struct Guest {
+        _host: Vec<HostPage>,
+        memory: GuestMemory,
+        drivers: [Driver; 4],
+        /// Whether a buffer is available to the device on each queue.
+        posted: [bool; 4],
+        queues: Vec<Virtqueue>,
+        console: VirtioConsole,
+        events: Arc<Mutex<Vec<Event>>>,
+    }
+
+    impl Guest {
+        fn new() -> Self {
+            let (host, memory) = host_backed(RAM, 4);
+            let drivers = [0, 1, 2, 3].map(|i| Driver::new(RAM + i * 0x400));
+            let mut queues = Vec::new();
+            queues.resize_with(2 + 2 * VIRTIO_CONSOLE_MAX_PORTS as usize, || {
+                Virtqueue::new(Driver::SIZE)
+            });
+            for (i, driver) in drivers.iter().enumerate() {
+                queues[QUEUE_CONTROL_RX + i] = driver.queue();
+            }
+            let events = Arc::new(Mutex::new(Vec::new()));
+            let mut console = VirtioConsole::new(Box::new(Recorder(events.clone())));
+            assert_eq!(console.add_port("agent"), Ok(1));
+            console.activate(VIRTIO_CONSOLE_F_MULTIPORT).unwrap();
+            Self {
+                _host: host,
+                memory,
+                drivers,
+                posted: [false; 4],
+                queues,
+                console,
+                events,
+            }
+        }
+
+        fn take_events(&self) -> Vec<Event> {
+            core::mem::take(&mut *self.events.lock())
+        }
+
+        /// Write `data` on the transmit queue `queue`.
+        fn send(&mut self, queue: usize, data: &[u8]) {
+            let (memory, driver) = (&self.memory, &mut self.drivers[queue - QUEUE_CONTROL_RX]);
+            memory.write(TX_BUF, data).unwrap();
+            driver.add(memory, &[(TX_BUF, data.len() as u32, false)]);
+            self.console
+                .queue_notify(queue, &mut self.queues, memory)
+                .unwrap();
+            assert_eq!(driver.used(memory).map(|(_, len)| len), Some(0));
+        }
+
+        fn send_control(&mut self, id: u32, event: u16, value: u16) {
+            let mut msg = [0; CONTROL_SIZE];
+            msg[0..4].copy_from_slice(&id.to_le_bytes());
+            msg[4..6].copy_from_slice(&event.to_le_bytes());
+            msg[6..8].copy_from_slice(&value.to_le_bytes());
+            self.send(QUEUE_CONTROL_TX, &msg);
+        }
+
+        /// Receive the next buffer the device wrote on the receive queue
+        /// `queue`, if any.
+        fn recv(&mut self, queue: usize) -> Option<Vec<u8>> {
+            let i = queue - QUEUE_CONTROL_RX;
+            let (memory, driver) = (&self.memory, &mut self.drivers[i]);
+            let buf = RX_BUFS + i * RX_BUF_SIZE as usize;
+            if !self.posted[i] {
+                driver.add(memory, &[(buf, RX_BUF_SIZE, true)]);
+                self.posted[i] = true;
+            }
+            self.console.poll(&mut self.queues, memory).unwrap();
+            let (_, len) = driver.used(memory)?;
+            self.posted[i] = false;
+            let mut data = vec![0; len as usize];
+            memory.read(buf, &mut data).unwrap();
+            Some(data)
+        }
+
+        /// Receive a control message: the port, the event, the value and
+        /// the extra data.
+        fn recv_control(&mut self) -> (u32, u16, u16, Vec<u8>) {
+            let mut msg = self.recv(QUEUE_CONTROL_RX).unwrap();
+            let id = u32::from_le_bytes(msg[0..4].try_into().unwrap());
+            let event = u16::from_le_bytes(msg[4..6].try_into().unwrap());
+            let value = u16::from_le_bytes(msg[6..8].try_into().unwrap());
+            (id, event, value, msg.split_off(CONTROL_SIZE))
+        }
+    }
+
+    #[test]
+    fn ports_are_announced_and_opened() {
+        let mut guest = Guest::new();
+        assert!(guest.recv(QUEUE_CONTROL_RX).is_none());
+        guest.send_control(0, VIRTIO_CONSOLE_DEVICE_READY, 1);
+        let added = |id| (id, VIRTIO_CONSOLE_DEVICE_ADD, 1, Vec::new());
+        assert_eq!(guest.recv_control(), added(0));
+        assert_eq!(guest.recv_control(), added(1));
+        // Ports added later are announced at once.
+        assert_eq!(guest.console.add_port("late"), Ok(2));
+        assert_eq!(guest.recv_control(), added(2));
+
+        guest.send_control(0, VIRTIO_CONSOLE_PORT_READY, 1);
+        let console_port = (0, VIRTIO_CONSOLE_CONSOLE_PORT, 1, Vec::new());
+        assert_eq!(guest.recv_control(), console_port);
+        let open = |id| (id, VIRTIO_CONSOLE_PORT_OPEN, 1, Vec::new());
+        assert_eq!(guest.recv_control(), open(0));
+        guest.send_control(1, VIRTIO_CONSOLE_PORT_READY, 1);
+        let name = (1, VIRTIO_CONSOLE_PORT_NAME, 1, b"agent".to_vec());
+        assert_eq!(guest.recv_control(), name);
+        assert_eq!(guest.recv_control(), open(1));
+        assert!(guest.recv(QUEUE_CONTROL_RX).is_none());
+
+        // Unknown ports and short messages are ignored.
+        guest.send_control(9, VIRTIO_CONSOLE_PORT_READY, 1);
+        guest.send(QUEUE_CONTROL_TX, &[0; 4]);
+        assert!(guest.recv(QUEUE_CONTROL_RX).is_none());
+        assert!(guest.take_events().is_empty());
+    }
+
+    #[test]
+    fn input_waits_for_guest_to_open_port() {
+        let mut guest = Guest::new();
+        guest.send_control(0, VIRTIO_CONSOLE_DEVICE_READY, 1);
+        assert_eq!(guest.console.send(1, b"ping"), Ok(4));
+        assert!(guest.recv(QUEUE_PORT1_RX).is_none());
+
+        guest.send_control(1, VIRTIO_CONSOLE_PORT_OPEN, 1);
+        assert!(guest.console.is_open(1));
+        assert_eq!(guest.recv(QUEUE_PORT1_RX), Some(b"ping".to_vec()));
+        guest.send(QUEUE_PORT1_TX, b"pong");
+
+        guest.send_control(1, VIRTIO_CONSOLE_PORT_OPEN, 0);
+        assert!(!guest.console.is_open(1));
+        assert_eq!(guest.console.send(1, b"again"), Ok(5));
+        assert!(guest.recv(QUEUE_PORT1_RX).is_none());
+        assert_eq!(
+            guest.take_events(),
+            [
+                Event::Open(1, true),
+                Event::Data(1, b"pong".to_vec()),
+                Event::Open(1, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn output_is_passed_in_chunks() {
+        let mut guest = Guest::new();
+        let data: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        guest.send(QUEUE_PORT1_TX, &data);
+        let (first, rest) = data.split_at(OUTPUT_CHUNK_SIZE);
+        assert_eq!(
+            guest.take_events(),
+            [
+                Event::Data(1, first.to_vec()),
+                Event::Data(1, rest.to_vec())
+            ]
+        );
+    }
+}
@@ -0,0 +1,366 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use super::{VirtioDevice, Virtqueue, VIRTIO_F_INDIRECT_DESC, VIRTIO_F_VERSION_1};
+use crate::devices::MmioDevice;
+use crate::snapshot::{Snapshot, SnapshotReader, SnapshotWriter};
+use crate::{GuestMemory, GuestPhysAddr, HyperError, HyperResult};
+
+/// Size of the register window of a virtio-mmio device, including the
+/// configuration space.
+pub const VIRTIO_MMIO_SIZE: usize = 0x200;
+
+const VIRTIO_MMIO_MAGIC: u32 = u32::from_le_bytes(*b"virt");
+const VIRTIO_MMIO_VERSION: u32 = 2;
+const VIRTIO_MMIO_VENDOR_ID: u32 = u32::from_le_bytes(*b"hcft");
+
+const REG_MAGIC_VALUE: usize = 0x000;
+const REG_VERSION: usize = 0x004;
+const REG_DEVICE_ID: usize = 0x008;
+const REG_VENDOR_ID: usize = 0x00c;
+const REG_DEVICE_FEATURES: usize = 0x010;
+const REG_DEVICE_FEATURES_SEL: usize = 0x014;
+const REG_DRIVER_FEATURES: usize = 0x020;
+const REG_DRIVER_FEATURES_SEL: usize = 0x024;
+const REG_QUEUE_SEL: usize = 0x030;
+const REG_QUEUE_NUM_MAX: usize = 0x034;
+const REG_QUEUE_NUM: usize = 0x038;
+const REG_QUEUE_READY: usize = 0x044;
+const REG_QUEUE_NOTIFY: usize = 0x050;
+const REG_INTERRUPT_STATUS: usize = 0x060;
+const REG_INTERRUPT_ACK: usize = 0x064;
+const REG_STATUS: usize = 0x070;
+const REG_QUEUE_DESC_LOW: usize = 0x080;
+const REG_QUEUE_DESC_HIGH: usize = 0x084;
+const REG_QUEUE_DRIVER_LOW: usize = 0x090;
+const REG_QUEUE_DRIVER_HIGH: usize = 0x094;
+const REG_QUEUE_DEVICE_LOW: usize = 0x0a0;
+const REG_QUEUE_DEVICE_HIGH: usize = 0x0a4;
+const REG_CONFIG_GENERATION: usize = 0x0fc;
+const REG_CONFIG: usize = 0x100;
+
+const STATUS_DRIVER: u32 = 2;
+const STATUS_DRIVER_OK: u32 = 4;
+const STATUS_FEATURES_OK: u32 = 8;
+const STATUS_DEVICE_NEEDS_RESET: u32 = 0x40;
+
+/// The device used buffers of a virtqueue.
+const INT_VRING: u32 = 1;
+/// The configuration space changed.
+const INT_CONFIG: u32 = 2;
+
+/// The features implemented by the transport and the virtqueues.
+const TRANSPORT_FEATURES: u64 = VIRTIO_F_VERSION_1 | VIRTIO_F_INDIRECT_DESC;
+
+/// A virtio-mmio transport (version 2, without legacy support), exposing a
+/// [`VirtioDevice`] in a [`VIRTIO_MMIO_SIZE`] window of guest physical
+/// addresses.
+///
+/// The virtqueues and the buffers of the driver must lie in the guest RAM
+/// given at creation. The level of the interrupt output is reported by
+/// [`VirtioMmio::irq_level`], and the platform delivers it through its
+/// virtual interrupt controller; [`VirtioMmio::poll`] lets the device make
+/// progress on the host side.
+pub struct VirtioMmio {
+    base: GuestPhysAddr,
+    device: Box<dyn VirtioDevice>,
+    memory: GuestMemory,
+    queues: Vec<Virtqueue>,
+    device_features_sel: u32,
+    driver_features_sel: u32,
+    driver_features: u64,
+    queue_sel: u32,
+    status: u32,
+    interrupt_status: u32,
+    config_generation: u32,
+}
+
+/// The device and the guest memory are attached by the host, so the snapshot
+/// must be restored on a transport of the same type of device. Otherwise it
+/// is `InvalidParam`. The device-specific state is not carried: the device is
+/// reset, and activated again if the driver had started it.
+impl Snapshot for VirtioMmio {
+    fn save(&self, w: &mut SnapshotWriter) {
+        self.device.device_type().save(w);
+        self.queues.len().save(w);
+        self.queues.iter().for_each(|queue| queue.save(w));
+        self.device_features_sel.save(w);
+        self.driver_features_sel.save(w);
+        self.driver_features.save(w);
+        self.queue_sel.save(w);
+        self.status.save(w);
+        self.interrupt_status.save(w);
+        self.config_generation.save(w);
+    }
+
+    fn restore(&mut self, r: &mut SnapshotReader<'_>) -> HyperResult {
+        if r.read::<u32>()? != self.device.device_type() || r.read::<usize>()? != self.queues.len()
+        {
+            return Err(HyperError::InvalidParam);
+        }
+        for queue in &mut self.queues {
+            queue.restore(r)?;
+        }
+        self.device_features_sel.restore(r)?;
+        self.driver_features_sel.restore(r)?;
+        self.driver_features.restore(r)?;
+        self.queue_sel.restore(r)?;
+        self.status.restore(r)?;
+        self.interrupt_status.restore(r)?;
+        self.config_generation.restore(r)?;
+        self.device.reset();
+        if self.is_active() {
+            let result = self.device.activate(self.driver_features);
+            self.check_device(result);
+        }
+        Ok(())
+    }
+}
+
+impl VirtioMmio {
+    /// Create a transport at guest physical address `base` for `device`,
+    /// whose driver places its virtqueues and buffers in `memory`.
+    pub fn new(base: GuestPhysAddr, device: Box<dyn VirtioDevice>, memory: GuestMemory) -> Self {
+        let max_size = device.max_queue_size();
+        let queues = (0..device.num_queues())
+            .map(|_| Virtqueue::new(max_size))
+            .collect();
+        Self {
+            base,
+            device,
+            memory,
+            queues,
+            device_features_sel: 0,
+            driver_features_sel: 0,
+            driver_features: 0,
+            queue_sel: 0,
+            status: 0,
+            interrupt_status: 0,
+            config_generation: 0,
+        }
+    }
+
+    /// Look the virtqueues and the buffers of the driver up in `memory`
+    /// rather than in the guest RAM given at creation, e.g. in the RAM of a
+    /// VM cloned from a template.
+    pub(crate) fn set_memory(&mut self, memory: GuestMemory) {
+        self.memory = memory;
+    }
+
+    /// The features the driver accepted.
+    pub fn driver_features(&self) -> u64 {
+        self.driver_features
+    }
+
+    /// Whether the driver is ready and the device is working.
+    pub fn is_active(&self) -> bool {
+        self.status & (STATUS_DRIVER_OK | STATUS_DEVICE_NEEDS_RESET) == STATUS_DRIVER_OK
+    }
+
+    /// Let the device make progress on the host side, and raise the
+    /// interrupt for the buffers it used and the changes of its
+    /// configuration.
+    pub fn poll(&mut self) {
+        if self.is_active() {
+            let result = self.device.poll(&mut self.queues, &self.memory);
+            self.check_device(result);
+        }
+        if self.device.take_config_change() {
+            self.config_generation = self.config_generation.wrapping_add(1);
+            if self.status & STATUS_DRIVER_OK != 0 {
+                self.raise_interrupt(INT_CONFIG);
+            }
+        }
+    }
+
+    /// The level of the interrupt output: whether an interrupt is not
+    /// acknowledged by the driver yet.
+    pub fn irq_level(&self) -> bool {
+        self.interrupt_status != 0
+    }
+
+    fn raise_interrupt(&mut self, cause: u32) {
+        self.interrupt_status |= cause;
+    }
+
+    /// Notify the driver of the used buffers, or mark the device as needing
+    /// a reset if it failed.
+    fn check_device(&mut self, result: HyperResult) {
+        if let Err(err) = result {
+            warn!(
+                "virtio device {} failed: {:?}",
+                self.device.device_type(),
+                err
+            );
+            self.status |= STATUS_DEVICE_NEEDS_RESET;
+            self.raise_interrupt(INT_CONFIG);
+        }
+        let mut used = false;
+        for queue in &mut self.queues {
+            used |= queue.take_notification();
+        }
+        if used {
+            self.raise_interrupt(INT_VRING);
+        }
+    }
+
+    fn device_features(&self) -> u64 {
+        self.device.features() | TRANSPORT_FEATURES
+    }
+
+    fn selected_queue(&mut self) -> Option<&mut Virtqueue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+
+    fn reset(&mut self) {
+        self.device.reset();
+        self.queues.iter_mut().for_each(Virtqueue::reset);
+        self.device_features_sel = 0;
+        self.driver_features_sel = 0;
+        self.driver_features = 0;
+        self.queue_sel = 0;
+        self.status = 0;
+        self.interrupt_status = 0;
+    }
+
+    fn set_status(&mut self, mut status: u32) {
+        if status == 0 {
+            self.reset();
+            return;
+        }
+        let newly_set = status & !self.status;
+        if newly_set & STATUS_FEATURES_OK != 0 {
+            // The driver reads the status back to learn that the features it
+            // accepted are refused.
+            let offered = self.device_features();
+            if self.driver_features & !offered != 0
+                || self.driver_features & VIRTIO_F_VERSION_1 == 0
+            {
+                status &= !STATUS_FEATURES_OK;
+            }
+        }
+        self.status = status;
+        if newly_set & STATUS_DRIVER_OK != 0 {
+            let result = self.device.activate(self.driver_features);
+            self.check_device(result);
+        }
+    }
+
+    fn queue_notify(&mut self, index: usize) {
+        if !self.is_active() || !self.queues.get(index).is_some_and(|queue| queue.ready) {
+            return;
+        }
+        let result = self
+            .device
+            .queue_notify(index, &mut self.queues, &self.memory);
+        self.check_device(result);
+    }
+
+    fn read_register(&mut self, offset: usize) -> u32 {
+        match offset {
+            REG_MAGIC_VALUE => VIRTIO_MMIO_MAGIC,
+            REG_VERSION => VIRTIO_MMIO_VERSION,
+            REG_DEVICE_ID => self.device.device_type(),
+            REG_VENDOR_ID => VIRTIO_MMIO_VENDOR_ID,
+            REG_DEVICE_FEATURES => match self.device_features_sel {
+                0 => self.device_features() as u32,
+                1 => (self.device_features() >> 32) as u32,
+                _ => 0,
+            },
+            REG_QUEUE_NUM_MAX => self
+                .selected_queue()
+                .map_or(0, |queue| queue.max_size() as u32),
+            REG_QUEUE_READY => self.selected_queue().map_or(0, |queue| queue.ready as u32),
+            REG_INTERRUPT_STATUS => self.interrupt_status,
+            REG_STATUS => self.status,
+            REG_CONFIG_GENERATION => self.config_generation,
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, offset: usize, value: u32) {
+        match offset {
+            REG_DEVICE_FEATURES_SEL => self.device_features_sel = value,
+            REG_DRIVER_FEATURES_SEL => self.driver_features_sel = value,
+            REG_DRIVER_FEATURES => {
+                if self.status & (STATUS_DRIVER | STATUS_FEATURES_OK) != STATUS_DRIVER {
+                    return;
+                }
+                let shift = match self.driver_features_sel {
+                    0 => 0,
+                    1 => 32,
+                    _ => return,
+                };
+                self.driver_features &= !(0xffff_ffff << shift);
+                self.driver_features |= (value as u64) << shift;
+            }
+            REG_QUEUE_SEL => self.queue_sel = value,
+            REG_QUEUE_NUM => {
+                if let Some(queue) = self.selected_queue().filter(|queue| !queue.ready) {
+                    queue.size = value as u16;
+                }
+            }
+            REG_QUEUE_READY => {
+                if let Some(queue) = self.selected_queue() {
+                    queue.ready = value == 1 && queue.size_valid();
+                }
+            }
+            REG_QUEUE_NOTIFY => self.queue_notify(value as usize),
+            REG_INTERRUPT_ACK => self.interrupt_status &= !value,
+            REG_STATUS => self.set_status(value),
+            REG_QUEUE_DESC_LOW
+            | REG_QUEUE_DESC_HIGH
+            | REG_QUEUE_DRIVER_LOW
+            | REG_QUEUE_DRIVER_HIGH
+            | REG_QUEUE_DEVICE_LOW
+            | REG_QUEUE_DEVICE_HIGH => {
+                let Some(queue) = self.selected_queue().filter(|queue| !queue.ready) else {
+                    return;
+                };
+                let addr = match offset & !0x7 {
+                    REG_QUEUE_DESC_LOW => &mut queue.desc_table,
+                    REG_QUEUE_DRIVER_LOW => &mut queue.avail_ring,
+                    _ => &mut queue.used_ring,
+                };
+                let shift = (offset & 0x4) * 8;
+                *addr &= !(0xffff_ffff << shift);
+                *addr |= (value as usize) << shift;
+            }
+            _ => {}
+        }
+    }
+}
+
+impl MmioDevice for VirtioMmio {
+    fn mmio_range(&self) -> Range<GuestPhysAddr> {
+        self.base..self.base + VIRTIO_MMIO_SIZE
+    }
+
+    fn read(&mut self, addr: GuestPhysAddr, width: usize) -> HyperResult<u64> {
+        let offset = addr - self.base;
+        if offset >= REG_CONFIG {
+            // Bytes past the configuration space read as zero.
+            Ok(self
+                .device
+                .read_config(offset - REG_CONFIG, width)
+                .unwrap_or(0))
+        } else if width == 4 {
+            Ok(self.read_register(offset) as u64)
+        } else {
+            // The registers only support 32-bit accesses.
+            Ok(0)
+        }
+    }
+
+    fn write(&mut self, addr: GuestPhysAddr, width: usize, value: u64) -> HyperResult {
+        let offset = addr - self.base;
+        if offset >= REG_CONFIG {
+            // Writes past the configuration space are ignored.
+            let _ = self.device.write_config(offset - REG_CONFIG, width, value);
+        } else if width == 4 {
+            self.write_register(offset, value as u32);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,173 @@
+mod balloon;
+mod block;
+mod console;
+mod mmio;
+mod net;
+mod net_switch;
+mod queue;
+mod vsock;
+
+pub use balloon::{VirtioBalloon, VIRTIO_BALLOON_F_DEFLATE_ON_OOM, VIRTIO_BALLOON_F_REPORTING};
+pub use block::{BlockBackend, MemoryBlockBackend, VirtioBlock, VIRTIO_BLK_SECTOR_SIZE};
+pub use console::{ConsoleHandler, VirtioConsole, VIRTIO_CONSOLE_MAX_PORTS};
+pub use mmio::{VirtioMmio, VIRTIO_MMIO_SIZE};
+pub use net::{NetBackend, VirtioNet, ETH_ALEN};
+pub use net_switch::{NetSwitch, SwitchPort};
+pub use queue::{Descriptor, DescriptorChain, Virtqueue};
+pub use vsock::{VirtioVsock, VsockConn, VsockHandler, VSOCK_HOST_CID};
+
+use alloc::sync::Arc;
+use spin::Mutex;
+
+use crate::{GuestMemory, HyperError, HyperResult};
+
+/// Virtio device ID of network cards.
+pub const VIRTIO_ID_NET: u32 = 1;
+/// Virtio device ID of block devices.
+pub const VIRTIO_ID_BLOCK: u32 = 2;
+/// Virtio device ID of consoles.
+pub const VIRTIO_ID_CONSOLE: u32 = 3;
+/// Virtio device ID of memory balloons.
+pub const VIRTIO_ID_BALLOON: u32 = 5;
+/// Virtio device ID of vsock transports.
+pub const VIRTIO_ID_VSOCK: u32 = 19;
+
+/// The driver may put descriptors in indirect tables.
+pub const VIRTIO_F_INDIRECT_DESC: u64 = 1 << 28;
+/// The device conforms to virtio 1.0 or later, rather than to the legacy
+/// interface.
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// The default number of descriptors of a virtqueue.
+pub const VIRTQUEUE_MAX_SIZE: u16 = 256;
+
+/// The device-specific part of a virtio device, plugged into a transport
+/// such as [`VirtioMmio`].
+///
+/// The transport negotiates the features, sets the virtqueues up with the
+/// driver and notifies the driver of the buffers the device used. Devices
+/// must be `Send`, as the transport may be shared between the physical CPUs
+/// running a VM. A device shared with the host, through an `Arc<Mutex<_>>`,
+/// is a device too.
+pub trait VirtioDevice: Send {
+    /// The virtio device ID, one of the `VIRTIO_ID_*`.
+    fn device_type(&self) -> u32;
+
+    /// The device-specific features offered to the driver. The transport adds
+    /// the ones it implements, e.g. [`VIRTIO_F_VERSION_1`].
+    fn features(&self) -> u64;
+
+    /// The number of virtqueues.
+    fn num_queues(&self) -> usize;
+
+    /// The largest size of the virtqueues.
+    fn max_queue_size(&self) -> u16 {
+        VIRTQUEUE_MAX_SIZE
+    }
+
+    /// Read `width` bytes at `offset` of the configuration space.
+    fn read_config(&self, offset: usize, width: usize) -> HyperResult<u64>;
+
+    /// Write `width` bytes of `value` at `offset` of the configuration
+    /// space. Writes to read-only fields are ignored.
+    fn write_config(&mut self, offset: usize, width: usize, value: u64) -> HyperResult {
+        let _ = (offset, width, value);
+        Ok(())
+    }
+
+    /// Start the device once the driver is ready, with the features the
+    /// driver accepted.
+    fn activate(&mut self, driver_features: u64) -> HyperResult {
+        let _ = driver_features;
+        Ok(())
+    }
+
+    /// Handle a notification of the driver that it made buffers available in
+    /// the virtqueue `index` of `queues`, whose rings are in `memory`. An
+    /// error marks the device as needing a reset.
+    fn queue_notify(
+        &mut self,
+        index: usize,
+        queues: &mut [Virtqueue],
+        memory: &GuestMemory,
+    ) -> HyperResult;
+
+    /// Make progress on the host side, e.g. complete requests or fill the
+    /// receive queues with input of the backend. Called periodically.
+    fn poll(&mut self, queues: &mut [Virtqueue], memory: &GuestMemory) -> HyperResult {
+        let _ = (queues, memory);
+        Ok(())
+    }
+
+    /// Whether the configuration space changed since the last call, so that
+    /// the driver is notified.
+    fn take_config_change(&mut self) -> bool {
+        false
+    }
+
+    /// Reset the device, when the driver does.
+    fn reset(&mut self);
+}
+
+/// Read `width` bytes at `offset` of the configuration space `config`, as
+/// a little-endian value.
+pub(crate) fn read_config_bytes(config: &[u8], offset: usize, width: usize) -> HyperResult<u64> {
+    let bytes = config
+        .get(offset..offset + width)
+        .ok_or(HyperError::InvalidParam)?;
+    Ok(bytes
+        .iter()
+        .rev()
+        .fold(0, |value, &byte| value << 8 | byte as u64))
+}
+
+impl<T: VirtioDevice> VirtioDevice for Arc<Mutex<T>> {
+    fn device_type(&self) -> u32 {
+        self.lock().device_type()
+    }
+
+    fn features(&self) -> u64 {
+        self.lock().features()
+    }
+
+    fn num_queues(&self) -> usize {
+        self.lock().num_queues()
+    }
+
+    fn max_queue_size(&self) -> u16 {
+        self.lock().max_queue_size()
+    }
+
+    fn read_config(&self, offset: usize, width: usize) -> HyperResult<u64> {
+        self.lock().read_config(offset, width)
+    }
+
+    fn write_config(&mut self, offset: usize, width: usize, value: u64) -> HyperResult {
+        self.lock().write_config(offset, width, value)
+    }
+
+    fn activate(&mut self, driver_features: u64) -> HyperResult {
+        self.lock().activate(driver_features)
+    }
+
+    fn queue_notify(
+        &mut self,
+        index: usize,
+        queues: &mut [Virtqueue],
+        memory: &GuestMemory,
+    ) -> HyperResult {
+        self.lock().queue_notify(index, queues, memory)
+    }
+
+    fn poll(&mut self, queues: &mut [Virtqueue], memory: &GuestMemory) -> HyperResult {
+        self.lock().poll(queues, memory)
+    }
+
+    fn take_config_change(&mut self) -> bool {
+        self.lock().take_config_change()
+    }
+
+    fn reset(&mut self) {
+        self.lock().reset()
+    }
+}
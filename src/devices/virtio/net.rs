@@ -0,0 +1,464 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use super::{read_config_bytes, VirtioDevice, Virtqueue, VIRTIO_ID_NET};
+use crate::{GuestMemory, HyperError, HyperResult};
+
+/// The guest may hand over frames whose checksum is left to the device.
+const VIRTIO_NET_F_CSUM: u64 = 1 << 0;
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+const VIRTIO_NET_F_MRG_RXBUF: u64 = 1 << 15;
+const VIRTIO_NET_F_STATUS: u64 = 1 << 16;
+const VIRTIO_NET_F_CTRL_VQ: u64 = 1 << 17;
+const VIRTIO_NET_F_CTRL_MAC_ADDR: u64 = 1 << 23;
+
+const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
+const VIRTIO_NET_S_LINK_UP: u16 = 1;
+
+/// The header preceding each frame: flags, gso_type, hdr_len, gso_size,
+/// csum_start, csum_offset and num_buffers.
+const NET_HDR_SIZE: usize = 12;
+const NET_HDR_NUM_BUFFERS: usize = 10;
+/// The largest packet of the transmit queue, as no segmentation offload is
+/// offered.
+const MAX_TX_PACKET_SIZE: usize = NET_HDR_SIZE + 64 * 1024;
+/// The largest command of the control queue: class, command and data.
+const MAX_CTRL_COMMAND_SIZE: usize = 2 + ETH_ALEN;
+
+const VIRTIO_NET_CTRL_MAC: u8 = 1;
+const VIRTIO_NET_CTRL_MAC_ADDR_SET: u8 = 1;
+const VIRTIO_NET_OK: u8 = 0;
+const VIRTIO_NET_ERR: u8 = 1;
+
+const QUEUE_RX: usize = 0;
+const QUEUE_TX: usize = 1;
+const QUEUE_CTRL: usize = 2;
+
+const CONFIG_MAC: usize = 0;
+const CONFIG_STATUS: usize = 6;
+const CONFIG_SIZE: usize = 8;
+
+/// The length of an Ethernet MAC address.
+pub const ETH_ALEN: usize = 6;
+
+/// The host side of a [`VirtioNet`], which carries Ethernet frames, such as
+/// a TAP device, a physical NIC or a port of a [`NetSwitch`](super::NetSwitch).
+/// Frames are whole, without the virtio header, and have valid checksums.
+pub trait NetBackend: Send {
+    /// Send a frame of the guest. Frames which cannot be sent are dropped.
+    fn send(&mut self, frame: &[u8]);
+
+    /// Take the next frame for the guest without blocking, if any.
+    fn recv(&mut self) -> Option<Vec<u8>>;
+}
+
+/// A virtio network card connected to a [`NetBackend`].
+///
+/// It completes the checksums the guest leaves to it, and receives frames
+/// into several buffers when the driver merges them. The guest may change
+/// its MAC address through the control queue. Received frames wait in the
+/// backend until the driver provides buffers, and are fetched by
+/// [`VirtioDevice::poll`].
+pub struct VirtioNet {
+    mac: [u8; ETH_ALEN],
+    backend: Box<dyn NetBackend>,
+    driver_features: u64,
+    /// A frame taken from the backend which did not fit in the available
+    /// buffers.
+    rx_pending: Option<Vec<u8>>,
+}
+
+impl VirtioNet {
+    /// Create a network card with MAC address `mac`, connected to `backend`.
+    pub fn new(mac: [u8; ETH_ALEN], backend: Box<dyn NetBackend>) -> Self {
+        Self {
+            mac,
+            backend,
+            driver_features: 0,
+            rx_pending: None,
+        }
+    }
+
+    /// The MAC address, as last set by the host or the guest.
+    pub fn mac(&self) -> [u8; ETH_ALEN] {
+        self.mac
+    }
+
+    /// The backend carrying the frames of the card.
+    pub fn backend_mut(&mut self) -> &mut dyn NetBackend {
+        self.backend.as_mut()
+    }
+
+    fn config(&self) -> [u8; CONFIG_SIZE] {
+        let mut config = [0; CONFIG_SIZE];
+        config[CONFIG_MAC..CONFIG_MAC + ETH_ALEN].copy_from_slice(&self.mac);
+        config[CONFIG_STATUS..CONFIG_STATUS + 2]
+            .copy_from_slice(&VIRTIO_NET_S_LINK_UP.to_le_bytes());
+        config
+    }
+
+    /// Send the frames the guest made available on the transmit queue.
+    fn transmit(&mut self, queue: &mut Virtqueue, memory: &GuestMemory) -> HyperResult {
+        while let Some(chain) = queue.pop(memory)? {
+            let packet = match chain.read_to_vec(memory, MAX_TX_PACKET_SIZE) {
+                Err(HyperError::InvalidParam) => {
+                    debug!(
+                        "virtio-net: {}-byte packet too large, dropped",
+                        chain.readable_len()
+                    );
+                    Vec::new()
+                }
+                result => result?,
+            };
+            queue.add_used(memory, chain.head(), 0)?;
+            if packet.len() <= NET_HDR_SIZE {
+                continue;
+            }
+            let (header, frame) = packet.split_at(NET_HDR_SIZE);
+            let mut frame = frame.to_vec();
+            if header[0] & VIRTIO_NET_HDR_F_NEEDS_CSUM != 0 {
+                let csum_start = u16::from_le_bytes([header[6], header[7]]) as usize;
+                let csum_offset = u16::from_le_bytes([header[8], header[9]]) as usize;
+                if complete_checksum(&mut frame, csum_start, csum_offset).is_err() {
+                    debug!("virtio-net: bad checksum offsets, frame dropped");
+                    continue;
+                }
+            }
+            self.backend.send(&frame);
+        }
+        Ok(())
+    }
+
+    /// Deliver the frames of the backend while the receive queue has
+    /// buffers.
+    fn receive(&mut self, queue: &mut Virtqueue, memory: &GuestMemory) -> HyperResult {
+        while let Some(frame) = self.rx_pending.take().or_else(|| self.backend.recv()) {
+            if !self.receive_frame(queue, memory, &frame)? {
+                self.rx_pending = Some(frame);
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy `frame` to the buffers of the receive queue. Returns `false` if
+    /// there are not enough buffers yet.
+    fn receive_frame(
+        &mut self,
+        queue: &mut Virtqueue,
+        memory: &GuestMemory,
+        frame: &[u8],
+    ) -> HyperResult<bool> {
+        let mergeable = self.driver_features & VIRTIO_NET_F_MRG_RXBUF != 0;
+        let total = NET_HDR_SIZE + frame.len();
+        let mut chains = Vec::new();
+        let mut capacity = 0;
+        while capacity < total {
+            let Some(chain) = queue.pop(memory)? else {
+                chains.iter().for_each(|_| queue.undo_pop());
+                return Ok(false);
+            };
+            capacity += chain.writable_len();
+            chains.push(chain);
+            if !mergeable {
+                break;
+            }
+        }
+        if capacity < total {
+            // Without merged buffers, the driver must provide buffers large
+            // enough for any frame.
+            debug!("virtio-net: {}-byte frame too large, dropped", frame.len());
+            queue.add_used(memory, chains[0].head(), 0)?;
+            return Ok(true);
+        }
+
+        let mut packet = vec![0; NET_HDR_SIZE];
+        packet[NET_HDR_NUM_BUFFERS..NET_HDR_SIZE]
+            .copy_from_slice(&(chains.len() as u16).to_le_bytes());
+        packet.extend_from_slice(frame);
+        let mut offset = 0;
+        for chain in &chains {
+            let len = chain.write(memory, 0, &packet[offset..])?;
+            offset += len;
+            queue.add_used(memory, chain.head(), len as u32)?;
+        }
+        Ok(true)
+    }
+
+    /// Handle the commands of the control queue.
+    fn control(&mut self, queue: &mut Virtqueue, memory: &GuestMemory) -> HyperResult {
+        while let Some(chain) = queue.pop(memory)? {
+            // Longer commands are not supported.
+            let command = match chain.read_to_vec(memory, MAX_CTRL_COMMAND_SIZE) {
+                Err(HyperError::InvalidParam) => Vec::new(),
+                result => result?,
+            };
+            let ack = match command.as_slice() {
+                [VIRTIO_NET_CTRL_MAC, VIRTIO_NET_CTRL_MAC_ADDR_SET, mac @ ..]
+                    if mac.len() == ETH_ALEN =>
+                {
+                    self.mac.copy_from_slice(mac);
+                    VIRTIO_NET_OK
+                }
+                _ => VIRTIO_NET_ERR,
+            };
+            chain.write(memory, 0, &[ack])?;
+            queue.add_used(memory, chain.head(), 1)?;
+        }
+        Ok(())
+    }
+}
+
+/// Complete the partial checksum of `frame`: the one's complement sum of the
+/// bytes from `start`, seeded by the guest with the sum of the pseudo-header
+/// at `start + offset`, is stored there.
+fn complete_checksum(frame: &mut [u8], start: usize, offset: usize) -> HyperResult {
+    let field = start.checked_add(offset).ok_or(HyperError::InvalidParam)?;
+    if field + 2 > frame.len() {
+        return Err(HyperError::InvalidParam);
+    }
+    let mut sum = frame[start..]
+        .chunks(2)
+        .map(|word| u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)]) as u32)
+        .sum::<u32>();
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    frame[field..field + 2].copy_from_slice(&(!(sum as u16)).to_be_bytes());
+    Ok(())
+}
+
+impl VirtioDevice for VirtioNet {
+    fn device_type(&self) -> u32 {
+        VIRTIO_ID_NET
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_NET_F_CSUM
+            | VIRTIO_NET_F_MAC
+            | VIRTIO_NET_F_MRG_RXBUF
+            | VIRTIO_NET_F_STATUS
+            | VIRTIO_NET_F_CTRL_VQ
+            | VIRTIO_NET_F_CTRL_MAC_ADDR
+    }
+
+    fn num_queues(&self) -> usize {
+        3
+    }
+
+    fn read_config(&self, offset: usize, width: usize) -> HyperResult<u64> {
+        read_config_bytes(&self.config(), offset, width)
+    }
+
+    fn activate(&mut self, driver_features: u64) -> HyperResult {
+        self.driver_features = driver_features;
+        Ok(())
+    }
+
+    fn queue_notify(
+        &mut self,
+        index: usize,
+        queues: &mut [Virtqueue],
+        memory: &GuestMemory,
+    ) -> HyperResult {
+        let queue = &mut queues[index];
+        match index {
+            QUEUE_RX => self.receive(queue, memory),
+            QUEUE_TX => self.transmit(queue, memory),
+            QUEUE_CTRL => self.control(queue, memory),
+            _ => Err(HyperError::InvalidParam),
+        }
+    }
+
+    fn poll(&mut self, queues: &mut [Virtqueue], memory: &GuestMemory) -> HyperResult {
+        self.receive(&mut queues[QUEUE_RX], memory)
+    }
+
+    fn reset(&mut self) {
+        self.driver_features = 0;
+        self.rx_pending = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::collections::VecDeque;
+    use alloc::sync::Arc;
+    use spin::Mutex;
+
+    use super::*;
+    use crate::devices::virtio::queue::tests::Driver;
+    use crate::memory::tests::{host_backed, HostPage};
+    use crate::GuestPhysAddr;
+
+    const RAM: GuestPhysAddr = 0x4000_0000;
+    const TX_BUF: GuestPhysAddr = RAM + 0x1000;
+    const RX_BUFS: GuestPhysAddr = RAM + 0x2000;
+    const RX_BUF_SIZE: u32 = 0x40;
+
+    const MAC: [u8; ETH_ALEN] = [0x52, 0x54, 0, 0x12, 0x34, 0x56];
+    const PEER_MAC: [u8; ETH_ALEN] = [0x52, 0x54, 0, 0x65, 0x43, 0x21];
+
+    /// The frames sent by the card, and those waiting for it.
+    #[derive(Default)]
+    struct Wire {
+        sent: Vec<Vec<u8>>,
+        incoming: VecDeque<Vec<u8>>,
+    }
+
+    struct Loopback(Arc<Mutex<Wire>>);
+
+    impl NetBackend for Loopback {
+        fn send(&mut self, frame: &[u8]) {
+            self.0.lock().sent.push(frame.to_vec());
+        }
+
+        fn recv(&mut self) -> Option<Vec<u8>> {
+            self.0.lock().incoming.pop_front()
+        }
+    }
+
+    /// An IPv4 frame from the peer to the card, carrying `payload`.
+    fn frame(payload: &[u8]) -> Vec<u8> {
+        let mut frame = [MAC, PEER_MAC].concat();
+        frame.extend_from_slice(&[0x08, 0x00]);
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    /// A network card driven by a guest which set up its receive and
+    /// transmit queues.
+    struct Guest {
+        _host: Vec<HostPage>,
+        memory: GuestMemory,
+        rx: Driver,
+        tx: Driver,
+        /// The receive buffers available to the device, by head.
+        rx_bufs: Vec<(u16, GuestPhysAddr)>,
+        queues: [Virtqueue; 3],
+        net: VirtioNet,
+        wire: Arc<Mutex<Wire>>,
+    }
+
+    impl Guest {
+        fn new(driver_features: u64) -> Self {
+            let (host, memory) = host_backed(RAM, 4);
+            let rx = Driver::new(RAM);
+            let tx = Driver::new(RAM + 0x400);
+            let queues = [rx.queue(), tx.queue(), Driver::new(RAM + 0x800).queue()];
+            let wire = Arc::new(Mutex::new(Wire::default()));
+            let mut net = VirtioNet::new(MAC, Box::new(Loopback(wire.clone())));
+            net.activate(driver_features).unwrap();
+            Self {
+                _host: host,
+                memory,
+                rx,
+                tx,
+                rx_bufs: Vec::new(),
+                queues,
+                net,
+                wire,
+            }
+        }
+
+        /// Transmit `frame` after `header`, in two buffers.
+        fn send(&mut self, header: &[u8; NET_HDR_SIZE], frame: &[u8]) {
+            let frame_buf = TX_BUF + NET_HDR_SIZE;
+            self.memory.write(TX_BUF, header).unwrap();
+            self.memory.write(frame_buf, frame).unwrap();
+            let buffers = [
+                (TX_BUF, NET_HDR_SIZE as u32, false),
+                (frame_buf, frame.len() as u32, false),
+            ];
+            self.tx.add(&self.memory, &buffers);
+            self.net
+                .queue_notify(QUEUE_TX, &mut self.queues, &self.memory)
+                .unwrap();
+            assert_eq!(self.tx.used(&self.memory).map(|(_, len)| len), Some(0));
+        }
+
+        /// Make `count` more receive buffers of `RX_BUF_SIZE` bytes
+        /// available.
+        fn post_rx_buffers(&mut self, count: usize) {
+            for _ in 0..count {
+                let buf = RX_BUFS + self.rx_bufs.len() * RX_BUF_SIZE as usize;
+                let head = self.rx.add(&self.memory, &[(buf, RX_BUF_SIZE, true)]);
+                self.rx_bufs.push((head, buf));
+            }
+        }
+
+        /// Take the next receive buffer the device filled, if any.
+        fn recv(&mut self) -> Option<Vec<u8>> {
+            self.net.poll(&mut self.queues, &self.memory).unwrap();
+            let (head, len) = self.rx.used(&self.memory)?;
+            let &(_, buf) = self.rx_bufs.iter().find(|&&(h, _)| h == head).unwrap();
+            let mut data = vec![0; len as usize];
+            self.memory.read(buf, &mut data).unwrap();
+            Some(data)
+        }
+    }
+
+    /// The header of a received packet spread over `num_buffers` buffers.
+    fn rx_header(num_buffers: u16) -> [u8; NET_HDR_SIZE] {
+        let mut header = [0; NET_HDR_SIZE];
+        header[NET_HDR_NUM_BUFFERS..].copy_from_slice(&num_buffers.to_le_bytes());
+        header
+    }
+
+    #[test]
+    fn transmitted_frames_get_their_checksums() {
+        let mut guest = Guest::new(VIRTIO_NET_F_CSUM);
+        let plain = frame(&[0x12, 0x34, 0x00, 0x00]);
+        guest.send(&[0; NET_HDR_SIZE], &plain);
+
+        // The checksum of the payload goes to its second word.
+        let mut header = [0; NET_HDR_SIZE];
+        header[0] = VIRTIO_NET_HDR_F_NEEDS_CSUM;
+        header[6..8].copy_from_slice(&14u16.to_le_bytes());
+        header[8..10].copy_from_slice(&2u16.to_le_bytes());
+        guest.send(&header, &frame(&[0x00, 0x01, 0x00, 0x00, 0x12, 0x34]));
+        // A checksum beyond the frame makes it dropped.
+        header[8..10].copy_from_slice(&6u16.to_le_bytes());
+        guest.send(&header, &frame(&[0x00, 0x01, 0x00, 0x00, 0x12, 0x34]));
+
+        let sent = core::mem::take(&mut guest.wire.lock().sent);
+        assert_eq!(sent, [plain, frame(&[0x00, 0x01, 0xed, 0xca, 0x12, 0x34])]);
+    }
+
+    #[test]
+    fn received_frames_wait_for_buffers() {
+        let mut guest = Guest::new(0);
+        let incoming = frame(&[1, 2, 3, 4]);
+        guest.wire.lock().incoming.push_back(incoming.clone());
+        assert_eq!(guest.recv(), None);
+
+        guest.post_rx_buffers(1);
+        let packet = guest.recv().unwrap();
+        assert_eq!(packet[..NET_HDR_SIZE], rx_header(1));
+        assert_eq!(packet[NET_HDR_SIZE..], incoming);
+        assert!(guest.wire.lock().incoming.is_empty());
+    }
+
+    #[test]
+    fn large_frames_need_merged_buffers() {
+        let incoming = frame(&[0xaa; 100]);
+
+        // Without merged buffers, a frame larger than a buffer is dropped.
+        let mut guest = Guest::new(0);
+        guest.wire.lock().incoming.push_back(incoming.clone());
+        guest.post_rx_buffers(2);
+        assert_eq!(guest.recv(), Some(Vec::new()));
+        assert_eq!(guest.recv(), None);
+
+        let mut guest = Guest::new(VIRTIO_NET_F_MRG_RXBUF);
+        guest.wire.lock().incoming.push_back(incoming.clone());
+        guest.post_rx_buffers(1);
+        assert_eq!(guest.recv(), None);
+        guest.post_rx_buffers(1);
+        let first = guest.recv().unwrap();
+        let second = guest.recv().unwrap();
+        assert_eq!(first.len(), RX_BUF_SIZE as usize);
+        assert_eq!(first[..NET_HDR_SIZE], rx_header(2));
+        assert_eq!([&first[NET_HDR_SIZE..], &second[..]].concat(), incoming);
+    }
+}
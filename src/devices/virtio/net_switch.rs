@@ -0,0 +1,204 @@
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use super::net::{NetBackend, ETH_ALEN};
+
+/// The frames queued for a port which its VM has not received yet; more are
+/// dropped.
+const PORT_QUEUE_LEN: usize = 256;
+/// The smallest frame carrying the destination and source addresses.
+const ETH_HLEN: usize = 14;
+
+struct SwitchState {
+    /// The frames queued for each port, or `None` for the ports removed.
+    ports: Vec<Option<VecDeque<Vec<u8>>>>,
+    /// The port each source MAC address was last seen on.
+    fdb: BTreeMap<[u8; ETH_ALEN], usize>,
+}
+
+impl SwitchState {
+    fn forward(&mut self, from: usize, frame: &[u8]) {
+        if frame.len() < ETH_HLEN {
+            return;
+        }
+        let dst: [u8; ETH_ALEN] = frame[..ETH_ALEN].try_into().unwrap();
+        let src: [u8; ETH_ALEN] = frame[ETH_ALEN..2 * ETH_ALEN].try_into().unwrap();
+        // Group addresses have the least significant bit of the first byte
+        // set, and are never sources.
+        if src[0] & 1 == 0 {
+            self.fdb.insert(src, from);
+        }
+        let unicast = dst[0] & 1 == 0;
+        match self.fdb.get(&dst) {
+            Some(&to) if unicast => {
+                if to != from {
+                    Self::enqueue(&mut self.ports[to], frame);
+                }
+            }
+            _ => {
+                for (to, port) in self.ports.iter_mut().enumerate() {
+                    if to != from {
+                        Self::enqueue(port, frame);
+                    }
+                }
+            }
+        }
+    }
+
+    fn enqueue(port: &mut Option<VecDeque<Vec<u8>>>, frame: &[u8]) {
+        if let Some(queue) = port.as_mut().filter(|queue| queue.len() < PORT_QUEUE_LEN) {
+            queue.push_back(frame.to_vec());
+        }
+    }
+}
+
+/// A software Ethernet switch connecting the [`VirtioNet`](super::VirtioNet)
+/// devices of several VMs, without any host network stack.
+///
+/// Each VM gets a [`SwitchPort`] as its backend. The switch learns the
+/// source addresses of the frames, forwards unicast frames to the port their
+/// destination was seen on, and floods the others to all the ports. Cloning
+/// it gives another handle to the same switch.
+#[derive(Clone)]
+pub struct NetSwitch {
+    state: Arc<Mutex<SwitchState>>,
+}
+
+impl NetSwitch {
+    /// Create a switch without ports.
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(SwitchState {
+                ports: Vec::new(),
+                fdb: BTreeMap::new(),
+            })),
+        }
+    }
+
+    /// Add a port, to be the backend of a network card.
+    pub fn add_port(&self) -> SwitchPort {
+        let mut state = self.state.lock();
+        let id = match state.ports.iter().position(Option::is_none) {
+            Some(id) => id,
+            None => {
+                state.ports.push(None);
+                state.ports.len() - 1
+            }
+        };
+        state.ports[id] = Some(VecDeque::new());
+        SwitchPort {
+            state: self.state.clone(),
+            id,
+        }
+    }
+}
+
+impl Default for NetSwitch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A port of a [`NetSwitch`]. It is removed from the switch when dropped.
+pub struct SwitchPort {
+    state: Arc<Mutex<SwitchState>>,
+    id: usize,
+}
+
+impl NetBackend for SwitchPort {
+    fn send(&mut self, frame: &[u8]) {
+        self.state.lock().forward(self.id, frame);
+    }
+
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        self.state.lock().ports[self.id].as_mut()?.pop_front()
+    }
+}
+
+impl Drop for SwitchPort {
+    fn drop(&mut self) {
+        let mut state = self.state.lock();
+        state.ports[self.id] = None;
+        state.fdb.retain(|_, &mut port| port != self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC_A: [u8; ETH_ALEN] = [0x52, 0x54, 0, 0, 0, 0xa];
+    const MAC_B: [u8; ETH_ALEN] = [0x52, 0x54, 0, 0, 0, 0xb];
+    const MAC_C: [u8; ETH_ALEN] = [0x52, 0x54, 0, 0, 0, 0xc];
+    const BROADCAST: [u8; ETH_ALEN] = [0xff; ETH_ALEN];
+
+    fn frame(dst: [u8; ETH_ALEN], src: [u8; ETH_ALEN]) -> Vec<u8> {
+        [&dst[..], &src[..], &[0x08, 0x00], b"ping"].concat()
+    }
+
+    fn drain(port: &mut SwitchPort) -> Vec<Vec<u8>> {
+        core::iter::from_fn(|| port.recv()).collect()
+    }
+
+    #[test]
+    fn unknown_destinations_are_flooded() {
+        let switch = NetSwitch::new();
+        let (mut a, mut b, mut c) = (switch.add_port(), switch.add_port(), switch.add_port());
+        let broadcast = frame(BROADCAST, MAC_A);
+        a.send(&broadcast);
+        let unicast = frame(MAC_B, MAC_A);
+        a.send(&unicast);
+        // Runts are dropped.
+        a.send(&unicast[..ETH_HLEN - 1]);
+
+        assert!(drain(&mut a).is_empty());
+        assert_eq!(drain(&mut b), [broadcast.clone(), unicast.clone()]);
+        assert_eq!(drain(&mut c), [broadcast, unicast]);
+    }
+
+    #[test]
+    fn learned_addresses_get_unicast_frames() {
+        let switch = NetSwitch::new();
+        let (mut a, mut b, mut c) = (switch.add_port(), switch.add_port(), switch.add_port());
+        a.send(&frame(BROADCAST, MAC_A));
+        b.send(&frame(BROADCAST, MAC_B));
+        for port in [&mut a, &mut b, &mut c] {
+            drain(port);
+        }
+
+        let to_a = frame(MAC_A, MAC_C);
+        c.send(&to_a);
+        assert_eq!(drain(&mut a), vec![to_a.clone()]);
+        assert!(drain(&mut b).is_empty());
+
+        // The address moves to the port it was last seen on.
+        b.send(&frame(BROADCAST, MAC_A));
+        for port in [&mut a, &mut c] {
+            drain(port);
+        }
+        c.send(&to_a);
+        assert!(drain(&mut a).is_empty());
+        assert_eq!(drain(&mut b), [to_a]);
+    }
+
+    #[test]
+    fn removed_ports_are_forgotten() {
+        let switch = NetSwitch::new();
+        let (mut a, mut b, mut c) = (switch.add_port(), switch.add_port(), switch.add_port());
+        a.send(&frame(BROADCAST, MAC_A));
+        drop(a);
+        for port in [&mut b, &mut c] {
+            drain(port);
+        }
+
+        // The new port takes the place of the removed one, but not its
+        // addresses.
+        let mut d = switch.add_port();
+        let to_a = frame(MAC_A, MAC_C);
+        c.send(&to_a);
+        assert_eq!(drain(&mut b), vec![to_a.clone()]);
+        assert_eq!(drain(&mut d), [to_a]);
+    }
+}
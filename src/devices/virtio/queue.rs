@@ -0,0 +1,643 @@
+use alloc::vec::Vec;
+use core::sync::atomic::{fence, Ordering};
+
+use crate::snapshot::{Snapshot, SnapshotReader, SnapshotWriter};
+use crate::{GuestMemory, GuestPhysAddr, HyperError, HyperResult};
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+const VIRTQ_DESC_F_INDIRECT: u16 = 4;
+
+const VIRTQ_AVAIL_F_NO_INTERRUPT: u16 = 1;
+
+const DESC_SIZE: usize = 16;
+const USED_ELEM_SIZE: usize = 8;
+/// The offset of the ring in the available and used rings, after the
+/// `flags` and `idx` fields.
+const RING_OFFSET: usize = 4;
+
+/// A buffer of a descriptor chain.
+#[derive(Debug, Clone, Copy)]
+pub struct Descriptor {
+    /// The guest physical address of the buffer.
+    pub addr: GuestPhysAddr,
+    /// The length of the buffer.
+    pub len: u32,
+    /// Whether the device writes the buffer, rather than reads it.
+    pub write: bool,
+}
+
+/// A request of the driver: a chain of device-readable buffers, followed by
+/// device-writable buffers.
+#[derive(Debug, Clone)]
+pub struct DescriptorChain {
+    head: u16,
+    descs: Vec<Descriptor>,
+}
+
+impl DescriptorChain {
+    /// The index of the first descriptor, which identifies the chain when it
+    /// is returned to the driver.
+    pub fn head(&self) -> u16 {
+        self.head
+    }
+
+    /// The buffers, in order, with the ones of indirect tables inlined.
+    pub fn descriptors(&self) -> &[Descriptor] {
+        &self.descs
+    }
+
+    /// The total length of the device-readable buffers.
+    pub fn readable_len(&self) -> usize {
+        self.readable().map(|desc| desc.len as usize).sum()
+    }
+
+    /// The total length of the device-writable buffers.
+    pub fn writable_len(&self) -> usize {
+        self.writable().map(|desc| desc.len as usize).sum()
+    }
+
+    /// Copy the device-readable bytes at `offset` into `buf`, as if the
+    /// buffers were contiguous. Returns the number of bytes copied, which is
+    /// short at the end of the buffers.
+    pub fn read(&self, memory: &GuestMemory, offset: usize, buf: &mut [u8]) -> HyperResult<usize> {
+        for_each_piece(self.readable(), offset, buf.len(), |gpa, pos, len| {
+            memory.read(gpa, &mut buf[pos..pos + len])
+        })
+    }
+
+    /// Read all the device-readable bytes, or fail with `InvalidParam` if
+    /// there are more than `max_len`.
+    pub fn read_to_vec(&self, memory: &GuestMemory, max_len: usize) -> HyperResult<Vec<u8>> {
+        let len = self.readable_len();
+        if len > max_len {
+            return Err(HyperError::InvalidParam);
+        }
+        let mut buf = vec![0; len];
+        self.read(memory, 0, &mut buf)?;
+        Ok(buf)
+    }
+
+    /// Copy `data` to the device-writable bytes at `offset`, as if the
+    /// buffers were contiguous. Returns the number of bytes copied, which is
+    /// short at the end of the buffers.
+    pub fn write(&self, memory: &GuestMemory, offset: usize, data: &[u8]) -> HyperResult<usize> {
+        for_each_piece(self.writable(), offset, data.len(), |gpa, pos, len| {
+            memory.write(gpa, &data[pos..pos + len])
+        })
+    }
+
+    fn readable(&self) -> impl Iterator<Item = &Descriptor> {
+        self.descs.iter().filter(|desc| !desc.write)
+    }
+
+    fn writable(&self) -> impl Iterator<Item = &Descriptor> {
+        self.descs.iter().filter(|desc| desc.write)
+    }
+}
+
+/// Call `f` with the guest physical address, the position in the caller's
+/// buffer and the length of each piece of the `len` bytes at `offset` of
+/// `descs`. Returns the number of bytes visited.
+fn for_each_piece<'a>(
+    descs: impl Iterator<Item = &'a Descriptor>,
+    mut offset: usize,
+    len: usize,
+    mut f: impl FnMut(GuestPhysAddr, usize, usize) -> HyperResult,
+) -> HyperResult<usize> {
+    let mut done = 0;
+    for desc in descs {
+        if done == len {
+            break;
+        }
+        let desc_len = desc.len as usize;
+        if offset >= desc_len {
+            offset -= desc_len;
+            continue;
+        }
+        let piece = (desc_len - offset).min(len - done);
+        f(desc.addr + offset, done, piece)?;
+        done += piece;
+        offset = 0;
+    }
+    Ok(done)
+}
+
+/// A split virtqueue, whose descriptor table, available ring and used ring
+/// live in guest memory.
+///
+/// The device takes the chains the driver made available with
+/// [`Virtqueue::pop`], and gives them back with [`Virtqueue::add_used`].
+/// The transport notifies the driver of the used buffers afterwards, unless
+/// it asked not to be.
+#[derive(Debug, Clone)]
+pub struct Virtqueue {
+    max_size: u16,
+    pub(crate) size: u16,
+    pub(crate) ready: bool,
+    pub(crate) desc_table: GuestPhysAddr,
+    pub(crate) avail_ring: GuestPhysAddr,
+    pub(crate) used_ring: GuestPhysAddr,
+    /// The index in the available ring of the next chain to take.
+    next_avail: u16,
+    /// The index in the used ring of the next chain to give back.
+    next_used: u16,
+    /// Whether used buffers were added that the driver wants to be notified
+    /// of.
+    notify: bool,
+}
+
+/// The largest size is set by the device, so a size above it is
+/// `InvalidParam`.
+impl Snapshot for Virtqueue {
+    fn save(&self, w: &mut SnapshotWriter) {
+        self.size.save(w);
+        self.ready.save(w);
+        self.desc_table.save(w);
+        self.avail_ring.save(w);
+        self.used_ring.save(w);
+        self.next_avail.save(w);
+        self.next_used.save(w);
+        self.notify.save(w);
+    }
+
+    fn restore(&mut self, r: &mut SnapshotReader<'_>) -> HyperResult {
+        self.size.restore(r)?;
+        self.ready.restore(r)?;
+        if self.size > self.max_size || (self.ready && !self.size_valid()) {
+            return Err(HyperError::InvalidParam);
+        }
+        self.desc_table.restore(r)?;
+        self.avail_ring.restore(r)?;
+        self.used_ring.restore(r)?;
+        self.next_avail.restore(r)?;
+        self.next_used.restore(r)?;
+        self.notify.restore(r)
+    }
+}
+
+impl Virtqueue {
+    /// Create a queue of up to `max_size` descriptors, not set up by the
+    /// driver yet.
+    pub const fn new(max_size: u16) -> Self {
+        Self {
+            max_size,
+            size: 0,
+            ready: false,
+            desc_table: 0,
+            avail_ring: 0,
+            used_ring: 0,
+            next_avail: 0,
+            next_used: 0,
+            notify: false,
+        }
+    }
+
+    /// The largest size the driver may choose.
+    pub fn max_size(&self) -> u16 {
+        self.max_size
+    }
+
+    /// The number of descriptors chosen by the driver.
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Whether the driver has set the queue up.
+    pub fn ready(&self) -> bool {
+        self.ready
+    }
+
+    /// Whether the size chosen by the driver is valid: split virtqueues are
+    /// a power of two long.
+    pub(crate) fn size_valid(&self) -> bool {
+        self.size.is_power_of_two() && self.size <= self.max_size
+    }
+
+    /// Take the next chain made available by the driver, or `None` if there
+    /// is none. Fails if the chain is malformed, e.g. it loops or a readable
+    /// buffer follows a writable one.
+    pub fn pop(&mut self, memory: &GuestMemory) -> HyperResult<Option<DescriptorChain>> {
+        if !self.ready {
+            return Ok(None);
+        }
+        let avail_idx = u16::from_le(memory.read_obj(self.avail_ring + 2)?);
+        if avail_idx == self.next_avail {
+            return Ok(None);
+        }
+        if avail_idx.wrapping_sub(self.next_avail) > self.size {
+            return Err(HyperError::InvalidParam);
+        }
+        // Read the ring entry only after the index that published it.
+        fence(Ordering::Acquire);
+        let slot = (self.next_avail % self.size) as usize;
+        let entry = self.avail_ring + RING_OFFSET + slot * 2;
+        let head = u16::from_le(memory.read_obj(entry)?);
+        self.next_avail = self.next_avail.wrapping_add(1);
+        self.read_chain(memory, head).map(Some)
+    }
+
+    /// Put back the last chain taken by [`Virtqueue::pop`], e.g. because the
+    /// device cannot use it yet. The next `pop` returns it again.
+    pub fn undo_pop(&mut self) {
+        self.next_avail = self.next_avail.wrapping_sub(1);
+    }
+
+    /// Give the chain `head` back to the driver, with `len` bytes written to
+    /// its device-writable buffers.
+    pub fn add_used(&mut self, memory: &GuestMemory, head: u16, len: u32) -> HyperResult {
+        if !self.ready {
+            return Err(HyperError::BadState);
+        }
+        let slot = (self.next_used % self.size) as usize;
+        let elem = self.used_ring + RING_OFFSET + slot * USED_ELEM_SIZE;
+        memory.write_obj(elem, &(head as u32).to_le())?;
+        memory.write_obj(elem + 4, &len.to_le())?;
+        self.next_used = self.next_used.wrapping_add(1);
+        // Publish the element before the index.
+        fence(Ordering::Release);
+        memory.write_obj(self.used_ring + 2, &self.next_used.to_le())?;
+        // Read the flags only after the index is visible, or the driver may
+        // miss the buffer after enabling interrupts again.
+        fence(Ordering::SeqCst);
+        let flags = u16::from_le(memory.read_obj(self.avail_ring)?);
+        if flags & VIRTQ_AVAIL_F_NO_INTERRUPT == 0 {
+            self.notify = true;
+        }
+        Ok(())
+    }
+
+    /// Whether used buffers were added since the last call that the driver
+    /// wants to be notified of.
+    pub(crate) fn take_notification(&mut self) -> bool {
+        core::mem::take(&mut self.notify)
+    }
+
+    /// Forget the setup of the driver, when the device is reset.
+    pub(crate) fn reset(&mut self) {
+        *self = Self::new(self.max_size);
+    }
+
+    fn read_chain(&self, memory: &GuestMemory, head: u16) -> HyperResult<DescriptorChain> {
+        let mut descs: Vec<Descriptor> = Vec::new();
+        let mut table = self.desc_table;
+        let mut table_len = self.size as usize;
+        let mut index = head as usize;
+        let mut indirect = false;
+        // A chain visits each descriptor of its table at most once, so a
+        // longer one loops. Chains are no longer than the queue either, with
+        // or without an indirect table.
+        let mut budget = table_len;
+        loop {
+            if index >= table_len || budget == 0 {
+                return Err(HyperError::InvalidParam);
+            }
+            budget -= 1;
+            let raw: [u8; DESC_SIZE] = memory.read_obj(table + index * DESC_SIZE)?;
+            let addr = u64::from_le_bytes(raw[0..8].try_into().unwrap()) as usize;
+            let len = u32::from_le_bytes(raw[8..12].try_into().unwrap());
+            let flags = u16::from_le_bytes(raw[12..14].try_into().unwrap());
+            let next = u16::from_le_bytes(raw[14..16].try_into().unwrap());
+
+            if flags & VIRTQ_DESC_F_INDIRECT != 0 {
+                // The indirect table holds the rest of the chain, and cannot
+                // itself refer to another table.
+                let len = len as usize;
+                if indirect || flags & VIRTQ_DESC_F_NEXT != 0 || len == 0 || len % DESC_SIZE != 0 {
+                    return Err(HyperError::InvalidParam);
+                }
+                if len / DESC_SIZE > self.size as usize {
+                    return Err(HyperError::InvalidParam);
+                }
+                indirect = true;
+                table = addr;
+                table_len = len / DESC_SIZE;
+                budget = table_len;
+                index = 0;
+                continue;
+            }
+
+            let write = flags & VIRTQ_DESC_F_WRITE != 0;
+            if !write && matches!(descs.last(), Some(desc) if desc.write) {
+                return Err(HyperError::InvalidParam);
+            }
+            if descs.len() == self.size as usize {
+                return Err(HyperError::InvalidParam);
+            }
+            descs.push(Descriptor { addr, len, write });
+            if flags & VIRTQ_DESC_F_NEXT == 0 {
+                break;
+            }
+            index = next as usize;
+        }
+        Ok(DescriptorChain { head, descs })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::memory::tests::{host_backed, HostPage};
+
+    const RAM: GuestPhysAddr = 0x4000_0000;
+    const DESC_TABLE: GuestPhysAddr = RAM;
+    const AVAIL_RING: GuestPhysAddr = RAM + 0x1000;
+    const USED_RING: GuestPhysAddr = RAM + 0x2000;
+    const BUFFERS: GuestPhysAddr = RAM + 0x3000;
+
+    /// A ready queue of 16 descriptors in 4 pages of RAM, whose last page
+    /// holds the buffers.
+    fn queue() -> (Vec<HostPage>, GuestMemory, Virtqueue) {
+        let (host, memory) = host_backed(RAM, 4);
+        let mut queue = Virtqueue::new(16);
+        queue.size = 16;
+        queue.ready = true;
+        queue.desc_table = DESC_TABLE;
+        queue.avail_ring = AVAIL_RING;
+        queue.used_ring = USED_RING;
+        (host, memory, queue)
+    }
+
+    fn set_desc(
+        memory: &GuestMemory,
+        table: GuestPhysAddr,
+        index: usize,
+        addr: GuestPhysAddr,
+        len: u32,
+        flags: u16,
+        next: u16,
+    ) {
+        let desc = table + index * DESC_SIZE;
+        memory.write_obj(desc, &(addr as u64).to_le()).unwrap();
+        memory.write_obj(desc + 8, &len.to_le()).unwrap();
+        memory.write_obj(desc + 12, &flags.to_le()).unwrap();
+        memory.write_obj(desc + 14, &next.to_le()).unwrap();
+    }
+
+    /// The driver side of a queue of [`Driver::SIZE`] descriptors, whose
+    /// rings are in the 1 KiB of guest memory at `base`.
+    pub(crate) struct Driver {
+        base: GuestPhysAddr,
+        next_desc: u16,
+        next_avail: u16,
+        next_used: u16,
+    }
+
+    impl Driver {
+        pub const SIZE: u16 = 16;
+        const AVAIL_OFFSET: usize = 0x100;
+        const USED_OFFSET: usize = 0x200;
+
+        pub fn new(base: GuestPhysAddr) -> Self {
+            Self {
+                base,
+                next_desc: 0,
+                next_avail: 0,
+                next_used: 0,
+            }
+        }
+
+        /// The queue of the device, set up by this driver.
+        pub fn queue(&self) -> Virtqueue {
+            let mut queue = Virtqueue::new(Self::SIZE);
+            queue.size = Self::SIZE;
+            queue.ready = true;
+            queue.desc_table = self.base;
+            queue.avail_ring = self.base + Self::AVAIL_OFFSET;
+            queue.used_ring = self.base + Self::USED_OFFSET;
+            queue
+        }
+
+        /// Make a chain of `buffers` available: their address, their length
+        /// and whether the device writes them. Returns its head.
+        pub fn add(&mut self, memory: &GuestMemory, buffers: &[(GuestPhysAddr, u32, bool)]) -> u16 {
+            let head = self.next_desc;
+            for (i, &(addr, len, write)) in buffers.iter().enumerate() {
+                let index = (head + i as u16) % Self::SIZE;
+                let next = (index + 1) % Self::SIZE;
+                let mut flags = if write { VIRTQ_DESC_F_WRITE } else { 0 };
+                if i + 1 < buffers.len() {
+                    flags |= VIRTQ_DESC_F_NEXT;
+                }
+                set_desc(memory, self.base, index as usize, addr, len, flags, next);
+            }
+            self.next_desc = (head + buffers.len() as u16) % Self::SIZE;
+
+            let avail = self.base + Self::AVAIL_OFFSET;
+            let slot = (self.next_avail % Self::SIZE) as usize;
+            memory
+                .write_obj(avail + RING_OFFSET + slot * 2, &head.to_le())
+                .unwrap();
+            self.next_avail = self.next_avail.wrapping_add(1);
+            memory
+                .write_obj(avail + 2, &self.next_avail.to_le())
+                .unwrap();
+            head
+        }
+
+        /// Take the next chain used by the device: its head and the number
+        /// of bytes written.
+        pub fn used(&mut self, memory: &GuestMemory) -> Option<(u16, u32)> {
+            let used = self.base + Self::USED_OFFSET;
+            if u16::from_le(memory.read_obj(used + 2).unwrap()) == self.next_used {
+                return None;
+            }
+            let elem = used + RING_OFFSET + (self.next_used % Self::SIZE) as usize * USED_ELEM_SIZE;
+            self.next_used = self.next_used.wrapping_add(1);
+            let head = u32::from_le(memory.read_obj(elem).unwrap());
+            let len = u32::from_le(memory.read_obj(elem + 4).unwrap());
+            Some((head as u16, len))
+        }
+    }
+
+    /// Make the chain `head` available as the first one.
+    fn publish(memory: &GuestMemory, head: u16) {
+        memory
+            .write_obj(AVAIL_RING + RING_OFFSET, &head.to_le())
+            .unwrap();
+        memory.write_obj(AVAIL_RING + 2, &1u16.to_le()).unwrap();
+    }
+
+    #[test]
+    fn pop_direct_chain() {
+        let (_host, memory, mut queue) = queue();
+        set_desc(&memory, DESC_TABLE, 3, BUFFERS, 8, VIRTQ_DESC_F_NEXT, 5);
+        set_desc(
+            &memory,
+            DESC_TABLE,
+            5,
+            BUFFERS + 0x100,
+            16,
+            VIRTQ_DESC_F_WRITE,
+            0,
+        );
+        publish(&memory, 3);
+
+        let chain = queue.pop(&memory).unwrap().unwrap();
+        assert_eq!(chain.head(), 3);
+        assert_eq!(chain.descriptors().len(), 2);
+        assert_eq!((chain.readable_len(), chain.writable_len()), (8, 16));
+        assert!(queue.pop(&memory).unwrap().is_none());
+
+        queue.add_used(&memory, chain.head(), 16).unwrap();
+        assert_eq!(memory.read_obj::<u32>(USED_RING + RING_OFFSET), Ok(3));
+        assert_eq!(memory.read_obj::<u32>(USED_RING + RING_OFFSET + 4), Ok(16));
+        assert_eq!(memory.read_obj::<u16>(USED_RING + 2), Ok(1));
+        assert!(queue.take_notification());
+    }
+
+    #[test]
+    fn pop_indirect_chain() {
+        let (_host, memory, mut queue) = queue();
+        let table = BUFFERS + 0x800;
+        set_desc(
+            &memory,
+            DESC_TABLE,
+            0,
+            table,
+            3 * DESC_SIZE as u32,
+            VIRTQ_DESC_F_INDIRECT,
+            0,
+        );
+        set_desc(&memory, table, 0, BUFFERS, 4, VIRTQ_DESC_F_NEXT, 2);
+        set_desc(&memory, table, 2, BUFFERS + 0x10, 8, VIRTQ_DESC_F_NEXT, 1);
+        set_desc(&memory, table, 1, BUFFERS + 0x20, 32, VIRTQ_DESC_F_WRITE, 0);
+        publish(&memory, 0);
+
+        let chain = queue.pop(&memory).unwrap().unwrap();
+        let addrs: Vec<_> = chain.descriptors().iter().map(|desc| desc.addr).collect();
+        assert_eq!(addrs, [BUFFERS, BUFFERS + 0x10, BUFFERS + 0x20]);
+        assert_eq!((chain.readable_len(), chain.writable_len()), (12, 32));
+    }
+
+    #[test]
+    fn pop_rejects_malformed_chains() {
+        let bad_chains: [&dyn Fn(&GuestMemory); 6] = [
+            // A loop.
+            &|memory| {
+                set_desc(memory, DESC_TABLE, 0, BUFFERS, 8, VIRTQ_DESC_F_NEXT, 1);
+                set_desc(memory, DESC_TABLE, 1, BUFFERS, 8, VIRTQ_DESC_F_NEXT, 0);
+            },
+            // A readable buffer after a writable one.
+            &|memory| {
+                let flags = VIRTQ_DESC_F_WRITE | VIRTQ_DESC_F_NEXT;
+                set_desc(memory, DESC_TABLE, 0, BUFFERS, 8, flags, 1);
+                set_desc(memory, DESC_TABLE, 1, BUFFERS, 8, 0, 0);
+            },
+            // An indirect table in an indirect table.
+            &|memory| {
+                let (table, len) = (BUFFERS + 0x800, DESC_SIZE as u32);
+                set_desc(memory, DESC_TABLE, 0, table, len, VIRTQ_DESC_F_INDIRECT, 0);
+                set_desc(memory, table, 0, table, len, VIRTQ_DESC_F_INDIRECT, 0);
+            },
+            // A next descriptor past the table.
+            &|memory| set_desc(memory, DESC_TABLE, 0, BUFFERS, 8, VIRTQ_DESC_F_NEXT, 16),
+            // An indirect table larger than the queue.
+            &|memory| {
+                let len = 17 * DESC_SIZE as u32;
+                set_desc(
+                    memory,
+                    DESC_TABLE,
+                    0,
+                    BUFFERS,
+                    len,
+                    VIRTQ_DESC_F_INDIRECT,
+                    0,
+                );
+            },
+            // A chain longer than the queue, through an indirect table.
+            &|memory| {
+                let table = BUFFERS + 0x800;
+                set_desc(memory, DESC_TABLE, 0, BUFFERS, 8, VIRTQ_DESC_F_NEXT, 1);
+                let len = 16 * DESC_SIZE as u32;
+                set_desc(memory, DESC_TABLE, 1, table, len, VIRTQ_DESC_F_INDIRECT, 0);
+                for index in 0..16 {
+                    set_desc(
+                        memory,
+                        table,
+                        index,
+                        BUFFERS,
+                        8,
+                        VIRTQ_DESC_F_NEXT,
+                        index as u16 + 1,
+                    );
+                }
+                set_desc(memory, table, 15, BUFFERS, 8, 0, 0);
+            },
+        ];
+        for make_chain in bad_chains {
+            let (_host, memory, mut queue) = queue();
+            make_chain(&memory);
+            publish(&memory, 0);
+            assert_eq!(queue.pop(&memory).unwrap_err(), HyperError::InvalidParam);
+        }
+    }
+
+    #[test]
+    fn access_across_descriptors() {
+        let (_host, memory, _) = queue();
+        let descriptor = |addr, len, write| Descriptor { addr, len, write };
+        let chain = DescriptorChain {
+            head: 0,
+            descs: vec![
+                descriptor(BUFFERS, 3, false),
+                descriptor(BUFFERS + 0x100, 5, false),
+                descriptor(BUFFERS + 0x200, 2, true),
+                descriptor(BUFFERS + 0x300, 4, true),
+            ],
+        };
+        memory.write(BUFFERS, &[1, 2, 3]).unwrap();
+        memory.write(BUFFERS + 0x100, &[4, 5, 6, 7, 8]).unwrap();
+
+        let mut buf = [0; 8];
+        assert_eq!(chain.read(&memory, 2, &mut buf), Ok(6));
+        assert_eq!(buf, [3, 4, 5, 6, 7, 8, 0, 0]);
+        assert_eq!(
+            chain.read_to_vec(&memory, 8),
+            Ok(vec![1, 2, 3, 4, 5, 6, 7, 8])
+        );
+        assert_eq!(chain.read_to_vec(&memory, 7), Err(HyperError::InvalidParam));
+
+        assert_eq!(chain.write(&memory, 1, &[9; 8]), Ok(5));
+        assert_eq!(memory.read_obj::<[u8; 2]>(BUFFERS + 0x200), Ok([0, 9]));
+        assert_eq!(
+            memory.read_obj::<[u8; 5]>(BUFFERS + 0x300),
+            Ok([9, 9, 9, 9, 0])
+        );
+    }
+
+    #[test]
+    fn snapshot_restores_progress() {
+        use crate::snapshot::SECTION_IRQCHIP;
+
+        let (_host, memory, mut queue) = queue();
+        set_desc(&memory, DESC_TABLE, 0, BUFFERS, 8, 0, 0);
+        publish(&memory, 0);
+        let chain = queue.pop(&memory).unwrap().unwrap();
+        queue.add_used(&memory, chain.head(), 0).unwrap();
+
+        let mut w = SnapshotWriter::new();
+        w.section(SECTION_IRQCHIP, |w| {
+            queue.save(w);
+            Ok(())
+        })
+        .unwrap();
+        let saved = w.finish();
+        let restore = |queue: &mut Virtqueue| {
+            let mut r = SnapshotReader::new(&saved)?;
+            r.section(SECTION_IRQCHIP, |r| queue.restore(r))?;
+            r.finish()
+        };
+
+        let mut restored = Virtqueue::new(16);
+        assert_eq!(restore(&mut restored), Ok(()));
+        assert_eq!((restored.size(), restored.ready()), (16, true));
+        assert_eq!((restored.next_avail, restored.next_used), (1, 1));
+        assert!(restored.take_notification());
+        assert!(restored.pop(&memory).unwrap().is_none());
+
+        // The device of the target supports smaller queues.
+        let mut small = Virtqueue::new(8);
+        assert_eq!(restore(&mut small), Err(HyperError::InvalidParam));
+    }
+}
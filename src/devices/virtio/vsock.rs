@@ -0,0 +1,737 @@
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+
+use super::{read_config_bytes, VirtioDevice, Virtqueue, VIRTIO_ID_VSOCK};
+use crate::{GuestMemory, HyperError, HyperResult};
+
+const VIRTIO_VSOCK_TYPE_STREAM: u16 = 1;
+
+const VIRTIO_VSOCK_OP_REQUEST: u16 = 1;
+const VIRTIO_VSOCK_OP_RESPONSE: u16 = 2;
+const VIRTIO_VSOCK_OP_RST: u16 = 3;
+const VIRTIO_VSOCK_OP_SHUTDOWN: u16 = 4;
+const VIRTIO_VSOCK_OP_RW: u16 = 5;
+const VIRTIO_VSOCK_OP_CREDIT_UPDATE: u16 = 6;
+const VIRTIO_VSOCK_OP_CREDIT_REQUEST: u16 = 7;
+
+/// The peer will receive no more data.
+const VIRTIO_VSOCK_SHUTDOWN_RCV: u32 = 1;
+/// The peer will send no more data.
+const VIRTIO_VSOCK_SHUTDOWN_SEND: u32 = 2;
+const VIRTIO_VSOCK_SHUTDOWN_ALL: u32 = VIRTIO_VSOCK_SHUTDOWN_RCV | VIRTIO_VSOCK_SHUTDOWN_SEND;
+
+/// The header preceding each packet: src_cid, dst_cid, src_port, dst_port,
+/// len, type, op, flags, buf_alloc and fwd_cnt.
+const HDR_SIZE: usize = 44;
+/// The largest payload of the packets sent to the guest.
+const MAX_PKT_PAYLOAD: usize = 64 * 1024;
+/// The receive buffer advertised to the guest for each connection. Data is
+/// passed to the handler at once, so it never fills up.
+const HOST_BUF_ALLOC: u32 = 256 * 1024;
+/// The data of each connection the host may queue for the guest.
+const CONN_OUTPUT_SIZE: usize = 256 * 1024;
+
+const QUEUE_RX: usize = 0;
+const QUEUE_TX: usize = 1;
+const QUEUE_EVENT: usize = 2;
+
+const CONFIG_SIZE: usize = 8;
+
+/// The context ID of the host.
+pub const VSOCK_HOST_CID: u64 = 2;
+
+/// A stream connection between a port of the guest and a port of the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct VsockConn {
+    /// The port of the guest.
+    pub guest_port: u32,
+    /// The port of the host.
+    pub host_port: u32,
+}
+
+/// The host side of a [`VirtioVsock`], called with the connections and the
+/// data of the guest.
+pub trait VsockHandler: Send {
+    /// The guest connects to `conn.host_port`. Returns whether the connection
+    /// is accepted.
+    fn on_connect(&mut self, conn: VsockConn) -> bool;
+
+    /// The guest accepted a connection opened by [`VirtioVsock::connect`].
+    fn on_connected(&mut self, conn: VsockConn) {
+        let _ = conn;
+    }
+
+    /// The guest sent `data` on `conn`.
+    fn on_data(&mut self, conn: VsockConn, data: &[u8]);
+
+    /// The guest closed `conn` or refused to open it, or the device was
+    /// reset.
+    fn on_close(&mut self, conn: VsockConn) {
+        let _ = conn;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConnState {
+    /// Opened by the host, waiting for the guest to accept.
+    Connecting,
+    Established,
+    /// Closed by the host, waiting for the guest to acknowledge.
+    Closing,
+}
+
+struct Connection {
+    state: ConnState,
+    /// The data of the host not sent to the guest yet.
+    output: VecDeque<u8>,
+    /// The bytes sent to the guest.
+    tx_cnt: u32,
+    /// The bytes received from the guest.
+    fwd_cnt: u32,
+    /// The `fwd_cnt` last advertised to the guest.
+    fwd_cnt_sent: u32,
+    /// The receive buffer of the guest, and the bytes it consumed.
+    peer_buf_alloc: u32,
+    peer_fwd_cnt: u32,
+    /// The `VIRTIO_VSOCK_SHUTDOWN_*` flags sent by the guest.
+    peer_shutdown: u32,
+    shutdown_sent: bool,
+}
+
+impl Connection {
+    fn new(state: ConnState, peer_buf_alloc: u32, peer_fwd_cnt: u32) -> Self {
+        Self {
+            state,
+            output: VecDeque::new(),
+            tx_cnt: 0,
+            fwd_cnt: 0,
+            fwd_cnt_sent: 0,
+            peer_buf_alloc,
+            peer_fwd_cnt,
+            peer_shutdown: 0,
+            shutdown_sent: false,
+        }
+    }
+
+    /// The bytes the guest can receive now.
+    fn peer_credit(&self) -> usize {
+        let in_flight = self.tx_cnt.wrapping_sub(self.peer_fwd_cnt);
+        self.peer_buf_alloc.saturating_sub(in_flight) as usize
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PacketHeader {
+    src_cid: u64,
+    dst_cid: u64,
+    src_port: u32,
+    dst_port: u32,
+    len: u32,
+    type_: u16,
+    op: u16,
+    flags: u32,
+    buf_alloc: u32,
+    fwd_cnt: u32,
+}
+
+impl PacketHeader {
+    fn parse(bytes: &[u8]) -> Self {
+        let u64_at =
+            |offset: usize| u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+        let u16_at =
+            |offset: usize| u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap());
+        Self {
+            src_cid: u64_at(0),
+            dst_cid: u64_at(8),
+            src_port: u32_at(16),
+            dst_port: u32_at(20),
+            len: u32_at(24),
+            type_: u16_at(28),
+            op: u16_at(30),
+            flags: u32_at(32),
+            buf_alloc: u32_at(36),
+            fwd_cnt: u32_at(40),
+        }
+    }
+
+    fn to_bytes(self) -> [u8; HDR_SIZE] {
+        let mut bytes = [0; HDR_SIZE];
+        bytes[0..8].copy_from_slice(&self.src_cid.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.dst_cid.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.src_port.to_le_bytes());
+        bytes[20..24].copy_from_slice(&self.dst_port.to_le_bytes());
+        bytes[24..28].copy_from_slice(&self.len.to_le_bytes());
+        bytes[28..30].copy_from_slice(&self.type_.to_le_bytes());
+        bytes[30..32].copy_from_slice(&self.op.to_le_bytes());
+        bytes[32..36].copy_from_slice(&self.flags.to_le_bytes());
+        bytes[36..40].copy_from_slice(&self.buf_alloc.to_le_bytes());
+        bytes[40..44].copy_from_slice(&self.fwd_cnt.to_le_bytes());
+        bytes
+    }
+}
+
+/// A virtio vsock transport, carrying stream sockets between the guest and
+/// the host without any network, e.g. for RPC to an agent of the guest.
+///
+/// The host has CID [`VSOCK_HOST_CID`]. Connections of the guest to the host
+/// and their data are passed to a [`VsockHandler`]. The host opens
+/// connections to the guest with [`VirtioVsock::connect`], and sends data
+/// with [`VirtioVsock::send`] within the buffer space the guest advertises.
+pub struct VirtioVsock {
+    guest_cid: u64,
+    handler: Box<dyn VsockHandler>,
+    conns: BTreeMap<VsockConn, Connection>,
+    /// The packets without data not received by the guest yet: connection,
+    /// operation and flags.
+    control: VecDeque<(VsockConn, u16, u32)>,
+}
+
+impl VirtioVsock {
+    /// Create a vsock transport for a guest with CID `guest_cid`, whose
+    /// connections go to `handler`.
+    pub fn new(guest_cid: u64, handler: Box<dyn VsockHandler>) -> Self {
+        Self {
+            guest_cid,
+            handler,
+            conns: BTreeMap::new(),
+            control: VecDeque::new(),
+        }
+    }
+
+    /// The context ID of the guest.
+    pub fn guest_cid(&self) -> u64 {
+        self.guest_cid
+    }
+
+    /// Connect `host_port` to `guest_port` of the guest. The handler is told
+    /// by [`VsockHandler::on_connected`] once the guest accepts.
+    pub fn connect(&mut self, host_port: u32, guest_port: u32) -> HyperResult<VsockConn> {
+        let conn = VsockConn {
+            guest_port,
+            host_port,
+        };
+        if self.conns.contains_key(&conn) {
+            return Err(HyperError::BadState);
+        }
+        self.conns
+            .insert(conn, Connection::new(ConnState::Connecting, 0, 0));
+        self.control.push_back((conn, VIRTIO_VSOCK_OP_REQUEST, 0));
+        Ok(conn)
+    }
+
+    /// Whether `conn` is open in both directions.
+    pub fn is_connected(&self, conn: VsockConn) -> bool {
+        self.conns
+            .get(&conn)
+            .is_some_and(|c| c.state == ConnState::Established)
+    }
+
+    /// Queue `data` for the guest on `conn`. Returns the number of bytes
+    /// accepted, which is short when the output of the connection is full.
+    pub fn send(&mut self, conn: VsockConn, data: &[u8]) -> HyperResult<usize> {
+        let c = self.conns.get_mut(&conn).ok_or(HyperError::NotFound)?;
+        if c.state == ConnState::Closing || c.peer_shutdown & VIRTIO_VSOCK_SHUTDOWN_RCV != 0 {
+            return Err(HyperError::BadState);
+        }
+        let len = data.len().min(CONN_OUTPUT_SIZE - c.output.len());
+        c.output.extend(&data[..len]);
+        Ok(len)
+    }
+
+    /// Close `conn`, once its queued data is sent.
+    pub fn close(&mut self, conn: VsockConn) -> HyperResult {
+        let c = self.conns.get_mut(&conn).ok_or(HyperError::NotFound)?;
+        match c.state {
+            ConnState::Connecting => {
+                self.conns.remove(&conn);
+                self.control.push_back((conn, VIRTIO_VSOCK_OP_RST, 0));
+            }
+            ConnState::Established => c.state = ConnState::Closing,
+            ConnState::Closing => {}
+        }
+        Ok(())
+    }
+
+    fn header(&mut self, conn: VsockConn, op: u16, flags: u32, len: usize) -> PacketHeader {
+        let (buf_alloc, fwd_cnt) = match self.conns.get_mut(&conn) {
+            Some(c) => {
+                c.fwd_cnt_sent = c.fwd_cnt;
+                (HOST_BUF_ALLOC, c.fwd_cnt)
+            }
+            None => (0, 0),
+        };
+        PacketHeader {
+            src_cid: VSOCK_HOST_CID,
+            dst_cid: self.guest_cid,
+            src_port: conn.host_port,
+            dst_port: conn.guest_port,
+            len: len as u32,
+            type_: VIRTIO_VSOCK_TYPE_STREAM,
+            op,
+            flags,
+            buf_alloc,
+            fwd_cnt,
+        }
+    }
+
+    /// Handle a packet of the guest.
+    fn handle_packet(&mut self, hdr: PacketHeader, payload: &[u8]) {
+        let conn = VsockConn {
+            guest_port: hdr.src_port,
+            host_port: hdr.dst_port,
+        };
+        if hdr.src_cid != self.guest_cid
+            || hdr.dst_cid != VSOCK_HOST_CID
+            || hdr.type_ != VIRTIO_VSOCK_TYPE_STREAM
+        {
+            if hdr.op != VIRTIO_VSOCK_OP_RST {
+                self.control.push_back((conn, VIRTIO_VSOCK_OP_RST, 0));
+            }
+            return;
+        }
+
+        let Some(c) = self.conns.get_mut(&conn) else {
+            match hdr.op {
+                VIRTIO_VSOCK_OP_REQUEST if self.handler.on_connect(conn) => {
+                    let c = Connection::new(ConnState::Established, hdr.buf_alloc, hdr.fwd_cnt);
+                    self.conns.insert(conn, c);
+                    self.control.push_back((conn, VIRTIO_VSOCK_OP_RESPONSE, 0));
+                }
+                VIRTIO_VSOCK_OP_RST => {}
+                _ => self.control.push_back((conn, VIRTIO_VSOCK_OP_RST, 0)),
+            }
+            return;
+        };
+        c.peer_buf_alloc = hdr.buf_alloc;
+        c.peer_fwd_cnt = hdr.fwd_cnt;
+
+        let state = c.state;
+        match hdr.op {
+            VIRTIO_VSOCK_OP_RESPONSE if state == ConnState::Connecting => {
+                c.state = ConnState::Established;
+                self.handler.on_connected(conn);
+            }
+            VIRTIO_VSOCK_OP_RW if state != ConnState::Connecting => {
+                c.fwd_cnt = c.fwd_cnt.wrapping_add(payload.len() as u32);
+                if c.fwd_cnt.wrapping_sub(c.fwd_cnt_sent) >= HOST_BUF_ALLOC / 2 {
+                    self.control
+                        .push_back((conn, VIRTIO_VSOCK_OP_CREDIT_UPDATE, 0));
+                }
+                self.handler.on_data(conn, payload);
+            }
+            VIRTIO_VSOCK_OP_CREDIT_UPDATE => {}
+            VIRTIO_VSOCK_OP_CREDIT_REQUEST => {
+                self.control
+                    .push_back((conn, VIRTIO_VSOCK_OP_CREDIT_UPDATE, 0));
+            }
+            VIRTIO_VSOCK_OP_SHUTDOWN if state != ConnState::Connecting => {
+                c.peer_shutdown |= hdr.flags & VIRTIO_VSOCK_SHUTDOWN_ALL;
+                if c.peer_shutdown == VIRTIO_VSOCK_SHUTDOWN_ALL {
+                    self.conns.remove(&conn);
+                    self.control.push_back((conn, VIRTIO_VSOCK_OP_RST, 0));
+                    if state != ConnState::Closing {
+                        self.handler.on_close(conn);
+                    }
+                }
+            }
+            VIRTIO_VSOCK_OP_RST => {
+                self.conns.remove(&conn);
+                if state != ConnState::Closing {
+                    self.handler.on_close(conn);
+                }
+            }
+            _ => {
+                debug!(
+                    "virtio-vsock: unexpected op {} on {:?}, reset",
+                    hdr.op, conn
+                );
+                self.conns.remove(&conn);
+                self.control.push_back((conn, VIRTIO_VSOCK_OP_RST, 0));
+                self.handler.on_close(conn);
+            }
+        }
+    }
+
+    /// Handle the packets the guest made available on the transmit queue.
+    fn transmit(&mut self, queue: &mut Virtqueue, memory: &GuestMemory) -> HyperResult {
+        while let Some(chain) = queue.pop(memory)? {
+            let mut raw = [0; HDR_SIZE];
+            if chain.read(memory, 0, &mut raw)? < HDR_SIZE {
+                queue.add_used(memory, chain.head(), 0)?;
+                continue;
+            }
+            let hdr = PacketHeader::parse(&raw);
+            // The guest may not send more than the buffer of the host.
+            if hdr.len > HOST_BUF_ALLOC {
+                debug!("virtio-vsock: {}-byte packet too large, dropped", hdr.len);
+                queue.add_used(memory, chain.head(), 0)?;
+                continue;
+            }
+            let mut payload = vec![0; hdr.len as usize];
+            let len = chain.read(memory, HDR_SIZE, &mut payload)?;
+            queue.add_used(memory, chain.head(), 0)?;
+            self.handle_packet(hdr, &payload[..len]);
+        }
+        Ok(())
+    }
+
+    /// Copy the pending packets to the receive queue: the ones without data
+    /// first, then the output of the connections as the guest has room for
+    /// it.
+    fn receive(&mut self, queue: &mut Virtqueue, memory: &GuestMemory) -> HyperResult {
+        while let Some(&(conn, op, flags)) = self.control.front() {
+            let Some(chain) = queue.pop(memory)? else {
+                return Ok(());
+            };
+            let hdr = self.header(conn, op, flags, 0);
+            let len = chain.write(memory, 0, &hdr.to_bytes())?;
+            queue.add_used(memory, chain.head(), len as u32)?;
+            self.control.pop_front();
+        }
+
+        let conns: Vec<VsockConn> = self.conns.keys().copied().collect();
+        for conn in conns {
+            let c = &self.conns[&conn];
+            if c.state == ConnState::Connecting {
+                continue;
+            }
+            loop {
+                let c = &self.conns[&conn];
+                let len = c.output.len().min(c.peer_credit()).min(MAX_PKT_PAYLOAD);
+                if len == 0 {
+                    break;
+                }
+                let Some(chain) = queue.pop(memory)? else {
+                    return Ok(());
+                };
+                let len = len.min(chain.writable_len().saturating_sub(HDR_SIZE));
+                if len == 0 {
+                    // The driver must provide buffers with room for data.
+                    queue.undo_pop();
+                    return Err(HyperError::InvalidParam);
+                }
+                let hdr = self.header(conn, VIRTIO_VSOCK_OP_RW, 0, len);
+                let c = self.conns.get_mut(&conn).unwrap();
+                let data: Vec<u8> = c.output.drain(..len).collect();
+                c.tx_cnt = c.tx_cnt.wrapping_add(len as u32);
+                chain.write(memory, 0, &hdr.to_bytes())?;
+                chain.write(memory, HDR_SIZE, &data)?;
+                queue.add_used(memory, chain.head(), (HDR_SIZE + len) as u32)?;
+            }
+
+            let c = &self.conns[&conn];
+            if c.state == ConnState::Closing && c.output.is_empty() && !c.shutdown_sent {
+                let Some(chain) = queue.pop(memory)? else {
+                    return Ok(());
+                };
+                let hdr = self.header(conn, VIRTIO_VSOCK_OP_SHUTDOWN, VIRTIO_VSOCK_SHUTDOWN_ALL, 0);
+                let len = chain.write(memory, 0, &hdr.to_bytes())?;
+                queue.add_used(memory, chain.head(), len as u32)?;
+                self.conns.get_mut(&conn).unwrap().shutdown_sent = true;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl VirtioDevice for VirtioVsock {
+    fn device_type(&self) -> u32 {
+        VIRTIO_ID_VSOCK
+    }
+
+    fn features(&self) -> u64 {
+        0
+    }
+
+    fn num_queues(&self) -> usize {
+        3
+    }
+
+    fn read_config(&self, offset: usize, width: usize) -> HyperResult<u64> {
+        let config: [u8; CONFIG_SIZE] = self.guest_cid.to_le_bytes();
+        read_config_bytes(&config, offset, width)
+    }
+
+    fn queue_notify(
+        &mut self,
+        index: usize,
+        queues: &mut [Virtqueue],
+        memory: &GuestMemory,
+    ) -> HyperResult {
+        match index {
+            QUEUE_RX => {}
+            QUEUE_TX => self.transmit(&mut queues[QUEUE_TX], memory)?,
+            // The buffers of the event queue are kept for transport resets,
+            // which are never sent.
+            QUEUE_EVENT => return Ok(()),
+            _ => return Err(HyperError::InvalidParam),
+        }
+        self.receive(&mut queues[QUEUE_RX], memory)
+    }
+
+    fn poll(&mut self, queues: &mut [Virtqueue], memory: &GuestMemory) -> HyperResult {
+        self.receive(&mut queues[QUEUE_RX], memory)
+    }
+
+    fn reset(&mut self) {
+        for (conn, c) in core::mem::take(&mut self.conns) {
+            if c.state != ConnState::Closing {
+                self.handler.on_close(conn);
+            }
+        }
+        self.control.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::sync::Arc;
+    use spin::Mutex;
+
+    use super::*;
+    use crate::devices::virtio::queue::tests::Driver;
+    use crate::memory::tests::{host_backed, HostPage};
+    use crate::GuestPhysAddr;
+
+    const RAM: GuestPhysAddr = 0x4000_0000;
+    const TX_BUF: GuestPhysAddr = RAM + 0x1000;
+    const RX_BUF: GuestPhysAddr = RAM + 0x2000;
+    const RX_BUF_SIZE: u32 = 0x100;
+
+    const GUEST_CID: u64 = 3;
+    const HOST_PORT: u32 = 1234;
+    const CONN: VsockConn = VsockConn {
+        guest_port: 5000,
+        host_port: HOST_PORT,
+    };
+
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        Connect(VsockConn),
+        Connected(VsockConn),
+        Data(VsockConn, Vec<u8>),
+        Close(VsockConn),
+    }
+
+    /// Accepts the connections to `HOST_PORT` and records the calls.
+    struct Recorder(Arc<Mutex<Vec<Event>>>);
+
+    impl VsockHandler for Recorder {
+        fn on_connect(&mut self, conn: VsockConn) -> bool {
+            self.0.lock().push(Event::Connect(conn));
+            conn.host_port == HOST_PORT
+        }
+
+        fn on_connected(&mut self, conn: VsockConn) {
+            self.0.lock().push(Event::Connected(conn));
+        }
+
+        fn on_data(&mut self, conn: VsockConn, data: &[u8]) {
+            self.0.lock().push(Event::Data(conn, data.to_vec()));
+        }
+
+        fn on_close(&mut self, conn: VsockConn) {
+            self.0.lock().push(Event::Close(conn));
+        }
+    }
+
+    /// A vsock device driven by a guest whose receive buffer is 8 bytes.
+    struct Guest {
+        _host: Vec<HostPage>,
+        memory: GuestMemory,
+        rx: Driver,
+        tx: Driver,
+        /// Whether a receive buffer is available to the device.
+        rx_posted: bool,
+        queues: [Virtqueue; 3],
+        vsock: VirtioVsock,
+        events: Arc<Mutex<Vec<Event>>>,
+    }
+
+    impl Guest {
+        const BUF_ALLOC: u32 = 8;
+
+        fn new() -> Self {
+            let (host, memory) = host_backed(RAM, 4);
+            let rx = Driver::new(RAM);
+            let tx = Driver::new(RAM + 0x400);
+            let queues = [rx.queue(), tx.queue(), Virtqueue::new(Driver::SIZE)];
+            let events = Arc::new(Mutex::new(Vec::new()));
+            let vsock = VirtioVsock::new(GUEST_CID, Box::new(Recorder(events.clone())));
+            Self {
+                _host: host,
+                memory,
+                rx,
+                tx,
+                rx_posted: false,
+                queues,
+                vsock,
+                events,
+            }
+        }
+
+        fn take_events(&self) -> Vec<Event> {
+            core::mem::take(&mut *self.events.lock())
+        }
+
+        /// Send a packet from `conn.guest_port`, having consumed `fwd_cnt`
+        /// bytes.
+        fn send(&mut self, conn: VsockConn, op: u16, flags: u32, fwd_cnt: u32, payload: &[u8]) {
+            let hdr = PacketHeader {
+                src_cid: GUEST_CID,
+                dst_cid: VSOCK_HOST_CID,
+                src_port: conn.guest_port,
+                dst_port: conn.host_port,
+                len: payload.len() as u32,
+                type_: VIRTIO_VSOCK_TYPE_STREAM,
+                op,
+                flags,
+                buf_alloc: Self::BUF_ALLOC,
+                fwd_cnt,
+            };
+            self.memory.write(TX_BUF, &hdr.to_bytes()).unwrap();
+            self.memory.write(TX_BUF + HDR_SIZE, payload).unwrap();
+            let len = (HDR_SIZE + payload.len()) as u32;
+            self.tx.add(&self.memory, &[(TX_BUF, len, false)]);
+            let (memory, queues) = (&self.memory, &mut self.queues);
+            self.vsock.queue_notify(QUEUE_TX, queues, memory).unwrap();
+            assert_eq!(self.tx.used(memory).map(|(_, len)| len), Some(0));
+        }
+
+        /// Receive the next packet, which has no data, and return its
+        /// connection, operation and flags.
+        fn recv_control(&mut self) -> (VsockConn, u16, u32) {
+            let (hdr, data) = self.recv().unwrap();
+            assert!(data.is_empty());
+            let conn = VsockConn {
+                guest_port: hdr.dst_port,
+                host_port: hdr.src_port,
+            };
+            (conn, hdr.op, hdr.flags)
+        }
+
+        /// Receive the next packet of the device, if any.
+        fn recv(&mut self) -> Option<(PacketHeader, Vec<u8>)> {
+            if !self.rx_posted {
+                self.rx.add(&self.memory, &[(RX_BUF, RX_BUF_SIZE, true)]);
+                self.rx_posted = true;
+            }
+            let (memory, queues) = (&self.memory, &mut self.queues);
+            self.vsock.poll(queues, memory).unwrap();
+            let (_, len) = self.rx.used(memory)?;
+            self.rx_posted = false;
+            let mut packet = vec![0; len as usize];
+            memory.read(RX_BUF, &mut packet).unwrap();
+            let hdr = PacketHeader::parse(&packet);
+            assert_eq!((hdr.src_cid, hdr.dst_cid), (VSOCK_HOST_CID, GUEST_CID));
+            assert_eq!(hdr.len as usize, packet.len() - HDR_SIZE);
+            Some((hdr, packet.split_off(HDR_SIZE)))
+        }
+    }
+
+    #[test]
+    fn guest_connects_and_shuts_down() {
+        let mut guest = Guest::new();
+        guest.send(CONN, VIRTIO_VSOCK_OP_REQUEST, 0, 0, &[]);
+        assert_eq!(guest.recv_control(), (CONN, VIRTIO_VSOCK_OP_RESPONSE, 0));
+        assert!(guest.vsock.is_connected(CONN));
+
+        guest.send(CONN, VIRTIO_VSOCK_OP_RW, 0, 0, b"hello");
+        guest.send(CONN, VIRTIO_VSOCK_OP_CREDIT_REQUEST, 0, 0, &[]);
+        let (hdr, _) = guest.recv().unwrap();
+        assert_eq!(hdr.op, VIRTIO_VSOCK_OP_CREDIT_UPDATE);
+        assert_eq!((hdr.buf_alloc, hdr.fwd_cnt), (HOST_BUF_ALLOC, 5));
+
+        // The guest stops sending first, then receiving.
+        guest.send(
+            CONN,
+            VIRTIO_VSOCK_OP_SHUTDOWN,
+            VIRTIO_VSOCK_SHUTDOWN_SEND,
+            0,
+            &[],
+        );
+        assert!(guest.recv().is_none());
+        assert_eq!(guest.vsock.send(CONN, b"bye"), Ok(3));
+        assert_eq!(guest.recv().unwrap().1, b"bye");
+        guest.send(
+            CONN,
+            VIRTIO_VSOCK_OP_SHUTDOWN,
+            VIRTIO_VSOCK_SHUTDOWN_RCV,
+            0,
+            &[],
+        );
+        assert_eq!(guest.recv_control(), (CONN, VIRTIO_VSOCK_OP_RST, 0));
+        assert!(!guest.vsock.is_connected(CONN));
+        assert_eq!(
+            guest.take_events(),
+            [
+                Event::Connect(CONN),
+                Event::Data(CONN, b"hello".to_vec()),
+                Event::Close(CONN),
+            ]
+        );
+    }
+
+    #[test]
+    fn bad_packets_are_reset() {
+        let mut guest = Guest::new();
+        // A port nobody listens on.
+        let refused = VsockConn {
+            guest_port: 5000,
+            host_port: 80,
+        };
+        guest.send(refused, VIRTIO_VSOCK_OP_REQUEST, 0, 0, &[]);
+        assert_eq!(guest.recv_control(), (refused, VIRTIO_VSOCK_OP_RST, 0));
+        // Data without a connection.
+        guest.send(CONN, VIRTIO_VSOCK_OP_RW, 0, 0, b"lost");
+        assert_eq!(guest.recv_control(), (CONN, VIRTIO_VSOCK_OP_RST, 0));
+        // A reset is not answered.
+        guest.send(CONN, VIRTIO_VSOCK_OP_RST, 0, 0, &[]);
+        assert!(guest.recv().is_none());
+        assert_eq!(guest.take_events(), [Event::Connect(refused)]);
+    }
+
+    #[test]
+    fn host_data_is_limited_by_guest_credit() {
+        let mut guest = Guest::new();
+        let conn = guest.vsock.connect(HOST_PORT, 5000).unwrap();
+        assert_eq!(conn, CONN);
+        assert_eq!(
+            guest.vsock.connect(HOST_PORT, 5000),
+            Err(HyperError::BadState)
+        );
+        assert_eq!(guest.recv_control(), (CONN, VIRTIO_VSOCK_OP_REQUEST, 0));
+        assert!(!guest.vsock.is_connected(CONN));
+        guest.send(CONN, VIRTIO_VSOCK_OP_RESPONSE, 0, 0, &[]);
+        assert!(guest.vsock.is_connected(CONN));
+
+        assert_eq!(guest.vsock.send(CONN, b"0123456789abcdef"), Ok(16));
+        let (hdr, data) = guest.recv().unwrap();
+        assert_eq!(
+            (hdr.op, data.as_slice()),
+            (VIRTIO_VSOCK_OP_RW, &b"01234567"[..])
+        );
+        // The receive buffer of the guest is full.
+        assert!(guest.recv().is_none());
+        guest.send(CONN, VIRTIO_VSOCK_OP_CREDIT_UPDATE, 0, 8, &[]);
+        let (_, data) = guest.recv().unwrap();
+        assert_eq!(data, b"89abcdef");
+
+        // The connection shuts down once the queued data is sent.
+        assert_eq!(guest.vsock.send(CONN, b"tail"), Ok(4));
+        guest.vsock.close(CONN).unwrap();
+        assert_eq!(guest.vsock.send(CONN, b"late"), Err(HyperError::BadState));
+        assert!(guest.recv().is_none());
+        guest.send(CONN, VIRTIO_VSOCK_OP_CREDIT_UPDATE, 0, 16, &[]);
+        assert_eq!(guest.recv().unwrap().1, b"tail");
+        let shutdown = (CONN, VIRTIO_VSOCK_OP_SHUTDOWN, VIRTIO_VSOCK_SHUTDOWN_ALL);
+        assert_eq!(guest.recv_control(), shutdown);
+        guest.send(CONN, VIRTIO_VSOCK_OP_RST, 0, 16, &[]);
+        assert!(!guest.vsock.is_connected(CONN));
+        // The host closed the connection, so it is not told again.
+        assert_eq!(guest.take_events(), [Event::Connected(CONN)]);
+    }
+}
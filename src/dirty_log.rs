@@ -0,0 +1,359 @@
+use alloc::vec::Vec;
+use page_table_entry::MappingFlags;
+
+use crate::memory::{RegionBacking, PAGE_SIZE_4K};
+use crate::utils::FlexBitmap;
+use crate::{
+    GuestMemory, GuestMemoryRegion, GuestPageTableTrait, GuestPhysAddr, HyperError, HyperResult,
+};
+
+/// How the pages a guest writes are detected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum DirtyTracking {
+    /// The pages are write-protected, and the first write to each of them
+    /// faults.
+    WriteProtect,
+    /// The pages are mapped as 4K pages, and the processor logs those whose
+    /// dirty flag it sets, e.g. with Intel PML.
+    Hardware,
+}
+
+/// A writable region of guest RAM, with one bit per 4K page written since
+/// the last collection.
+struct LoggedRegion {
+    region: GuestMemoryRegion,
+    dirty: FlexBitmap,
+}
+
+impl LoggedRegion {
+    fn page_index(&self, gpa: GuestPhysAddr) -> usize {
+        (gpa - self.region.gpa) / PAGE_SIZE_4K
+    }
+}
+
+/// The dirty-page log of a nested page table, and of the host writes to the
+/// guest memory it covers.
+///
+/// Only the writable regions of the guest RAM, mapped in the nested page
+/// table, are logged. With [`DirtyTracking::WriteProtect`], their pages are
+/// write-protected, and the first write of the guest to each of them faults
+/// and is recorded; the pages backed on demand while logging are protected
+/// the same way when they are mapped. The host writes through the
+/// [`GuestMemory`] of the VM or one of its clones are recorded as well.
+/// Collecting the log returns a bitmap of one bit per 4K page in `usize`
+/// words, and tracks the writes to the collected pages again; the caller
+/// flushes the stale translations of the nested page table after each
+/// change. Clones of a template VM do not support it, as their pages are
+/// shared until the first write.
+pub(crate) struct DirtyLog {
+    tracking: DirtyTracking,
+    regions: Vec<LoggedRegion>,
+    memory: GuestMemory,
+}
+
+impl DirtyLog {
+    /// Start logging the writes to the writable regions of `memory`, which
+    /// must be mapped in `gpt`, whether the guest does them or the host does
+    /// them through `memory` or one of its clones.
+    pub fn start<G: GuestPageTableTrait>(
+        memory: &GuestMemory,
+        gpt: &mut G,
+        tracking: DirtyTracking,
+    ) -> HyperResult<Self> {
+        let regions: Vec<_> = memory
+            .regions()
+            .iter()
+            .filter(|region| region.flags.contains(MappingFlags::WRITE))
+            .map(|&region| LoggedRegion {
+                region,
+                dirty: FlexBitmap::new(region.size / PAGE_SIZE_4K),
+            })
+            .collect();
+        for logged in &regions {
+            let region = &logged.region;
+            track_writes(gpt, tracking, region, region.gpa, region.size)?;
+        }
+        memory.start_write_log();
+        Ok(Self {
+            tracking,
+            regions,
+            memory: memory.clone(),
+        })
+    }
+
+    /// How the written pages are detected.
+    pub fn tracking(&self) -> DirtyTracking {
+        self.tracking
+    }
+
+    fn find_region(&mut self, gpa: GuestPhysAddr) -> Option<&mut LoggedRegion> {
+        self.regions
+            .iter_mut()
+            .find(|logged| logged.region.contains(gpa))
+    }
+
+    /// Record a write to the page of `gpa`, reported by the processor.
+    pub fn mark_dirty(&mut self, gpa: GuestPhysAddr) {
+        if let Some(logged) = self.find_region(gpa) {
+            let index = logged.page_index(gpa);
+            logged.dirty.set(index, true);
+        }
+    }
+
+    /// Handle a guest write to the write-protected `gpa`: record the page
+    /// as dirty, and let the guest write to it until the next collection.
+    /// Returns `false` if `gpa` is not logged RAM, or is backed on demand
+    /// and not mapped yet, and the fault is not caused by the log.
+    pub fn handle_write_fault<G: GuestPageTableTrait>(
+        &mut self,
+        gpt: &mut G,
+        gpa: GuestPhysAddr,
+    ) -> HyperResult<bool> {
+        if self.tracking != DirtyTracking::WriteProtect {
+            return Ok(false);
+        }
+        let page = gpa & !(PAGE_SIZE_4K - 1);
+        if gpt.translate(page).is_err() {
+            return Ok(false);
+        }
+        let Some(logged) = self.find_region(gpa) else {
+            return Ok(false);
+        };
+        let index = logged.page_index(gpa);
+        logged.dirty.set(index, true);
+        gpt.protect_region(page, PAGE_SIZE_4K, logged.region.flags)?;
+        Ok(true)
+    }
+
+    /// Take the pages of the `size` bytes at `gpa` written since the last
+    /// call, as a bitmap of one bit per 4K page in `usize` words, and track
+    /// the writes to them again. The pages the host wrote are still mapped
+    /// as the log left them, so only those of the guest are protected again.
+    /// The caller flushes the stale translations of `gpt` afterwards.
+    pub fn get_and_clear<G: GuestPageTableTrait>(
+        &mut self,
+        gpt: &mut G,
+        gpa: GuestPhysAddr,
+        size: usize,
+    ) -> HyperResult<Vec<usize>> {
+        if (gpa | size) % PAGE_SIZE_4K != 0 {
+            return Err(HyperError::InvalidParam);
+        }
+        let end = gpa.checked_add(size).ok_or(HyperError::InvalidParam)?;
+        let mut result = FlexBitmap::new(size / PAGE_SIZE_4K);
+        let tracking = self.tracking;
+        for logged in &mut self.regions {
+            let region = logged.region;
+            let start = region.gpa.max(gpa);
+            let stop = region.end().min(end);
+            // Runs of dirty pages are protected together.
+            let mut run: Option<GuestPhysAddr> = None;
+            let mut page = start;
+            while page <= stop {
+                let dirty = page < stop && {
+                    let index = logged.page_index(page);
+                    let dirty = logged.dirty.get(index) != 0;
+                    if dirty {
+                        logged.dirty.set(index, false);
+                        result.set((page - gpa) / PAGE_SIZE_4K, true);
+                    }
+                    dirty
+                };
+                match (run, dirty) {
+                    (None, true) => run = Some(page),
+                    (Some(run_start), false) => {
+                        track_writes(gpt, tracking, &region, run_start, page - run_start)?;
+                        run = None;
+                    }
+                    _ => {}
+                }
+                page += PAGE_SIZE_4K;
+            }
+        }
+        for page in self.memory.take_written(gpa, size) {
+            if self
+                .regions
+                .iter()
+                .any(|logged| logged.region.contains(page))
+            {
+                result.set((page - gpa) / PAGE_SIZE_4K, true);
+            }
+        }
+        Ok(result.slice().to_vec())
+    }
+
+    /// Stop logging, and give the guest its write permissions back.
+    pub fn stop<G: GuestPageTableTrait>(self, gpt: &mut G) -> HyperResult {
+        self.memory.stop_write_log();
+        if self.tracking == DirtyTracking::WriteProtect {
+            for logged in &self.regions {
+                let region = &logged.region;
+                for_each_mapped_run(gpt, region, region.gpa, region.size, |gpt, gpa, size| {
+                    gpt.protect_region(gpa, size, region.flags)
+                })?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn read_only(flags: MappingFlags) -> MappingFlags {
+    flags - MappingFlags::WRITE
+}
+
+/// Make the guest's writes to the `size` bytes at `gpa`, in `region`,
+/// detected again.
+fn track_writes<G: GuestPageTableTrait>(
+    gpt: &mut G,
+    tracking: DirtyTracking,
+    region: &GuestMemoryRegion,
+    gpa: GuestPhysAddr,
+    size: usize,
+) -> HyperResult {
+    for_each_mapped_run(gpt, region, gpa, size, |gpt, gpa, size| match tracking {
+        DirtyTracking::WriteProtect => gpt.protect_region(gpa, size, read_only(region.flags)),
+        // Mapping the pages again clears their dirty flags.
+        DirtyTracking::Hardware => remap_4k(gpt, gpa, size, region.flags),
+    })
+}
+
+/// Call `f` with each run of mapped pages of the `size` bytes at `gpa`, in
+/// `region`. All the pages are mapped, but those of a region backed on
+/// demand that the guest did not touch yet, which are skipped: they are
+/// mapped as the log requires on their first access.
+fn for_each_mapped_run<G: GuestPageTableTrait>(
+    gpt: &mut G,
+    region: &GuestMemoryRegion,
+    gpa: GuestPhysAddr,
+    size: usize,
+    mut f: impl FnMut(&mut G, GuestPhysAddr, usize) -> HyperResult,
+) -> HyperResult {
+    if region.backing == RegionBacking::Host {
+        return f(gpt, gpa, size);
+    }
+    let end = gpa + size;
+    let mut run: Option<GuestPhysAddr> = None;
+    let mut page = gpa;
+    while page <= end {
+        let mapped = page < end && gpt.translate(page).is_ok();
+        match (run, mapped) {
+            (None, true) => run = Some(page),
+            (Some(run_start), false) => {
+                f(gpt, run_start, page - run_start)?;
+                run = None;
+            }
+            _ => {}
+        }
+        page += PAGE_SIZE_4K;
+    }
+    Ok(())
+}
+
+/// Map the `size` bytes at `gpa` of `gpt` again with `flags`, as 4K pages.
+fn remap_4k<G: GuestPageTableTrait>(
+    gpt: &mut G,
+    gpa: GuestPhysAddr,
+    size: usize,
+    flags: MappingFlags,
+) -> HyperResult {
+    for page in (gpa..gpa + size).step_by(PAGE_SIZE_4K) {
+        let hpa = gpt.translate(page)?;
+        gpt.unmap_region(page, PAGE_SIZE_4K)?;
+        gpt.map(page, hpa, flags)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::tests::{host_backed, rw, MockPageTable};
+
+    const RAM: GuestPhysAddr = 0x8000_0000;
+
+    /// A log of 4 pages of RAM at `RAM`, and a read-only page after them.
+    fn logged() -> (MockPageTable, DirtyLog) {
+        let mut memory = GuestMemory::new();
+        memory
+            .add_region(RAM, 0x10_0000, 4 * PAGE_SIZE_4K, rw())
+            .unwrap();
+        let rom = RAM + 4 * PAGE_SIZE_4K;
+        memory
+            .add_region(rom, 0x20_0000, PAGE_SIZE_4K, MappingFlags::READ)
+            .unwrap();
+        let mut gpt = MockPageTable::default();
+        for region in memory.regions() {
+            gpt.map_region(region.gpa, region.hva, region.size, region.flags)
+                .unwrap();
+        }
+        let log = DirtyLog::start(&memory, &mut gpt, DirtyTracking::WriteProtect).unwrap();
+        (gpt, log)
+    }
+
+    #[test]
+    fn write_faults_are_collected_once() {
+        let (mut gpt, mut log) = logged();
+        assert!((0..4).all(|i| gpt.flags(RAM + i * PAGE_SIZE_4K) == MappingFlags::READ));
+
+        assert_eq!(log.handle_write_fault(&mut gpt, RAM + 0x1000), Ok(true));
+        assert_eq!(log.handle_write_fault(&mut gpt, RAM + 0x2008), Ok(true));
+        assert_eq!(gpt.flags(RAM + 0x1000), rw());
+        assert_eq!(gpt.flags(RAM + 0x2000), rw());
+
+        let size = 4 * PAGE_SIZE_4K;
+        assert_eq!(log.get_and_clear(&mut gpt, RAM, size), Ok(vec![0b110]));
+        assert!((0..4).all(|i| gpt.flags(RAM + i * PAGE_SIZE_4K) == MappingFlags::READ));
+        assert_eq!(log.get_and_clear(&mut gpt, RAM, size), Ok(vec![0]));
+    }
+
+    #[test]
+    fn get_and_clear_subrange() {
+        let (mut gpt, mut log) = logged();
+        log.handle_write_fault(&mut gpt, RAM).unwrap();
+        log.handle_write_fault(&mut gpt, RAM + 0x3000).unwrap();
+
+        let bitmap = log.get_and_clear(&mut gpt, RAM + 0x2000, 2 * PAGE_SIZE_4K);
+        assert_eq!(bitmap, Ok(vec![0b10]));
+        assert_eq!(gpt.flags(RAM), rw());
+        assert_eq!(gpt.flags(RAM + 0x3000), MappingFlags::READ);
+        assert_eq!(log.get_and_clear(&mut gpt, RAM, PAGE_SIZE_4K), Ok(vec![1]));
+        assert_eq!(
+            log.get_and_clear(&mut gpt, RAM + 1, PAGE_SIZE_4K),
+            Err(HyperError::InvalidParam)
+        );
+    }
+
+    #[test]
+    fn other_faults_are_not_logged() {
+        let (mut gpt, mut log) = logged();
+        let rom = RAM + 4 * PAGE_SIZE_4K;
+        assert_eq!(log.handle_write_fault(&mut gpt, rom), Ok(false));
+        assert_eq!(log.handle_write_fault(&mut gpt, 0x9000_0000), Ok(false));
+        assert_eq!(gpt.flags(rom), MappingFlags::READ);
+
+        log.stop(&mut gpt).unwrap();
+        assert!((0..4).all(|i| gpt.flags(RAM + i * PAGE_SIZE_4K) == rw()));
+        assert_eq!(gpt.flags(rom), MappingFlags::READ);
+    }
+
+    #[test]
+    fn host_writes_are_logged() {
+        let (_host, memory) = host_backed(RAM, 4);
+        let mut gpt = MockPageTable::default();
+        let region = memory.regions()[0];
+        gpt.map_region(region.gpa, region.hva, region.size, region.flags)
+            .unwrap();
+        let mut log = DirtyLog::start(&memory, &mut gpt, DirtyTracking::WriteProtect).unwrap();
+
+        let device_view = memory.clone();
+        device_view.write_obj(RAM + 0x1ffc, &0u64).unwrap();
+        let size = 4 * PAGE_SIZE_4K;
+        assert_eq!(log.get_and_clear(&mut gpt, RAM, size), Ok(vec![0b110]));
+        assert_eq!(gpt.flags(RAM + 0x1000), MappingFlags::READ);
+        assert_eq!(log.get_and_clear(&mut gpt, RAM, size), Ok(vec![0]));
+
+        log.stop(&mut gpt).unwrap();
+        memory.fill(RAM, 0, PAGE_SIZE_4K).unwrap();
+        assert!(memory.take_written(RAM, size).is_empty());
+    }
+}
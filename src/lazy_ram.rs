@@ -0,0 +1,201 @@
+use core::marker::PhantomData;
+use page_table_entry::MappingFlags;
+
+use crate::memory::{RegionBacking, PAGE_SIZE_4K};
+use crate::{
+    GuestMemory, GuestPageTableTrait, GuestPhysAddr, HyperCraftHal, HyperError, HyperResult,
+};
+
+/// The guest RAM regions of a VM whose pages are allocated, zeroed and
+/// mapped on the first nested page fault, so that the guest only takes the
+/// host memory it touches. The regions belong to the [`GuestMemory`] of the
+/// VM, through which the host reaches the same pages, and whose last clone
+/// frees them.
+///
+/// A region must not be mapped in the nested page table when it is added.
+/// The first access of the guest to each of its pages faults, and a zeroed
+/// page from [`HyperCraftHal::alloc_page`] is mapped there with the flags of
+/// the region, unless the host wrote to it through the [`GuestMemory`]
+/// first. The pages the guest gives up, e.g. through a
+/// [`VirtioBalloon`](crate::VirtioBalloon), can be reclaimed: they are freed
+/// and unmapped, and backed on demand again when the guest touches them.
+/// Reclaiming a range which is not all RAM backed on demand is
+/// [`HyperError::NotSupported`].
+pub(crate) struct LazyRam<H: HyperCraftHal> {
+    memory: GuestMemory,
+    _phantom: PhantomData<H>,
+}
+
+impl<H: HyperCraftHal> LazyRam<H> {
+    pub fn new() -> Self {
+        Self {
+            memory: GuestMemory::new(),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Add the `size` bytes at `gpa` to `memory`, the RAM of the VM, backed
+    /// on demand, and map their pages with `flags`. The range must be page
+    /// aligned and not overlap another region of `memory`.
+    pub fn add_region(
+        &mut self,
+        memory: &mut GuestMemory,
+        gpa: GuestPhysAddr,
+        size: usize,
+        flags: MappingFlags,
+    ) -> HyperResult {
+        memory.add_lazy_region::<H>(gpa, size, flags)?;
+        self.memory = memory.clone();
+        Ok(())
+    }
+
+    /// Whether `gpa` is in a lazy region.
+    pub fn contains(&self, gpa: GuestPhysAddr) -> bool {
+        self.memory
+            .find_region(gpa)
+            .is_some_and(|region| region.backing == RegionBacking::OnDemand)
+    }
+
+    /// The number of pages allocated so far.
+    pub fn nr_pages(&self) -> usize {
+        self.memory.nr_backed_pages()
+    }
+
+    /// Handle a nested page fault at `gpa`: map its page in `gpt`, after
+    /// allocating a zeroed one if the host did not write to it yet. If
+    /// `write_protect` is set, e.g. while dirty logging write-protects the
+    /// RAM, the page is mapped read-only, and the first write to it faults
+    /// again. Returns `false` if `gpa` is not in a lazy region, or its page
+    /// is already mapped and the fault has another cause. The caller flushes
+    /// the stale translations of `gpt` afterwards.
+    pub fn handle_fault<G: GuestPageTableTrait>(
+        &mut self,
+        gpt: &mut G,
+        gpa: GuestPhysAddr,
+        write_protect: bool,
+    ) -> HyperResult<bool> {
+        let Some(region) = self.memory.find_region(gpa) else {
+            return Ok(false);
+        };
+        let page = gpa & !(PAGE_SIZE_4K - 1);
+        if region.backing != RegionBacking::OnDemand || gpt.translate(page).is_ok() {
+            return Ok(false);
+        }
+        let flags = if write_protect {
+            region.flags - MappingFlags::WRITE
+        } else {
+            region.flags
+        };
+        let hva = self.memory.back_page(page)?;
+        gpt.map(page, H::virt_to_phys(hva), flags)?;
+        Ok(true)
+    }
+
+    /// Unmap the allocated pages of the `size` bytes at `gpa` from `gpt`,
+    /// call `flush` to flush the stale translations of `gpt`, and free them,
+    /// so that the guest cannot reach them through the TLB once they are
+    /// reused. They are backed on demand again, so the guest must not rely
+    /// on their contents. Returns the number of pages freed.
+    ///
+    /// Fails with [`HyperError::NotSupported`] if a page of the range is not
+    /// in a lazy region: the host memory behind the other RAM is not
+    /// allocated page by page, and cannot be given back.
+    pub fn reclaim<G: GuestPageTableTrait>(
+        &mut self,
+        gpt: &mut G,
+        gpa: GuestPhysAddr,
+        size: usize,
+        flush: impl FnOnce() -> HyperResult,
+    ) -> HyperResult<usize> {
+        if (gpa | size) % PAGE_SIZE_4K != 0 {
+            return Err(HyperError::InvalidParam);
+        }
+        let end = gpa.checked_add(size).ok_or(HyperError::InvalidParam)?;
+        let mut addr = gpa;
+        while addr < end {
+            match self.memory.find_region(addr) {
+                Some(region) if region.backing == RegionBacking::OnDemand => addr = region.end(),
+                _ => return Err(HyperError::NotSupported),
+            }
+        }
+        let pages = self.memory.backed_pages(gpa, end - gpa);
+        if pages.is_empty() {
+            return Ok(0);
+        }
+        for &page in &pages {
+            if gpt.translate(page).is_ok() {
+                gpt.unmap(page)?;
+            }
+        }
+        flush()?;
+        self.memory.free_pages(&pages);
+        Ok(pages.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dirty_log::{DirtyLog, DirtyTracking};
+    use crate::memory::tests::{rw, MockPageTable, TestHal};
+
+    const RAM: GuestPhysAddr = 0x8000_0000;
+
+    fn lazy_ram() -> (GuestMemory, LazyRam<TestHal>, MockPageTable) {
+        let mut memory = GuestMemory::new();
+        let mut lazy = LazyRam::new();
+        lazy.add_region(&mut memory, RAM, 4 * PAGE_SIZE_4K, rw())
+            .unwrap();
+        (memory, lazy, MockPageTable::default())
+    }
+
+    #[test]
+    fn fault_maps_page_written_by_host() {
+        let (memory, mut lazy, mut gpt) = lazy_ram();
+        memory.write_obj(RAM + 0x1008, &0x55u8).unwrap();
+        assert_eq!(lazy.nr_pages(), 1);
+
+        assert_eq!(lazy.handle_fault(&mut gpt, RAM + 0x1000, false), Ok(true));
+        let hva = memory.gpa_to_hva(RAM + 0x1008).unwrap();
+        assert_eq!(gpt.translate(RAM + 0x1008), Ok(hva));
+        assert_eq!(lazy.handle_fault(&mut gpt, RAM + 0x1000, false), Ok(false));
+        assert_eq!(lazy.handle_fault(&mut gpt, RAM + 0x3000, false), Ok(true));
+        assert_eq!(lazy.handle_fault(&mut gpt, RAM + 0x4000, false), Ok(false));
+        assert_eq!(lazy.nr_pages(), 2);
+
+        let size = 4 * PAGE_SIZE_4K;
+        let beyond = lazy.reclaim(&mut gpt, RAM, size + PAGE_SIZE_4K, || Ok(()));
+        assert_eq!(beyond, Err(HyperError::NotSupported));
+        assert_eq!(lazy.nr_pages(), 2);
+        let flush = || {
+            // The pages are only freed once the guest cannot reach them.
+            assert_eq!(memory.nr_backed_pages(), 2);
+            Ok(())
+        };
+        assert_eq!(lazy.reclaim(&mut gpt, RAM, size, flush), Ok(2));
+        assert!(gpt.pages.is_empty());
+        assert_eq!(memory.read_obj::<u8>(RAM + 0x1008), Ok(0));
+        assert_eq!(lazy.nr_pages(), 0);
+    }
+
+    #[test]
+    fn pages_backed_while_logging_are_logged() {
+        let (memory, mut lazy, mut gpt) = lazy_ram();
+        lazy.handle_fault(&mut gpt, RAM, false).unwrap();
+        let mut log = DirtyLog::start(&memory, &mut gpt, DirtyTracking::WriteProtect).unwrap();
+        assert_eq!(gpt.flags(RAM), MappingFlags::READ);
+
+        assert_eq!(lazy.handle_fault(&mut gpt, RAM + 0x2000, true), Ok(true));
+        assert_eq!(gpt.flags(RAM + 0x2000), MappingFlags::READ);
+        assert_eq!(log.handle_write_fault(&mut gpt, RAM + 0x3000), Ok(false));
+        assert_eq!(log.handle_write_fault(&mut gpt, RAM + 0x2000), Ok(true));
+        assert_eq!(gpt.flags(RAM + 0x2000), rw());
+
+        let size = 4 * PAGE_SIZE_4K;
+        assert_eq!(log.get_and_clear(&mut gpt, RAM, size), Ok(vec![0b100]));
+        assert_eq!(gpt.flags(RAM + 0x2000), MappingFlags::READ);
+        log.stop(&mut gpt).unwrap();
+        assert_eq!(gpt.flags(RAM), rw());
+        assert_eq!(gpt.flags(RAM + 0x2000), rw());
+    }
+}
@@ -21,6 +21,9 @@ extern crate log;
 #[macro_use]
 extern crate alloc;
 
+#[macro_use]
+mod snapshot;
+
 #[cfg(target_arch = "aarch64")]
 #[path = "arch/aarch64/mod.rs"]
 mod arch;
@@ -31,9 +34,15 @@ mod arch;
 #[path = "arch/x86_64/mod.rs"]
 mod arch;
 
+mod cow;
+mod devices;
+mod dirty_log;
 mod hal;
+mod lazy_ram;
 mod memory;
+mod migration;
 mod traits;
+mod utils;
 #[cfg(not(target_arch = "x86_64"))]
 mod vcpus;
 
@@ -51,19 +60,42 @@ pub use arch::{
     NestedPageTable, PerCpu, VCpu, VM,
 };
 
+pub use devices::{
+    BlockBackend, CharBackend, ConsoleHandler, Descriptor, DescriptorChain, MemoryBlockBackend,
+    MmioDevice, NetBackend, NetSwitch, PortIoDevice, SwitchPort, Uart16550, UartAttachment,
+    VirtioBalloon, VirtioBlock, VirtioConsole, VirtioDevice, VirtioMmio, VirtioNet, VirtioVsock,
+    Virtqueue, VsockConn, VsockHandler, ETH_ALEN, UART_COM1_IRQ, UART_COM1_PORT,
+    VIRTIO_BALLOON_F_DEFLATE_ON_OOM, VIRTIO_BALLOON_F_REPORTING, VIRTIO_BLK_SECTOR_SIZE,
+    VIRTIO_CONSOLE_MAX_PORTS, VIRTIO_F_INDIRECT_DESC, VIRTIO_F_VERSION_1, VIRTIO_ID_BALLOON,
+    VIRTIO_ID_BLOCK, VIRTIO_ID_CONSOLE, VIRTIO_ID_NET, VIRTIO_ID_VSOCK, VIRTIO_MMIO_SIZE,
+    VIRTQUEUE_MAX_SIZE, VSOCK_HOST_CID,
+};
 pub use hal::HyperCraftHal;
 pub use memory::{
-    GuestPageNum, GuestPageTableTrait, GuestPhysAddr, GuestVirtAddr, HostPageNum, HostPhysAddr,
-    HostVirtAddr,
+    ByteValued, GuestMemory, GuestMemoryRegion, GuestPageNum, GuestPageTableTrait, GuestPhysAddr,
+    GuestVirtAddr, HostPageNum, HostPhysAddr, HostVirtAddr, NestedPageTableExt, RegionBacking,
+};
+pub use migration::{
+    LoopbackConnection, Migratable, MigrationReceiver, MigrationSender, MIGRATION_DIRTY_THRESHOLD,
+    MIGRATION_MAX_ROUNDS,
 };
 #[cfg(not(target_arch = "x86_64"))]
 pub use vcpus::VmCpus;
 
 #[cfg(target_arch = "aarch64")]
-pub use arch::lower_aarch64_synchronous;
+pub use arch::{lower_aarch64_irq, lower_aarch64_synchronous, VmExitInfo};
+#[cfg(target_arch = "aarch64")]
+pub use arch::{emu_register_dev, emu_remove_dev, EmuContext, EmuDevice, Pl011, PL011_SIZE};
+#[cfg(target_arch = "aarch64")]
+pub use arch::{
+    gic_hw_init, GicVersion, Vgic, VgicRedistributors, VmLifecycleEvent, GICD_SIZE,
+    GICR_SIZE_PER_VCPU, GICV3_GICD_SIZE, GIC_MAINTENANCE_IRQ,
+};
+#[cfg(target_arch = "aarch64")]
+pub use arch::VTIMER_IRQ;
 
 #[cfg(target_arch = "x86_64")]
-pub use arch::{VmxExitReason, VmxExitInfo};
+pub use arch::{ept_max_page_size, VmxExitReason, VmxExitInfo};
 
 /// The error type for hypervisor operation failures.
 #[derive(Debug, PartialEq)]
@@ -92,4 +124,6 @@ pub enum HyperError {
     DecodeError,
     /// Disabled.
     Disabled,
+    /// Connection error.
+    ConnectionError,
 }
@@ -1,5 +1,13 @@
-use crate::{HyperCraftHal, HyperResult};
-use page_table_entry::MappingFlags;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::mem::{size_of, MaybeUninit};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+use crate::{HyperCraftHal, HyperError, HyperResult};
+use page_table::{PageSize, PageTable64, PagingError, PagingIf, PagingMetaData};
+use page_table_entry::{GenericPTE, MappingFlags};
 
 /// Guest physical address.
 pub type GuestPhysAddr = usize;
@@ -32,18 +40,100 @@ pub trait GuestPageTableTrait {
         flags: MappingFlags,
     ) -> HyperResult<()>;
 
+    /// Map the guest physical page of `page_size` starts from `gpa` to the
+    /// host physical page starts from `hpa` with `flags`, as one leaf entry.
+    /// Both addresses must be aligned to `page_size`.
+    ///
+    /// Returns [`HyperError::NotSupported`] for the page sizes the table
+    /// cannot map, which the default does for all but 4K pages. Wrappers of
+    /// the EPT and of the RISC-V nested page table forward to
+    /// [`NestedPageTableExt::map_nested_page`].
+    fn map_page(
+        &mut self,
+        gpa: GuestPhysAddr,
+        hpa: HostPhysAddr,
+        page_size: PageSize,
+        flags: MappingFlags,
+    ) -> HyperResult<()> {
+        match page_size {
+            PageSize::Size4K => self.map(gpa, hpa, flags),
+            _ => Err(HyperError::NotSupported),
+        }
+    }
+
     /// Map a guest physical region starts from `gpa` to the host physical
+    /// region starts from `hpa` with `flags`.
+    ///
+    /// The default maps 1G and 2M pages with [`Self::map_page`] wherever
+    /// `gpa`, `hpa` and the remaining size allow it, and 4K pages elsewhere.
     fn map_region(
         &mut self,
         gpa: GuestPhysAddr,
         hpa: HostPhysAddr,
         size: usize,
         flags: MappingFlags,
-    ) -> HyperResult<()>;
+    ) -> HyperResult<()> {
+        if (gpa | hpa | size) % PAGE_SIZE_4K != 0 {
+            return Err(HyperError::InvalidParam);
+        }
+        let mut offset = 0;
+        'next: while offset < size {
+            let (gpa, hpa) = (gpa + offset, hpa + offset);
+            for page_size in [PageSize::Size1G, PageSize::Size2M] {
+                let len = page_size as usize;
+                if (gpa | hpa) % len != 0 || size - offset < len {
+                    continue;
+                }
+                match self.map_page(gpa, hpa, page_size, flags) {
+                    Ok(()) => {
+                        offset += len;
+                        continue 'next;
+                    }
+                    Err(HyperError::NotSupported) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            self.map(gpa, hpa, flags)?;
+            offset += PAGE_SIZE_4K;
+        }
+        Ok(())
+    }
 
-    /// Unmap the guest physical frame `hpa`
+    /// Unmap the guest physical frame `hpa`. If it is part of a larger page,
+    /// the whole page is unmapped.
     fn unmap(&mut self, gpa: GuestPhysAddr) -> HyperResult<()>;
 
+    /// Unmap the guest physical region of `size` bytes starts from `gpa`.
+    /// The larger pages it covers partially are split, so that the rest of
+    /// them stays mapped.
+    fn unmap_region(&mut self, gpa: GuestPhysAddr, size: usize) -> HyperResult<()> {
+        update_region(self, gpa, size, None)
+    }
+
+    /// Change the permissions of the mapped guest physical region of `size`
+    /// bytes starts from `gpa` to `flags`, splitting the larger pages it
+    /// covers partially.
+    fn protect_region(
+        &mut self,
+        gpa: GuestPhysAddr,
+        size: usize,
+        flags: MappingFlags,
+    ) -> HyperResult<()> {
+        update_region(self, gpa, size, Some(flags))
+    }
+
+    /// Query the leaf entry which maps `gpa`: the host physical address of
+    /// the start of its page, its flags and its page size.
+    ///
+    /// The default returns [`HyperError::NotSupported`], and the region
+    /// operations then assume every page is a 4K page. Wrappers of the EPT
+    /// and of the RISC-V nested page table forward to
+    /// [`NestedPageTableExt::query_nested_page`].
+    fn query(&self, gpa: GuestPhysAddr) -> HyperResult<(HostPhysAddr, MappingFlags, PageSize)> {
+        let _ = gpa;
+        Err(HyperError::NotSupported)
+    }
+
     /// Translate the host physical address which the guest physical frame of
     /// `gpa` maps to.
     fn translate(&self, gpa: GuestPhysAddr) -> HyperResult<HostPhysAddr>;
@@ -70,3 +160,912 @@ pub trait GuestPageTableTrait {
     /// Get root physical address
     fn root_paddr(&self) -> HostPhysAddr;
 }
+
+/// The large-page operations of a nested page table built on
+/// [`PageTable64`], which the [`GuestPageTableTrait::map_page`] and
+/// [`GuestPageTableTrait::query`] implementations of its wrapper forward to.
+pub trait NestedPageTableExt {
+    /// Map the guest physical page of `page_size` at `gpa` to `hpa` with
+    /// `flags`, as one leaf entry. Returns [`HyperError::NotSupported`] for
+    /// the page sizes the table cannot map.
+    fn map_nested_page(
+        &mut self,
+        gpa: GuestPhysAddr,
+        hpa: HostPhysAddr,
+        page_size: PageSize,
+        flags: MappingFlags,
+    ) -> HyperResult;
+
+    /// Query the leaf entry which maps `gpa`: the host physical address of
+    /// the start of its page, its flags and its page size.
+    fn query_nested_page(
+        &self,
+        gpa: GuestPhysAddr,
+    ) -> HyperResult<(HostPhysAddr, MappingFlags, PageSize)>;
+}
+
+fn paging_error(err: PagingError) -> HyperError {
+    match err {
+        PagingError::NoMemory => HyperError::NoMemory,
+        PagingError::NotAligned => HyperError::InvalidParam,
+        PagingError::NotMapped => HyperError::NotFound,
+        PagingError::AlreadyMapped | PagingError::MappedToHugePage => HyperError::BadState,
+    }
+}
+
+/// Map a page of `page_size` in `pt`, which supports leaf pages up to
+/// `max_page_size`. See [`NestedPageTableExt::map_nested_page`].
+pub(crate) fn map_page_in<M: PagingMetaData, PTE: GenericPTE, I: PagingIf>(
+    pt: &mut PageTable64<M, PTE, I>,
+    gpa: GuestPhysAddr,
+    hpa: HostPhysAddr,
+    page_size: PageSize,
+    max_page_size: PageSize,
+    flags: MappingFlags,
+) -> HyperResult {
+    if page_size as usize > max_page_size as usize {
+        return Err(HyperError::NotSupported);
+    }
+    if (gpa | hpa) % page_size as usize != 0 {
+        return Err(HyperError::InvalidParam);
+    }
+    pt.map(gpa.into(), hpa.into(), page_size, flags).map_err(paging_error)
+}
+
+/// Query the leaf entry of `gpa` in `pt`. See
+/// [`NestedPageTableExt::query_nested_page`].
+pub(crate) fn query_page_in<M: PagingMetaData, PTE: GenericPTE, I: PagingIf>(
+    pt: &PageTable64<M, PTE, I>,
+    gpa: GuestPhysAddr,
+) -> HyperResult<(HostPhysAddr, MappingFlags, PageSize)> {
+    let (hpa, flags, page_size) = pt.query(gpa.into()).map_err(paging_error)?;
+    let hpa: usize = hpa.into();
+    Ok((hpa & !(page_size as usize - 1), flags, page_size))
+}
+
+/// Unmap the region of `size` bytes at `gpa` of `gpt`, or remap it with
+/// `flags`. The parts of a leaf page outside the region are mapped again
+/// with the old flags, in pages as large as their alignment allows.
+fn update_region<G: GuestPageTableTrait + ?Sized>(
+    gpt: &mut G,
+    gpa: GuestPhysAddr,
+    size: usize,
+    flags: Option<MappingFlags>,
+) -> HyperResult {
+    if (gpa | size) % PAGE_SIZE_4K != 0 {
+        return Err(HyperError::InvalidParam);
+    }
+    let end = gpa.checked_add(size).ok_or(HyperError::InvalidParam)?;
+    let mut addr = gpa;
+    while addr < end {
+        let (page_start, page_hpa, old_flags, page_len) = match gpt.query(addr) {
+            Ok((hpa, old_flags, page_size)) => {
+                let len = page_size as usize;
+                (addr & !(len - 1), hpa, Some(old_flags), len)
+            }
+            Err(HyperError::NotSupported) => (addr, gpt.translate(addr)?, None, PAGE_SIZE_4K),
+            Err(e) => return Err(e),
+        };
+        let page_end = page_start + page_len;
+        let (start, stop) = (addr, page_end.min(end));
+        gpt.unmap(page_start)?;
+        if let Some(old_flags) = old_flags {
+            if page_start < start {
+                gpt.map_region(page_start, page_hpa, start - page_start, old_flags)?;
+            }
+            if stop < page_end {
+                let offset = stop - page_start;
+                gpt.map_region(stop, page_hpa + offset, page_end - stop, old_flags)?;
+            }
+        }
+        if let Some(flags) = flags {
+            gpt.map_region(start, page_hpa + (start - page_start), stop - start, flags)?;
+        }
+        addr = stop;
+    }
+    Ok(())
+}
+
+/// Types that are valid for any bit pattern, and can be read from or written
+/// to guest memory as plain bytes.
+///
+/// # Safety
+///
+/// The type must have no padding, and every byte sequence of its size must
+/// be a valid value of it.
+pub unsafe trait ByteValued: Copy {}
+
+macro_rules! impl_byte_valued {
+    ($($t: ty),*) => {
+        $(unsafe impl ByteValued for $t {})*
+    };
+}
+
+impl_byte_valued!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize);
+
+unsafe impl<T: ByteValued, const N: usize> ByteValued for [T; N] {}
+
+/// How the pages of a [`GuestMemoryRegion`] are backed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionBacking {
+    /// By the host memory mapped at `hva`.
+    Host,
+    /// By host pages allocated and zeroed on the first access, through the
+    /// nested page table or through the [`GuestMemory`]. `hva` is 0.
+    OnDemand,
+    /// By the host memory mapped at `hva`, which belongs to another VM and
+    /// is only read: the first write to each page, through the nested page
+    /// table or through the [`GuestMemory`], copies it to a private page.
+    CopyOnWrite,
+}
+
+/// A contiguous range of guest RAM, backed by host memory mapped at `hva`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GuestMemoryRegion {
+    /// The guest physical address of the region.
+    pub gpa: GuestPhysAddr,
+    /// The host virtual address of the backing memory.
+    pub hva: HostVirtAddr,
+    /// The size of the region in bytes.
+    pub size: usize,
+    /// The permissions of the guest's mapping.
+    pub flags: MappingFlags,
+    /// How the pages are backed.
+    pub backing: RegionBacking,
+}
+
+impl GuestMemoryRegion {
+    /// The guest physical address just past the region.
+    pub fn end(&self) -> GuestPhysAddr {
+        self.gpa + self.size
+    }
+
+    /// Whether `gpa` is in the region.
+    pub fn contains(&self, gpa: GuestPhysAddr) -> bool {
+        (self.gpa..self.end()).contains(&gpa)
+    }
+}
+
+/// The pages written through a [`GuestMemory`] and its clones while dirty
+/// logging is on. The host writes them, e.g. for device models, so the
+/// nested page table does not catch them.
+#[derive(Debug, Default)]
+struct WriteLog {
+    enabled: AtomicBool,
+    pages: Mutex<BTreeSet<GuestPhysAddr>>,
+}
+
+/// The host pages of the regions of a [`GuestMemory`] backed on demand or
+/// copied on write, allocated so far. Its clones share them, and the last
+/// one frees them.
+#[derive(Debug)]
+struct DemandPages {
+    /// The host pages, by guest physical address.
+    pages: Mutex<BTreeMap<GuestPhysAddr, HostVirtAddr>>,
+    /// The pages copied on write since the last [`GuestMemory::take_copied`].
+    copied: Mutex<BTreeSet<GuestPhysAddr>>,
+    alloc_page: fn() -> Option<HostVirtAddr>,
+    dealloc_page: fn(HostVirtAddr),
+}
+
+impl DemandPages {
+    fn new<H: HyperCraftHal>() -> Self {
+        Self {
+            pages: Mutex::new(BTreeMap::new()),
+            copied: Mutex::new(BTreeSet::new()),
+            alloc_page: H::alloc_page,
+            dealloc_page: H::dealloc_page,
+        }
+    }
+}
+
+impl Drop for DemandPages {
+    fn drop(&mut self) {
+        let dealloc_page = self.dealloc_page;
+        self.pages
+            .get_mut()
+            .values()
+            .for_each(|&hva| dealloc_page(hva));
+    }
+}
+
+/// The RAM of a guest, as a set of host-backed regions.
+///
+/// Unlike the accessors of [`GuestPageTableTrait`], it reaches the memory
+/// through the host mappings, so it works for any VM, whether it runs or
+/// not. Its clones share the log of the pages written through them, and
+/// the private pages of the regions backed on demand or copied on write.
+#[derive(Debug, Clone, Default)]
+pub struct GuestMemory {
+    /// The regions, sorted by guest physical address.
+    regions: Vec<GuestMemoryRegion>,
+    writes: Arc<WriteLog>,
+    demand: Option<Arc<DemandPages>>,
+}
+
+impl GuestMemory {
+    /// Create a guest memory without any region.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `size` bytes of RAM at `gpa`, backed by the host memory at `hva`.
+    /// The range must be page aligned and not overlap another region.
+    pub fn add_region(
+        &mut self,
+        gpa: GuestPhysAddr,
+        hva: HostVirtAddr,
+        size: usize,
+        flags: MappingFlags,
+    ) -> HyperResult {
+        self.insert_region(GuestMemoryRegion {
+            gpa,
+            hva,
+            size,
+            flags,
+            backing: RegionBacking::Host,
+        })
+    }
+
+    /// Add `size` bytes of RAM at `gpa` backed on demand by pages of `H`.
+    /// The clones of this memory made afterwards share the pages, and the
+    /// last of them frees them.
+    pub(crate) fn add_lazy_region<H: HyperCraftHal>(
+        &mut self,
+        gpa: GuestPhysAddr,
+        size: usize,
+        flags: MappingFlags,
+    ) -> HyperResult {
+        self.demand
+            .get_or_insert_with(|| Arc::new(DemandPages::new::<H>()));
+        self.insert_region(GuestMemoryRegion {
+            gpa,
+            hva: 0,
+            size,
+            flags,
+            backing: RegionBacking::OnDemand,
+        })
+    }
+
+    /// Add `size` bytes of RAM at `gpa`, backed by the host memory at `hva`
+    /// of another VM until they are written: the first write to each page
+    /// copies it to a private page of `H`. The clones of this memory made
+    /// afterwards share the copies, and the last of them frees them.
+    pub(crate) fn add_cow_region<H: HyperCraftHal>(
+        &mut self,
+        gpa: GuestPhysAddr,
+        hva: HostVirtAddr,
+        size: usize,
+        flags: MappingFlags,
+    ) -> HyperResult {
+        self.demand
+            .get_or_insert_with(|| Arc::new(DemandPages::new::<H>()));
+        self.insert_region(GuestMemoryRegion {
+            gpa,
+            hva,
+            size,
+            flags,
+            backing: RegionBacking::CopyOnWrite,
+        })
+    }
+
+    fn insert_region(&mut self, region: GuestMemoryRegion) -> HyperResult {
+        let aligned = |addr: usize| addr % PAGE_SIZE_4K == 0;
+        if region.size == 0 || !aligned(region.gpa) || !aligned(region.hva) || !aligned(region.size)
+        {
+            return Err(HyperError::InvalidParam);
+        }
+        let end = region
+            .gpa
+            .checked_add(region.size)
+            .ok_or(HyperError::InvalidParam)?;
+        let index = self.regions.partition_point(|r| r.gpa < region.gpa);
+        let overlaps_prev = index > 0 && self.regions[index - 1].end() > region.gpa;
+        let overlaps_next = index < self.regions.len() && self.regions[index].gpa < end;
+        if overlaps_prev || overlaps_next {
+            return Err(HyperError::InvalidParam);
+        }
+        self.regions.insert(index, region);
+        Ok(())
+    }
+
+    /// Remove the region that starts at `gpa`, and return it.
+    pub fn remove_region(&mut self, gpa: GuestPhysAddr) -> HyperResult<GuestMemoryRegion> {
+        let index = self
+            .regions
+            .iter()
+            .position(|r| r.gpa == gpa)
+            .ok_or(HyperError::NotFound)?;
+        Ok(self.regions.remove(index))
+    }
+
+    /// The regions, sorted by guest physical address.
+    pub fn regions(&self) -> &[GuestMemoryRegion] {
+        &self.regions
+    }
+
+    /// The region that contains `gpa`.
+    pub fn find_region(&self, gpa: GuestPhysAddr) -> Option<&GuestMemoryRegion> {
+        let index = self.regions.partition_point(|r| r.end() <= gpa);
+        self.regions.get(index).filter(|r| r.contains(gpa))
+    }
+
+    /// Translate `gpa` to the host virtual address of its backing memory.
+    /// Fails with [`HyperError::NotFound`] if it is backed on demand, and
+    /// not backed yet. For the pages copied on write that are not copied
+    /// yet, it is the address of the shared page, which must not be written.
+    pub fn gpa_to_hva(&self, gpa: GuestPhysAddr) -> HyperResult<HostVirtAddr> {
+        let region = self.find_region(gpa).ok_or(HyperError::OutOfRange)?;
+        match region.backing {
+            RegionBacking::Host => Ok(region.hva + (gpa - region.gpa)),
+            RegionBacking::OnDemand | RegionBacking::CopyOnWrite => {
+                let page = gpa & !(PAGE_SIZE_4K - 1);
+                let hva = self.demand_page(region, page, false)?;
+                Ok(hva.ok_or(HyperError::NotFound)? + (gpa - page))
+            }
+        }
+    }
+
+    /// Whether the page of `gpa` is RAM with host memory behind it: always
+    /// true for the regions not backed on demand.
+    pub fn is_backed(&self, gpa: GuestPhysAddr) -> bool {
+        self.gpa_to_hva(gpa).is_ok()
+    }
+
+    /// The host page which backs `page` of `region`, backed on demand or
+    /// copied on write. If it has no private page, one is allocated if
+    /// `allocate` is set, and zeroed or copied from the shared page;
+    /// otherwise it is `None`, or the shared page if copied on write.
+    fn demand_page(
+        &self,
+        region: &GuestMemoryRegion,
+        page: GuestPhysAddr,
+        allocate: bool,
+    ) -> HyperResult<Option<HostVirtAddr>> {
+        let demand = self.demand.as_ref().ok_or(HyperError::BadState)?;
+        let mut pages = demand.pages.lock();
+        if let Some(&hva) = pages.get(&page) {
+            return Ok(Some(hva));
+        }
+        let shared = region.hva + (page - region.gpa);
+        if !allocate {
+            return Ok((region.backing == RegionBacking::CopyOnWrite).then_some(shared));
+        }
+        let hva = (demand.alloc_page)().ok_or(HyperError::NoMemory)?;
+        match region.backing {
+            RegionBacking::CopyOnWrite => {
+                let src = shared as *const u8;
+                unsafe { core::ptr::copy_nonoverlapping(src, hva as *mut u8, PAGE_SIZE_4K) };
+                demand.copied.lock().insert(page);
+            }
+            _ => unsafe { core::ptr::write_bytes(hva as *mut u8, 0, PAGE_SIZE_4K) },
+        }
+        pages.insert(page, hva);
+        Ok(Some(hva))
+    }
+
+    /// The private host page which backs the page of `gpa`, in a region
+    /// backed on demand or copied on write. It is allocated if there is
+    /// none yet, and zeroed or copied from the shared page.
+    pub(crate) fn back_page(&self, gpa: GuestPhysAddr) -> HyperResult<HostVirtAddr> {
+        match self.find_region(gpa) {
+            Some(region) if region.backing != RegionBacking::Host => {
+                let page = self.demand_page(region, gpa & !(PAGE_SIZE_4K - 1), true)?;
+                Ok(page.unwrap())
+            }
+            _ => Err(HyperError::InvalidParam),
+        }
+    }
+
+    /// Take the pages copied on write since the last call, through this
+    /// memory and its clones.
+    pub(crate) fn take_copied(&self) -> Vec<GuestPhysAddr> {
+        self.demand.as_ref().map_or(Vec::new(), |demand| {
+            core::mem::take(&mut *demand.copied.lock())
+                .into_iter()
+                .collect()
+        })
+    }
+
+    /// The pages backed on demand or copied on write of the `size` bytes at
+    /// `gpa` which have a private page.
+    pub(crate) fn backed_pages(&self, gpa: GuestPhysAddr, size: usize) -> Vec<GuestPhysAddr> {
+        let Some(demand) = &self.demand else {
+            return Vec::new();
+        };
+        let end = gpa.saturating_add(size);
+        let pages = demand.pages.lock();
+        pages.range(gpa..end).map(|(&page, _)| page).collect()
+    }
+
+    /// Free the host pages behind `pages`, which are backed on demand. They
+    /// are backed again on their next access.
+    pub(crate) fn free_pages(&self, pages: &[GuestPhysAddr]) {
+        let Some(demand) = &self.demand else {
+            return;
+        };
+        let mut backed = demand.pages.lock();
+        for page in pages {
+            if let Some(hva) = backed.remove(page) {
+                (demand.dealloc_page)(hva);
+            }
+        }
+    }
+
+    /// The number of pages backed on demand or copied on write which have a
+    /// private page.
+    pub(crate) fn nr_backed_pages(&self) -> usize {
+        self.demand
+            .as_ref()
+            .map_or(0, |demand| demand.pages.lock().len())
+    }
+
+    /// Call `f` with the host address, offset in the range and length of
+    /// each piece of the `len` bytes at `gpa`, which may span adjacent
+    /// regions. The host address is `None` for the pages backed on demand
+    /// that are not backed yet, unless `allocate` is set and they are backed
+    /// first; likewise, the pages copied on write are copied first if
+    /// `allocate` is set. Fails without calling `f` if a byte of the range is not RAM,
+    /// and with [`HyperError::NoMemory`] if a page cannot be backed.
+    fn for_each_piece(
+        &self,
+        gpa: GuestPhysAddr,
+        len: usize,
+        allocate: bool,
+        mut f: impl FnMut(Option<HostVirtAddr>, usize, usize),
+    ) -> HyperResult {
+        let end = gpa.checked_add(len).ok_or(HyperError::OutOfRange)?;
+        let mut addr = gpa;
+        while addr < end {
+            let region = self.find_region(addr).ok_or(HyperError::OutOfRange)?;
+            addr = region.end().min(end);
+        }
+        let mut addr = gpa;
+        while addr < end {
+            let region = self.find_region(addr).unwrap();
+            let (hva, piece) = match region.backing {
+                RegionBacking::Host => (
+                    Some(region.hva + (addr - region.gpa)),
+                    region.end().min(end) - addr,
+                ),
+                RegionBacking::OnDemand | RegionBacking::CopyOnWrite => {
+                    let page = addr & !(PAGE_SIZE_4K - 1);
+                    let hva = self.demand_page(region, page, allocate)?;
+                    let piece = (page + PAGE_SIZE_4K).min(end) - addr;
+                    (hva.map(|hva| hva + (addr - page)), piece)
+                }
+            };
+            f(hva, addr - gpa, piece);
+            addr += piece;
+        }
+        Ok(())
+    }
+
+    /// Copy `buf.len()` bytes at `gpa` into `buf`. The pages backed on
+    /// demand that are not backed yet read as zeros.
+    pub fn read(&self, gpa: GuestPhysAddr, buf: &mut [u8]) -> HyperResult {
+        let dst = buf.as_mut_ptr();
+        self.for_each_piece(gpa, buf.len(), false, |hva, offset, len| unsafe {
+            match hva {
+                Some(hva) => core::ptr::copy_nonoverlapping(hva as *const u8, dst.add(offset), len),
+                None => core::ptr::write_bytes(dst.add(offset), 0, len),
+            }
+        })
+    }
+
+    /// Copy `buf` to `gpa`.
+    pub fn write(&self, gpa: GuestPhysAddr, buf: &[u8]) -> HyperResult {
+        let src = buf.as_ptr();
+        self.for_each_piece(gpa, buf.len(), true, |hva, offset, len| unsafe {
+            core::ptr::copy_nonoverlapping(src.add(offset), hva.unwrap() as *mut u8, len);
+        })?;
+        self.log_write(gpa, buf.len());
+        Ok(())
+    }
+
+    /// Fill the `len` bytes at `gpa` with `byte`.
+    pub fn fill(&self, gpa: GuestPhysAddr, byte: u8, len: usize) -> HyperResult {
+        self.for_each_piece(gpa, len, true, |hva, _, len| unsafe {
+            core::ptr::write_bytes(hva.unwrap() as *mut u8, byte, len);
+        })?;
+        self.log_write(gpa, len);
+        Ok(())
+    }
+
+    /// Record the pages of the `len` bytes written at `gpa`, if the write
+    /// log is on.
+    fn log_write(&self, gpa: GuestPhysAddr, len: usize) {
+        if len == 0 || !self.writes.enabled.load(Ordering::Acquire) {
+            return;
+        }
+        let first = gpa & !(PAGE_SIZE_4K - 1);
+        let mut pages = self.writes.pages.lock();
+        pages.extend((first..gpa + len).step_by(PAGE_SIZE_4K));
+    }
+
+    /// Start logging the pages written through this memory and its clones,
+    /// for the dirty log.
+    pub(crate) fn start_write_log(&self) {
+        self.writes.pages.lock().clear();
+        self.writes.enabled.store(true, Ordering::Release);
+    }
+
+    /// Stop logging the pages written, and forget them.
+    pub(crate) fn stop_write_log(&self) {
+        self.writes.enabled.store(false, Ordering::Release);
+        self.writes.pages.lock().clear();
+    }
+
+    /// Take the logged pages of the `size` bytes at `gpa`.
+    pub(crate) fn take_written(&self, gpa: GuestPhysAddr, size: usize) -> Vec<GuestPhysAddr> {
+        let mut pages = self.writes.pages.lock();
+        let taken: Vec<_> = pages.range(gpa..gpa + size).copied().collect();
+        taken.iter().for_each(|page| {
+            pages.remove(page);
+        });
+        taken
+    }
+
+    /// Read a `T` at `gpa`, which need not be aligned.
+    pub fn read_obj<T: ByteValued>(&self, gpa: GuestPhysAddr) -> HyperResult<T> {
+        let mut obj = MaybeUninit::<T>::uninit();
+        let buf =
+            unsafe { core::slice::from_raw_parts_mut(obj.as_mut_ptr() as *mut u8, size_of::<T>()) };
+        self.read(gpa, buf)?;
+        Ok(unsafe { obj.assume_init() })
+    }
+
+    /// Write `obj` to `gpa`, which need not be aligned.
+    pub fn write_obj<T: ByteValued>(&self, gpa: GuestPhysAddr, obj: &T) -> HyperResult {
+        let buf =
+            unsafe { core::slice::from_raw_parts(obj as *const T as *const u8, size_of::<T>()) };
+        self.write(gpa, buf)
+    }
+
+    /// Map every region into the nested page table `gpt`, but those backed
+    /// on demand, whose pages are mapped on their first access, and those
+    /// copied on write, which the VM cloned from a template maps. The backing
+    /// memory need not be physically contiguous: each physically contiguous
+    /// run of pages is mapped with one [`GuestPageTableTrait::map_region`],
+    /// so that it can use larger pages.
+    pub fn map_into<H: HyperCraftHal, G: GuestPageTableTrait>(&self, gpt: &mut G) -> HyperResult {
+        let host_backed = self
+            .regions
+            .iter()
+            .filter(|region| region.backing == RegionBacking::Host);
+        for region in host_backed {
+            let mut run_start = 0;
+            while run_start < region.size {
+                let hpa = H::virt_to_phys(region.hva + run_start);
+                let mut run_end = run_start + PAGE_SIZE_4K;
+                while run_end < region.size
+                    && H::virt_to_phys(region.hva + run_end) == hpa + (run_end - run_start)
+                {
+                    run_end += PAGE_SIZE_4K;
+                }
+                gpt.map_region(
+                    region.gpa + run_start,
+                    hpa,
+                    run_end - run_start,
+                    region.flags,
+                )?;
+                run_start = run_end;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use core::alloc::Layout;
+
+    use super::*;
+
+    /// A page of host memory, to back guest RAM in tests.
+    #[repr(C, align(4096))]
+    #[derive(Clone)]
+    pub(crate) struct HostPage(pub [u8; PAGE_SIZE_4K]);
+
+    /// A guest memory of one region of `pages` pages at `gpa`, backed by
+    /// the returned host pages.
+    pub(crate) fn host_backed(gpa: GuestPhysAddr, pages: usize) -> (Vec<HostPage>, GuestMemory) {
+        let host = vec![HostPage([0; PAGE_SIZE_4K]); pages];
+        let mut memory = GuestMemory::new();
+        memory
+            .add_region(gpa, host.as_ptr() as usize, pages * PAGE_SIZE_4K, rw())
+            .unwrap();
+        (host, memory)
+    }
+
+    /// A nested page table of any page size, which records its leaf entries.
+    #[derive(Default)]
+    pub(crate) struct MockPageTable {
+        /// The leaf entries, by guest physical address.
+        pub pages: BTreeMap<GuestPhysAddr, (HostPhysAddr, MappingFlags, PageSize)>,
+    }
+
+    impl MockPageTable {
+        fn entry(
+            &self,
+            gpa: GuestPhysAddr,
+        ) -> Option<(GuestPhysAddr, HostPhysAddr, MappingFlags, PageSize)> {
+            let (&start, &(hpa, flags, size)) = self.pages.range(..=gpa).next_back()?;
+            (gpa < start + size as usize).then_some((start, hpa, flags, size))
+        }
+
+        /// The flags of the page that maps `gpa`.
+        pub fn flags(&self, gpa: GuestPhysAddr) -> MappingFlags {
+            self.entry(gpa).unwrap().2
+        }
+    }
+
+    impl GuestPageTableTrait for MockPageTable {
+        fn new() -> HyperResult<Self> {
+            Ok(Self::default())
+        }
+
+        fn map(
+            &mut self,
+            gpa: GuestPhysAddr,
+            hpa: HostPhysAddr,
+            flags: MappingFlags,
+        ) -> HyperResult {
+            self.map_page(gpa, hpa, PageSize::Size4K, flags)
+        }
+
+        fn map_page(
+            &mut self,
+            gpa: GuestPhysAddr,
+            hpa: HostPhysAddr,
+            page_size: PageSize,
+            flags: MappingFlags,
+        ) -> HyperResult {
+            let len = page_size as usize;
+            if (gpa | hpa) % len != 0 {
+                return Err(HyperError::InvalidParam);
+            }
+            let overlaps =
+                self.entry(gpa).is_some() || self.pages.range(gpa..gpa + len).next().is_some();
+            if overlaps {
+                return Err(HyperError::BadState);
+            }
+            self.pages.insert(gpa, (hpa, flags, page_size));
+            Ok(())
+        }
+
+        fn unmap(&mut self, gpa: GuestPhysAddr) -> HyperResult {
+            let (start, ..) = self.entry(gpa).ok_or(HyperError::NotFound)?;
+            self.pages.remove(&start);
+            Ok(())
+        }
+
+        fn query(&self, gpa: GuestPhysAddr) -> HyperResult<(HostPhysAddr, MappingFlags, PageSize)> {
+            let (_, hpa, flags, size) = self.entry(gpa).ok_or(HyperError::NotFound)?;
+            Ok((hpa, flags, size))
+        }
+
+        fn translate(&self, gpa: GuestPhysAddr) -> HyperResult<HostPhysAddr> {
+            let (start, hpa, ..) = self.entry(gpa).ok_or(HyperError::NotFound)?;
+            Ok(hpa + (gpa - start))
+        }
+
+        fn token(&self) -> usize {
+            0
+        }
+
+        fn read_guest_phys_addrs(
+            &self,
+            _: GuestPhysAddr,
+            _: *mut u8,
+            _: usize,
+        ) -> HyperResult<usize> {
+            Err(HyperError::NotSupported)
+        }
+
+        fn write_guest_phys_addrs(
+            &mut self,
+            _: GuestPhysAddr,
+            _: *const u8,
+            _: usize,
+        ) -> HyperResult {
+            Err(HyperError::NotSupported)
+        }
+
+        fn root_paddr(&self) -> HostPhysAddr {
+            0
+        }
+    }
+
+    pub(crate) fn rw() -> MappingFlags {
+        MappingFlags::READ | MappingFlags::WRITE
+    }
+
+    /// A HAL whose pages come from the heap, and whose host virtual
+    /// addresses are host physical addresses.
+    pub(crate) struct TestHal;
+
+    impl TestHal {
+        fn layout(num_pages: usize) -> Layout {
+            Layout::from_size_align(num_pages * PAGE_SIZE_4K, PAGE_SIZE_4K).unwrap()
+        }
+    }
+
+    impl HyperCraftHal for TestHal {
+        fn alloc_pages(num_pages: usize) -> Option<HostVirtAddr> {
+            let ptr = unsafe { alloc::alloc::alloc(Self::layout(num_pages)) };
+            (!ptr.is_null()).then_some(ptr as HostVirtAddr)
+        }
+
+        fn dealloc_pages(va: HostVirtAddr, num_pages: usize) {
+            unsafe { alloc::alloc::dealloc(va as *mut u8, Self::layout(num_pages)) }
+        }
+
+        fn phys_to_virt(pa: HostPhysAddr) -> HostVirtAddr {
+            pa
+        }
+
+        fn virt_to_phys(va: HostVirtAddr) -> HostPhysAddr {
+            va
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        fn vmexit_handler<G: GuestPageTableTrait, C: gdbstub::conn::ConnectionExt>(
+            _: &mut crate::arch::VCpu<Self, G, C>,
+        ) -> HyperResult {
+            Err(HyperError::NotSupported)
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        fn current_time_nanos() -> u64 {
+            0
+        }
+    }
+
+    const GB: usize = PageSize::Size1G as usize;
+    const MB2: usize = PageSize::Size2M as usize;
+
+    #[test]
+    fn map_region_uses_large_pages() {
+        let mut gpt = MockPageTable::default();
+        let gpa = GB - MB2;
+        gpt.map_region(gpa, gpa, MB2 + GB + MB2 + PAGE_SIZE_4K, rw())
+            .unwrap();
+        let pages: Vec<_> = gpt
+            .pages
+            .iter()
+            .map(|(&gpa, &(_, _, size))| (gpa, size))
+            .collect();
+        assert_eq!(
+            pages,
+            [
+                (GB - MB2, PageSize::Size2M),
+                (GB, PageSize::Size1G),
+                (2 * GB, PageSize::Size2M),
+                (2 * GB + MB2, PageSize::Size4K),
+            ]
+        );
+        assert_eq!(
+            gpt.map_region(0x1800, 0, PAGE_SIZE_4K, rw()),
+            Err(HyperError::InvalidParam)
+        );
+    }
+
+    #[test]
+    fn protect_region_splits_large_page() {
+        let mut gpt = MockPageTable::default();
+        let hpa = 0x8000_0000;
+        gpt.map_region(MB2, hpa, MB2, rw()).unwrap();
+        gpt.protect_region(MB2 + PAGE_SIZE_4K, PAGE_SIZE_4K, MappingFlags::READ)
+            .unwrap();
+        assert_eq!(gpt.pages.len(), 512);
+        assert!(gpt
+            .pages
+            .values()
+            .all(|&(_, _, size)| size == PageSize::Size4K));
+        assert_eq!(gpt.query(MB2), Ok((hpa, rw(), PageSize::Size4K)));
+        assert_eq!(
+            gpt.query(MB2 + PAGE_SIZE_4K),
+            Ok((hpa + PAGE_SIZE_4K, MappingFlags::READ, PageSize::Size4K))
+        );
+        assert_eq!(gpt.translate(2 * MB2 - 1), Ok(hpa + MB2 - 1));
+        assert_eq!(gpt.flags(2 * MB2 - 1), rw());
+    }
+
+    #[test]
+    fn unmap_region_keeps_rest_of_large_page() {
+        let mut gpt = MockPageTable::default();
+        let hpa = 4 * GB;
+        gpt.map_region(GB, hpa, GB, rw()).unwrap();
+        gpt.unmap_region(GB + MB2, MB2).unwrap();
+        assert_eq!(gpt.pages.len(), 511);
+        assert!(gpt
+            .pages
+            .values()
+            .all(|&(_, _, size)| size == PageSize::Size2M));
+        assert_eq!(gpt.translate(GB + MB2), Err(HyperError::NotFound));
+        assert_eq!(gpt.translate(GB + 2 * MB2), Ok(hpa + 2 * MB2));
+        assert_eq!(gpt.translate(2 * GB - 1), Ok(hpa + GB - 1));
+    }
+
+    #[test]
+    fn access_across_regions() {
+        let (_low, mut memory) = host_backed(0x8000_0000, 1);
+        let high = vec![HostPage([0; PAGE_SIZE_4K]); 1];
+        let high_hva = high.as_ptr() as usize;
+        memory
+            .add_region(0x8000_1000, high_hva, PAGE_SIZE_4K, rw())
+            .unwrap();
+        let low_hva = memory.gpa_to_hva(0x8000_0000).unwrap();
+
+        let mut pieces = Vec::new();
+        memory
+            .for_each_piece(0x8000_0ff8, 16, false, |hva, offset, len| {
+                pieces.push((hva.unwrap(), offset, len))
+            })
+            .unwrap();
+        assert_eq!(pieces, [(low_hva + 0xff8, 0, 8), (high_hva, 8, 8)]);
+
+        memory
+            .write_obj(0x8000_0ffc, &0x1122_3344_5566_7788u64)
+            .unwrap();
+        assert_eq!(
+            memory.read_obj::<u64>(0x8000_0ffc),
+            Ok(0x1122_3344_5566_7788)
+        );
+        assert_eq!(high[0].0[..4], [0x44, 0x33, 0x22, 0x11]);
+    }
+
+    #[test]
+    fn access_across_hole_fails() {
+        let (host, mut memory) = host_backed(0x8000_0000, 1);
+        let high = vec![HostPage([0; PAGE_SIZE_4K]); 1];
+        memory
+            .add_region(0x8000_2000, high.as_ptr() as usize, PAGE_SIZE_4K, rw())
+            .unwrap();
+        let mut calls = 0;
+        let result = memory.for_each_piece(0x8000_0f00, 0x2000, false, |_, _, _| calls += 1);
+        assert_eq!(result, Err(HyperError::OutOfRange));
+        assert_eq!(calls, 0);
+        assert_eq!(
+            memory.write(0x8000_0ff0, &[0xff; 32]),
+            Err(HyperError::OutOfRange)
+        );
+        assert!(host[0].0.iter().all(|&byte| byte == 0));
+        assert_eq!(
+            memory.read_obj::<u8>(0x8000_1000),
+            Err(HyperError::OutOfRange)
+        );
+    }
+
+    #[test]
+    fn lazy_region_is_backed_on_access() {
+        let (_host, mut memory) = host_backed(0x8000_0000, 1);
+        memory
+            .add_lazy_region::<TestHal>(0x8000_1000, 4 * PAGE_SIZE_4K, rw())
+            .unwrap();
+        let view = memory.clone();
+        assert_eq!(view.read_obj::<u64>(0x8000_0ffc), Ok(0));
+        assert_eq!(memory.nr_backed_pages(), 0);
+        assert_eq!(memory.gpa_to_hva(0x8000_1000), Err(HyperError::NotFound));
+
+        view.write_obj(0x8000_0ffc, &u64::MAX).unwrap();
+        assert_eq!(memory.backed_pages(0x8000_0000, 0x10_0000), [0x8000_1000]);
+        assert_eq!(memory.read_obj::<u64>(0x8000_0ffc), Ok(u64::MAX));
+        assert!(memory.is_backed(0x8000_0000));
+        assert!(!memory.is_backed(0x8000_2000));
+
+        let hva = memory.back_page(0x8000_2010).unwrap();
+        assert_eq!(memory.gpa_to_hva(0x8000_2010), Ok(hva + 0x10));
+        assert_eq!(view.read_obj::<u8>(0x8000_2010), Ok(0));
+        assert_eq!(memory.back_page(0x8000_0000), Err(HyperError::InvalidParam));
+
+        memory.free_pages(&[0x8000_1000]);
+        assert_eq!(view.read_obj::<u32>(0x8000_1000), Ok(0));
+        assert_eq!(memory.nr_backed_pages(), 1);
+
+        let mut gpt = MockPageTable::default();
+        memory.map_into::<TestHal, _>(&mut gpt).unwrap();
+        assert_eq!(gpt.pages.keys().copied().collect::<Vec<_>>(), [0x8000_0000]);
+    }
+}
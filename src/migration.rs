@@ -0,0 +1,567 @@
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use gdbstub::conn::{Connection, ConnectionExt};
+use page_table_entry::MappingFlags;
+use spin::Mutex;
+
+use crate::memory::PAGE_SIZE_4K;
+use crate::{GuestMemory, GuestMemoryRegion, GuestPhysAddr, HyperError, HyperResult};
+
+/// The first bytes of a migration stream.
+const MIGRATION_MAGIC: [u8; 4] = *b"HCMG";
+
+/// The version of the migration protocol.
+const MIGRATION_VERSION: u32 = 1;
+
+/// A message of guest pages: the address of the first one as a `u64`, the
+/// number of pages as a `u32`, and their contents.
+const MSG_PAGES: u8 = 1;
+/// A message of the vCPU and device state: its length as a `u64`, and the
+/// state in the snapshot format.
+const MSG_STATE: u8 = 2;
+/// The last message of the stream.
+const MSG_DONE: u8 = 3;
+
+/// The maximum number of pages of a [`MSG_PAGES`] message.
+const MAX_PAGES_PER_MSG: usize = 256;
+
+/// The maximum length of the state of a [`MSG_STATE`] message, so that a
+/// corrupt stream cannot make the receiver allocate any amount of memory.
+const MAX_STATE_LEN: usize = 16 << 20;
+
+/// The default maximum number of pre-copy rounds.
+pub const MIGRATION_MAX_ROUNDS: usize = 30;
+
+/// The default number of dirty pages under which the pre-copy rounds stop.
+pub const MIGRATION_DIRTY_THRESHOLD: usize = 64;
+
+/// A VM that can be migrated, or a vCPU on architectures whose VM is
+/// driven by a single one.
+pub trait Migratable {
+    /// Start logging the pages the guest writes in the writable regions of
+    /// `memory`. Fails with [`HyperError::NotSupported`] if the guest has
+    /// RAM outside of `memory`, e.g. shared with a template VM.
+    fn enable_dirty_log(&mut self, memory: &GuestMemory) -> HyperResult;
+
+    /// Take the pages of the `size` bytes at `gpa` written since the last
+    /// call, as a bitmap of one bit per 4K page in `usize` words.
+    fn get_and_clear_dirty_log(
+        &mut self,
+        gpa: GuestPhysAddr,
+        size: usize,
+    ) -> HyperResult<Vec<usize>>;
+
+    /// Stop dirty logging.
+    fn disable_dirty_log(&mut self) -> HyperResult;
+
+    /// Save the vCPU and device state, everything of a snapshot but the RAM.
+    /// The vCPUs must not be running.
+    fn export_state(&mut self) -> HyperResult<Vec<u8>>;
+
+    /// Restore the state saved by [`Migratable::export_state`].
+    fn import_state(&mut self, state: &[u8]) -> HyperResult;
+}
+
+fn conn_err<E>(_: E) -> HyperError {
+    HyperError::ConnectionError
+}
+
+fn send_u32<C: Connection>(conn: &mut C, value: u32) -> HyperResult {
+    conn.write_all(&value.to_le_bytes()).map_err(conn_err)
+}
+
+fn send_u64<C: Connection>(conn: &mut C, value: u64) -> HyperResult {
+    conn.write_all(&value.to_le_bytes()).map_err(conn_err)
+}
+
+fn recv_exact<C: ConnectionExt>(conn: &mut C, buf: &mut [u8]) -> HyperResult {
+    for byte in buf.iter_mut() {
+        *byte = conn.read().map_err(conn_err)?;
+    }
+    Ok(())
+}
+
+fn recv_u32<C: ConnectionExt>(conn: &mut C) -> HyperResult<u32> {
+    let mut bytes = [0; 4];
+    recv_exact(conn, &mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn recv_u64<C: ConnectionExt>(conn: &mut C) -> HyperResult<u64> {
+    let mut bytes = [0; 8];
+    recv_exact(conn, &mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// The source side of a pre-copy migration.
+///
+/// [`MigrationSender::start`] sends all the RAM while the guest keeps
+/// running, with dirty logging enabled. Each [`MigrationSender::iterate`]
+/// round then sends the pages written since the previous one, until few
+/// enough are left. The host then stops the vCPUs and calls
+/// [`MigrationSender::complete`] to send the last pages and the vCPU and
+/// device state.
+pub struct MigrationSender {
+    max_rounds: usize,
+    dirty_threshold: usize,
+    rounds: usize,
+    last_dirty: usize,
+    pages_sent: usize,
+    buf: Vec<u8>,
+}
+
+impl Default for MigrationSender {
+    fn default() -> Self {
+        Self::new(MIGRATION_MAX_ROUNDS, MIGRATION_DIRTY_THRESHOLD)
+    }
+}
+
+impl MigrationSender {
+    /// Create a sender which stops the pre-copy rounds after `max_rounds`,
+    /// or once a round finds at most `dirty_threshold` dirty pages.
+    pub fn new(max_rounds: usize, dirty_threshold: usize) -> Self {
+        Self {
+            max_rounds,
+            dirty_threshold,
+            rounds: 0,
+            last_dirty: 0,
+            pages_sent: 0,
+            buf: Vec::new(),
+        }
+    }
+
+    /// The number of pre-copy rounds done.
+    pub fn rounds(&self) -> usize {
+        self.rounds
+    }
+
+    /// The number of pages sent so far, counting the pages sent again.
+    pub fn pages_sent(&self) -> usize {
+        self.pages_sent
+    }
+
+    /// Start the migration of `vm`, whose RAM is `memory`: enable dirty
+    /// logging, and send all the RAM, but the pages backed on demand that
+    /// are not backed yet.
+    pub fn start<V: Migratable, C: Connection>(
+        &mut self,
+        vm: &mut V,
+        memory: &GuestMemory,
+        conn: &mut C,
+    ) -> HyperResult {
+        conn.write_all(&MIGRATION_MAGIC).map_err(conn_err)?;
+        send_u32(conn, MIGRATION_VERSION)?;
+        vm.enable_dirty_log(memory)?;
+        for region in memory.regions() {
+            self.send_runs(memory, conn, region, |page| {
+                memory.is_backed(region.gpa + page * PAGE_SIZE_4K)
+            })?;
+        }
+        conn.flush().map_err(conn_err)
+    }
+
+    /// Send the pages written since the previous round. Returns whether the
+    /// pre-copy rounds are over, and the host should stop the vCPUs and call
+    /// [`MigrationSender::complete`].
+    pub fn iterate<V: Migratable, C: Connection>(
+        &mut self,
+        vm: &mut V,
+        memory: &GuestMemory,
+        conn: &mut C,
+    ) -> HyperResult<bool> {
+        self.last_dirty = self.send_dirty_pages(vm, memory, conn)?;
+        self.rounds += 1;
+        Ok(self.last_dirty <= self.dirty_threshold || self.rounds >= self.max_rounds)
+    }
+
+    /// Send the last dirty pages and the vCPU and device state, and stop
+    /// dirty logging. The vCPUs must not be running anymore.
+    pub fn complete<V: Migratable, C: Connection>(
+        &mut self,
+        vm: &mut V,
+        memory: &GuestMemory,
+        conn: &mut C,
+    ) -> HyperResult {
+        self.send_dirty_pages(vm, memory, conn)?;
+        vm.disable_dirty_log()?;
+        let state = vm.export_state()?;
+        conn.write(MSG_STATE).map_err(conn_err)?;
+        send_u64(conn, state.len() as u64)?;
+        conn.write_all(&state).map_err(conn_err)?;
+        conn.write(MSG_DONE).map_err(conn_err)?;
+        conn.flush().map_err(conn_err)
+    }
+
+    /// Give up the migration, e.g. after an error, and stop dirty logging.
+    /// The source VM can go on running.
+    pub fn cancel<V: Migratable>(&mut self, vm: &mut V) -> HyperResult {
+        vm.disable_dirty_log()
+    }
+
+    /// Migrate `vm` with the vCPUs stopped throughout: all the pre-copy
+    /// rounds run back to back before completing.
+    pub fn migrate<V: Migratable, C: Connection>(
+        &mut self,
+        vm: &mut V,
+        memory: &GuestMemory,
+        conn: &mut C,
+    ) -> HyperResult {
+        self.start(vm, memory, conn)?;
+        while !self.iterate(vm, memory, conn)? {}
+        self.complete(vm, memory, conn)
+    }
+
+    /// Send the dirty pages of the writable regions. Returns their number.
+    fn send_dirty_pages<V: Migratable, C: Connection>(
+        &mut self,
+        vm: &mut V,
+        memory: &GuestMemory,
+        conn: &mut C,
+    ) -> HyperResult<usize> {
+        let mut count = 0;
+        for region in memory.regions() {
+            if !region.flags.contains(MappingFlags::WRITE) {
+                continue;
+            }
+            let dirty = vm.get_and_clear_dirty_log(region.gpa, region.size)?;
+            count += self.send_runs(memory, conn, region, |page| {
+                dirty[page / usize::BITS as usize] >> (page % usize::BITS as usize) & 1 != 0
+            })?;
+        }
+        conn.flush().map_err(conn_err)?;
+        Ok(count)
+    }
+
+    /// Send the pages of `region` whose index `is_selected`, in runs of
+    /// adjacent ones. Returns their number.
+    fn send_runs<C: Connection>(
+        &mut self,
+        memory: &GuestMemory,
+        conn: &mut C,
+        region: &GuestMemoryRegion,
+        is_selected: impl Fn(usize) -> bool,
+    ) -> HyperResult<usize> {
+        let nr_pages = region.size / PAGE_SIZE_4K;
+        let mut count = 0;
+        let mut page = 0;
+        while page < nr_pages {
+            if !is_selected(page) {
+                page += 1;
+                continue;
+            }
+            let first = page;
+            while page < nr_pages && is_selected(page) {
+                page += 1;
+            }
+            self.send_pages(
+                memory,
+                conn,
+                region.gpa + first * PAGE_SIZE_4K,
+                page - first,
+            )?;
+            count += page - first;
+        }
+        Ok(count)
+    }
+
+    /// Send the `nr_pages` pages from `gpa`, in messages of at most
+    /// [`MAX_PAGES_PER_MSG`] pages.
+    fn send_pages<C: Connection>(
+        &mut self,
+        memory: &GuestMemory,
+        conn: &mut C,
+        mut gpa: GuestPhysAddr,
+        mut nr_pages: usize,
+    ) -> HyperResult {
+        while nr_pages > 0 {
+            let count = nr_pages.min(MAX_PAGES_PER_MSG);
+            self.buf.resize(count * PAGE_SIZE_4K, 0);
+            memory.read(gpa, &mut self.buf)?;
+            conn.write(MSG_PAGES).map_err(conn_err)?;
+            send_u64(conn, gpa as u64)?;
+            send_u32(conn, count as u32)?;
+            conn.write_all(&self.buf).map_err(conn_err)?;
+            self.pages_sent += count;
+            gpa += count * PAGE_SIZE_4K;
+            nr_pages -= count;
+        }
+        Ok(())
+    }
+}
+
+/// The destination side of a migration.
+#[derive(Default)]
+pub struct MigrationReceiver {
+    pages_received: usize,
+}
+
+impl MigrationReceiver {
+    /// Create a receiver.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of pages received so far, counting the pages received
+    /// again.
+    pub fn pages_received(&self) -> usize {
+        self.pages_received
+    }
+
+    /// Receive a migrated VM into `vm`, which must be set up like the source
+    /// one and not running, with `memory` of the same regions mapped in its
+    /// guest page table. Returns once the whole VM is received, and `vm` can
+    /// run.
+    pub fn receive<V: Migratable, C: ConnectionExt>(
+        &mut self,
+        vm: &mut V,
+        memory: &GuestMemory,
+        conn: &mut C,
+    ) -> HyperResult {
+        let mut magic = [0; 4];
+        recv_exact(conn, &mut magic)?;
+        if magic != MIGRATION_MAGIC {
+            return Err(HyperError::DecodeError);
+        }
+        if recv_u32(conn)? != MIGRATION_VERSION {
+            return Err(HyperError::NotSupported);
+        }
+        let mut buf = Vec::new();
+        let mut state_received = false;
+        loop {
+            match conn.read().map_err(conn_err)? {
+                MSG_PAGES => {
+                    let gpa = recv_u64(conn)? as GuestPhysAddr;
+                    let count = recv_u32(conn)? as usize;
+                    if count > MAX_PAGES_PER_MSG {
+                        return Err(HyperError::DecodeError);
+                    }
+                    buf.resize(count * PAGE_SIZE_4K, 0);
+                    recv_exact(conn, &mut buf)?;
+                    memory.write(gpa, &buf)?;
+                    self.pages_received += count;
+                }
+                MSG_STATE if !state_received => {
+                    let len = usize::try_from(recv_u64(conn)?)
+                        .ok()
+                        .filter(|&len| len <= MAX_STATE_LEN)
+                        .ok_or(HyperError::DecodeError)?;
+                    let mut state = alloc::vec![0; len];
+                    recv_exact(conn, &mut state)?;
+                    vm.import_state(&state)?;
+                    state_received = true;
+                }
+                MSG_DONE if state_received => return Ok(()),
+                _ => return Err(HyperError::DecodeError),
+            }
+        }
+    }
+}
+
+/// One end of an in-memory connection, for migrating between VMs of the
+/// same host.
+pub struct LoopbackConnection {
+    tx: Arc<Mutex<VecDeque<u8>>>,
+    rx: Arc<Mutex<VecDeque<u8>>>,
+}
+
+impl LoopbackConnection {
+    /// Create the two connected ends.
+    pub fn pair() -> (Self, Self) {
+        let a = Arc::new(Mutex::new(VecDeque::new()));
+        let b = Arc::new(Mutex::new(VecDeque::new()));
+        (
+            Self {
+                tx: a.clone(),
+                rx: b.clone(),
+            },
+            Self { tx: b, rx: a },
+        )
+    }
+
+    /// Whether the other end was dropped.
+    fn peer_closed(&self) -> bool {
+        Arc::strong_count(&self.rx) == 1
+    }
+}
+
+impl Connection for LoopbackConnection {
+    type Error = HyperError;
+
+    fn write(&mut self, byte: u8) -> Result<(), Self::Error> {
+        self.tx.lock().push_back(byte);
+        Ok(())
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), Self::Error> {
+        self.tx.lock().extend(buf);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+impl ConnectionExt for LoopbackConnection {
+    /// Wait for the other end to write a byte. Once it is dropped, the bytes
+    /// left can still be read, and reading more fails.
+    fn read(&mut self) -> Result<u8, Self::Error> {
+        loop {
+            if let Some(byte) = self.rx.lock().pop_front() {
+                return Ok(byte);
+            }
+            if self.peer_closed() {
+                return Err(HyperError::ConnectionError);
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    fn peek(&mut self) -> Result<Option<u8>, Self::Error> {
+        Ok(self.rx.lock().front().copied())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dirty_log::{DirtyLog, DirtyTracking};
+    use crate::memory::tests::{host_backed, MockPageTable};
+    use crate::GuestPageTableTrait;
+
+    const RAM: GuestPhysAddr = 0x8000_0000;
+    const NR_PAGES: usize = 8;
+
+    /// A VM whose vCPU and device state is a byte string.
+    struct MockVm {
+        gpt: MockPageTable,
+        log: Option<DirtyLog>,
+        state: Vec<u8>,
+    }
+
+    impl MockVm {
+        fn new(memory: &GuestMemory) -> Self {
+            let mut gpt = MockPageTable::default();
+            for region in memory.regions() {
+                gpt.map_region(region.gpa, region.hva, region.size, region.flags)
+                    .unwrap();
+            }
+            Self {
+                gpt,
+                log: None,
+                state: Vec::new(),
+            }
+        }
+
+        /// Write `byte` to `gpa` as the guest: the write faults if the page
+        /// is write-protected.
+        fn guest_write(&mut self, memory: &GuestMemory, gpa: GuestPhysAddr, byte: u8) {
+            if let Some(log) = &mut self.log {
+                log.handle_write_fault(&mut self.gpt, gpa).unwrap();
+            }
+            let hva = memory.gpa_to_hva(gpa).unwrap();
+            unsafe { *(hva as *mut u8) = byte };
+        }
+    }
+
+    impl Migratable for MockVm {
+        fn enable_dirty_log(&mut self, memory: &GuestMemory) -> HyperResult {
+            let log = DirtyLog::start(memory, &mut self.gpt, DirtyTracking::WriteProtect)?;
+            self.log = Some(log);
+            Ok(())
+        }
+
+        fn get_and_clear_dirty_log(
+            &mut self,
+            gpa: GuestPhysAddr,
+            size: usize,
+        ) -> HyperResult<Vec<usize>> {
+            let log = self.log.as_mut().ok_or(HyperError::BadState)?;
+            log.get_and_clear(&mut self.gpt, gpa, size)
+        }
+
+        fn disable_dirty_log(&mut self) -> HyperResult {
+            let log = self.log.take().ok_or(HyperError::BadState)?;
+            log.stop(&mut self.gpt)
+        }
+
+        fn export_state(&mut self) -> HyperResult<Vec<u8>> {
+            Ok(self.state.clone())
+        }
+
+        fn import_state(&mut self, state: &[u8]) -> HyperResult {
+            self.state = state.to_vec();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn migrate_over_loopback() {
+        let (_src_host, src_memory) = host_backed(RAM, NR_PAGES);
+        let (_dst_host, dst_memory) = host_backed(RAM, NR_PAGES);
+        for page in 0..NR_PAGES {
+            let gpa = RAM + page * PAGE_SIZE_4K;
+            src_memory.fill(gpa, page as u8 + 1, PAGE_SIZE_4K).unwrap();
+        }
+        let mut src = MockVm::new(&src_memory);
+        src.state = b"vcpu and devices".to_vec();
+        let mut dst = MockVm::new(&dst_memory);
+        let (mut src_conn, mut dst_conn) = LoopbackConnection::pair();
+
+        let mut sender = MigrationSender::new(MIGRATION_MAX_ROUNDS, 1);
+        sender.start(&mut src, &src_memory, &mut src_conn).unwrap();
+        assert_eq!(sender.pages_sent(), NR_PAGES);
+
+        // The guest and a device model write while the RAM is being sent.
+        src.guest_write(&src_memory, RAM + 0x1008, 0xaa);
+        src.guest_write(&src_memory, RAM + 0x1010, 0xbb);
+        src_memory.write_obj(RAM + 0x5ffe, &0xccddu16).unwrap();
+        assert_eq!(
+            sender.iterate(&mut src, &src_memory, &mut src_conn),
+            Ok(false)
+        );
+        assert_eq!(sender.pages_sent(), NR_PAGES + 2);
+
+        src.guest_write(&src_memory, RAM + 0x7000, 0xee);
+        assert_eq!(
+            sender.iterate(&mut src, &src_memory, &mut src_conn),
+            Ok(true)
+        );
+        sender
+            .complete(&mut src, &src_memory, &mut src_conn)
+            .unwrap();
+        assert!(src.log.is_none());
+
+        let mut receiver = MigrationReceiver::new();
+        receiver
+            .receive(&mut dst, &dst_memory, &mut dst_conn)
+            .unwrap();
+        assert_eq!(receiver.pages_received(), sender.pages_sent());
+        assert_eq!(dst.state, src.state);
+        let size = NR_PAGES * PAGE_SIZE_4K;
+        let (mut src_ram, mut dst_ram) = (vec![0; size], vec![0; size]);
+        src_memory.read(RAM, &mut src_ram).unwrap();
+        dst_memory.read(RAM, &mut dst_ram).unwrap();
+        assert!(src_ram == dst_ram);
+        assert_eq!(dst_memory.read_obj::<u8>(RAM + 0x1010), Ok(0xbb));
+        assert_eq!(dst_memory.read_obj::<u16>(RAM + 0x5ffe), Ok(0xccdd));
+    }
+
+    #[test]
+    fn oversized_state_is_rejected() {
+        let (_host, memory) = host_backed(RAM, 1);
+        let mut vm = MockVm::new(&memory);
+        let (mut src_conn, mut dst_conn) = LoopbackConnection::pair();
+        src_conn.write_all(&MIGRATION_MAGIC).unwrap();
+        send_u32(&mut src_conn, MIGRATION_VERSION).unwrap();
+        src_conn.write(MSG_STATE).unwrap();
+        send_u64(&mut src_conn, u64::MAX).unwrap();
+
+        let mut receiver = MigrationReceiver::new();
+        assert_eq!(
+            receiver.receive(&mut vm, &memory, &mut dst_conn),
+            Err(HyperError::DecodeError)
+        );
+    }
+}
@@ -0,0 +1,436 @@
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+use crate::{GuestMemory, HyperError, HyperResult};
+
+/// The first bytes of a snapshot.
+const SNAPSHOT_MAGIC: [u8; 4] = *b"HCVM";
+
+/// The version of the snapshot format, bumped on incompatible changes.
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// The architecture a snapshot was taken on, which is the only one it can
+/// be restored on.
+#[cfg(target_arch = "aarch64")]
+const SNAPSHOT_ARCH: u32 = 1;
+#[cfg(target_arch = "riscv64")]
+const SNAPSHOT_ARCH: u32 = 2;
+#[cfg(target_arch = "x86_64")]
+const SNAPSHOT_ARCH: u32 = 3;
+
+/// The tag of the section of VM-wide state, such as timers.
+pub(crate) const SECTION_VM: [u8; 4] = *b"VM  ";
+/// The tag of the section of the vCPU registers.
+pub(crate) const SECTION_VCPUS: [u8; 4] = *b"VCPU";
+/// The tag of the section of the emulated interrupt controllers, timers and
+/// platform devices.
+pub(crate) const SECTION_IRQCHIP: [u8; 4] = *b"IRQC";
+/// The tag of the guest RAM section.
+pub(crate) const SECTION_RAM: [u8; 4] = *b"RAM ";
+
+/// State saved to and restored from a snapshot, field by field.
+///
+/// Values are little-endian, and `restore` reads them in the order `save`
+/// wrote them. Restoring only overwrites the state the snapshot carries, so
+/// the host-specific state of the target stays as it was set up.
+pub(crate) trait Snapshot {
+    /// Append the state to `w`.
+    fn save(&self, w: &mut SnapshotWriter);
+    /// Overwrite the state with the next one of `r`.
+    fn restore(&mut self, r: &mut SnapshotReader<'_>) -> HyperResult;
+}
+
+/// Implement [`Snapshot`] for a struct by saving the listed fields in order.
+macro_rules! impl_snapshot_fields {
+    ($ty: ty { $($field: tt),* $(,)? }) => {
+        impl crate::snapshot::Snapshot for $ty {
+            fn save(&self, w: &mut crate::snapshot::SnapshotWriter) {
+                $(crate::snapshot::Snapshot::save(&self.$field, w);)*
+            }
+
+            fn restore(
+                &mut self,
+                r: &mut crate::snapshot::SnapshotReader<'_>,
+            ) -> crate::HyperResult {
+                $(crate::snapshot::Snapshot::restore(&mut self.$field, r)?;)*
+                Ok(())
+            }
+        }
+    };
+}
+
+/// Builds a snapshot: a header, then sections of a 4-byte tag, a 64-bit
+/// length and the saved state.
+pub(crate) struct SnapshotWriter {
+    buf: Vec<u8>,
+}
+
+impl SnapshotWriter {
+    /// Start a snapshot of this architecture.
+    pub fn new() -> Self {
+        let mut w = Self { buf: Vec::new() };
+        w.put_bytes(&SNAPSHOT_MAGIC);
+        SNAPSHOT_VERSION.save(&mut w);
+        SNAPSHOT_ARCH.save(&mut w);
+        w
+    }
+
+    /// Append `bytes` as they are.
+    pub fn put_bytes(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Append `len` zero bytes, and return them to be filled in.
+    pub fn reserve(&mut self, len: usize) -> &mut [u8] {
+        let start = self.buf.len();
+        self.buf.resize(start + len, 0);
+        &mut self.buf[start..]
+    }
+
+    /// Append a section tagged `tag`, with the state `f` writes.
+    pub fn section(
+        &mut self,
+        tag: [u8; 4],
+        f: impl FnOnce(&mut Self) -> HyperResult,
+    ) -> HyperResult {
+        self.put_bytes(&tag);
+        let len_offset = self.buf.len();
+        0u64.save(self);
+        f(self)?;
+        let len = (self.buf.len() - len_offset - 8) as u64;
+        self.buf[len_offset..len_offset + 8].copy_from_slice(&len.to_le_bytes());
+        Ok(())
+    }
+
+    /// The finished snapshot.
+    pub fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+/// Reads the state of a snapshot back, in the order it was written.
+pub(crate) struct SnapshotReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> SnapshotReader<'a> {
+    /// Check the header of `data`. Snapshots of other versions or
+    /// architectures are `NotSupported`.
+    pub fn new(data: &'a [u8]) -> HyperResult<Self> {
+        let mut r = Self { data };
+        if r.take(SNAPSHOT_MAGIC.len())? != SNAPSHOT_MAGIC {
+            return Err(HyperError::DecodeError);
+        }
+        let (mut version, mut arch) = (0u32, 0u32);
+        version.restore(&mut r)?;
+        arch.restore(&mut r)?;
+        if version != SNAPSHOT_VERSION || arch != SNAPSHOT_ARCH {
+            return Err(HyperError::NotSupported);
+        }
+        Ok(r)
+    }
+
+    /// Take the next `len` bytes.
+    pub fn take(&mut self, len: usize) -> HyperResult<&'a [u8]> {
+        if self.data.len() < len {
+            return Err(HyperError::DecodeError);
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    /// Read the next value.
+    pub fn read<T: Snapshot + Default>(&mut self) -> HyperResult<T> {
+        let mut value = T::default();
+        value.restore(self)?;
+        Ok(value)
+    }
+
+    /// Read the section tagged `tag`, which must be next, with `f`. It must
+    /// consume the whole section.
+    pub fn section(
+        &mut self,
+        tag: [u8; 4],
+        f: impl FnOnce(&mut SnapshotReader<'a>) -> HyperResult,
+    ) -> HyperResult {
+        if self.take(tag.len())? != tag {
+            return Err(HyperError::DecodeError);
+        }
+        let len = self.read::<u64>()?;
+        let len = usize::try_from(len).map_err(|_| HyperError::DecodeError)?;
+        let mut section = SnapshotReader {
+            data: self.take(len)?,
+        };
+        f(&mut section)?;
+        section.finish()
+    }
+
+    /// Check that everything was read.
+    pub fn finish(self) -> HyperResult {
+        if self.data.is_empty() {
+            Ok(())
+        } else {
+            Err(HyperError::DecodeError)
+        }
+    }
+}
+
+macro_rules! impl_snapshot_int {
+    ($($ty: ty),*) => {
+        $(impl Snapshot for $ty {
+            fn save(&self, w: &mut SnapshotWriter) {
+                w.put_bytes(&self.to_le_bytes());
+            }
+
+            fn restore(&mut self, r: &mut SnapshotReader<'_>) -> HyperResult {
+                let bytes = r.take(core::mem::size_of::<$ty>())?;
+                *self = <$ty>::from_le_bytes(bytes.try_into().unwrap());
+                Ok(())
+            }
+        })*
+    };
+}
+
+impl_snapshot_int!(u8, u16, u32, u64, u128, usize);
+
+impl Snapshot for bool {
+    fn save(&self, w: &mut SnapshotWriter) {
+        (*self as u8).save(w);
+    }
+
+    fn restore(&mut self, r: &mut SnapshotReader<'_>) -> HyperResult {
+        *self = match r.read::<u8>()? {
+            0 => false,
+            1 => true,
+            _ => return Err(HyperError::DecodeError),
+        };
+        Ok(())
+    }
+}
+
+impl<T: Snapshot, const N: usize> Snapshot for [T; N] {
+    fn save(&self, w: &mut SnapshotWriter) {
+        self.iter().for_each(|item| item.save(w));
+    }
+
+    fn restore(&mut self, r: &mut SnapshotReader<'_>) -> HyperResult {
+        self.iter_mut().try_for_each(|item| item.restore(r))
+    }
+}
+
+impl<A: Snapshot, B: Snapshot> Snapshot for (A, B) {
+    fn save(&self, w: &mut SnapshotWriter) {
+        self.0.save(w);
+        self.1.save(w);
+    }
+
+    fn restore(&mut self, r: &mut SnapshotReader<'_>) -> HyperResult {
+        self.0.restore(r)?;
+        self.1.restore(r)
+    }
+}
+
+impl<T: Snapshot + Default> Snapshot for Option<T> {
+    fn save(&self, w: &mut SnapshotWriter) {
+        self.is_some().save(w);
+        if let Some(value) = self {
+            value.save(w);
+        }
+    }
+
+    fn restore(&mut self, r: &mut SnapshotReader<'_>) -> HyperResult {
+        *self = if r.read::<bool>()? {
+            Some(r.read()?)
+        } else {
+            None
+        };
+        Ok(())
+    }
+}
+
+impl<T: Snapshot + Default> Snapshot for Vec<T> {
+    fn save(&self, w: &mut SnapshotWriter) {
+        self.len().save(w);
+        self.iter().for_each(|item| item.save(w));
+    }
+
+    fn restore(&mut self, r: &mut SnapshotReader<'_>) -> HyperResult {
+        let len = r.read::<usize>()?;
+        // Each item takes at least a byte, which bounds a corrupted length.
+        if len > r.data.len() {
+            return Err(HyperError::DecodeError);
+        }
+        self.resize_with(len, T::default);
+        self.iter_mut().try_for_each(|item| item.restore(r))
+    }
+}
+
+impl<T: Snapshot + Default> Snapshot for VecDeque<T> {
+    fn save(&self, w: &mut SnapshotWriter) {
+        self.len().save(w);
+        self.iter().for_each(|item| item.save(w));
+    }
+
+    fn restore(&mut self, r: &mut SnapshotReader<'_>) -> HyperResult {
+        let len = r.read::<usize>()?;
+        if len > r.data.len() {
+            return Err(HyperError::DecodeError);
+        }
+        self.clear();
+        for _ in 0..len {
+            self.push_back(r.read()?);
+        }
+        Ok(())
+    }
+}
+
+/// Save the layout and the contents of the regions of `memory` as the RAM
+/// section.
+pub(crate) fn save_memory(memory: &GuestMemory, w: &mut SnapshotWriter) -> HyperResult {
+    w.section(SECTION_RAM, |w| {
+        memory.regions().len().save(w);
+        for region in memory.regions() {
+            region.gpa.save(w);
+            region.size.save(w);
+            memory.read(region.gpa, w.reserve(region.size))?;
+        }
+        Ok(())
+    })
+}
+
+/// Restore the RAM section into `memory`, whose regions must have the
+/// layout of the snapshot's. Otherwise it is `InvalidParam`.
+pub(crate) fn restore_memory(memory: &GuestMemory, r: &mut SnapshotReader<'_>) -> HyperResult {
+    r.section(SECTION_RAM, |r| {
+        if r.read::<usize>()? != memory.regions().len() {
+            return Err(HyperError::InvalidParam);
+        }
+        for region in memory.regions() {
+            let (gpa, size) = (r.read::<usize>()?, r.read::<usize>()?);
+            if (gpa, size) != (region.gpa, region.size) {
+                return Err(HyperError::InvalidParam);
+            }
+            memory.write(gpa, r.take(size)?)?;
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Default, PartialEq)]
+    struct State {
+        count: u32,
+        enabled: bool,
+        queue: VecDeque<u16>,
+        data: Vec<u8>,
+        limit: Option<u64>,
+    }
+
+    impl_snapshot_fields!(State {
+        count,
+        enabled,
+        queue,
+        data,
+        limit,
+    });
+
+    fn state() -> State {
+        State {
+            count: 7,
+            enabled: true,
+            queue: VecDeque::from([1, 2, 3]),
+            data: vec![0xaa; 5],
+            limit: Some(u64::MAX),
+        }
+    }
+
+    fn snapshot(f: impl FnOnce(&mut SnapshotWriter) -> HyperResult) -> Vec<u8> {
+        let mut w = SnapshotWriter::new();
+        w.section(SECTION_VM, f).unwrap();
+        w.finish()
+    }
+
+    /// A snapshot of `state()`.
+    fn saved() -> Vec<u8> {
+        snapshot(|w| {
+            state().save(w);
+            Ok(())
+        })
+    }
+
+    fn restore(data: &[u8]) -> HyperResult<State> {
+        let mut r = SnapshotReader::new(data)?;
+        let mut state = State::default();
+        r.section(SECTION_VM, |r| state.restore(r))?;
+        r.finish()?;
+        Ok(state)
+    }
+
+    /// The length of the saved `state()`.
+    fn state_len() -> usize {
+        let mut w = SnapshotWriter::new();
+        let header_len = w.buf.len();
+        state().save(&mut w);
+        w.finish().len() - header_len
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = saved();
+        assert_eq!(restore(&data), Ok(state()));
+    }
+
+    #[test]
+    fn corrupted_lengths() {
+        // A vector longer than the section.
+        let data = snapshot(|w| {
+            7u32.save(w);
+            true.save(w);
+            usize::MAX.save(w);
+            Ok(())
+        });
+        assert_eq!(restore(&data), Err(HyperError::DecodeError));
+
+        // A section longer than the snapshot.
+        let mut data = saved();
+        let len_offset = data.len() - state_len() - 8;
+        data[len_offset..len_offset + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert_eq!(restore(&data), Err(HyperError::DecodeError));
+
+        // A section with data left after its state.
+        let data = snapshot(|w| {
+            state().save(w);
+            0u8.save(w);
+            Ok(())
+        });
+        assert_eq!(restore(&data), Err(HyperError::DecodeError));
+
+        // A truncated snapshot.
+        let data = saved();
+        assert_eq!(
+            restore(&data[..data.len() - 1]),
+            Err(HyperError::DecodeError)
+        );
+    }
+
+    #[test]
+    fn bad_header() {
+        let mut data = saved();
+        data[0] = b'X';
+        assert_eq!(restore(&data), Err(HyperError::DecodeError));
+
+        let mut data = saved();
+        data[4..8].copy_from_slice(&(SNAPSHOT_VERSION + 1).to_le_bytes());
+        assert_eq!(restore(&data), Err(HyperError::NotSupported));
+
+        let mut data = saved();
+        data[8..12].copy_from_slice(&0u32.to_le_bytes());
+        assert_eq!(restore(&data), Err(HyperError::NotSupported));
+
+        assert_eq!(restore(b"HC"), Err(HyperError::DecodeError));
+    }
+}